@@ -84,14 +84,3 @@ pub fn get_disk_free<P: AsRef<Path>>(path: P) -> Result<ByteSize> {
 
     unreachable!()
 }
-
-pub fn get_paths_size<P>(paths: &[P]) -> Result<ByteSize>
-where
-    P: AsRef<Path>,
-{
-    let mut size = 0;
-    for path in paths {
-        size += path.as_ref().metadata()?.len();
-    }
-    Ok(ByteSize(size))
-}
@@ -9,18 +9,74 @@ use common::defines::{APP_CONFIG_PATH, TEMP_DIR_IN};
 
 #[cfg(not(feature = "android-proot"))]
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub enum NspExtractor {
+pub enum NcaExtractor {
     #[default]
     Hactoolnet,
-    Hactool,
+    Hac2l,
 }
 
-#[cfg(not(feature = "android-proot"))]
+/// Preferred language for the application names read from NACPs.
+///
+/// https://switchbrew.org/wiki/NACP#ApplicationTitle
+///
+/// Languages in the order of their title entries.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub enum NcaExtractor {
+#[cfg_attr(feature = "clap", derive(PartialOrd, Ord, clap::ValueEnum))]
+pub enum Language {
     #[default]
-    Hactoolnet,
-    Hac2l,
+    AmericanEnglish = 0,
+    BritishEnglish = 1,
+    Japanese = 2,
+    French = 3,
+    German = 4,
+    LatinAmericanSpanish = 5,
+    Spanish = 6,
+    Italian = 7,
+    Dutch = 8,
+    CanadianFrench = 9,
+    Portuguese = 10,
+    Russian = 11,
+    Korean = 12,
+    TraditionalChinese = 13,
+    SimplifiedChinese = 14,
+    BrazilianPortuguese = 15,
+}
+
+impl Language {
+    pub const ALL: [Language; 16] = [
+        Language::AmericanEnglish,
+        Language::BritishEnglish,
+        Language::Japanese,
+        Language::French,
+        Language::German,
+        Language::LatinAmericanSpanish,
+        Language::Spanish,
+        Language::Italian,
+        Language::Dutch,
+        Language::CanadianFrench,
+        Language::Portuguese,
+        Language::Russian,
+        Language::Korean,
+        Language::TraditionalChinese,
+        Language::SimplifiedChinese,
+        Language::BrazilianPortuguese,
+    ];
+}
+
+/// Format of the packages produced by update and pack.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Program NCAs are compressed to NCZs, making it a NSZ.
+    Nsz,
+}
+
+/// Same as nsz's default level.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 18;
+
+fn default_zstd_level() -> i32 {
+    DEFAULT_ZSTD_LEVEL
 }
 
 const SCHEMA_VERSION: u8 = 2;
@@ -29,11 +85,21 @@ const SCHEMA_VERSION: u8 = 2;
 pub struct Config {
     schema_version: u8,
     #[cfg(not(feature = "android-proot"))]
-    pub nsp_extractor: NspExtractor,
-    #[cfg(not(feature = "android-proot"))]
     pub nca_extractor: NcaExtractor,
     pub yanu_dir: Option<PathBuf>,
     pub temp_dir: PathBuf,
+    #[serde(default)]
+    pub language: Language,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default = "default_zstd_level")]
+    pub zstd_level: i32,
+    /// No. of blocks compressed in parallel, `0` uses all the available cores.
+    #[serde(default)]
+    pub compression_workers: usize,
+    /// Split the NSPs that are produced into FAT32 sized parts.
+    #[serde(default)]
+    pub split_output: bool,
     #[cfg(unix)]
     pub hacpack_rev: String,
     #[cfg(unix)]
@@ -42,8 +108,6 @@ pub struct Config {
     pub hac2l_rev: String,
     #[cfg(unix)]
     pub atmosphere_rev: String,
-    #[cfg(unix)]
-    pub four_nxci_rev: String,
 }
 
 impl Default for Config {
@@ -51,11 +115,14 @@ impl Default for Config {
         Self {
             schema_version: SCHEMA_VERSION,
             #[cfg(not(feature = "android-proot"))]
-            nsp_extractor: Default::default(),
-            #[cfg(not(feature = "android-proot"))]
             nca_extractor: Default::default(),
             yanu_dir: Default::default(),
             temp_dir: TEMP_DIR_IN.to_owned(),
+            language: Default::default(),
+            compression: Default::default(),
+            zstd_level: DEFAULT_ZSTD_LEVEL,
+            compression_workers: 0,
+            split_output: false,
             #[cfg(unix)]
             hacpack_rev: "7845e7be8d03a263c33430f9e8c2512f7c280c88".into(),
             #[cfg(unix)]
@@ -64,8 +131,6 @@ impl Default for Config {
             hac2l_rev: "7fc1b3a32c6a870c47d7459b23fd7c7b63014186".into(),
             #[cfg(unix)]
             atmosphere_rev: "1afb184c143f4319e5d6d4ea27260e61830c42a0".into(),
-            #[cfg(unix)]
-            four_nxci_rev: "33044e650ad58a72d231d9793383f5f279b73884".into(),
         }
     }
 }
@@ -29,18 +29,6 @@ pub enum BackendKind {
     ))]
     Hactoolnet,
     Hac2l,
-    FourNXCI,
-}
-
-#[cfg(not(feature = "android-proot"))]
-impl From<config::NspExtractor> for BackendKind {
-    fn from(value: config::NspExtractor) -> Self {
-        use config::NspExtractor;
-        match value {
-            NspExtractor::Hactoolnet => Self::Hactoolnet,
-            NspExtractor::Hactool => Self::Hactool,
-        }
-    }
 }
 
 #[cfg(not(feature = "android-proot"))]
@@ -63,10 +51,7 @@ impl fmt::Display for BackendKind {
 impl BackendKind {
     // This is important, don't remove it again!
     fn filename(&self) -> String {
-        let filename = match self {
-            BackendKind::FourNXCI => "4nxci".into(),
-            _ => format!("{}", self).to_lowercase(),
-        };
+        let filename = format!("{}", self).to_lowercase();
         #[cfg(unix)]
         {
             filename
@@ -98,7 +83,6 @@ impl Backend {
                     BackendKind::Hactool => cache.store_bytes(defines::HACTOOL, &filename)?,
                     BackendKind::Hactoolnet => cache.store_bytes(defines::HACTOOLNET, &filename)?,
                     BackendKind::Hac2l => cache.store_bytes(defines::HAC2L, &filename)?,
-                    BackendKind::FourNXCI => cache.store_bytes(defines::FOURNXCI, &filename)?,
                 }
             }
             #[cfg(unix)]
@@ -118,7 +102,6 @@ impl Backend {
                     BackendKind::Hactool => cache.store_bytes(defines::HACTOOL, &filename)?,
                     #[cfg(feature = "android-proot")]
                     BackendKind::Hac2l => cache.store_bytes(defines::HAC2L, &filename)?,
-                    BackendKind::FourNXCI => cache.store_bytes(defines::FOURNXCI, &filename)?,
                 };
                 set_executable_bit(&cached_path, true)?;
                 cached_path
@@ -148,7 +131,6 @@ impl Backend {
             )?)?,
             #[cfg(feature = "android-proot")]
             BackendKind::Hac2l => Backend::try_new(kind)?.path,
-            BackendKind::FourNXCI => cache.store_path(build::four_nxci(&cfg.four_nxci_rev)?)?,
         };
         set_executable_bit(&cached_path, true)?;
 
@@ -374,50 +356,6 @@ pub mod build {
         bail!("Failed to build {}", kind);
     }
 
-    pub fn four_nxci(rev: &str) -> Result<PathBuf> {
-        let kind = BackendKind::FourNXCI;
-        info!("Building {}", kind);
-        let src_dir = tempdir()?;
-
-        if !Command::new("git")
-            .args(["clone", "https://github.com/The-4n/4NXCI.git"])
-            .arg(src_dir.path())
-            .status()?
-            .success()
-        {
-            bail!("Failed to clone {} repo", kind)
-        }
-
-        git_checkout(src_dir.path(), rev)?;
-
-        info!("Renaming config file");
-        fs::rename(
-            src_dir.path().join("config.mk.template"),
-            src_dir.path().join("config.mk"),
-        )?;
-
-        info!("Running make");
-        if !Command::new("make")
-            .args([
-                "-j",
-                &(NPROC.as_ref().map_err(|err| eyre!(err))? / 2).to_string(),
-            ])
-            .current_dir(&src_dir)
-            .status()?
-            .success()
-        {
-            bail!("Failed to build {}", kind);
-        }
-
-        //* Moving bin from temp dir to cache dir
-        let filename = kind.filename();
-        fs_err::create_dir_all(APP_CACHE_DIR.as_path())?;
-        let dest = APP_CACHE_DIR.join(&filename);
-        move_file(src_dir.path().join(&filename), &dest)?;
-
-        Ok(dest)
-    }
-
     fn git_checkout<P: AsRef<Path>>(repo: P, rev: &str) -> Result<()> {
         if Command::new("git")
             .args(["checkout", rev])
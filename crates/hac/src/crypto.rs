@@ -0,0 +1,122 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use rsa::{BigUint, Pss, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+pub const AES_BLOCK_SIZE: usize = 0x10;
+
+/// AES-128-XTS as used by the Switch, the only difference from the standard
+/// being that the sector number is stored big-endian in the tweak.
+#[derive(Clone)]
+pub struct XtsCipher {
+    data: Aes128,
+    tweak: Aes128,
+}
+
+impl XtsCipher {
+    /// `key` is the data key followed by the tweak key.
+    pub fn new(key: &[u8; 0x20]) -> Self {
+        Self {
+            data: Aes128::new(GenericArray::from_slice(&key[..0x10])),
+            tweak: Aes128::new(GenericArray::from_slice(&key[0x10..])),
+        }
+    }
+    /// Decrypts `buf` in place, it's treated as consecutive sectors starting at `sector`.
+    pub fn decrypt(&self, buf: &mut [u8], sector_size: usize, sector: u64) {
+        self.apply(buf, sector_size, sector, |block| {
+            self.data.decrypt_block(GenericArray::from_mut_slice(block))
+        })
+    }
+    /// Encrypts `buf` in place, it's treated as consecutive sectors starting at `sector`.
+    pub fn encrypt(&self, buf: &mut [u8], sector_size: usize, sector: u64) {
+        self.apply(buf, sector_size, sector, |block| {
+            self.data.encrypt_block(GenericArray::from_mut_slice(block))
+        })
+    }
+    fn apply<F>(&self, buf: &mut [u8], sector_size: usize, sector: u64, cipher: F)
+    where
+        F: Fn(&mut [u8]),
+    {
+        debug_assert!(sector_size % AES_BLOCK_SIZE == 0);
+        for (i, sector_buf) in buf.chunks_mut(sector_size).enumerate() {
+            let mut tweak = [0u8; AES_BLOCK_SIZE];
+            tweak[8..].copy_from_slice(&(sector + i as u64).to_be_bytes());
+            self.tweak
+                .encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+
+            for block in sector_buf.chunks_exact_mut(AES_BLOCK_SIZE) {
+                xor(block, &tweak);
+                cipher(block);
+                xor(block, &tweak);
+                gf_double(&mut tweak);
+            }
+        }
+    }
+}
+
+/// Multiplies the tweak by α in GF(2^128), little-endian.
+fn gf_double(tweak: &mut [u8; AES_BLOCK_SIZE]) {
+    let carry = tweak[AES_BLOCK_SIZE - 1] >> 7;
+    for i in (1..AES_BLOCK_SIZE).rev() {
+        tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+    }
+    tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+}
+
+pub fn xor(buf: &mut [u8], with: &[u8]) {
+    buf.iter_mut().zip(with).for_each(|(a, b)| *a ^= b);
+}
+
+/// Decrypts `buf` in place with AES-128-ECB, `buf` must be block aligned.
+pub fn aes_ecb_decrypt(key: &[u8; 0x10], buf: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for block in buf.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+/// Encrypts `buf` in place with AES-128-ECB, `buf` must be block aligned.
+pub fn aes_ecb_encrypt(key: &[u8; 0x10], buf: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for block in buf.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+/// Applies AES-128-CTR to `buf` in place, it works both ways.
+///
+/// The counter is `upper_counter` followed by the block number, both big-endian,
+/// where `offset` is the position of `buf` in the stream, i.e. as used by NCA sections.
+pub fn aes_ctr_apply(key: &[u8; 0x10], upper_counter: u64, offset: u64, buf: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    let mut block_index = offset / AES_BLOCK_SIZE as u64;
+    let mut skip = (offset % AES_BLOCK_SIZE as u64) as usize;
+    let mut buf = buf;
+    while !buf.is_empty() {
+        let mut keystream = [0u8; AES_BLOCK_SIZE];
+        keystream[..8].copy_from_slice(&upper_counter.to_be_bytes());
+        keystream[8..].copy_from_slice(&block_index.to_be_bytes());
+        cipher.encrypt_block(GenericArray::from_mut_slice(&mut keystream));
+
+        let len = buf.len().min(AES_BLOCK_SIZE - skip);
+        let (chunk, rest) = buf.split_at_mut(len);
+        xor(chunk, &keystream[skip..]);
+        buf = rest;
+        skip = 0;
+        block_index += 1;
+    }
+}
+
+/// Verifies a RSA-2048-PSS signature over `data`, with SHA-256 and a salt as long as the hash.
+///
+/// The public exponent is always 65537 for the keys used by the Switch.
+pub fn rsa2048_pss_verify(modulus: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let Ok(key) = RsaPublicKey::new(BigUint::from_bytes_be(modulus), BigUint::from(0x10001u32))
+    else {
+        return false;
+    };
+    key.verify(Pss::new::<Sha256>(), &Sha256::digest(data), signature)
+        .is_ok()
+}
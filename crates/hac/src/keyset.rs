@@ -0,0 +1,503 @@
+use crate::crypto::aes_ecb_decrypt;
+use common::defines::DEFAULT_PRODKEYS_PATH;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{collections::HashMap, fmt, path::Path};
+use tracing::{debug, info};
+
+pub type Key128 = [u8; 0x10];
+pub type Key256 = [u8; 0x20];
+
+/// No. of master key revisions that are looked for, i.e. `master_key_00` to `master_key_1f`.
+pub const MASTER_KEY_REVISIONS: usize = 0x20;
+/// No. of NCA header fixed key generations, i.e. `nca_hdr_fixed_key_modulus_00` and `_01`.
+pub const NCA_HEADER_KEY_GENERATIONS: usize = 2;
+
+/// https://switchbrew.org/wiki/NCA#Header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAreaKeyIndex {
+    Application = 0x00,
+    Ocean = 0x01,
+    System = 0x02,
+}
+
+impl KeyAreaKeyIndex {
+    const ALL: [KeyAreaKeyIndex; 3] = [
+        KeyAreaKeyIndex::Application,
+        KeyAreaKeyIndex::Ocean,
+        KeyAreaKeyIndex::System,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        KeyAreaKeyIndex::ALL.get(byte as usize).copied()
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            KeyAreaKeyIndex::Application => "application",
+            KeyAreaKeyIndex::Ocean => "ocean",
+            KeyAreaKeyIndex::System => "system",
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/Cryptosystem
+///
+/// Keys parsed from a `prod.keys` keyfile, with the missing ones derived from
+/// their sources and the master keys wherever possible.
+#[derive(Default, Clone)]
+pub struct Keyset {
+    header_key: Option<Key256>,
+    master_keys: [Option<Key128>; MASTER_KEY_REVISIONS],
+    title_keks: [Option<Key128>; MASTER_KEY_REVISIONS],
+    key_area_keys: [[Option<Key128>; MASTER_KEY_REVISIONS]; 3],
+    /// RSA-2048 public key moduli for the first NCA header signature.
+    nca_header_moduli: [Option<Vec<u8>>; NCA_HEADER_KEY_GENERATIONS],
+}
+
+impl fmt::Debug for Keyset {
+    // Not printing the keys themselves
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyset")
+            .field("header_key", &self.header_key.is_some())
+            .field("master_keys", &present_revisions(&self.master_keys))
+            .field("title_keks", &present_revisions(&self.title_keks))
+            .field(
+                "key_area_keys",
+                &self
+                    .key_area_keys
+                    .iter()
+                    .map(|keys| present_revisions(keys))
+                    .collect::<Vec<_>>(),
+            )
+            .field(
+                "nca_header_moduli",
+                &self
+                    .nca_header_moduli
+                    .iter()
+                    .map(|modulus| modulus.is_some())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+fn present_revisions(keys: &[Option<Key128>]) -> Vec<u8> {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, key)| key.is_some())
+        .map(|(revision, _)| revision as u8)
+        .collect()
+}
+
+/// Expected size of the keys that are used, `None` for the rest.
+fn expected_key_size(name: &str) -> Option<usize> {
+    match name {
+        "header_key" | "header_key_source" => return Some(0x20),
+        "header_kek_source"
+        | "titlekek_source"
+        | "aes_kek_generation_source"
+        | "aes_key_generation_source"
+        | "key_area_key_application_source"
+        | "key_area_key_ocean_source"
+        | "key_area_key_system_source" => return Some(0x10),
+        _ => {}
+    }
+    match split_revision(name) {
+        Some((
+            "master_key"
+            | "titlekek"
+            | "key_area_key_application"
+            | "key_area_key_ocean"
+            | "key_area_key_system",
+            _,
+        )) => Some(0x10),
+        Some(("nca_hdr_fixed_key_modulus", _)) => Some(0x100),
+        _ => None,
+    }
+}
+
+/// Splits `master_key_0a` into `("master_key", 0x0a)`.
+fn split_revision(name: &str) -> Option<(&str, usize)> {
+    let (base, revision) = name.rsplit_once('_')?;
+    if revision.len() != 2 {
+        return None;
+    }
+    Some((base, usize::from_str_radix(revision, 16).ok()?))
+}
+
+/// https://switchbrew.org/wiki/Cryptosystem#Keys
+///
+/// `generate_kek` as it's called by the other tools.
+fn generate_kek(
+    source: &Key128,
+    master_key: &Key128,
+    kek_seed: &Key128,
+    key_seed: Option<&Key128>,
+) -> Key128 {
+    let mut kek = *kek_seed;
+    aes_ecb_decrypt(master_key, &mut kek);
+    let mut src_kek = *source;
+    aes_ecb_decrypt(&kek, &mut src_kek);
+    if let Some(key_seed) = key_seed {
+        let mut key = *key_seed;
+        aes_ecb_decrypt(&src_kek, &mut key);
+        key
+    } else {
+        src_kek
+    }
+}
+
+impl Keyset {
+    /// Reads the keyfile at `DEFAULT_PRODKEYS_PATH`.
+    pub fn try_default() -> Result<Self> {
+        Keyset::from_file(DEFAULT_PRODKEYS_PATH.as_path())
+    }
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())?;
+        Keyset::parse(&content)
+            .map_err(|err| eyre!("Failed to parse '{}': {}", path.as_ref().display(), err))
+    }
+    /// Parses the `key_name = hex` format, lines starting with `;` or `#` are ignored.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries: HashMap<String, Vec<u8>> = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| eyre!("line {}: expected 'key_name = value'", i + 1))?;
+            let name = name.trim().to_lowercase();
+            let value = hex::decode(value.trim())
+                .map_err(|err| eyre!("line {}: '{}' isn't valid hex, {}", i + 1, name, err))?;
+            if let Some(size) = expected_key_size(&name) {
+                if value.len() != size {
+                    bail!(
+                        "line {}: '{}' should be {} bytes long but it's {} bytes",
+                        i + 1,
+                        name,
+                        size,
+                        value.len()
+                    );
+                }
+            }
+            entries.insert(name, value);
+        }
+
+        let key128 = |name: &str| -> Option<Key128> {
+            entries
+                .get(name)
+                .map(|key| key.as_slice().try_into().unwrap())
+        };
+        let mut keyset = Keyset::default();
+        for (name, value) in &entries {
+            let Some((base, revision)) = split_revision(name) else {
+                continue;
+            };
+            if revision >= MASTER_KEY_REVISIONS {
+                debug!(key = %name, "Ignoring key with an out of range revision");
+                continue;
+            }
+            if base == "nca_hdr_fixed_key_modulus" {
+                if let Some(modulus) = keyset.nca_header_moduli.get_mut(revision) {
+                    *modulus = Some(value.clone());
+                }
+                continue;
+            }
+            let key = match expected_key_size(name) {
+                Some(_) => Some(value.as_slice().try_into().unwrap()),
+                None => continue,
+            };
+            match base {
+                "master_key" => keyset.master_keys[revision] = key,
+                "titlekek" => keyset.title_keks[revision] = key,
+                _ => {
+                    for index in KeyAreaKeyIndex::ALL {
+                        if base == format!("key_area_key_{}", index.name()) {
+                            keyset.key_area_keys[index as usize][revision] = key;
+                        }
+                    }
+                }
+            }
+        }
+        keyset.header_key = entries
+            .get("header_key")
+            .map(|key| key.as_slice().try_into().unwrap());
+
+        // Deriving the missing keys
+        let kek_seed = key128("aes_kek_generation_source");
+        let key_seed = key128("aes_key_generation_source");
+        for revision in 0..MASTER_KEY_REVISIONS {
+            let Some(master_key) = keyset.master_keys[revision] else {
+                continue;
+            };
+            if keyset.title_keks[revision].is_none() {
+                if let Some(mut title_kek) = key128("titlekek_source") {
+                    aes_ecb_decrypt(&master_key, &mut title_kek);
+                    keyset.title_keks[revision] = Some(title_kek);
+                }
+            }
+            if let Some(kek_seed) = kek_seed.as_ref() {
+                for index in KeyAreaKeyIndex::ALL {
+                    let key_area_key = &mut keyset.key_area_keys[index as usize][revision];
+                    if key_area_key.is_none() {
+                        if let Some(source) =
+                            key128(&format!("key_area_key_{}_source", index.name()))
+                        {
+                            *key_area_key = Some(generate_kek(
+                                &source,
+                                &master_key,
+                                kek_seed,
+                                key_seed.as_ref(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        if keyset.header_key.is_none() {
+            if let (
+                Some(header_kek_source),
+                Some(header_key_source),
+                Some(master_key),
+                Some(kek_seed),
+            ) = (
+                key128("header_kek_source"),
+                entries.get("header_key_source"),
+                keyset.master_keys[0],
+                kek_seed,
+            ) {
+                let header_kek = generate_kek(
+                    &header_kek_source,
+                    &master_key,
+                    &kek_seed,
+                    key_seed.as_ref(),
+                );
+                let mut header_key: Key256 = header_key_source.as_slice().try_into().unwrap();
+                aes_ecb_decrypt(&header_kek, &mut header_key);
+                keyset.header_key = Some(header_key);
+            }
+        }
+
+        debug!(?keyset);
+        Ok(keyset)
+    }
+    /// Checks that the keys needed for NCAs are present, i.e. the header key
+    /// and at least one generation of application key area keys and title KEKs.
+    pub fn validate(&self) -> Result<()> {
+        self.header_key()?;
+        if self.key_area_keys[KeyAreaKeyIndex::Application as usize]
+            .iter()
+            .all(|key| key.is_none())
+        {
+            bail!(
+                "No 'key_area_key_application_XX' keys were found, they can't be derived without \
+                'master_key_XX', 'aes_kek_generation_source', 'aes_key_generation_source' \
+                and 'key_area_key_application_source'"
+            );
+        }
+        if self.title_keks.iter().all(|key| key.is_none()) {
+            bail!(
+                "No 'titlekek_XX' keys were found, they can't be derived without \
+                'master_key_XX' and 'titlekek_source'"
+            );
+        }
+        Ok(())
+    }
+    /// Validates the keyfile and copies it to `DEFAULT_PRODKEYS_PATH`.
+    pub fn import<P: AsRef<Path>>(keyfile: P) -> Result<Self> {
+        let keyset = Keyset::from_file(keyfile.as_ref())?;
+        keyset
+            .validate()
+            .map_err(|err| eyre!("'{}' is missing keys: {}", keyfile.as_ref().display(), err))?;
+
+        let dest = DEFAULT_PRODKEYS_PATH.as_path();
+        fs::create_dir_all(
+            dest.parent()
+                .ok_or_else(|| eyre!("Failed to find parent"))?,
+        )?;
+        fs::copy(keyfile.as_ref(), dest)?;
+        info!(
+            keyfile = %keyfile.as_ref().display(),
+            master_key_revisions = %keyset.master_key_revisions_fmt(),
+            "Imported keyfile"
+        );
+
+        Ok(keyset)
+    }
+    pub fn header_key(&self) -> Result<&Key256> {
+        self.header_key.as_ref().ok_or_else(|| {
+            eyre!(
+                "'header_key' wasn't found, it can't be derived without 'header_kek_source', \
+                'header_key_source', 'master_key_00', 'aes_kek_generation_source' \
+                and 'aes_key_generation_source'"
+            )
+        })
+    }
+    pub fn title_kek(&self, revision: u8) -> Result<&Key128> {
+        self.title_keks
+            .get(revision as usize)
+            .and_then(|key| key.as_ref())
+            .ok_or_else(|| eyre!("'titlekek_{:02x}' wasn't found", revision))
+    }
+    pub fn key_area_key(&self, index: KeyAreaKeyIndex, revision: u8) -> Result<&Key128> {
+        self.key_area_keys[index as usize]
+            .get(revision as usize)
+            .and_then(|key| key.as_ref())
+            .ok_or_else(|| {
+                eyre!(
+                    "'key_area_key_{}_{:02x}' wasn't found",
+                    index.name(),
+                    revision
+                )
+            })
+    }
+    /// Modulus of the fixed key that the first NCA header signature is made with,
+    /// `generation` being the SignatureKeyGeneration of the header.
+    pub fn nca_header_modulus(&self, generation: u8) -> Result<&[u8]> {
+        self.nca_header_moduli
+            .get(generation as usize)
+            .and_then(|modulus| modulus.as_deref())
+            .ok_or_else(|| {
+                eyre!(
+                    "'nca_hdr_fixed_key_modulus_{:02x}' wasn't found",
+                    generation
+                )
+            })
+    }
+    /// Revisions of the master keys that are present.
+    pub fn master_key_revisions(&self) -> Vec<u8> {
+        present_revisions(&self.master_keys)
+    }
+    /// For eg. `00-0f`, or `none`.
+    pub fn master_key_revisions_fmt(&self) -> String {
+        let revisions = self.master_key_revisions();
+        match (revisions.first(), revisions.last()) {
+            (Some(first), Some(last)) if (*last - *first) as usize + 1 == revisions.len() => {
+                format!("{:02x}-{:02x}", first, last)
+            }
+            (Some(_), Some(_)) => revisions
+                .iter()
+                .map(|revision| format!("{:02x}", revision))
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => "none".into(),
+        }
+    }
+}
+
+/// Made up keys for the tests, they're only good for round-trips.
+#[cfg(test)]
+pub(crate) const TEST_KEYS: &str = "\
+header_key = 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f
+key_area_key_application_00 = 202122232425262728292a2b2c2d2e2f
+titlekek_00 = 303132333435363738393a3b3c3d3e3f
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keyfile() -> Result<()> {
+        let keyset = Keyset::parse(&format!(
+            "; comment\n# another comment\n\nMASTER_KEY_01 = {}\n{}unknown_key = abcd\n",
+            "40".repeat(0x10),
+            TEST_KEYS
+        ))?;
+
+        assert_eq!(keyset.header_key()?[..4], [0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(
+            keyset.key_area_key(KeyAreaKeyIndex::Application, 0)?[0],
+            0x20
+        );
+        assert_eq!(keyset.title_kek(0)?[0], 0x30);
+        assert!(keyset.title_kek(1).is_err());
+        assert!(keyset.key_area_key(KeyAreaKeyIndex::Ocean, 0).is_err());
+        assert_eq!(keyset.master_key_revisions(), vec![1]);
+        keyset.validate()?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_bad_lines() {
+        let err = Keyset::parse("titlekek_00 = 3031").unwrap_err();
+        assert!(err.to_string().contains("should be 16 bytes"), "{}", err);
+        assert!(Keyset::parse("titlekek_00 = zz").is_err());
+        assert!(Keyset::parse("titlekek_00").is_err());
+    }
+
+    #[test]
+    fn derive_missing_keys() -> Result<()> {
+        let master_key = [0x40; 0x10];
+        let titlekek_source = [0x50; 0x10];
+        let keyset = Keyset::parse(&format!(
+            "master_key_00 = {}\ntitlekek_source = {}\n",
+            hex::encode(master_key),
+            hex::encode(titlekek_source)
+        ))?;
+
+        let mut title_kek = titlekek_source;
+        aes_ecb_decrypt(&master_key, &mut title_kek);
+        assert_eq!(keyset.title_kek(0)?, &title_kek);
+        // Not enough sources for these
+        assert!(keyset.header_key().is_err());
+        assert!(keyset.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn derive_keys_known_vectors() -> Result<()> {
+        // Sources are 00 01 02.. 10 11 12.. and so on, expected keys worked out with
+        // a separate AES implementation following switchbrew
+        let seq = |start: u8, len: u8| hex::encode((start..start + len).collect::<Vec<_>>());
+        let keyset = Keyset::parse(&format!(
+            "master_key_00 = {}\n\
+             aes_kek_generation_source = {}\n\
+             aes_key_generation_source = {}\n\
+             key_area_key_application_source = {}\n\
+             header_kek_source = {}\n\
+             header_key_source = {}\n",
+            seq(0x00, 0x10),
+            seq(0x10, 0x10),
+            seq(0x20, 0x10),
+            seq(0x30, 0x10),
+            seq(0x40, 0x10),
+            seq(0x50, 0x20),
+        ))?;
+
+        assert_eq!(
+            hex::encode(keyset.key_area_key(KeyAreaKeyIndex::Application, 0)?),
+            "f3c664ff72e5e68c817eb5cd32faec02"
+        );
+        assert_eq!(
+            hex::encode(keyset.header_key()?),
+            "5f60621d41fb4c84d6dc1802cef83e807342240eb1fa274ba406e78b4b798094"
+        );
+        // No sources for these
+        assert!(keyset.key_area_key(KeyAreaKeyIndex::Ocean, 0).is_err());
+        assert!(keyset.title_kek(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn master_key_revisions_fmt() -> Result<()> {
+        let keyfile = |revisions: &[u8]| {
+            revisions
+                .iter()
+                .map(|revision| format!("master_key_{:02x} = {}\n", revision, "40".repeat(0x10)))
+                .collect::<String>()
+        };
+        assert_eq!(Keyset::parse("")?.master_key_revisions_fmt(), "none");
+        assert_eq!(
+            Keyset::parse(&keyfile(&[0, 1, 2]))?.master_key_revisions_fmt(),
+            "00-02"
+        );
+        assert_eq!(
+            Keyset::parse(&keyfile(&[0, 2, 0x10]))?.master_key_revisions_fmt(),
+            "00,02,10"
+        );
+        Ok(())
+    }
+}
@@ -9,5 +9,7 @@
 compile_error!("This traget configuration is not supported");
 
 pub mod backend;
+pub mod crypto;
+pub mod keyset;
 pub mod utils;
 pub mod vfs;
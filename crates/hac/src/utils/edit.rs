@@ -0,0 +1,148 @@
+use common::{defines::DEFAULT_PRODKEYS_PATH, utils::move_file};
+use config::Config;
+use eyre::{bail, eyre, Result};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+use crate::{
+    backend::{Backend, BackendKind},
+    keyset::Keyset,
+    utils::nca_ticket,
+    vfs::{
+        nacp::{get_nacp_file, Language, Nacp, StartupUserAccount},
+        nca::{self, Nca},
+    },
+};
+
+/// NACP fields to change, the ones left as `None` are kept as is.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NacpEdit {
+    pub name: Option<String>,
+    pub publisher: Option<String>,
+    /// Title entries to set `name`/`publisher` for,
+    /// all the non-empty ones are used if it's empty.
+    pub languages: Vec<Language>,
+    pub display_version: Option<String>,
+    pub startup_user_account: Option<StartupUserAccount>,
+}
+
+impl NacpEdit {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.publisher.is_none()
+            && self.display_version.is_none()
+            && self.startup_user_account.is_none()
+    }
+    pub fn apply(&self, nacp: &mut Nacp) -> Result<()> {
+        if self.name.is_some() || self.publisher.is_some() {
+            let mut languages = if self.languages.is_empty() {
+                Language::ALL
+                    .into_iter()
+                    .filter(|language| !nacp.title(*language).is_empty())
+                    .collect()
+            } else {
+                self.languages.clone()
+            };
+            if languages.is_empty() {
+                languages.push(Language::default());
+            }
+            for language in languages {
+                nacp.set_title(language, self.name.as_deref(), self.publisher.as_deref())?;
+            }
+        }
+        if let Some(version) = &self.display_version {
+            nacp.set_display_version(version)?;
+        }
+        if let Some(startup_user_account) = self.startup_user_account {
+            nacp.startup_user_account = startup_user_account;
+        }
+        Ok(())
+    }
+}
+
+/// Edit `control.nacp` of an unpacked Control RomFS in place.
+pub fn edit_nacp_dir<P: AsRef<Path>>(romfs_dir: P, edit: &NacpEdit) -> Result<Nacp> {
+    if edit.is_empty() {
+        bail!("Nothing to edit in the NACP");
+    }
+    let nacp_path = get_nacp_file(romfs_dir.as_ref()).ok_or_else(|| {
+        eyre!(
+            "Couldn't find NACP file in '{}'",
+            romfs_dir.as_ref().display()
+        )
+    })?;
+    let mut nacp = Nacp::try_new(&nacp_path)?;
+    edit.apply(&mut nacp)?;
+    debug!(?nacp, "Edited NACP");
+    nacp.save(&nacp_path)?;
+    Ok(nacp)
+}
+
+/// Edit the NACP of a Control NCA, the rebuilt NCA is placed in `outdir`
+/// and can be used with `pack_fs_data`.
+///
+/// Control NCAs with a RightsID need their `ticket`, by default the one next to the NCA
+/// is used. The rebuilt NCA doesn't need it anymore.
+pub fn edit_control_nca<N, O>(
+    control_path: N,
+    ticket: Option<&Path>,
+    edit: &NacpEdit,
+    outdir: O,
+    cfg: &Config,
+) -> Result<(Nca, Nacp)>
+where
+    N: AsRef<Path>,
+    O: AsRef<Path>,
+{
+    let keyset = Keyset::try_default()?;
+    let header_key = keyset.header_key()?;
+    #[cfg(not(feature = "android-proot"))]
+    let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
+    #[cfg(feature = "android-proot")]
+    let nca_extractor = Backend::try_new(BackendKind::Hac2l)?;
+    let packer = Backend::try_new(BackendKind::Hacpack)?;
+
+    // Validating NCA as Control Type
+    let control_nca = Nca::try_new(header_key, control_path.as_ref())
+        .ok()
+        .filter(|nca| nca.header.content_type == nca::ContentType::Control)
+        .ok_or_else(|| {
+            eyre!(
+                "'{}' is not a Control Type NCA",
+                control_path.as_ref().display()
+            )
+        })?;
+    let ticket = nca_ticket(&control_nca, ticket)?;
+
+    let control_romfs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+    control_nca.unpack_romfs(
+        &nca_extractor,
+        &keyset,
+        ticket.as_ref().map(|(_, title_key)| title_key),
+        control_romfs_dir.path(),
+    )?;
+    let nacp = edit_nacp_dir(control_romfs_dir.path(), edit)?;
+
+    let temp_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+    let mut edited_nca = Nca::pack_control(
+        header_key,
+        &packer,
+        &control_nca.get_program_id(),
+        DEFAULT_PRODKEYS_PATH.as_path(),
+        control_romfs_dir.path(),
+        temp_dir.path(),
+        &cfg.temp_dir,
+    )?;
+
+    let dest: PathBuf = outdir.as_ref().join(
+        edited_nca
+            .path
+            .file_name()
+            .expect("File should've a filename"),
+    );
+    move_file(&edited_nca.path, &dest)?;
+    edited_nca.path = dest;
+    info!(nca = %edited_nca.path.display(), "Edited Control NCA");
+
+    Ok((edited_nca, nacp))
+}
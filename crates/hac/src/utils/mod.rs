@@ -1,123 +1,400 @@
+pub mod edit;
 pub mod pack;
 pub mod unpack;
 pub mod update;
+pub mod verify;
 
-use crate::vfs::{nacp::NacpData, ticket::TitleKey};
-use common::{
-    defines::{DEFAULT_TITLEKEYS_PATH, SWITCH_DIR},
-    error::MultiReport,
-    utils::move_file,
+use crate::{
+    backend::Backend,
+    keyset::Keyset,
+    vfs::{
+        cnmt::{self, Cnmt},
+        exefs::ExeFs,
+        nacp::{get_nacp_file, Language, Nacp},
+        nca::{nca_with_kind, Nca},
+        ncz::NczWriter,
+        nsp::Nsp,
+        split::move_parts,
+        ticket::TitleKey,
+    },
 };
+use common::utils::ext_matches;
+use config::{Compression, Config};
 use eyre::{bail, eyre, Result};
 use fs_err as fs;
-use std::{
-    io::{self, ErrorKind},
-    path::PathBuf,
-};
+use std::path::{Path, PathBuf};
 use tracing::{info, warn};
 
-pub fn clear_titlekeys() -> Result<()> {
-    match fs::remove_file(DEFAULT_TITLEKEYS_PATH.as_path()) {
-        Ok(_) => Ok(()),
-        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+/// Renames the NSP to `<name> [<program_id>][v<version>]<suffix>.nsp`, the name
+/// is in the given `language` if the NACP has it. NSZs and split NSPs keep their extension.
+pub fn formatted_nsp_rename(
+    nsp_path: &mut PathBuf,
+    nacp: &Nacp,
+    program_id: &str,
+    suffix: &str,
+    language: Language,
+) -> Result<()> {
+    let ext = nsp_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("nsp")
+        .to_ascii_lowercase();
+    let dest = nsp_path
+        .parent()
+        .ok_or_else(|| eyre!("Failed to get parent"))?
+        .join(format!(
+            "{} [{}][v{}]{suffix}.{ext}",
+            nacp.get_application_name(language),
+            program_id,
+            nacp.get_application_version()
+        ));
+
+    info!(from = %nsp_path.display(), to = %dest.display(), "Moving");
+    move_parts(&nsp_path, &dest)?;
+    *nsp_path = dest;
+
+    Ok(())
+}
+
+/// Reads the NACP and the icon in the `preferred` language from the Control NCA of the NSP,
+/// only the Meta and Control NCAs are extracted for it so it's quick even for large NSPs.
+pub fn nsp_control_data<P: AsRef<Path>>(
+    nsp: &mut Nsp,
+    preferred: Language,
+    tempdir_in: P,
+) -> Result<(Nacp, Option<Vec<u8>>)> {
+    let keyset = Keyset::try_default()?;
+    let header_key = keyset.header_key()?;
+    if let Err(err) = nsp.derive_title_key() {
+        warn!(?err);
+    }
+
+    let pfs0 = nsp.pfs0()?;
+    let data_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+    for entry in pfs0
+        .entries
+        .iter()
+        .filter(|entry| entry.name.ends_with(".cnmt.nca"))
+    {
+        nsp.extract_entry(entry, data_dir.path())?;
+    }
+    let (_, cnmt) = Cnmt::from_dir(header_key, &keyset, data_dir.path())?;
+    let record = cnmt
+        .contents(cnmt::ContentType::Control)
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre!("CNMT of '{}' has no Control content", nsp.path.display()))?;
+    // Could be compressed, in which case it's extracted as a NCA anyways
+    let content_id = hex::encode(record.content_id);
+    let entry = pfs0
+        .entries
+        .iter()
+        .find(|entry| Path::new(&entry.name).file_stem() == Some(content_id.as_ref()))
+        .ok_or_else(|| {
+            eyre!(
+                "Couldn't find Control NCA '{}' in '{}'",
+                record.nca_filename(),
+                nsp.path.display()
+            )
+        })?;
+    nsp.extract_entry(entry, data_dir.path())?;
+    let control = cnmt.content_nca(header_key, data_dir.path(), cnmt::ContentType::Control)?;
+
+    let nacp = Nacp::from_control_nca(&control, &keyset, nsp.title_key.as_ref())?;
+    let icon =
+        match Nacp::icon_from_control_nca(&control, &keyset, nsp.title_key.as_ref(), preferred) {
+            Ok(icon) => Some(icon),
+            Err(err) => {
+                warn!(%err);
+                None
+            }
+        };
+    Ok((nacp, icon))
+}
+
+/// Reads the CNMT of the unpacked NSP in `dir`, if there's a usable one.
+pub(crate) fn read_cnmt<P: AsRef<Path>>(
+    header_key: &[u8; 0x20],
+    keyset: &Keyset,
+    dir: P,
+) -> Option<Cnmt> {
+    match Cnmt::from_dir(header_key, keyset, dir.as_ref()) {
+        Ok((_, cnmt)) => Some(cnmt),
         Err(err) => {
-            bail!(err)
+            warn!(%err, dir = %dir.as_ref().display(), "Failed to read CNMT");
+            None
+        }
+    }
+}
+
+/// Finds the NCA of `content_type` in `dir` from the records of the `cnmt`,
+/// falls back to the largest NCA of that type if there's no CNMT or it's not usable.
+pub(crate) fn content_nca<P: AsRef<Path>>(
+    header_key: &[u8; 0x20],
+    cnmt: Option<&Cnmt>,
+    dir: P,
+    content_type: cnmt::ContentType,
+) -> Option<Nca> {
+    if let Some(cnmt) = cnmt {
+        match cnmt.content_nca(header_key, dir.as_ref(), content_type) {
+            Ok(nca) => return Some(nca),
+            Err(err) => warn!(%err, "Falling back to guessing the NCA by its size"),
         }
     }
+    nca_with_kind(header_key, dir.as_ref(), content_type.nca_content_type())
+        .map(|mut ncas| ncas.remove(0))
 }
 
-/// Store TitleKeys to `DEFAULT_TITLEKEYS_PATH`.
-pub fn store_titlekeys<'a, I>(keys: I) -> Result<()>
+/// Finds the NCA of every `content_type` record of the `cnmt` in `dir` along with its id offset,
+/// ordered by it. Falls back to all the NCAs of the type in `dir` if there's no CNMT
+/// or it's not usable, the id offsets are then told from their ProgramIDs.
+pub(crate) fn content_ncas<P: AsRef<Path>>(
+    header_key: &[u8; 0x20],
+    cnmt: Option<&Cnmt>,
+    dir: P,
+    content_type: cnmt::ContentType,
+) -> Vec<(Nca, u8)> {
+    if let Some(cnmt) = cnmt {
+        match cnmt.content_ncas(header_key, dir.as_ref(), content_type) {
+            Ok(ncas) => return ncas,
+            Err(err) => {
+                warn!(%err, "Falling back to finding the {:?} NCAs by their type", content_type)
+            }
+        }
+    }
+    let ncas = nca_with_kind(header_key, dir.as_ref(), content_type.nca_content_type())
+        .unwrap_or_default();
+    // The programs are at an offset from the ApplicationID, i.e. the ProgramID of the first one
+    let Some(application_id) = ncas
+        .iter()
+        .map(|nca| u64::from_be_bytes(nca.header.program_id))
+        .min()
+    else {
+        return vec![];
+    };
+    let mut ncas = ncas
+        .into_iter()
+        .filter_map(|nca| {
+            match u8::try_from(u64::from_be_bytes(nca.header.program_id) - application_id) {
+                Ok(id_offset) => Some((nca, id_offset)),
+                Err(_) => {
+                    warn!(nca = %nca.path.display(), program_id = %nca.get_program_id(), "ProgramID is out of range of the ApplicationID, skipping it");
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    ncas.sort_by_key(|(_, id_offset)| *id_offset);
+    ncas
+}
+
+/// Reads the NACP straight from a Control NCA, falling back to unpacking its RomFS
+/// with the `extractor` which is given the TitleKeys.
+pub(crate) fn control_nacp<'a, I, P>(
+    control: &Nca,
+    keyset: &Keyset,
+    title_keys: I,
+    extractor: &Backend,
+    tempdir_in: P,
+) -> Result<Nacp>
 where
-    I: Iterator<Item = &'a TitleKey>,
+    I: IntoIterator<Item = &'a TitleKey>,
+    P: AsRef<Path>,
 {
-    info!(keyfile = ?SWITCH_DIR, "Storing TitleKeys");
-    fs::create_dir_all(SWITCH_DIR.as_path())?;
-    fs::write(
-        DEFAULT_TITLEKEYS_PATH.as_path(),
-        keys.map(|key| key.to_string())
-            .collect::<Vec<_>>()
-            .join("\n")
-            + "\n",
+    let title_keys = title_keys.into_iter().collect::<Vec<_>>();
+    match Nacp::from_control_nca(control, keyset, title_keys.iter().copied()) {
+        Ok(nacp) => return Ok(nacp),
+        Err(err) => warn!(%err, "Failed to read NACP from NCA, unpacking its RomFS instead"),
+    }
+    let control_romfs_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+    control.unpack_romfs(extractor, keyset, title_keys, control_romfs_dir.path())?;
+    Nacp::try_new(
+        get_nacp_file(control_romfs_dir.path()).ok_or_else(|| {
+            eyre!("Couldn't find NACP file, should be due to improper extraction")
+        })?,
     )
-    .map_err(|err| eyre!(err))
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct CleanupDirsOnDrop {
-    dirs: Vec<PathBuf>,
-}
+/// Finds the ticket for the RightsID of the `nca`, i.e. the given `ticket` or else one of
+/// the `.tik` files next to the NCA, which is where `unpack` leaves them.
+/// NCAs without a RightsID don't need one.
+pub(crate) fn nca_ticket(nca: &Nca, ticket: Option<&Path>) -> Result<Option<(PathBuf, TitleKey)>> {
+    if !nca.header.has_rights_id() {
+        return Ok(None);
+    }
+    let rights_id = hex::encode(nca.header.rights_id);
+    if let Some(ticket) = ticket {
+        let title_key = TitleKey::try_new(ticket)?;
+        if title_key.rights_id() != &nca.header.rights_id {
+            bail!(
+                "Ticket '{}' isn't for RightsID '{}' of '{}'",
+                ticket.display(),
+                rights_id,
+                nca.path.display()
+            );
+        }
+        return Ok(Some((ticket.to_owned(), title_key)));
+    }
 
-impl CleanupDirsOnDrop {
-    pub fn new<I: IntoIterator<Item = PathBuf>>(dirs: I) -> Self {
-        Self {
-            dirs: dirs.into_iter().collect(),
+    let dir = nca
+        .path
+        .parent()
+        .ok_or_else(|| eyre!("Failed to get parent"))?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !ext_matches(&path, "tik") {
+            continue;
+        }
+        match TitleKey::try_new(&path) {
+            Ok(title_key) if title_key.rights_id() == &nca.header.rights_id => {
+                info!(tik = %path.display(), %rights_id, "Found Ticket");
+                return Ok(Some((path, title_key)));
+            }
+            Ok(_) => {}
+            Err(err) => warn!(%err),
         }
     }
-    fn close_impl(&mut self) -> Result<()> {
-        let errs = self
-            .dirs
-            .iter()
-            .flat_map(|dir| fs::remove_dir_all(dir).err())
-            .filter_map(|err| {
-                if err.kind() != io::ErrorKind::NotFound {
-                    Some(eyre!(err))
-                } else {
-                    None
-                }
-            })
-            .inspect(|err| warn!(%err))
-            .collect::<Vec<_>>();
-
-        if errs.is_empty() {
-            Ok(())
-        } else {
-            bail!(MultiReport::new(errs).join("\n"));
+    bail!(
+        "Couldn't find the Ticket for RightsID '{}' of '{}', select it",
+        rights_id,
+        nca.path.display()
+    )
+}
+
+/// Unpacks the RomFS/ExeFS of the `base` Program NCA with the `patch` NCA applied on top,
+/// falling back to the `extractor` if it couldn't be done natively.
+pub(crate) fn unpack_program<'a, I, R, E>(
+    base: &Nca,
+    patch: Option<&Nca>,
+    keyset: &Keyset,
+    title_keys: I,
+    extractor: &Backend,
+    romfs_dir: R,
+    exefs_dir: E,
+) -> Result<()>
+where
+    I: IntoIterator<Item = &'a TitleKey>,
+    R: AsRef<Path>,
+    E: AsRef<Path>,
+{
+    let title_keys = title_keys.into_iter().collect::<Vec<_>>();
+    let unpacked = match patch {
+        Some(patch) => base
+            .open_patched_romfs(patch, keyset, title_keys.iter().copied())
+            .and_then(|mut romfs| romfs.extract_all(romfs_dir.as_ref()))
+            .and_then(|_| {
+                patch.extract_exefs(keyset, title_keys.iter().copied(), exefs_dir.as_ref())
+            }),
+        None => base
+            .open_romfs(keyset, title_keys.iter().copied())
+            .and_then(|mut romfs| romfs.extract_all(romfs_dir.as_ref()))
+            .and_then(|_| {
+                base.extract_exefs(keyset, title_keys.iter().copied(), exefs_dir.as_ref())
+            }),
+    };
+    match unpacked {
+        Ok(_) => return Ok(()),
+        Err(err) => warn!(%err, "Failed to unpack natively, using the backend instead"),
+    }
+
+    for dir in [romfs_dir.as_ref(), exefs_dir.as_ref()] {
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
         }
     }
-    pub fn close(mut self) -> Result<()> {
-        let res = self.close_impl();
-        std::mem::forget(self);
-        res
+    if let Err(err) = base.unpack_all(
+        extractor,
+        patch.unwrap_or(base),
+        keyset,
+        title_keys,
+        romfs_dir.as_ref(),
+        exefs_dir.as_ref(),
+    ) {
+        // The backend also errors out on parts that aren't needed,
+        // so it's only fatal if the output is unusable
+        warn!(%err);
+        ExeFs::from_dir(exefs_dir.as_ref())?;
+        if !romfs_dir.as_ref().is_dir() {
+            bail!("Failed to unpack RomFS from '{}'", base.path.display());
+        }
     }
+    Ok(())
 }
 
-impl Drop for CleanupDirsOnDrop {
-    fn drop(&mut self) {
-        _ = self.close_impl();
+/// Replaces the Program NCA with a NCZ in the same dir when compression is enabled,
+/// it's done after the Meta NCA is built since the content records are of the NCA.\
+/// `nca_path` must be the path returned by `CnmtBuilder::build` as it renames the NCAs.
+pub(crate) fn compress_program<P: AsRef<Path>>(
+    nca_path: P,
+    keyset: &Keyset,
+    cfg: &Config,
+) -> Result<()> {
+    if cfg.compression != Compression::Nsz {
+        return Ok(());
     }
+    let nca = Nca::try_new(keyset.header_key()?, nca_path.as_ref())?;
+    let dir = nca
+        .path
+        .parent()
+        .ok_or_else(|| eyre!("Failed to get parent"))?;
+    NczWriter::new(cfg.zstd_level, cfg.compression_workers).compress(
+        &nca,
+        keyset,
+        None::<&TitleKey>,
+        dir,
+    )?;
+    fs::remove_file(&nca.path)?;
+    Ok(())
 }
 
-macro_rules! hacpack_cleanup_install {
-    ($parent:expr) => {
-        crate::utils::CleanupDirsOnDrop::new([
-            $parent.join("hacpack_temp"),
-            $parent.join("hacpack_backup"),
-        ])
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keyset::TEST_KEYS,
+        vfs::{nca::tests::write_control_nca, ticket::tests::common_ticket},
     };
-}
 
-pub(super) use hacpack_cleanup_install;
+    #[test]
+    fn rights_id_control_nca() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let romfs_dir = dir.path().join("romfs");
+        fs::create_dir(&romfs_dir)?;
+        let mut nacp = Nacp::read(&mut vec![0u8; Nacp::SIZE].as_slice())?;
+        nacp.set_title(Language::AmericanEnglish, Some("Game"), Some("Publisher"))?;
+        nacp.save(romfs_dir.join("control.nacp"))?;
 
-pub fn formatted_nsp_rename(
-    nsp_path: &mut PathBuf,
-    nacp_data: &NacpData,
-    program_id: &str,
-    suffix: &str,
-) -> Result<()> {
-    let dest = nsp_path
-        .parent()
-        .ok_or_else(|| eyre!("Failed to get parent"))?
-        .join(format!(
-            "{} [{}][v{}]{suffix}.nsp",
-            nacp_data.get_application_name(),
-            program_id,
-            nacp_data.get_application_version()
-        ));
+        // Like the basedata dir of an unpacked NSP
+        let data_dir = dir.path().join("basedata");
+        fs::create_dir(&data_dir)?;
+        let rights_id = [0x01, 0x00, 0xAB, 0xCD, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (control, title_key) = write_control_nca(
+            &keyset,
+            &romfs_dir,
+            Some(rights_id),
+            &data_dir.join("control.nca"),
+        )?;
+        assert!(control.header.has_rights_id());
+        assert!(Nacp::from_control_nca(&control, &keyset, None).is_err());
+        assert!(nca_ticket(&control, None).is_err());
 
-    info!(from = %nsp_path.display(), to = %dest.display(), "Moving");
-    move_file(&nsp_path, &dest)?;
-    *nsp_path = dest;
+        let other_ticket = dir.path().join("other.tik");
+        fs::write(&other_ticket, common_ticket([0x42; 0x10], title_key))?;
+        let ticket = data_dir.join(format!("{}.tik", hex::encode(rights_id)));
+        fs::write(&ticket, common_ticket(rights_id, title_key))?;
+        assert!(nca_ticket(&control, Some(&other_ticket)).is_err());
+        let (found, title_key) = nca_ticket(&control, None)?.unwrap();
+        assert_eq!(found, ticket);
+        assert_eq!(nca_ticket(&control, Some(&ticket))?.unwrap().0, ticket);
 
-    Ok(())
+        let read = Nacp::from_control_nca(&control, &keyset, [&title_key])?;
+        assert_eq!(read.title(Language::AmericanEnglish).name, "Game");
+
+        // Key area crypto doesn't need a ticket at all
+        let (control, _) = write_control_nca(&keyset, &romfs_dir, None, &dir.path().join("b.nca"))?;
+        assert!(nca_ticket(&control, Some(&other_ticket))?.is_none());
+        assert!(Nacp::from_control_nca(&control, &keyset, None).is_ok());
+        Ok(())
+    }
 }
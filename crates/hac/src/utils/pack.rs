@@ -3,47 +3,44 @@ use config::Config;
 use eyre::{eyre, Result};
 use fs_err as fs;
 use std::path::Path;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     backend::{Backend, BackendKind},
-    utils::hacpack_cleanup_install,
+    keyset::Keyset,
+    utils::{compress_program, control_nacp, nca_ticket},
     vfs::{
-        nacp::{get_nacp_file, NacpData},
+        cnmt::{self, CnmtBuilder, ContentMetaType},
+        exefs::ExeFs,
+        nacp::Nacp,
         nca::{self, Nca},
         nsp::Nsp,
+        split::FAT32_PART_SIZE,
         PROGRAMID_LEN,
     },
 };
 
 /// Pack romfs/exefs back to NSP.
+///
+/// Control NCAs with a RightsID need their `ticket`, by default the one next to the NCA
+/// is used. It's packed into the NSP along with the NCA.
 pub fn pack_fs_data<N, E, R, O>(
     control_path: N,
+    ticket: Option<&Path>,
     mut program_id: String,
     romfs_dir: R,
     exefs_dir: E,
     outdir: O,
     cfg: &Config,
-) -> Result<(Nsp, NacpData)>
+) -> Result<(Nsp, Nacp)>
 where
     N: AsRef<Path>,
     E: AsRef<Path>,
     R: AsRef<Path>,
     O: AsRef<Path>,
 {
-    let curr_dir = std::env::current_dir()?;
-    let _hacpack_cleanup_bind = hacpack_cleanup_install!(curr_dir);
-
-    #[cfg(all(
-        target_arch = "x86_64",
-        any(target_os = "windows", target_os = "linux")
-    ))]
-    let readers = vec![
-        Backend::try_new(BackendKind::Hactoolnet)?,
-        Backend::try_new(BackendKind::Hac2l)?,
-    ];
-    #[cfg(feature = "android-proot")]
-    let readers = vec![Backend::try_new(BackendKind::Hac2l)?];
+    let keyset = Keyset::try_default()?;
+    let header_key = keyset.header_key()?;
     #[cfg(not(feature = "android-proot"))]
     let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
     #[cfg(feature = "android-proot")]
@@ -51,50 +48,57 @@ where
     let packer = Backend::try_new(BackendKind::Hacpack)?;
 
     // Validating NCA as Control Type
-    let control_nca = readers
-        .iter()
-        .map(|reader| Nca::try_new(reader, control_path.as_ref()).ok())
-        .find(|nca| matches!(nca, Some(nca) if nca.content_type == nca::ContentType::Control))
-        .flatten()
+    let control_nca = Nca::try_new(header_key, control_path.as_ref())
+        .ok()
+        .filter(|nca| nca.header.content_type == nca::ContentType::Control)
         .ok_or_else(|| {
             eyre!(
                 "'{}' is not a Control Type NCA",
                 control_path.as_ref().display()
             )
         })?;
+    let ticket = nca_ticket(&control_nca, ticket)?;
 
     program_id.truncate(PROGRAMID_LEN as _);
     debug!(?program_id, "Selected ProgramID for packing");
+    let title_id = u64::from_str_radix(&program_id, 16)?;
+
+    // A mismatch isn't an error as such, but the NSP won't boot
+    match ExeFs::from_dir(exefs_dir.as_ref()) {
+        Ok(exefs) if !exefs.npdm.matches_program_id(title_id) => warn!(
+            npdm_program_id = format!("{:016x}", exefs.npdm.aci0.program_id),
+            npdm_program_ids = format!(
+                "{:016x}-{:016x}",
+                exefs.npdm.acid.program_ids.start(),
+                exefs.npdm.acid.program_ids.end()
+            ),
+            %program_id,
+            "NPDM ProgramID doesn't match the TitleID being packed, the NSP will fail to boot"
+        ),
+        Ok(_) => {}
+        Err(err) => warn!(%err, "Couldn't check the NPDM ProgramID"),
+    }
 
     // Getting Nacp data
-    let control_romfs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
-    control_nca.unpack_romfs(&nca_extractor, control_romfs_dir.path())?;
-    let nacp_data =
-        NacpData::try_new(get_nacp_file(control_romfs_dir.path()).ok_or_else(|| {
-            eyre!("Couldn't find NACP file, should be due to improper extraction")
-        })?)?;
+    let nacp_data = control_nacp(
+        &control_nca,
+        &keyset,
+        ticket.as_ref().map(|(_, title_key)| title_key),
+        &nca_extractor,
+        &cfg.temp_dir,
+    )?;
 
     let temp_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
 
     // !Packing fs files to NCA
     let patched_nca = Nca::pack_program(
-        readers.iter(),
+        header_key,
         &packer,
         &program_id,
         DEFAULT_PRODKEYS_PATH.as_path(),
         romfs_dir.as_ref(),
         exefs_dir.as_ref(),
         temp_dir.path(),
-    )?;
-
-    // !Generating Meta NCA
-    Nca::create_meta(
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        &patched_nca,
-        &control_nca,
-        temp_dir.path(),
         &cfg.temp_dir,
     )?;
 
@@ -103,15 +107,30 @@ where
         .path
         .file_name()
         .expect("File should've a filename");
-    fs::copy(&control_nca.path, temp_dir.path().join(control_filename))?;
+    let control_copy = temp_dir.path().join(control_filename);
+    fs::copy(&control_nca.path, &control_copy)?;
+    if let Some((ticket_path, _)) = &ticket {
+        for path in [ticket_path.to_owned(), ticket_path.with_extension("cert")] {
+            if path.is_file() {
+                let filename = path.file_name().expect("File should've a filename");
+                fs::copy(&path, temp_dir.path().join(filename))?;
+            }
+        }
+    }
+
+    // !Generating Meta NCA
+    let (_, content_paths) = CnmtBuilder::new(title_id, ContentMetaType::Application)
+        .add_nca(&patched_nca)?
+        .add_content(&control_copy, cnmt::ContentType::Control, 0)
+        .build(&keyset, temp_dir.path())?;
+    compress_program(&content_paths[0], &keyset, cfg)?;
 
     // !Packing NCAs to NSP
     let packed_nsp = Nsp::pack(
-        &packer,
         &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
         temp_dir.path(),
         outdir.as_ref(),
+        cfg.split_output.then_some(FAT32_PART_SIZE),
     )?;
 
     Ok((packed_nsp, nacp_data))
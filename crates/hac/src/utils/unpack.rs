@@ -1,10 +1,8 @@
 use crate::{
     backend::{Backend, BackendKind},
-    utils::{clear_titlekeys, store_titlekeys},
-    vfs::{
-        nca::{self, nca_with_kind},
-        nsp::Nsp,
-    },
+    keyset::Keyset,
+    utils::{content_nca, read_cnmt, unpack_program},
+    vfs::{cnmt, exefs::ExeFs, nso::Nso, nsp::Nsp},
 };
 use config::Config;
 use eyre::{eyre, Result};
@@ -25,17 +23,8 @@ pub fn unpack_nsp<O>(
 where
     O: AsRef<Path>,
 {
-    #[cfg(not(feature = "android-proot"))]
-    let readers = vec![
-        Backend::try_new(BackendKind::Hactoolnet)?,
-        Backend::try_new(BackendKind::Hac2l)?,
-    ];
-    #[cfg(feature = "android-proot")]
-    let readers = vec![Backend::try_new(BackendKind::Hac2l)?];
-    #[cfg(not(feature = "android-proot"))]
-    let nsp_extractor = Backend::try_new(BackendKind::from(cfg.nsp_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nsp_extractor = Backend::try_new(BackendKind::Hactool)?;
+    let keyset = Keyset::try_default()?;
+    let header_key = keyset.header_key()?;
     #[cfg(not(feature = "android-proot"))]
     let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
     #[cfg(feature = "android-proot")]
@@ -46,66 +35,80 @@ where
     let romfs_dir = outdir.as_ref().join("romfs");
     let exefs_dir = outdir.as_ref().join("exefs");
 
-    // Important to do before any sort of unpacking
-    // to avoid them being used when they were not supposed to
-    clear_titlekeys()?;
-
     // !Extracting pfs0
-    base.unpack(&nsp_extractor, &base_data_dir)?;
+    base.unpack(&base_data_dir)?;
     // Setting TitleKeys
-    if let Err(err) = base.derive_title_key(&base_data_dir) {
+    if let Err(err) = base.derive_title_key() {
         warn!(?err);
     }
 
     // If update is also to be extracted
     if let Some(update) = update.as_deref_mut() {
         // !Extracting pfs0
-        update.unpack(&nsp_extractor, &update_data_dir)?;
+        update.unpack(&update_data_dir)?;
         // Setting TitleKeys
-        if let Err(err) = update.derive_title_key(&update_data_dir) {
+        if let Err(err) = update.derive_title_key() {
             warn!(?err);
         }
     }
 
-    // !Storing TitleKeys file
-    if let Some(update) = update.as_deref_mut() {
-        store_titlekeys(
-            [&base.title_key, &update.title_key]
-                .iter()
-                .filter_map(|key| key.as_ref()),
-        )?;
-    } else {
-        store_titlekeys([&base.title_key].iter().filter_map(|key| key.as_ref()))?;
-    }
-
     // !Getting Base NCA
-    let base_nca = readers
-        .iter()
-        .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-        .map(|reader| nca_with_kind(reader, &base_data_dir, nca::ContentType::Program))
-        .find(|filtered| filtered.is_some())
-        .flatten()
-        .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?
-        .remove(0);
+    let base_cnmt = read_cnmt(header_key, &keyset, &base_data_dir);
+    let base_nca = content_nca(
+        header_key,
+        base_cnmt.as_ref(),
+        &base_data_dir,
+        cnmt::ContentType::Program,
+    )
+    .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?;
     debug!(?base_nca);
 
-    if let Some(patch) = update.as_deref() {
-        // !Getting Patch NCA
-        let patch_nca = readers
-            .iter()
-            .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-            .map(|reader| nca_with_kind(reader, &update_data_dir, nca::ContentType::Program))
-            .find(|filtered| filtered.is_some())
-            .flatten()
-            .ok_or_else(|| eyre!("Failed to find Patch NCA in '{}'", patch.path.display()))?
-            .remove(0);
-        debug!(?patch_nca);
+    let patch_nca = match update.as_deref() {
+        Some(patch) => {
+            // !Getting Patch NCA
+            let patch_cnmt = read_cnmt(header_key, &keyset, &update_data_dir);
+            let patch_nca = content_nca(
+                header_key,
+                patch_cnmt.as_ref(),
+                &update_data_dir,
+                cnmt::ContentType::Program,
+            )
+            .ok_or_else(|| eyre!("Failed to find Patch NCA in '{}'", patch.path.display()))?;
+            debug!(?patch_nca);
+            Some(patch_nca)
+        }
+        None => None,
+    };
 
-        // !Unpacking FS files from NCAs
-        _ = base_nca.unpack_all(&nca_extractor, &patch_nca, &romfs_dir, &exefs_dir);
-    } else {
-        // !Unpacking FS files from NCAs
-        _ = base_nca.unpack_all(&nca_extractor, &base_nca, &romfs_dir, &exefs_dir);
+    // !Unpacking FS files from NCAs
+    unpack_program(
+        &base_nca,
+        patch_nca.as_ref(),
+        &keyset,
+        [
+            base.title_key.as_ref(),
+            update
+                .as_deref()
+                .and_then(|update| update.title_key.as_ref()),
+        ]
+        .into_iter()
+        .flatten(),
+        &nca_extractor,
+        &romfs_dir,
+        &exefs_dir,
+    )?;
+
+    // Build IDs are what exefs patches and cheats are made for
+    match ExeFs::from_dir(&exefs_dir) {
+        Ok(exefs) => {
+            for path in &exefs.nsos {
+                match Nso::from_file(path) {
+                    Ok(nso) => info!(nso = %path.display(), build_id = nso.build_id()),
+                    Err(err) => warn!(%err),
+                }
+            }
+        }
+        Err(err) => warn!(%err),
     }
 
     Ok((
@@ -1,48 +1,40 @@
-use std::{collections::HashSet, path::Path};
+use std::path::Path;
 
 use common::defines::DEFAULT_PRODKEYS_PATH;
 use config::Config;
-use eyre::{eyre, Result};
+use eyre::{bail, Result};
 use fs_err as fs;
-use tracing::{debug, info, warn};
+use tracing::{debug, warn};
 
 use crate::{
     backend::{Backend, BackendKind},
-    utils::{clear_titlekeys, store_titlekeys},
+    keyset::Keyset,
+    utils::{compress_program, content_ncas, control_nacp, read_cnmt, unpack_program},
     vfs::{
-        nacp::{get_nacp_file, NacpData},
-        nca::{self, nca_with_filters, nca_with_kind, Nca},
+        cnmt::{self, CnmtBuilder, ContentMetaType},
+        nacp::Nacp,
+        nca::Nca,
         nsp::Nsp,
+        split::FAT32_PART_SIZE,
     },
 };
 
-use super::hacpack_cleanup_install;
-
 /// Apply update NSP to the base NSP.
+///
+/// Every program of the base is patched with the update's program of the same id offset,
+/// i.e. titles with multiple programs keep all of them along with their Control NCAs.
 pub fn update_nsp<O>(
     base: &mut Nsp,
     update: &mut Nsp,
     program_id: Option<&str>,
     outdir: O,
     cfg: &Config,
-) -> Result<(Nsp, NacpData, String)>
+) -> Result<(Nsp, Nacp, String)>
 where
     O: AsRef<Path>,
 {
-    let curr_dir = std::env::current_dir()?;
-    let _hacpack_cleanup_bind = hacpack_cleanup_install!(curr_dir);
-
-    #[cfg(not(feature = "android-proot"))]
-    let readers = vec![
-        Backend::try_new(BackendKind::Hactoolnet)?,
-        Backend::try_new(BackendKind::Hac2l)?,
-    ];
-    #[cfg(feature = "android-proot")]
-    let readers = vec![Backend::try_new(BackendKind::Hac2l)?];
-    #[cfg(not(feature = "android-proot"))]
-    let nsp_extractor = Backend::try_new(BackendKind::from(cfg.nsp_extractor))?;
-    #[cfg(feature = "android-proot")]
-    let nsp_extractor = Backend::try_new(BackendKind::Hactool)?;
+    let keyset = Keyset::try_default()?;
+    let header_key = keyset.header_key()?;
     #[cfg(not(feature = "android-proot"))]
     let nca_extractor = Backend::try_new(BackendKind::from(cfg.nca_extractor))?;
     #[cfg(feature = "android-proot")]
@@ -54,142 +46,229 @@ where
     fs::create_dir_all(base_data_dir.path())?;
     fs::create_dir_all(update_data_dir.path())?;
 
-    clear_titlekeys()?;
-
     // !Extracting pfs0
-    base.unpack(&nsp_extractor, base_data_dir.path())?;
-    update.unpack(&nsp_extractor, update_data_dir.path())?;
+    base.unpack(base_data_dir.path())?;
+    update.unpack(update_data_dir.path())?;
 
     // Setting TitleKeys
-    if let Err(err) = base.derive_title_key(base_data_dir.path()) {
+    if let Err(err) = base.derive_title_key() {
         warn!(?err);
     }
-    if let Err(err) = update.derive_title_key(update_data_dir.path()) {
+    if let Err(err) = update.derive_title_key() {
         warn!(?err);
     }
 
-    // !Storing TitleKeys file
-    store_titlekeys(
-        [&base.title_key, &update.title_key]
-            .iter()
-            .filter_map(|key| key.as_ref()),
-    )?;
+    // !Getting Base NCAs
+    let base_cnmt = read_cnmt(header_key, &keyset, base_data_dir.path());
+    let base_ncas = content_ncas(
+        header_key,
+        base_cnmt.as_ref(),
+        base_data_dir.path(),
+        cnmt::ContentType::Program,
+    );
+    if base_ncas.is_empty() {
+        bail!("Failed to find Base NCA in '{}'", base.path.display());
+    }
+    debug!(?base_ncas);
 
-    // !Getting Base NCA
-    let base_nca = readers
-        .iter()
-        .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-        .map(|reader| nca_with_kind(reader, base_data_dir.path(), nca::ContentType::Program))
-        .find(|filtered| filtered.is_some())
-        .flatten()
-        .ok_or_else(|| eyre!("Failed to find Base NCA in '{}'", base.path.display()))?
-        .remove(0);
-    debug!(?base_nca);
-
-    // !Getting Update and Control NCA
-    let filters = HashSet::from([nca::ContentType::Program, nca::ContentType::Control]);
-    let mut filtered_ncas = readers
-        .iter()
-        .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-        .map(|reader| nca_with_filters(reader, update_data_dir.path(), &filters))
-        .find(|filtered| {
-            filters
+    // !Getting Update and Control NCAs
+    let update_cnmt = read_cnmt(header_key, &keyset, update_data_dir.path());
+    let mut update_ncas = content_ncas(
+        header_key,
+        update_cnmt.as_ref(),
+        update_data_dir.path(),
+        cnmt::ContentType::Program,
+    );
+    let mut control_ncas = content_ncas(
+        header_key,
+        update_cnmt.as_ref(),
+        update_data_dir.path(),
+        cnmt::ContentType::Control,
+    );
+    if update_ncas.is_empty() || control_ncas.is_empty() {
+        bail!(
+            "Failed to find Update and/or Control NCA in '{}'",
+            update.path.display()
+        );
+    }
+    debug!(?update_ncas);
+    debug!(?control_ncas);
+
+    // Pairing up the programs by their id offset
+    let programs = base_ncas
+        .into_iter()
+        .map(|(base_nca, id_offset)| {
+            let update_nca = update_ncas
                 .iter()
-                .map(|kind| filtered.get(kind))
-                .all(|ncas| ncas.is_some())
+                .position(|(_, update_id_offset)| *update_id_offset == id_offset)
+                .map(|index| update_ncas.remove(index).0);
+            if update_nca.is_none() {
+                warn!(program_id = %base_nca.get_program_id(), id_offset, "Update has no Program NCA for it, repacking it unpatched");
+            }
+            (base_nca, update_nca, id_offset)
         })
-        .ok_or_else(|| {
-            eyre!(
-                "Failed to find Update and/or Control NCA in '{}'",
-                update.path.display()
-            )
-        })?;
-    let update_nca = filtered_ncas
-        .remove(&nca::ContentType::Program)
-        .expect("Should be Some due the all() check")
-        .remove(0);
-    let mut control_nca = filtered_ncas
-        .remove(&nca::ContentType::Control)
-        .expect("Should be Some due the all() check")
-        .remove(0);
-    debug!(?update_nca);
-    debug!(?control_nca);
-
-    // Getting Nacp data
-    let control_romfs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
-    control_nca.unpack_romfs(&nca_extractor, control_romfs_dir.path())?;
-    let nacp_data =
-        NacpData::try_new(get_nacp_file(control_romfs_dir.path()).ok_or_else(|| {
-            eyre!("Couldn't find NACP file, should be due to improper extraction")
-        })?)?;
-    if let Err(err) = control_romfs_dir.close() {
-        warn!(%err);
+        .collect::<Vec<_>>();
+    for (nca, id_offset) in &update_ncas {
+        warn!(nca = %nca.path.display(), program_id = %nca.get_program_id(), id_offset, "Base has no Program NCA for it, skipping it");
     }
 
-    let fs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
-    let romfs_dir = fs_dir.path().join("romfs");
-    let exefs_dir = fs_dir.path().join("exefs");
-    // !Unpacking FS files from NCAs
-    _ = base_nca.unpack_all(&nca_extractor, &update_nca, &romfs_dir, &exefs_dir); // !Ignoring err
+    // Getting Nacp data from the Control NCA of the main program
+    let nacp_data = control_nacp(
+        &control_ncas[0].0,
+        &keyset,
+        update.title_key.as_ref(),
+        &nca_extractor,
+        &cfg.temp_dir,
+    )?;
 
     let program_id = match program_id {
         Some(program_id) => program_id.into(),
-        None => base_nca.get_program_id().to_lowercase(),
+        None => {
+            // The programs are at an offset from the ApplicationID
+            let application_id = match &base_cnmt {
+                Some(base_cnmt) => base_cnmt.title_id,
+                None => programs
+                    .iter()
+                    .map(|(base_nca, _, id_offset)| {
+                        u64::from_be_bytes(base_nca.header.program_id) - *id_offset as u64
+                    })
+                    .min()
+                    .expect("There should be at least one program"),
+            };
+            format!("{:016x}", application_id)
+        }
     };
     debug!(?program_id, "Selected TitleID for packing");
 
-    // !Moving Control NCA
+    // !Moving Control NCAs
     let nca_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
     fs::create_dir_all(nca_dir.path())?;
-    let control_nca_filename = control_nca
-        .path
-        .file_name()
-        .expect("File should've a filename");
-    fs::rename(&control_nca.path, nca_dir.path().join(control_nca_filename))?;
-    control_nca.path = nca_dir.path().join(control_nca_filename);
-
-    // Early cleanup
-    if let Err(err) = base_data_dir.close() {
-        warn!(?err);
+    for (control_nca, _) in &mut control_ncas {
+        let control_nca_path = nca_dir.path().join(
+            control_nca
+                .path
+                .file_name()
+                .expect("File should've a filename"),
+        );
+        fs::rename(&control_nca.path, &control_nca_path)?;
+        control_nca.path = control_nca_path;
     }
-    if let Err(err) = update_data_dir.close() {
-        warn!(?err);
+
+    // !Moving Manual NCAs, they're carried over as is
+    let mut manual_ncas = vec![];
+    for record in update_cnmt.iter().flat_map(|cnmt| {
+        [
+            cnmt::ContentType::HtmlDocument,
+            cnmt::ContentType::LegalInformation,
+        ]
+        .into_iter()
+        .flat_map(|content_type| cnmt.contents(content_type))
+    }) {
+        let from = update_data_dir.path().join(record.nca_filename());
+        if !from.is_file() {
+            warn!(nca = %from.display(), content_type = ?record.content_type, "Couldn't find NCA listed in CNMT, skipping it");
+            continue;
+        }
+        let to = nca_dir.path().join(record.nca_filename());
+        fs::rename(&from, &to)?;
+        manual_ncas.push((to, record.content_type, record.id_offset));
     }
 
-    // !Packing fs files to NCA
-    let patched_nca = Nca::pack_program(
-        readers.iter(),
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        &romfs_dir,
-        &exefs_dir,
-        nca_dir.path(),
-    )?;
+    let mut patched_ncas = vec![];
+    for (base_nca, update_nca, id_offset) in &programs {
+        let id_offset = *id_offset;
+        let packed_program_id = format!(
+            "{:016x}",
+            u64::from_str_radix(&program_id, 16)? + id_offset as u64
+        );
+        debug!(?packed_program_id, id_offset, "Patching program");
+
+        let fs_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+        let romfs_dir = fs_dir.path().join("romfs");
+        let exefs_dir = fs_dir.path().join("exefs");
+        // !Unpacking FS files from NCAs
+        unpack_program(
+            base_nca,
+            update_nca.as_ref(),
+            &keyset,
+            [&base.title_key, &update.title_key]
+                .into_iter()
+                .filter_map(|key| key.as_ref()),
+            &nca_extractor,
+            &romfs_dir,
+            &exefs_dir,
+        )?;
+
+        // !Packing fs files to NCA
+        // Packed on its own as the packed NCA is told apart from the rest by its type
+        let pack_dir = tempfile::tempdir_in(&cfg.temp_dir)?;
+        let mut patched_nca = Nca::pack_program(
+            header_key,
+            &packer,
+            &packed_program_id,
+            DEFAULT_PRODKEYS_PATH.as_path(),
+            &romfs_dir,
+            &exefs_dir,
+            pack_dir.path(),
+            &cfg.temp_dir,
+        )?;
+        let patched_nca_path = nca_dir.path().join(
+            patched_nca
+                .path
+                .file_name()
+                .expect("File should've a filename"),
+        );
+        fs::rename(&patched_nca.path, &patched_nca_path)?;
+        patched_nca.path = patched_nca_path;
+        patched_ncas.push((patched_nca, id_offset));
+
+        // Cleaning up extracted FS files
+        if let Err(err) = fs_dir.close() {
+            warn!(?err);
+        }
+    }
 
-    // Cleaning up extracted FS files
-    if let Err(err) = fs_dir.close() {
+    // Cleaning up unpacked NSPs
+    if let Err(err) = base_data_dir.close() {
+        warn!(?err);
+    }
+    if let Err(err) = update_data_dir.close() {
         warn!(?err);
     }
 
     // !Generating Meta NCA
-    Nca::create_meta(
-        &packer,
-        &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
-        &patched_nca,
-        &control_nca,
-        nca_dir.path(),
-        &cfg.temp_dir,
-    )?;
+    let mut cnmt_builder = CnmtBuilder::new(
+        u64::from_str_radix(&program_id, 16)?,
+        ContentMetaType::Application,
+    );
+    // Carrying over the version so that it's not mistaken for the base
+    if let Some(update_cnmt) = &update_cnmt {
+        cnmt_builder.version(update_cnmt.version);
+        if let Some(version) = update_cnmt.required_system_version() {
+            cnmt_builder.required_system_version(version);
+        }
+    }
+    for (patched_nca, id_offset) in &patched_ncas {
+        cnmt_builder.add_content(&patched_nca.path, cnmt::ContentType::Program, *id_offset);
+    }
+    for (control_nca, id_offset) in &control_ncas {
+        cnmt_builder.add_content(&control_nca.path, cnmt::ContentType::Control, *id_offset);
+    }
+    for (path, content_type, id_offset) in &manual_ncas {
+        cnmt_builder.add_content(path, *content_type, *id_offset);
+    }
+    let (_, content_paths) = cnmt_builder.build(&keyset, nca_dir.path())?;
+    // The Program NCAs were added first
+    for path in &content_paths[..patched_ncas.len()] {
+        compress_program(path, &keyset, cfg)?;
+    }
 
     // !Packing NCAs to NSP
     let patched_nsp = Nsp::pack(
-        &packer,
         &program_id,
-        DEFAULT_PRODKEYS_PATH.as_path(),
         nca_dir.path(),
         outdir.as_ref(),
+        cfg.split_output.then_some(FAT32_PART_SIZE),
     )?;
 
     Ok((patched_nsp, nacp_data, program_id))
@@ -0,0 +1,535 @@
+use crate::{
+    keyset::Keyset,
+    vfs::{
+        cnmt::{self, Cnmt},
+        extract_entry_to,
+        nca::{ContentType, FsType, Nca, NcaHeader, Signature, SignatureStatus},
+        ncz::Ncz,
+        nsp::{Nsp, Pfs0},
+        split::SplitFile,
+        ticket::TitleKey,
+        xci::{PartitionKind, Xci},
+        SubStorage,
+    },
+};
+use common::utils::ext_matches;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    io::{self, Write},
+    path::Path,
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentStatus {
+    Valid,
+    /// Hash or size doesn't match the content record.
+    Mismatch,
+    /// Listed in the CNMT but not in the package.
+    Missing,
+    /// Missing DeltaFragment, they're usually left out of Patch packages.
+    Skipped,
+    /// Couldn't be read, for eg. a truncated entry or a broken NCZ.
+    Unreadable(String),
+}
+
+impl fmt::Display for ContentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentStatus::Valid => write!(f, "valid"),
+            ContentStatus::Mismatch => write!(f, "hash mismatch"),
+            ContentStatus::Missing => write!(f, "missing"),
+            ContentStatus::Skipped => write!(f, "skipped, not included"),
+            ContentStatus::Unreadable(err) => write!(f, "unreadable, {}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentReport {
+    /// NCA filename going by the content record.
+    pub name: String,
+    pub title_id: String,
+    pub content_type: cnmt::ContentType,
+    pub status: ContentStatus,
+    /// `None` if the NCA header couldn't be read.
+    pub signature: Option<SignatureStatus>,
+}
+
+impl ContentReport {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, ContentStatus::Valid | ContentStatus::Skipped)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub contents: Vec<ContentReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.contents.iter().all(|content| content.is_ok())
+    }
+    pub fn failed(&self) -> impl Iterator<Item = &ContentReport> {
+        self.contents.iter().filter(|content| !content.is_ok())
+    }
+}
+
+/// NSP or the secure partition of a XCI, entries of both are read in place.
+enum Package {
+    Nsp(Nsp, Pfs0),
+    Xci(Xci),
+}
+
+impl Package {
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if let Ok(nsp) = Nsp::try_new(path.as_ref()) {
+            let pfs0 = nsp.pfs0()?;
+            return Ok(Package::Nsp(nsp, pfs0));
+        }
+        let xci = Xci::try_new(path.as_ref())?;
+        if xci.partition(PartitionKind::Secure).is_none() {
+            bail!("'{}' has no secure partition", path.as_ref().display());
+        }
+        Ok(Package::Xci(xci))
+    }
+    /// Names and sizes of the entries.
+    fn entries(&self) -> Vec<(&str, u64)> {
+        match self {
+            Package::Nsp(_, pfs0) => pfs0
+                .entries
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.size))
+                .collect(),
+            Package::Xci(xci) => xci
+                .partition(PartitionKind::Secure)
+                .map(|hfs0| {
+                    hfs0.entries
+                        .iter()
+                        .map(|entry| (entry.name.as_str(), entry.size))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+    fn open_entry(&self, name: &str) -> Result<SubStorage<SplitFile>> {
+        match self {
+            Package::Nsp(nsp, pfs0) => nsp.open_entry(
+                pfs0.entry(name)
+                    .ok_or_else(|| eyre!("Couldn't find '{}'", name))?,
+            ),
+            Package::Xci(xci) => xci.open_entry(
+                xci.partition(PartitionKind::Secure)
+                    .and_then(|hfs0| hfs0.entry(name))
+                    .ok_or_else(|| eyre!("Couldn't find '{}'", name))?,
+            ),
+        }
+    }
+}
+
+/// Hashes everything written to it, while keeping the first `limit` bytes in a file
+/// if there's one, so that the start of a NCA can be read without extracting all of it.
+struct HashWriter {
+    hasher: Sha256,
+    prefix: Option<(io::BufWriter<fs::File>, u64)>,
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        if let Some((file, limit)) = &mut self.prefix {
+            let len = (*limit).min(buf.len() as u64) as usize;
+            file.write_all(&buf[..len])?;
+            *limit -= len as u64;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.prefix {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Hashes an entry, NCZs are hashed as the NCA they decompress to.
+///
+/// The first bytes of the (decompressed) entry are also written to `prefix` if given.
+/// Returns the hash along with the size of what was hashed.
+fn hash_entry(
+    package: &Package,
+    name: &str,
+    size: u64,
+    prefix: Option<(&Path, u64)>,
+) -> Result<([u8; 0x20], u64)> {
+    let mut reader = io::BufReader::new(package.open_entry(name)?);
+    let mut writer = HashWriter {
+        hasher: Sha256::new(),
+        prefix: match prefix {
+            Some((path, limit)) => Some((io::BufWriter::new(fs::File::create(path)?), limit)),
+            None => None,
+        },
+    };
+    let hashed = if ext_matches(name, "ncz") {
+        Ncz::decompress(reader, &mut writer)?
+    } else {
+        let hashed = io::copy(&mut reader, &mut writer)?;
+        if hashed != size {
+            bail!("truncated, read {:#x} of {:#x} bytes", hashed, size);
+        }
+        hashed
+    };
+    writer.flush()?;
+    Ok((writer.hasher.finalize().into(), hashed))
+}
+
+/// Hashes a content entry and checks its header signatures, the NCA header is at the start
+/// of NCZs as well. Program NCAs have their ExeFS kept in `temp_dir` while being hashed,
+/// for the ACID public key in their NPDM.
+fn hash_content<'a, I>(
+    package: &Package,
+    keyset: &Keyset,
+    title_keys: I,
+    name: &str,
+    size: u64,
+    temp_dir: &Path,
+) -> (Result<([u8; 0x20], u64)>, Option<SignatureStatus>)
+where
+    I: IntoIterator<Item = &'a TitleKey>,
+{
+    let header = match package
+        .open_entry(name)
+        .and_then(|mut reader| NcaHeader::read(&mut reader, keyset.header_key()?))
+    {
+        Ok(header) => header,
+        Err(err) => {
+            warn!(nca = %name, %err, "Failed to read NCA header");
+            return (hash_entry(package, name, size, None), None);
+        }
+    };
+    let exefs_end = match header.content_type {
+        ContentType::Program => header
+            .fs_headers
+            .iter()
+            .zip(header.fs_entries)
+            .find(|(fs_header, _)| {
+                fs_header
+                    .as_ref()
+                    .map_or(false, |fs_header| fs_header.fs_type == FsType::PartitionFs)
+            })
+            .map(|(_, entry)| entry.end_offset),
+        _ => None,
+    };
+    let Some(exefs_end) = exefs_end else {
+        return (
+            hash_entry(package, name, size, None),
+            Some(header.signature_status(keyset, None)),
+        );
+    };
+
+    // Named as a NCA so that it can be opened as one
+    let prefix_path = temp_dir.join(format!("{}.prefix.nca", name));
+    let hashed = hash_entry(package, name, size, Some((&prefix_path, exefs_end)));
+    let signature = match keyset
+        .header_key()
+        .and_then(|header_key| Nca::try_new(header_key, &prefix_path))
+    {
+        Ok(nca) => nca.signature_status(keyset, title_keys),
+        Err(err) => {
+            warn!(nca = %name, %err, "Failed to read the ExeFS of the NCA");
+            let mut status = header.signature_status(keyset, None);
+            status.acid = Some(Signature::Unchecked);
+            status
+        }
+    };
+    if let Err(err) = fs::remove_file(&prefix_path) {
+        warn!(%err);
+    }
+    (hashed, Some(signature))
+}
+
+/// Hashes every content of a NSP or XCI and checks them against the content records
+/// of their Meta NCAs, Meta NCAs are checked against their name since that's their ContentID.
+///
+/// NSZ/XCZ and split packages are accepted as well.
+pub fn verify_package<P, Q>(path: P, tempdir_in: Q) -> Result<VerifyReport>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    verify(&Keyset::try_default()?, path, tempdir_in)
+}
+
+fn verify<P, Q>(keyset: &Keyset, path: P, tempdir_in: Q) -> Result<VerifyReport>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let header_key = keyset.header_key()?;
+
+    info!(package = %path.as_ref().display(), "Verifying package");
+    let package = Package::open(path.as_ref())?;
+    let entries = package.entries();
+    let find_entry = |content_id: &str| {
+        entries
+            .iter()
+            .find(|(name, _)| {
+                ["nca", "ncz"]
+                    .iter()
+                    .any(|ext| *name == format!("{}.{}", content_id, ext))
+            })
+            .copied()
+    };
+
+    let mut title_keys = vec![];
+    for (name, _) in entries.iter().filter(|(name, _)| ext_matches(name, "tik")) {
+        match package
+            .open_entry(name)
+            .and_then(|mut reader| TitleKey::from_reader(&mut reader))
+        {
+            Ok(title_key) => title_keys.push(title_key),
+            Err(err) => warn!(tik = %name, %err, "Failed to read Ticket"),
+        }
+    }
+
+    let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+    let mut report = VerifyReport::default();
+    let mut meta_ncas = 0;
+    for (name, size) in entries
+        .iter()
+        .copied()
+        .filter(|(name, _)| name.ends_with(".cnmt.nca"))
+    {
+        meta_ncas += 1;
+        let content_id = name.trim_end_matches(".cnmt.nca");
+        let status = match hash_entry(&package, name, size, None) {
+            Ok((hash, _)) if hex::encode(&hash[..0x10]) == content_id => ContentStatus::Valid,
+            Ok(_) => ContentStatus::Mismatch,
+            Err(err) => ContentStatus::Unreadable(err.to_string()),
+        };
+        let meta = package
+            .open_entry(name)
+            .and_then(|reader| extract_entry_to(reader, name, size, temp_dir.path()))
+            .and_then(|path| Nca::try_new(header_key, path));
+        let signature = meta
+            .as_ref()
+            .ok()
+            .map(|nca| nca.header.signature_status(keyset, None));
+        let cnmt = match meta.and_then(|nca| Cnmt::from_nca(&nca, keyset)) {
+            Ok(cnmt) => cnmt,
+            Err(err) => {
+                warn!(meta = %name, %err, "Failed to read CNMT");
+                report.contents.push(ContentReport {
+                    name: name.to_owned(),
+                    title_id: String::new(),
+                    content_type: cnmt::ContentType::Meta,
+                    status: match status {
+                        ContentStatus::Valid => ContentStatus::Unreadable(err.to_string()),
+                        other => other,
+                    },
+                    signature,
+                });
+                continue;
+            }
+        };
+        report.contents.push(ContentReport {
+            name: name.to_owned(),
+            title_id: cnmt.title_id_str(),
+            content_type: cnmt::ContentType::Meta,
+            status,
+            signature,
+        });
+
+        for record in &cnmt.content_records {
+            let content_id = hex::encode(record.content_id);
+            let (status, signature) = match find_entry(&content_id) {
+                None if record.content_type == cnmt::ContentType::DeltaFragment => {
+                    (ContentStatus::Skipped, None)
+                }
+                None => (ContentStatus::Missing, None),
+                Some((entry_name, entry_size)) => {
+                    info!(nca = %entry_name, content_type = ?record.content_type, "Hashing");
+                    let (hashed, signature) = hash_content(
+                        &package,
+                        keyset,
+                        &title_keys,
+                        entry_name,
+                        entry_size,
+                        temp_dir.path(),
+                    );
+                    let status = match hashed {
+                        Ok((hash, size)) if hash == record.hash && size == record.size => {
+                            ContentStatus::Valid
+                        }
+                        Ok(_) => ContentStatus::Mismatch,
+                        Err(err) => ContentStatus::Unreadable(err.to_string()),
+                    };
+                    (status, signature)
+                }
+            };
+            if !matches!(status, ContentStatus::Valid | ContentStatus::Skipped) {
+                warn!(nca = %record.nca_filename(), %status, "Content failed verification");
+            }
+            report.contents.push(ContentReport {
+                name: record.nca_filename(),
+                title_id: cnmt.title_id_str(),
+                content_type: record.content_type,
+                status,
+                signature,
+            });
+        }
+    }
+    if meta_ncas == 0 {
+        bail!(
+            "Couldn't find any Meta NCA in '{}'",
+            path.as_ref().display()
+        );
+    }
+
+    info!(
+        package = %path.as_ref().display(),
+        contents = report.contents.len(),
+        failed = report.failed().count(),
+        "Verified package"
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keyset::TEST_KEYS,
+        vfs::{cnmt::CnmtBuilder, nca::tests::write_control_nca, nsp::NspWriter},
+    };
+    use cnmt::ContentMetaType;
+    use std::path::PathBuf;
+
+    /// NCAs of an Application in `dir/ncas`, a Control, a LegalInformation and a DeltaFragment
+    /// NCA along with their Meta NCA.\
+    /// Returns the paths of the contents in that order, then of the Meta NCA.
+    fn build_ncas(keyset: &Keyset, dir: &Path) -> Result<(Vec<PathBuf>, PathBuf)> {
+        let nca_dir = dir.join("ncas");
+        fs::create_dir(&nca_dir)?;
+        let mut builder = CnmtBuilder::new(0x0100_ABCD_0000_0000, ContentMetaType::Application);
+        for (i, content_type) in [
+            cnmt::ContentType::Control,
+            cnmt::ContentType::LegalInformation,
+            cnmt::ContentType::DeltaFragment,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            // Different data so that they get different ContentIDs
+            let romfs_dir = dir.join(format!("romfs{}", i));
+            fs::create_dir(&romfs_dir)?;
+            fs::write(romfs_dir.join("data.bin"), [i as u8; 0x100])?;
+            let nca_path = nca_dir.join(format!("{}.nca", i));
+            write_control_nca(keyset, &romfs_dir, None, &nca_path)?;
+            builder.add_content(nca_path, content_type, 0);
+        }
+        let (meta_nca, content_paths) = builder.build(keyset, &nca_dir)?;
+        Ok((content_paths, meta_nca.path))
+    }
+
+    /// Packs `dir/ncas` to a NSP and verifies it, returns the status of every content.
+    fn verify_ncas(keyset: &Keyset, dir: &Path) -> Result<Vec<(cnmt::ContentType, ContentStatus)>> {
+        let nsp_path = dir.join("game.nsp");
+        NspWriter::from_dir(dir.join("ncas"))?.write(&nsp_path)?;
+        let report = verify(keyset, &nsp_path, dir)?;
+        assert!(report
+            .contents
+            .iter()
+            .all(|content| content.title_id == "0100abcd00000000"));
+        Ok(report
+            .contents
+            .into_iter()
+            .map(|content| (content.content_type, content.status))
+            .collect())
+    }
+
+    #[test]
+    fn verify_valid() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        build_ncas(&keyset, dir.path())?;
+        assert_eq!(
+            verify_ncas(&keyset, dir.path())?,
+            [
+                (cnmt::ContentType::Meta, ContentStatus::Valid),
+                (cnmt::ContentType::Control, ContentStatus::Valid),
+                (cnmt::ContentType::LegalInformation, ContentStatus::Valid),
+                (cnmt::ContentType::DeltaFragment, ContentStatus::Valid),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_corrupted() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let (content_paths, _) = build_ncas(&keyset, dir.path())?;
+        // Past the header, so that it's still read as a NCA
+        let mut data = fs::read(&content_paths[0])?;
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        fs::write(&content_paths[0], data)?;
+
+        let statuses = verify_ncas(&keyset, dir.path())?;
+        assert_eq!(
+            statuses[1],
+            (cnmt::ContentType::Control, ContentStatus::Mismatch)
+        );
+        assert!(statuses[2..]
+            .iter()
+            .all(|(_, status)| *status == ContentStatus::Valid));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_missing() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let (content_paths, _) = build_ncas(&keyset, dir.path())?;
+        fs::remove_file(&content_paths[1])?;
+        fs::remove_file(&content_paths[2])?;
+
+        // Only DeltaFragments are expected to be left out
+        assert_eq!(
+            verify_ncas(&keyset, dir.path())?,
+            [
+                (cnmt::ContentType::Meta, ContentStatus::Valid),
+                (cnmt::ContentType::Control, ContentStatus::Valid),
+                (cnmt::ContentType::LegalInformation, ContentStatus::Missing),
+                (cnmt::ContentType::DeltaFragment, ContentStatus::Skipped),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_misnamed_meta() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let (_, meta_path) = build_ncas(&keyset, dir.path())?;
+        fs::rename(
+            &meta_path,
+            meta_path.with_file_name(format!("{}.cnmt.nca", "0".repeat(0x20))),
+        )?;
+
+        let statuses = verify_ncas(&keyset, dir.path())?;
+        assert_eq!(
+            statuses[0],
+            (cnmt::ContentType::Meta, ContentStatus::Mismatch)
+        );
+        assert!(statuses[1..]
+            .iter()
+            .all(|(_, status)| *status == ContentStatus::Valid));
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+use crate::{
+    crypto::aes_ctr_apply,
+    keyset::Key128,
+    vfs::{read_u32_le, read_u64_le},
+};
+use eyre::{bail, Result};
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// https://switchbrew.org/wiki/NCA#BucketTreeHeader
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BucketTreeHeader {
+    pub version: u32,
+    pub entry_count: u32,
+}
+
+impl BucketTreeHeader {
+    const MAGIC: [u8; 4] = *b"BKTR";
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf[..4] != BucketTreeHeader::MAGIC {
+            bail!("Invalid BKTR magic");
+        }
+        Ok(Self {
+            version: read_u32_le(buf, 0x4),
+            entry_count: read_u32_le(buf, 0x8),
+        })
+    }
+}
+
+/// A bucket tree table, relative to the start of the section.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BucketTreeInfo {
+    pub offset: u64,
+    pub size: u64,
+    pub header: BucketTreeHeader,
+}
+
+impl BucketTreeInfo {
+    /// Size of the L1/L2 nodes and the entry sets.
+    const NODE_SIZE: usize = 0x4000;
+    const NODE_HEADER_SIZE: usize = 0x10;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        Ok(Self {
+            offset: read_u64_le(buf, 0x0),
+            size: read_u64_le(buf, 0x8),
+            header: BucketTreeHeader::parse(&buf[0x10..0x20])?,
+        })
+    }
+    /// Reads the table from `reader`, which must be the decrypted section,
+    /// and returns the raw entries along with the end offset of the last one.
+    ///
+    /// The offset nodes are skipped over as the entries are all read anyway.
+    fn read_entries<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        entry_size: usize,
+    ) -> Result<(Vec<Vec<u8>>, u64)> {
+        let entry_count = self.header.entry_count as usize;
+        let entries_per_set =
+            (BucketTreeInfo::NODE_SIZE - BucketTreeInfo::NODE_HEADER_SIZE) / entry_size;
+        let entry_set_count = (entry_count + entries_per_set - 1) / entries_per_set;
+        // L2 nodes are only there when the L1 node can't hold all the entry set offsets
+        let offsets_per_node = (BucketTreeInfo::NODE_SIZE - BucketTreeInfo::NODE_HEADER_SIZE) / 8;
+        let l2_node_count = if entry_set_count <= offsets_per_node {
+            0
+        } else {
+            let l2_in_l1 = (entry_set_count + offsets_per_node - 1) / offsets_per_node;
+            let remaining = entry_set_count - (offsets_per_node - (l2_in_l1 - 1));
+            (remaining + offsets_per_node - 1) / offsets_per_node
+        };
+        let entry_sets_offset = (1 + l2_node_count) * BucketTreeInfo::NODE_SIZE;
+        let table_size = entry_sets_offset + entry_set_count * BucketTreeInfo::NODE_SIZE;
+        if (table_size as u64) > self.size {
+            bail!(
+                "BKTR table needs {:#x} bytes but it's only {:#x}",
+                table_size,
+                self.size
+            );
+        }
+
+        let mut table = vec![0u8; table_size];
+        reader.seek(SeekFrom::Start(self.offset))?;
+        reader.read_exact(&mut table)?;
+
+        let mut entries = Vec::with_capacity(entry_count);
+        let mut end_offset = 0;
+        for (index, entry_set) in table[entry_sets_offset..]
+            .chunks_exact(BucketTreeInfo::NODE_SIZE)
+            .enumerate()
+        {
+            let set_index = read_u32_le(entry_set, 0x0) as usize;
+            let count = read_u32_le(entry_set, 0x4) as usize;
+            if set_index != index || count > entries_per_set {
+                bail!("Invalid BKTR entry set {}", index);
+            }
+            end_offset = read_u64_le(entry_set, 0x8);
+            entries.extend(
+                entry_set[BucketTreeInfo::NODE_HEADER_SIZE..]
+                    .chunks_exact(entry_size)
+                    .take(count)
+                    .map(|entry| entry.to_vec()),
+            );
+        }
+        if entries.len() != entry_count {
+            bail!(
+                "BKTR has {} entries instead of {}",
+                entries.len(),
+                entry_count
+            );
+        }
+        Ok((entries, end_offset))
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA#PatchInfo
+///
+/// Present in the RomFS section of a patch NCA, the relocation (indirect) table maps
+/// the patched RomFS to the base and patch data, and the subsection (AES-CTR-EX) table
+/// holds the counters of the patch data.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PatchInfo {
+    pub indirect: BucketTreeInfo,
+    pub aes_ctr_ex: BucketTreeInfo,
+}
+
+impl PatchInfo {
+    pub(crate) fn parse(buf: &[u8]) -> Result<Self> {
+        Ok(Self {
+            indirect: BucketTreeInfo::parse(&buf[0x0..0x20])?,
+            aes_ctr_ex: BucketTreeInfo::parse(&buf[0x20..0x40])?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndirectEntry {
+    pub virtual_offset: u64,
+    pub physical_offset: u64,
+    /// 0 for the base and 1 for the patch.
+    pub storage_index: u32,
+}
+
+impl IndirectEntry {
+    const SIZE: usize = 0x14;
+
+    /// Reads the relocation table from the decrypted patch section.
+    pub fn read_table<R: Read + Seek>(
+        info: &BucketTreeInfo,
+        reader: &mut R,
+    ) -> Result<(Vec<Self>, u64)> {
+        let (entries, end_offset) = info.read_entries(reader, IndirectEntry::SIZE)?;
+        let entries = entries
+            .iter()
+            .map(|entry| Self {
+                virtual_offset: read_u64_le(entry, 0x0),
+                physical_offset: read_u64_le(entry, 0x8),
+                storage_index: read_u32_le(entry, 0x10),
+            })
+            .collect::<Vec<_>>();
+        validate_offsets(entries.iter().map(|entry| entry.virtual_offset), end_offset)?;
+        Ok((entries, end_offset))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AesCtrExEntry {
+    pub offset: u64,
+    pub encrypted: bool,
+    /// Replaces the lower half of the upper counter.
+    pub generation: u32,
+}
+
+impl AesCtrExEntry {
+    const SIZE: usize = 0x10;
+
+    /// Reads the subsection table from the decrypted patch section.
+    pub fn read_table<R: Read + Seek>(
+        info: &BucketTreeInfo,
+        reader: &mut R,
+    ) -> Result<(Vec<Self>, u64)> {
+        let (entries, end_offset) = info.read_entries(reader, AesCtrExEntry::SIZE)?;
+        let entries = entries
+            .iter()
+            .map(|entry| Self {
+                offset: read_u64_le(entry, 0x0),
+                encrypted: entry[0x8] == 0,
+                generation: read_u32_le(entry, 0xC),
+            })
+            .collect::<Vec<_>>();
+        validate_offsets(entries.iter().map(|entry| entry.offset), end_offset)?;
+        Ok((entries, end_offset))
+    }
+}
+
+/// Entries must start at 0 and be sorted, as they're looked up with a binary search.
+fn validate_offsets<I: Iterator<Item = u64>>(offsets: I, end_offset: u64) -> Result<()> {
+    let mut offsets = offsets.peekable();
+    if offsets.peek().is_none() {
+        bail!("BKTR has no entries");
+    }
+    let mut prev = None;
+    for offset in offsets {
+        if prev.map_or(offset != 0, |prev| offset <= prev) || offset >= end_offset {
+            bail!("BKTR entries aren't sorted");
+        }
+        prev = Some(offset);
+    }
+    Ok(())
+}
+
+/// Decrypts the patch data of a patch section, each subsection has its own counter.
+///
+/// `inner` is the still encrypted section and `base_offset` its offset in the NCA.
+pub struct AesCtrExStorage<R> {
+    inner: R,
+    key: Key128,
+    upper_counter: u64,
+    base_offset: u64,
+    entries: Vec<AesCtrExEntry>,
+}
+
+impl<R> fmt::Debug for AesCtrExStorage<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AesCtrExStorage")
+            .field("upper_counter", &self.upper_counter)
+            .field("base_offset", &self.base_offset)
+            .field("entries", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read + Seek> AesCtrExStorage<R> {
+    pub fn new(
+        inner: R,
+        key: Key128,
+        upper_counter: u64,
+        base_offset: u64,
+        entries: Vec<AesCtrExEntry>,
+    ) -> Self {
+        Self {
+            inner,
+            key,
+            upper_counter,
+            base_offset,
+            entries,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for AesCtrExStorage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.inner.stream_position()?;
+        let index = self
+            .entries
+            .partition_point(|entry| entry.offset <= pos)
+            .checked_sub(1);
+        // The last subsection goes on till the end of the section
+        let len = match index.and_then(|index| self.entries.get(index + 1)) {
+            Some(next) => buf.len().min((next.offset - pos) as usize),
+            None => buf.len(),
+        };
+        let read = self.inner.read(&mut buf[..len])?;
+        match index.map(|index| self.entries[index]) {
+            Some(entry) if entry.encrypted => aes_ctr_apply(
+                &self.key,
+                self.upper_counter & 0xFFFF_FFFF_0000_0000 | entry.generation as u64,
+                self.base_offset + pos,
+                &mut buf[..read],
+            ),
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "read outside of the AES-CTR-EX subsections",
+                ))
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for AesCtrExStorage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA#Patch_RomFS
+///
+/// The patched section, each range of it is read from either the base or the patch.
+pub struct IndirectStorage<B, P> {
+    base: B,
+    patch: P,
+    entries: Vec<IndirectEntry>,
+    size: u64,
+    pos: u64,
+}
+
+impl<B, P> fmt::Debug for IndirectStorage<B, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndirectStorage")
+            .field("entries", &self.entries.len())
+            .field("size", &self.size)
+            .field("pos", &self.pos)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Read + Seek, P: Read + Seek> IndirectStorage<B, P> {
+    /// `base` is the decrypted base section and `patch` the decrypted patch section.
+    pub fn new(base: B, patch: P, entries: Vec<IndirectEntry>, size: u64) -> Self {
+        Self {
+            base,
+            patch,
+            entries,
+            size,
+            pos: 0,
+        }
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<B: Read + Seek, P: Read + Seek> Read for IndirectStorage<B, P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        // Entries start at 0, so there's always one
+        let index = self
+            .entries
+            .partition_point(|entry| entry.virtual_offset <= self.pos)
+            - 1;
+        let entry = self.entries[index];
+        let entry_end = self
+            .entries
+            .get(index + 1)
+            .map_or(self.size, |next| next.virtual_offset);
+        let len = buf.len().min((entry_end - self.pos) as usize);
+        let physical_offset = entry.physical_offset + (self.pos - entry.virtual_offset);
+
+        let read = match entry.storage_index {
+            0 => {
+                self.base.seek(SeekFrom::Start(physical_offset))?;
+                self.base.read(&mut buf[..len])?
+            }
+            1 => {
+                self.patch.seek(SeekFrom::Start(physical_offset))?;
+                self.patch.read(&mut buf[..len])?
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown indirect storage index {}", other),
+                ))
+            }
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<B: Read + Seek, P: Read + Seek> Seek for IndirectStorage<B, P> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match new_pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: Key128 = [0x77; 0x10];
+    const UPPER_COUNTER: u64 = 0x0000_0001_0000_0000;
+    const BASE_OFFSET: u64 = 0xC000;
+
+    /// Single entry set table, preceded by its L1 node.
+    fn bucket_tree(entries: &[Vec<u8>], end_offset: u64) -> Vec<u8> {
+        let mut table = vec![0u8; BucketTreeInfo::NODE_SIZE];
+        table[0x4..0x8].copy_from_slice(&1u32.to_le_bytes());
+        table[0x8..0x10].copy_from_slice(&end_offset.to_le_bytes());
+        let mut entry_set = vec![0u8; BucketTreeInfo::NODE_HEADER_SIZE];
+        entry_set[0x4..0x8].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        entry_set[0x8..0x10].copy_from_slice(&end_offset.to_le_bytes());
+        entry_set.extend(entries.concat());
+        entry_set.resize(BucketTreeInfo::NODE_SIZE, 0);
+        table.extend(entry_set);
+        table
+    }
+
+    fn bucket_tree_info(offset: u64, size: u64, entry_count: u32) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend(offset.to_le_bytes());
+        buf.extend(size.to_le_bytes());
+        buf.extend(BucketTreeHeader::MAGIC);
+        buf.extend(1u32.to_le_bytes());
+        buf.extend(entry_count.to_le_bytes());
+        buf.extend([0u8; 4]);
+        buf
+    }
+
+    fn indirect_entry(virtual_offset: u64, physical_offset: u64, storage_index: u32) -> Vec<u8> {
+        let mut entry = vec![];
+        entry.extend(virtual_offset.to_le_bytes());
+        entry.extend(physical_offset.to_le_bytes());
+        entry.extend(storage_index.to_le_bytes());
+        entry
+    }
+
+    fn aes_ctr_ex_entry(offset: u64, encrypted: bool, generation: u32) -> Vec<u8> {
+        let mut entry = vec![];
+        entry.extend(offset.to_le_bytes());
+        entry.extend([if encrypted { 0 } else { 1 }, 0, 0, 0]);
+        entry.extend(generation.to_le_bytes());
+        entry
+    }
+
+    /// Decrypted patch section with the patch data, followed by both tables.
+    fn patch_section() -> (Vec<u8>, PatchInfo) {
+        let mut section = vec![0x50; 0x200];
+        let indirect_offset = section.len() as u64;
+        section.extend(bucket_tree(
+            &[
+                indirect_entry(0x0, 0x0, 0),
+                indirect_entry(0x100, 0x0, 1),
+                indirect_entry(0x180, 0x100, 0),
+            ],
+            0x300,
+        ));
+        let aes_ctr_ex_offset = section.len() as u64;
+        section.extend(bucket_tree(
+            &[
+                aes_ctr_ex_entry(0x0, true, 1),
+                aes_ctr_ex_entry(0x80, false, 0),
+                aes_ctr_ex_entry(0x100, true, 2),
+            ],
+            0x200,
+        ));
+
+        let mut patch_info = bucket_tree_info(indirect_offset, 0x8000, 3);
+        patch_info.extend(bucket_tree_info(aes_ctr_ex_offset, 0x8000, 3));
+        (section, PatchInfo::parse(&patch_info).unwrap())
+    }
+
+    #[test]
+    fn read_tables() -> Result<()> {
+        let (section, patch_info) = patch_section();
+        let mut reader = Cursor::new(section);
+
+        let (entries, end_offset) = IndirectEntry::read_table(&patch_info.indirect, &mut reader)?;
+        assert_eq!(end_offset, 0x300);
+        assert_eq!(
+            entries[1],
+            IndirectEntry {
+                virtual_offset: 0x100,
+                physical_offset: 0x0,
+                storage_index: 1,
+            }
+        );
+
+        let (entries, end_offset) = AesCtrExEntry::read_table(&patch_info.aes_ctr_ex, &mut reader)?;
+        assert_eq!(end_offset, 0x200);
+        assert_eq!(
+            entries,
+            [
+                AesCtrExEntry {
+                    offset: 0x0,
+                    encrypted: true,
+                    generation: 1,
+                },
+                AesCtrExEntry {
+                    offset: 0x80,
+                    encrypted: false,
+                    generation: 0,
+                },
+                AesCtrExEntry {
+                    offset: 0x100,
+                    encrypted: true,
+                    generation: 2,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_rejects_bad_tables() {
+        let (section, mut patch_info) = patch_section();
+        let mut reader = Cursor::new(section);
+
+        let mut too_small = patch_info.indirect;
+        too_small.size = BucketTreeInfo::NODE_SIZE as u64;
+        assert!(IndirectEntry::read_table(&too_small, &mut reader).is_err());
+
+        // The entry set holds more entries than the header says
+        patch_info.indirect.header.entry_count = 2;
+        assert!(IndirectEntry::read_table(&patch_info.indirect, &mut reader).is_err());
+        assert!(PatchInfo::parse(&[0u8; 0x40]).is_err());
+    }
+
+    #[test]
+    fn read_patched_section() -> Result<()> {
+        let (section, patch_info) = patch_section();
+        let mut reader = Cursor::new(section);
+        let (indirect_entries, size) =
+            IndirectEntry::read_table(&patch_info.indirect, &mut reader)?;
+        let (aes_ctr_ex_entries, _) =
+            AesCtrExEntry::read_table(&patch_info.aes_ctr_ex, &mut reader)?;
+
+        // Encrypting the patch data the way AesCtrExStorage decrypts it
+        let patch_data = (0..0x200).map(|i| (i % 0xFF) as u8).collect::<Vec<_>>();
+        let mut encrypted = patch_data.clone();
+        for (range, generation) in [(0x0..0x80, 1), (0x100..0x200, 2)] {
+            aes_ctr_apply(
+                &KEY,
+                UPPER_COUNTER | generation,
+                BASE_OFFSET + range.start as u64,
+                &mut encrypted[range],
+            );
+        }
+        let mut patch = AesCtrExStorage::new(
+            Cursor::new(encrypted),
+            KEY,
+            UPPER_COUNTER | 0xFFFF,
+            BASE_OFFSET,
+            aes_ctr_ex_entries,
+        );
+        let mut decrypted = vec![];
+        patch.read_to_end(&mut decrypted)?;
+        assert_eq!(decrypted, patch_data);
+
+        let base_data = vec![0xBA; 0x300];
+        let mut storage = IndirectStorage::new(
+            Cursor::new(base_data.clone()),
+            Cursor::new(patch_data.clone()),
+            indirect_entries,
+            size,
+        );
+        assert_eq!(storage.size(), 0x300);
+        let mut patched = vec![];
+        storage.read_to_end(&mut patched)?;
+        let mut expected = base_data[..0x100].to_vec();
+        expected.extend(&patch_data[..0x80]);
+        expected.extend(&base_data[0x100..0x280]);
+        assert_eq!(patched, expected);
+
+        storage.seek(SeekFrom::Start(0xF0))?;
+        let mut buf = [0u8; 0x20];
+        storage.read_exact(&mut buf)?;
+        assert_eq!(buf, expected[0xF0..0x110]);
+        Ok(())
+    }
+}
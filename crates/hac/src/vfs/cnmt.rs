@@ -0,0 +1,871 @@
+use crate::{
+    keyset::Keyset,
+    vfs::{
+        hash_file,
+        nca::{self, Nca, SdkVersion},
+        nsp::Pfs0,
+        read_u32_le, read_u64_le,
+        ticket::TitleKey,
+    },
+};
+use common::utils::move_file;
+use derivative::Derivative;
+use eyre::{bail, eyre, Result};
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+/// https://switchbrew.org/wiki/NCM_services#ContentMetaType
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentMetaType {
+    SystemProgram = 0x01,
+    SystemData = 0x02,
+    SystemUpdate = 0x03,
+    BootImagePackage = 0x04,
+    BootImagePackageSafe = 0x05,
+    Application = 0x80,
+    Patch = 0x81,
+    AddOnContent = 0x82,
+    Delta = 0x83,
+    DataPatch = 0x84,
+}
+
+impl ContentMetaType {
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0x01 => Self::SystemProgram,
+            0x02 => Self::SystemData,
+            0x03 => Self::SystemUpdate,
+            0x04 => Self::BootImagePackage,
+            0x05 => Self::BootImagePackageSafe,
+            0x80 => Self::Application,
+            0x81 => Self::Patch,
+            0x82 => Self::AddOnContent,
+            0x83 => Self::Delta,
+            0x84 => Self::DataPatch,
+            other => bail!("Unknown ContentMetaType {:#x}", other),
+        })
+    }
+}
+
+impl fmt::Display for ContentMetaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// https://switchbrew.org/wiki/NCM_services#ContentType
+///
+/// Not to be confused with the ContentType in the NCA header, they're numbered differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    Meta = 0x00,
+    Program = 0x01,
+    Data = 0x02,
+    Control = 0x03,
+    HtmlDocument = 0x04,
+    LegalInformation = 0x05,
+    DeltaFragment = 0x06,
+}
+
+impl ContentType {
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0x00 => Self::Meta,
+            0x01 => Self::Program,
+            0x02 => Self::Data,
+            0x03 => Self::Control,
+            0x04 => Self::HtmlDocument,
+            0x05 => Self::LegalInformation,
+            0x06 => Self::DeltaFragment,
+            other => bail!("Unknown content ContentType {:#x}", other),
+        })
+    }
+    /// ContentType that the NCA of such content has in its header.
+    pub fn nca_content_type(&self) -> nca::ContentType {
+        match self {
+            ContentType::Meta => nca::ContentType::Meta,
+            ContentType::Program => nca::ContentType::Program,
+            ContentType::Data | ContentType::DeltaFragment => nca::ContentType::Data,
+            ContentType::Control => nca::ContentType::Control,
+            ContentType::HtmlDocument | ContentType::LegalInformation => nca::ContentType::Manual,
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/CNMT#Extended_header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedHeader {
+    Application {
+        patch_id: u64,
+        required_system_version: u32,
+        required_application_version: u32,
+    },
+    Patch {
+        application_id: u64,
+        required_system_version: u32,
+    },
+    AddOnContent {
+        application_id: u64,
+        required_application_version: u32,
+    },
+    Delta {
+        application_id: u64,
+    },
+    /// System titles, or an extended header that's too short.
+    None,
+}
+
+/// https://switchbrew.org/wiki/CNMT#Packaged_Content_Info
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct ContentRecord {
+    #[derivative(Debug(format_with = "hex_fmt"))]
+    pub hash: [u8; 0x20],
+    #[derivative(Debug(format_with = "hex_fmt"))]
+    pub content_id: [u8; 0x10],
+    pub size: u64,
+    pub content_type: ContentType,
+    /// Differentiates the programs of a multi-program application.
+    pub id_offset: u8,
+}
+
+impl ContentRecord {
+    const SIZE: usize = 0x38;
+
+    /// NCAs are named after their ContentID.
+    pub fn nca_filename(&self) -> String {
+        format!("{}.nca", hex::encode(self.content_id))
+    }
+}
+
+/// https://switchbrew.org/wiki/CNMT#Content_Meta_Info
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentMetaRecord {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+}
+
+impl ContentMetaRecord {
+    const SIZE: usize = 0x10;
+}
+
+fn hex_fmt(bytes: &[u8], fmt: &mut fmt::Formatter) -> fmt::Result {
+    fmt.write_fmt(format_args!("{:?}", hex::encode(bytes)))
+}
+
+/// https://switchbrew.org/wiki/CNMT
+///
+/// Packaged content meta, i.e. the `.cnmt` file in a Meta NCA's PFS0.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Cnmt {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub attributes: u8,
+    pub required_download_system_version: u32,
+    pub extended_header: ExtendedHeader,
+    pub content_records: Vec<ContentRecord>,
+    pub meta_records: Vec<ContentMetaRecord>,
+    #[derivative(Debug(format_with = "hex_fmt"))]
+    pub digest: [u8; 0x20],
+}
+
+impl Cnmt {
+    const HEADER_SIZE: usize = 0x20;
+    const DIGEST_SIZE: usize = 0x20;
+
+    /// Reads the CNMT from the PFS0 of a Meta NCA.
+    pub fn from_nca(nca: &Nca, keyset: &Keyset) -> Result<Self> {
+        if nca.header.content_type != nca::ContentType::Meta {
+            bail!("'{}' is not a Meta Type NCA", nca.path.display());
+        }
+
+        info!(nca = %nca.path.display(), "Reading CNMT");
+        // Meta NCAs never use TitleKey crypto
+        let mut pfs0_storage = nca.open_fs_data(0, keyset, None::<&TitleKey>)?;
+        let pfs0 = Pfs0::read(&mut pfs0_storage)
+            .map_err(|err| eyre!("'{}' {}", nca.path.display(), err))?;
+        let entry = pfs0
+            .entries_with_ext("cnmt")
+            .next()
+            .ok_or_else(|| eyre!("Couldn't find a CNMT in '{}'", nca.path.display()))?;
+        debug!(cnmt = %entry.name, size = entry.size, "Found CNMT");
+
+        pfs0_storage.seek(SeekFrom::Start(entry.offset))?;
+        let mut buf = vec![0u8; entry.size as usize];
+        pfs0_storage.read_exact(&mut buf)?;
+        Cnmt::parse(&buf).map_err(|err| eyre!("'{}' has an invalid CNMT: {}", entry.name, err))
+    }
+    /// Reads the CNMT of the first Meta NCA in `dir`.
+    pub fn from_dir<P: AsRef<Path>>(
+        header_key: &[u8; 0x20],
+        keyset: &Keyset,
+        dir: P,
+    ) -> Result<(Nca, Self)> {
+        let meta_nca = nca::nca_with_kind(header_key, dir.as_ref(), nca::ContentType::Meta)
+            .ok_or_else(|| eyre!("Couldn't find a Meta NCA in '{}'", dir.as_ref().display()))?
+            .remove(0);
+        let cnmt = Cnmt::from_nca(&meta_nca, keyset)?;
+        Ok((meta_nca, cnmt))
+    }
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf)?;
+        Cnmt::parse(&buf)
+    }
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Cnmt::HEADER_SIZE {
+            bail!("CNMT is truncated");
+        }
+        let meta_type = ContentMetaType::from_byte(buf[0xC])?;
+        let extended_header_size = u16::from_le_bytes([buf[0xE], buf[0xF]]) as usize;
+        let content_count = u16::from_le_bytes([buf[0x10], buf[0x11]]) as usize;
+        let meta_count = u16::from_le_bytes([buf[0x12], buf[0x13]]) as usize;
+
+        let records_offset = Cnmt::HEADER_SIZE + extended_header_size;
+        let meta_records_offset = records_offset + content_count * ContentRecord::SIZE;
+        let required_len = meta_records_offset + meta_count * ContentMetaRecord::SIZE;
+        if buf.len() < required_len {
+            bail!(
+                "CNMT is truncated, expected at least {:#x} bytes but got {:#x}",
+                required_len,
+                buf.len()
+            );
+        }
+
+        let extended_header =
+            Cnmt::parse_extended_header(meta_type, &buf[Cnmt::HEADER_SIZE..records_offset]);
+        let content_records = buf[records_offset..meta_records_offset]
+            .chunks_exact(ContentRecord::SIZE)
+            .map(|record| {
+                // 40-bit size, followed by the content attributes on newer firmwares
+                let mut size = [0u8; 8];
+                size[..5].copy_from_slice(&record[0x30..0x35]);
+                Ok(ContentRecord {
+                    hash: record[..0x20].try_into().unwrap(),
+                    content_id: record[0x20..0x30].try_into().unwrap(),
+                    size: u64::from_le_bytes(size),
+                    content_type: ContentType::from_byte(record[0x36])?,
+                    id_offset: record[0x37],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let meta_records = buf[meta_records_offset..required_len]
+            .chunks_exact(ContentMetaRecord::SIZE)
+            .map(|record| {
+                Ok(ContentMetaRecord {
+                    title_id: read_u64_le(record, 0x0),
+                    version: read_u32_le(record, 0x8),
+                    meta_type: ContentMetaType::from_byte(record[0xC])?,
+                    attributes: record[0xD],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // The digest is always at the end, after the extended data if there's any
+        let mut digest = [0u8; Cnmt::DIGEST_SIZE];
+        if buf.len() >= required_len + Cnmt::DIGEST_SIZE {
+            digest.copy_from_slice(&buf[buf.len() - Cnmt::DIGEST_SIZE..]);
+        } else {
+            warn!("CNMT has no digest");
+        }
+
+        let cnmt = Self {
+            title_id: read_u64_le(buf, 0x0),
+            version: read_u32_le(buf, 0x8),
+            meta_type,
+            attributes: buf[0x14],
+            required_download_system_version: read_u32_le(buf, 0x18),
+            extended_header,
+            content_records,
+            meta_records,
+            digest,
+        };
+        debug!(?cnmt);
+
+        Ok(cnmt)
+    }
+    fn parse_extended_header(meta_type: ContentMetaType, buf: &[u8]) -> ExtendedHeader {
+        match meta_type {
+            ContentMetaType::Application if buf.len() >= 0x10 => ExtendedHeader::Application {
+                patch_id: read_u64_le(buf, 0x0),
+                required_system_version: read_u32_le(buf, 0x8),
+                required_application_version: read_u32_le(buf, 0xC),
+            },
+            ContentMetaType::Patch if buf.len() >= 0xC => ExtendedHeader::Patch {
+                application_id: read_u64_le(buf, 0x0),
+                required_system_version: read_u32_le(buf, 0x8),
+            },
+            ContentMetaType::AddOnContent if buf.len() >= 0xC => ExtendedHeader::AddOnContent {
+                application_id: read_u64_le(buf, 0x0),
+                required_application_version: read_u32_le(buf, 0x8),
+            },
+            ContentMetaType::Delta if buf.len() >= 0x8 => ExtendedHeader::Delta {
+                application_id: read_u64_le(buf, 0x0),
+            },
+            _ => ExtendedHeader::None,
+        }
+    }
+    /// Serializes the CNMT, extended data (for eg. a Patch's history) isn't kept.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut extended_header = vec![];
+        match self.extended_header {
+            ExtendedHeader::Application {
+                patch_id,
+                required_system_version,
+                required_application_version,
+            } => {
+                extended_header.extend(patch_id.to_le_bytes());
+                extended_header.extend(required_system_version.to_le_bytes());
+                extended_header.extend(required_application_version.to_le_bytes());
+            }
+            ExtendedHeader::Patch {
+                application_id,
+                required_system_version,
+            } => {
+                extended_header.extend(application_id.to_le_bytes());
+                extended_header.extend(required_system_version.to_le_bytes());
+                // Extended data size and padding
+                extended_header.extend([0u8; 0xC]);
+            }
+            ExtendedHeader::AddOnContent {
+                application_id,
+                required_application_version,
+            } => {
+                extended_header.extend(application_id.to_le_bytes());
+                extended_header.extend(required_application_version.to_le_bytes());
+                extended_header.extend([0u8; 0x4]);
+            }
+            ExtendedHeader::Delta { application_id } => {
+                extended_header.extend(application_id.to_le_bytes());
+                extended_header.extend([0u8; 0x8]);
+            }
+            ExtendedHeader::None => {}
+        }
+
+        let mut buf = Vec::with_capacity(
+            Cnmt::HEADER_SIZE
+                + extended_header.len()
+                + self.content_records.len() * ContentRecord::SIZE
+                + self.meta_records.len() * ContentMetaRecord::SIZE
+                + Cnmt::DIGEST_SIZE,
+        );
+        buf.extend(self.title_id.to_le_bytes());
+        buf.extend(self.version.to_le_bytes());
+        buf.push(self.meta_type as u8);
+        buf.push(0);
+        buf.extend((extended_header.len() as u16).to_le_bytes());
+        buf.extend((self.content_records.len() as u16).to_le_bytes());
+        buf.extend((self.meta_records.len() as u16).to_le_bytes());
+        buf.push(self.attributes);
+        buf.extend([0u8; 3]);
+        buf.extend(self.required_download_system_version.to_le_bytes());
+        buf.extend([0u8; 4]);
+        buf.extend(extended_header);
+        for record in &self.content_records {
+            buf.extend(record.hash);
+            buf.extend(record.content_id);
+            buf.extend(&record.size.to_le_bytes()[..6]);
+            buf.push(record.content_type as u8);
+            buf.push(record.id_offset);
+        }
+        for record in &self.meta_records {
+            buf.extend(record.title_id.to_le_bytes());
+            buf.extend(record.version.to_le_bytes());
+            buf.push(record.meta_type as u8);
+            buf.push(record.attributes);
+            buf.extend([0u8; 2]);
+        }
+        buf.extend(self.digest);
+        buf
+    }
+    /// Name of the CNMT file in the Meta NCA, for eg. `Application_0100000000010000.cnmt`.
+    pub fn filename(&self) -> String {
+        format!("{}_{}.cnmt", self.meta_type, self.title_id_str())
+    }
+    pub fn title_id_str(&self) -> String {
+        format!("{:016x}", self.title_id)
+    }
+    pub fn required_system_version(&self) -> Option<u32> {
+        match self.extended_header {
+            ExtendedHeader::Application {
+                required_system_version,
+                ..
+            }
+            | ExtendedHeader::Patch {
+                required_system_version,
+                ..
+            } => Some(required_system_version),
+            _ => None,
+        }
+    }
+    pub fn required_application_version(&self) -> Option<u32> {
+        match self.extended_header {
+            ExtendedHeader::Application {
+                required_application_version,
+                ..
+            }
+            | ExtendedHeader::AddOnContent {
+                required_application_version,
+                ..
+            } => Some(required_application_version),
+            _ => None,
+        }
+    }
+    /// Content records of `content_type`, ordered by their id offset.
+    pub fn contents(&self, content_type: ContentType) -> Vec<&ContentRecord> {
+        let mut records = self
+            .content_records
+            .iter()
+            .filter(|record| record.content_type == content_type)
+            .collect::<Vec<_>>();
+        records.sort_by_key(|record| record.id_offset);
+        records
+    }
+    /// Finds the NCA of the first `content_type` record in `dir`.
+    pub fn content_nca<P: AsRef<Path>>(
+        &self,
+        header_key: &[u8; 0x20],
+        dir: P,
+        content_type: ContentType,
+    ) -> Result<Nca> {
+        let record = self
+            .contents(content_type)
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                eyre!(
+                    "CNMT of '{}' has no {:?} content",
+                    self.title_id_str(),
+                    content_type
+                )
+            })?;
+        record_nca(header_key, dir.as_ref(), record)
+    }
+    /// Finds the NCAs of every `content_type` record in `dir` along with their id offset,
+    /// ordered by it.
+    pub fn content_ncas<P: AsRef<Path>>(
+        &self,
+        header_key: &[u8; 0x20],
+        dir: P,
+        content_type: ContentType,
+    ) -> Result<Vec<(Nca, u8)>> {
+        let records = self.contents(content_type);
+        if records.is_empty() {
+            bail!(
+                "CNMT of '{}' has no {:?} content",
+                self.title_id_str(),
+                content_type
+            );
+        }
+        records
+            .into_iter()
+            .map(|record| {
+                Ok((
+                    record_nca(header_key, dir.as_ref(), record)?,
+                    record.id_offset,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Opens the NCA of the `record` in `dir`, making sure it's of the recorded type.
+fn record_nca(header_key: &[u8; 0x20], dir: &Path, record: &ContentRecord) -> Result<Nca> {
+    let path: PathBuf = dir.join(record.nca_filename());
+    let nca = Nca::try_new(header_key, &path)?;
+    if nca.header.content_type != record.content_type.nca_content_type() {
+        bail!(
+            "'{}' was expected to be {} Type NCA but is {}",
+            path.display(),
+            record.content_type.nca_content_type(),
+            nca.header.content_type
+        );
+    }
+    debug!(nca = %path.display(), content_type = ?record.content_type, "Found NCA from CNMT");
+    Ok(nca)
+}
+
+/// Builds a Meta NCA listing the given contents.
+#[derive(Debug, Clone)]
+pub struct CnmtBuilder {
+    title_id: u64,
+    meta_type: ContentMetaType,
+    version: u32,
+    required_system_version: u32,
+    required_application_version: u32,
+    contents: Vec<(PathBuf, ContentType, u8)>,
+}
+
+impl CnmtBuilder {
+    /// Only Application, Patch and AddOnContent are supported.
+    pub fn new(title_id: u64, meta_type: ContentMetaType) -> Self {
+        Self {
+            title_id,
+            meta_type,
+            version: 0,
+            required_system_version: 0,
+            required_application_version: 0,
+            contents: vec![],
+        }
+    }
+    pub fn version(&mut self, version: u32) -> &mut Self {
+        self.version = version;
+        self
+    }
+    /// Only used by Application and Patch.
+    pub fn required_system_version(&mut self, version: u32) -> &mut Self {
+        self.required_system_version = version;
+        self
+    }
+    /// Only used by Application and AddOnContent.
+    pub fn required_application_version(&mut self, version: u32) -> &mut Self {
+        self.required_application_version = version;
+        self
+    }
+    /// Adds a content NCA, use `add_nca` if its type can be told from the NCA header.
+    pub fn add_content<P: AsRef<Path>>(
+        &mut self,
+        nca_path: P,
+        content_type: ContentType,
+        id_offset: u8,
+    ) -> &mut Self {
+        self.contents
+            .push((nca_path.as_ref().to_owned(), content_type, id_offset));
+        self
+    }
+    /// Adds a content NCA going by its header, Manual NCAs can be either HtmlDocument
+    /// or LegalInformation so they must be added with `add_content`.
+    pub fn add_nca(&mut self, nca: &Nca) -> Result<&mut Self> {
+        let content_type = match nca.header.content_type {
+            nca::ContentType::Program => ContentType::Program,
+            nca::ContentType::Control => ContentType::Control,
+            nca::ContentType::Data | nca::ContentType::PublicData => ContentType::Data,
+            other => bail!(
+                "Can't tell the content type of '{}' from its {} Type",
+                nca.path.display(),
+                other
+            ),
+        };
+        Ok(self.add_content(&nca.path, content_type, 0))
+    }
+    /// Writes the Meta NCA to `outdir` as `<ContentID>.cnmt.nca`.
+    ///
+    /// The added NCAs are hashed and renamed after their ContentID, i.e. the first half
+    /// of the hash, if they aren't already.\
+    /// Returns the Meta NCA along with the final paths of the added NCAs, in the order
+    /// they were added.
+    pub fn build<P: AsRef<Path>>(&self, keyset: &Keyset, outdir: P) -> Result<(Nca, Vec<PathBuf>)> {
+        let header_key = keyset.header_key()?;
+        let extended_header =
+            match self.meta_type {
+                ContentMetaType::Application => ExtendedHeader::Application {
+                    patch_id: self.title_id | 0x800,
+                    required_system_version: self.required_system_version,
+                    required_application_version: self.required_application_version,
+                },
+                ContentMetaType::Patch => ExtendedHeader::Patch {
+                    application_id: self.title_id & !0x800,
+                    required_system_version: self.required_system_version,
+                },
+                ContentMetaType::AddOnContent => ExtendedHeader::AddOnContent {
+                    application_id: self.title_id.checked_sub(0x1000).ok_or_else(|| {
+                        eyre!("Invalid AddOnContent TitleID {:016x}", self.title_id)
+                    })? & !0xFFF,
+                    required_application_version: self.required_application_version,
+                },
+                other => bail!("Building {} CNMT isn't supported", other),
+            };
+
+        let mut content_records = vec![];
+        let mut content_paths = vec![];
+        // The Meta NCA must be decryptable with the keys that the contents need
+        let mut key_generation = 0;
+        let mut sdk_version = None;
+        for (path, content_type, id_offset) in &self.contents {
+            let hash = hash_file(path)?;
+            let content_id: [u8; 0x10] = hash[..0x10].try_into().unwrap();
+            let record = ContentRecord {
+                hash,
+                content_id,
+                size: path.metadata()?.len(),
+                content_type: *content_type,
+                id_offset: *id_offset,
+            };
+
+            let nca_path = path.with_file_name(record.nca_filename());
+            if path != &nca_path {
+                debug!(from = %path.display(), to = %nca_path.display(), "Renaming NCA to its ContentID");
+                move_file(path, &nca_path)?;
+            }
+            let nca = Nca::try_new(header_key, &nca_path)?;
+            key_generation = key_generation.max(nca.header.key_generation);
+            if sdk_version.is_none() || *content_type == ContentType::Program {
+                sdk_version = Some(nca.header.sdk_version);
+            }
+            debug!(?record, "Added content record");
+            content_records.push(record);
+            content_paths.push(nca_path);
+        }
+
+        let cnmt = Cnmt {
+            title_id: self.title_id,
+            version: self.version,
+            meta_type: self.meta_type,
+            attributes: 0,
+            required_download_system_version: 0,
+            extended_header,
+            content_records,
+            meta_records: vec![],
+            digest: [0; Cnmt::DIGEST_SIZE],
+        };
+        info!(
+            title_id = %cnmt.title_id_str(),
+            meta_type = %cnmt.meta_type,
+            version = cnmt.version,
+            contents = cnmt.content_records.len(),
+            "Generating Meta NCA"
+        );
+
+        let cnmt_bytes = cnmt.to_bytes();
+        let cnmt_filename = cnmt.filename();
+        let mut pfs0 = Pfs0::build_header([(cnmt_filename.as_str(), cnmt_bytes.len() as u64)]);
+        pfs0.extend(cnmt_bytes);
+
+        let temp_path = outdir.as_ref().join(format!("{}.nca", cnmt_filename));
+        let mut meta_nca = Nca::write_pfs0(
+            keyset,
+            nca::ContentType::Meta,
+            self.title_id,
+            key_generation,
+            sdk_version.unwrap_or(SdkVersion(0)),
+            &pfs0,
+            &temp_path,
+        )?;
+        let content_id = hex::encode(&hash_file(&temp_path)?[..0x10]);
+        meta_nca.path = outdir.as_ref().join(format!("{}.cnmt.nca", content_id));
+        move_file(&temp_path, &meta_nca.path)?;
+
+        info!(nca = %meta_nca.path.display(), "Generated Meta NCA");
+        Ok((meta_nca, content_paths))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyset::TEST_KEYS;
+
+    fn content_record(content_type: ContentType, id_offset: u8) -> ContentRecord {
+        ContentRecord {
+            hash: [content_type as u8 + 0x10; 0x20],
+            content_id: [content_type as u8 + 0x10; 0x10],
+            size: 0x12_3456_7890,
+            content_type,
+            id_offset,
+        }
+    }
+
+    #[test]
+    fn read_patch_cnmt() -> Result<()> {
+        let mut buf = vec![];
+        buf.extend(0x0100_ABCD_0000_0800u64.to_le_bytes());
+        buf.extend(0x10000u32.to_le_bytes());
+        buf.push(ContentMetaType::Patch as u8);
+        buf.push(0);
+        buf.extend(0x18u16.to_le_bytes());
+        buf.extend(1u16.to_le_bytes());
+        buf.extend(0u16.to_le_bytes());
+        buf.push(0);
+        buf.extend([0u8; 3]);
+        buf.extend(0x0C00_0000u32.to_le_bytes());
+        buf.extend([0u8; 4]);
+        // Extended header with an extended data size of 4
+        buf.extend(0x0100_ABCD_0000_0000u64.to_le_bytes());
+        buf.extend(0x0800_0000u32.to_le_bytes());
+        buf.extend(4u32.to_le_bytes());
+        buf.extend([0u8; 8]);
+        // Content record
+        buf.extend([0xAA; 0x20]);
+        buf.extend([0xBB; 0x10]);
+        buf.extend([0x00, 0x10, 0x00, 0x00, 0x01, 0x00]);
+        buf.extend([ContentType::Program as u8, 0x01]);
+        // Extended data and digest
+        buf.extend([0xEE; 4]);
+        buf.extend([0xDD; 0x20]);
+
+        let cnmt = Cnmt::read(&mut buf.as_slice())?;
+        assert_eq!(cnmt.title_id_str(), "0100abcd00000800");
+        assert_eq!(cnmt.version, 0x10000);
+        assert_eq!(cnmt.meta_type, ContentMetaType::Patch);
+        assert_eq!(cnmt.required_download_system_version, 0x0C00_0000);
+        assert_eq!(
+            cnmt.extended_header,
+            ExtendedHeader::Patch {
+                application_id: 0x0100_ABCD_0000_0000,
+                required_system_version: 0x0800_0000,
+            }
+        );
+        assert_eq!(cnmt.required_system_version(), Some(0x0800_0000));
+        assert_eq!(cnmt.required_application_version(), None);
+        assert_eq!(
+            cnmt.content_records,
+            [ContentRecord {
+                hash: [0xAA; 0x20],
+                content_id: [0xBB; 0x10],
+                size: 0x01_0000_1000,
+                content_type: ContentType::Program,
+                id_offset: 1,
+            }]
+        );
+        assert_eq!(
+            cnmt.content_records[0].nca_filename(),
+            format!("{}.nca", "bb".repeat(0x10))
+        );
+        assert_eq!(cnmt.digest, [0xDD; 0x20]);
+        assert_eq!(cnmt.filename(), "Patch_0100abcd00000800.cnmt");
+        Ok(())
+    }
+
+    #[test]
+    fn cnmt_round_trip() -> Result<()> {
+        let cnmt = Cnmt {
+            title_id: 0x0100_ABCD_0000_0000,
+            version: 0,
+            meta_type: ContentMetaType::Application,
+            attributes: 0,
+            required_download_system_version: 0,
+            extended_header: ExtendedHeader::Application {
+                patch_id: 0x0100_ABCD_0000_0800,
+                required_system_version: 0x0C00_0000,
+                required_application_version: 0,
+            },
+            content_records: vec![
+                content_record(ContentType::Program, 1),
+                content_record(ContentType::Control, 0),
+                content_record(ContentType::Program, 0),
+            ],
+            meta_records: vec![ContentMetaRecord {
+                title_id: 0x0100_ABCD_0000_1001,
+                version: 0x10000,
+                meta_type: ContentMetaType::AddOnContent,
+                attributes: 0,
+            }],
+            digest: [0xDD; 0x20],
+        };
+        let bytes = cnmt.to_bytes();
+        let parsed = Cnmt::read(&mut bytes.as_slice())?;
+        assert_eq!(parsed.title_id, cnmt.title_id);
+        assert_eq!(parsed.meta_type, cnmt.meta_type);
+        assert_eq!(parsed.extended_header, cnmt.extended_header);
+        assert_eq!(parsed.content_records, cnmt.content_records);
+        assert_eq!(parsed.meta_records, cnmt.meta_records);
+        assert_eq!(parsed.digest, cnmt.digest);
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        let programs = parsed.contents(ContentType::Program);
+        assert_eq!(
+            programs
+                .iter()
+                .map(|record| record.id_offset)
+                .collect::<Vec<_>>(),
+            [0, 1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_truncated_cnmt() {
+        let cnmt = Cnmt {
+            title_id: 0x0100_ABCD_0000_0000,
+            version: 0,
+            meta_type: ContentMetaType::Application,
+            attributes: 0,
+            required_download_system_version: 0,
+            extended_header: ExtendedHeader::None,
+            content_records: vec![content_record(ContentType::Program, 0)],
+            meta_records: vec![],
+            digest: [0; 0x20],
+        };
+        let bytes = cnmt.to_bytes();
+        let records_end = bytes.len() - Cnmt::DIGEST_SIZE;
+        assert!(Cnmt::read(&mut &bytes[..records_end - 1]).is_err());
+        assert!(Cnmt::read(&mut &bytes[..0x10]).is_err());
+    }
+
+    #[test]
+    fn build_meta_nca() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let header_key = keyset.header_key()?;
+        let dir = tempfile::tempdir()?;
+        let write_nca = |name: &str, content_type: nca::ContentType, key_generation: u8| {
+            let mut pfs0 = Pfs0::build_header([(name, 4)]);
+            pfs0.extend(b"DATA");
+            Nca::write_pfs0(
+                &keyset,
+                content_type,
+                0x0100_ABCD_0000_0000,
+                key_generation,
+                SdkVersion(0x000C_1100),
+                &pfs0,
+                dir.path().join(format!("{}.nca", name)),
+            )
+        };
+        let program = write_nca("program", nca::ContentType::Program, 0)?;
+        let control = write_nca("control", nca::ContentType::Control, 1)?;
+
+        let outdir = dir.path().join("out");
+        std::fs::create_dir(&outdir)?;
+        let (meta_nca, content_paths) =
+            CnmtBuilder::new(0x0100_ABCD_0000_0800, ContentMetaType::Patch)
+                .version(0x10000)
+                .required_system_version(0x0C00_0000)
+                .add_nca(&program)?
+                .add_content(&control.path, ContentType::Control, 0)
+                .build(&keyset, &outdir)?;
+
+        assert_eq!(meta_nca.header.content_type, nca::ContentType::Meta);
+        assert_eq!(meta_nca.header.key_generation, 1);
+        assert_eq!(meta_nca.header.sdk_version, SdkVersion(0x000C_1100));
+        let content_id = hex::encode(&hash_file(&meta_nca.path)?[..0x10]);
+        assert_eq!(
+            meta_nca.path,
+            outdir.join(format!("{}.cnmt.nca", content_id))
+        );
+        assert!(!program.path.exists() && !control.path.exists());
+
+        let (_, cnmt) = Cnmt::from_dir(header_key, &keyset, &outdir)?;
+        assert_eq!(cnmt.title_id, 0x0100_ABCD_0000_0800);
+        assert_eq!(cnmt.version, 0x10000);
+        assert_eq!(
+            cnmt.extended_header,
+            ExtendedHeader::Patch {
+                application_id: 0x0100_ABCD_0000_0000,
+                required_system_version: 0x0C00_0000,
+            }
+        );
+        assert_eq!(cnmt.content_records.len(), 2);
+        for (record, (path, content_type)) in cnmt.content_records.iter().zip([
+            (&content_paths[0], ContentType::Program),
+            (&content_paths[1], ContentType::Control),
+        ]) {
+            assert_eq!(record.content_type, content_type);
+            assert_eq!(record.hash, hash_file(path)?);
+            assert_eq!(path.file_name().unwrap(), record.nca_filename().as_str());
+            assert_eq!(record.size, path.metadata()?.len());
+        }
+        assert_eq!(
+            cnmt.content_nca(header_key, dir.path(), ContentType::Control)?
+                .path,
+            content_paths[1]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::vfs::npdm::Npdm;
+use eyre::{bail, Result};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// https://switchbrew.org/wiki/ExeFS
+///
+/// An unpacked ExeFS, i.e. the NPDM and the NSOs of a program.
+#[derive(Debug, Clone)]
+pub struct ExeFs {
+    pub path: PathBuf,
+    pub npdm: Npdm,
+    /// NSOs that are present, in the order they're loaded in.
+    pub nsos: Vec<PathBuf>,
+}
+
+impl ExeFs {
+    pub const NPDM_NAME: &str = "main.npdm";
+    /// https://switchbrew.org/wiki/ExeFS#Loading
+    pub const NSO_NAMES: [&str; 13] = [
+        "rtld", "main", "subsdk0", "subsdk1", "subsdk2", "subsdk3", "subsdk4", "subsdk5",
+        "subsdk6", "subsdk7", "subsdk8", "subsdk9", "sdk",
+    ];
+
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let npdm_path = dir.as_ref().join(ExeFs::NPDM_NAME);
+        if !npdm_path.is_file() {
+            bail!(
+                "Couldn't find '{}' in '{}', it's not an ExeFS",
+                ExeFs::NPDM_NAME,
+                dir.as_ref().display()
+            );
+        }
+        let exefs = Self {
+            path: dir.as_ref().to_owned(),
+            npdm: Npdm::from_file(npdm_path)?,
+            nsos: ExeFs::NSO_NAMES
+                .iter()
+                .map(|name| dir.as_ref().join(name))
+                .filter(|path| path.is_file())
+                .collect(),
+        };
+        debug!(?exefs, "Read ExeFS");
+        Ok(exefs)
+    }
+    pub fn nso(&self, name: &str) -> Option<&Path> {
+        self.nsos
+            .iter()
+            .find(|path| {
+                path.file_name()
+                    .map_or(false, |file_name| file_name == name)
+            })
+            .map(|path| path.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::npdm::tests::{build_npdm, PROGRAM_ID};
+    use fs_err as fs;
+
+    #[test]
+    fn exefs_from_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(ExeFs::from_dir(dir.path()).is_err());
+
+        fs::write(dir.path().join(ExeFs::NPDM_NAME), build_npdm())?;
+        for name in ["sdk", "main", "rtld", "unrelated"] {
+            fs::write(dir.path().join(name), b"NSO0")?;
+        }
+        let exefs = ExeFs::from_dir(dir.path())?;
+        assert_eq!(exefs.npdm.aci0.program_id, PROGRAM_ID);
+        assert_eq!(
+            exefs.nsos,
+            ["rtld", "main", "sdk"].map(|name| dir.path().join(name))
+        );
+        assert_eq!(exefs.nso("main"), Some(dir.path().join("main").as_path()));
+        assert_eq!(exefs.nso("subsdk0"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use crate::vfs::align_up;
+use eyre::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use tracing::debug;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IvfcLevel {
+    /// Offset relative to the start of the section.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// https://switchbrew.org/wiki/NCA#HierarchicalIntegrity
+///
+/// Hash tree used by RomFS sections, each level holds the SHA-256 of every block
+/// of the next one and the last level is the RomFS itself.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Ivfc {
+    pub levels: [IvfcLevel; Ivfc::LEVEL_COUNT],
+    /// Hash of the first level.
+    pub master_hash: [u8; 0x20],
+}
+
+impl Ivfc {
+    pub const MAGIC: [u8; 4] = *b"IVFC";
+    const VERSION: u32 = 0x20000;
+    const BLOCK_SIZE_LOG2: u32 = 14;
+    pub const BLOCK_SIZE: u64 = 1 << Ivfc::BLOCK_SIZE_LOG2;
+    /// Excluding the master hash.
+    pub const LEVEL_COUNT: usize = 6;
+    /// Size of the IVFC header in the FsHeader's HashData.
+    pub const HASH_DATA_SIZE: usize = 0xE0;
+    const HASH_SIZE: u64 = 0x20;
+
+    /// Levels needed for `data_size` bytes, each one starts at a block boundary.
+    fn layout(data_size: u64) -> [IvfcLevel; Ivfc::LEVEL_COUNT] {
+        let mut levels = [IvfcLevel::default(); Ivfc::LEVEL_COUNT];
+        levels[Ivfc::LEVEL_COUNT - 1].size = data_size;
+        for index in (0..Ivfc::LEVEL_COUNT - 1).rev() {
+            levels[index].size = align_up(levels[index + 1].size, Ivfc::BLOCK_SIZE)
+                / Ivfc::BLOCK_SIZE
+                * Ivfc::HASH_SIZE;
+        }
+        for index in 1..Ivfc::LEVEL_COUNT {
+            let prev = levels[index - 1];
+            levels[index].offset = align_up(prev.offset + prev.size, Ivfc::BLOCK_SIZE);
+        }
+        levels
+    }
+    /// Level holding the actual data, i.e. the RomFS.
+    pub fn data_level(&self) -> IvfcLevel {
+        self.levels[Ivfc::LEVEL_COUNT - 1]
+    }
+    /// Size of the section, excluding any padding after the data.
+    pub fn size(&self) -> u64 {
+        let data = self.data_level();
+        data.offset + data.size
+    }
+    /// The IVFC header as stored at the start of the FsHeader's HashData.
+    pub fn hash_data(&self) -> [u8; Ivfc::HASH_DATA_SIZE] {
+        let mut buf = [0u8; Ivfc::HASH_DATA_SIZE];
+        buf[0x0..0x4].copy_from_slice(&Ivfc::MAGIC);
+        buf[0x4..0x8].copy_from_slice(&Ivfc::VERSION.to_le_bytes());
+        buf[0x8..0xC].copy_from_slice(&(Ivfc::HASH_SIZE as u32).to_le_bytes());
+        buf[0xC..0x10].copy_from_slice(&(Ivfc::LEVEL_COUNT as u32 + 1).to_le_bytes());
+        for (index, level) in self.levels.iter().enumerate() {
+            let start = 0x10 + index * 0x18;
+            buf[start..start + 0x8].copy_from_slice(&level.offset.to_le_bytes());
+            buf[start + 0x8..start + 0x10].copy_from_slice(&level.size.to_le_bytes());
+            buf[start + 0x10..start + 0x14].copy_from_slice(&Ivfc::BLOCK_SIZE_LOG2.to_le_bytes());
+        }
+        // Signature salt is left empty
+        buf[0xC0..0xE0].copy_from_slice(&self.master_hash);
+        buf
+    }
+}
+
+/// Hashes the data written to it and places it after the hash levels,
+/// which are written out by `finish`.
+///
+/// As the layout depends on it, the size of the data must be known up front.
+pub struct IvfcWriter<W> {
+    writer: W,
+    section_start: u64,
+    levels: [IvfcLevel; Ivfc::LEVEL_COUNT],
+    block: Vec<u8>,
+    /// Hashes of the data blocks, i.e. the level before the data.
+    hashes: Vec<u8>,
+    written: u64,
+}
+
+impl<W: Write + Seek> IvfcWriter<W> {
+    /// `writer` must be at the start of the section.
+    pub fn new(mut writer: W, data_size: u64) -> Result<Self> {
+        let levels = Ivfc::layout(data_size);
+        let data_offset = levels[Ivfc::LEVEL_COUNT - 1].offset;
+        let section_start = writer.stream_position()?;
+        debug!(data_size, section_start, data_offset, "Writing IVFC");
+        writer.seek(SeekFrom::Start(section_start + data_offset))?;
+        Ok(Self {
+            writer,
+            section_start,
+            levels,
+            block: Vec::with_capacity(Ivfc::BLOCK_SIZE as usize),
+            hashes: Vec::with_capacity(levels[Ivfc::LEVEL_COUNT - 2].size as usize),
+            written: 0,
+        })
+    }
+    /// Writes the hash levels, the writer is left at the end of the data.
+    pub fn finish(mut self) -> Result<(Ivfc, W)> {
+        let data_size = self.levels[Ivfc::LEVEL_COUNT - 1].size;
+        if self.written != data_size {
+            bail!(
+                "Expected {:#x} bytes of IVFC data but got {:#x}",
+                data_size,
+                self.written
+            );
+        }
+        if !self.block.is_empty() {
+            self.hashes.extend(block_hash(&self.block));
+        }
+        let data_end = self.writer.stream_position()?;
+
+        let mut hash_levels = vec![self.hashes];
+        for _ in 1..Ivfc::LEVEL_COUNT - 1 {
+            let hashes = hash_levels[0]
+                .chunks(Ivfc::BLOCK_SIZE as usize)
+                .flat_map(block_hash)
+                .collect::<Vec<u8>>();
+            hash_levels.insert(0, hashes);
+        }
+        let master_hash = block_hash(&hash_levels[0]);
+
+        self.writer.seek(SeekFrom::Start(self.section_start))?;
+        for (level, hashes) in self.levels.iter().zip(&hash_levels) {
+            debug_assert_eq!(level.size, hashes.len() as u64);
+            self.writer.write_all(hashes)?;
+            let padding = align_up(level.size, Ivfc::BLOCK_SIZE) - level.size;
+            io::copy(&mut io::repeat(0).take(padding), &mut self.writer)?;
+        }
+        self.writer.seek(SeekFrom::Start(data_end))?;
+        self.writer.flush()?;
+
+        Ok((
+            Ivfc {
+                levels: self.levels,
+                master_hash,
+            },
+            self.writer,
+        ))
+    }
+}
+
+impl<W: Write> Write for IvfcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(Ivfc::BLOCK_SIZE as usize - self.block.len());
+        let written = self.writer.write(&buf[..len])?;
+        self.block.extend_from_slice(&buf[..written]);
+        self.written += written as u64;
+        if self.block.len() == Ivfc::BLOCK_SIZE as usize {
+            self.hashes.extend(block_hash(&self.block));
+            self.block.clear();
+        }
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// SHA-256 of a block, a partial one is hashed as if padded with zeroes.
+fn block_hash(block: &[u8]) -> [u8; 0x20] {
+    let mut hasher = Sha256::new();
+    hasher.update(block);
+    hasher.update(vec![0u8; Ivfc::BLOCK_SIZE as usize - block.len()]);
+    hasher.finalize().into()
+}
@@ -1,14 +1,36 @@
+use crate::{crypto::aes_ctr_apply, keyset::Key128, vfs::ncz::Ncz};
+use common::utils::ext_matches;
 use eyre::{bail, Result};
+use fs_err as fs;
 use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::debug;
 
+pub mod bktr;
+pub mod cnmt;
+pub mod exefs;
+pub mod ivfc;
 pub mod nacp;
 pub mod nca;
+pub mod ncz;
+pub mod npdm;
+pub mod nso;
 pub mod nsp;
+pub mod romfs;
+pub mod split;
 pub mod ticket;
 pub mod xci;
 
 // Yes....this is vfs....
 
+/// Addresses in the XCI and NCA headers are in these units.
+pub const MEDIA_UNIT_SIZE: u64 = 0x200;
+
 // No. of hexadecimal characters
 pub const PROGRAMID_LEN: u8 = 16;
 
@@ -33,3 +55,228 @@ pub fn filter_out_lines(pat: &str, buf: &[u8]) -> String {
 pub fn filter_out_key_mismatches(buf: &[u8]) -> String {
     filter_out_lines("Failed to match key", buf)
 }
+
+/// A window into an underlying reader, i.e. `offset..offset + size`.
+///
+/// Used for reading files embedded within a container (for eg. NCA in a NSP)
+/// without having to extract them first.
+#[derive(Debug)]
+pub struct SubStorage<R> {
+    inner: R,
+    offset: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> SubStorage<R> {
+    pub fn new(inner: R, offset: u64, size: u64) -> Self {
+        Self {
+            inner,
+            offset,
+            size,
+            pos: 0,
+        }
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for SubStorage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.size - self.pos) as usize);
+        self.inner.seek(SeekFrom::Start(self.offset + self.pos))?;
+        let read = self.inner.read(&mut buf[..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for SubStorage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match new_pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Decrypts an AES-CTR encrypted reader on the fly.
+///
+/// `base_offset` is where `inner` starts in the stream that the counter is derived from,
+/// for eg. the offset of a section within its NCA.
+pub struct AesCtrStorage<R> {
+    inner: R,
+    key: Key128,
+    upper_counter: u64,
+    base_offset: u64,
+}
+
+impl<R> fmt::Debug for AesCtrStorage<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AesCtrStorage")
+            .field("upper_counter", &self.upper_counter)
+            .field("base_offset", &self.base_offset)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read + Seek> AesCtrStorage<R> {
+    pub fn new(inner: R, key: Key128, upper_counter: u64, base_offset: u64) -> Self {
+        Self {
+            inner,
+            key,
+            upper_counter,
+            base_offset,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for AesCtrStorage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.inner.stream_position()?;
+        let read = self.inner.read(buf)?;
+        aes_ctr_apply(
+            &self.key,
+            self.upper_counter,
+            self.base_offset + pos,
+            &mut buf[..read],
+        );
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for AesCtrStorage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// SHA-256 of a whole file.
+pub(crate) fn hash_file<P: AsRef<Path>>(path: P) -> Result<[u8; 0x20]> {
+    let mut hasher = Sha256::new();
+    io::copy(
+        &mut io::BufReader::new(fs::File::open(path.as_ref())?),
+        &mut hasher,
+    )?;
+    Ok(hasher.finalize().into())
+}
+
+/// Streams an entry of a container out to the `to` dir as `name`.
+///
+/// NCZs are decompressed on the way out and saved as NCAs instead.
+pub(crate) fn extract_entry_to<R, P>(reader: R, name: &str, size: u64, to: P) -> Result<PathBuf>
+where
+    R: Read,
+    P: AsRef<Path>,
+{
+    if name.contains(['/', '\\']) || name == ".." {
+        bail!("Invalid entry name '{}'", name);
+    }
+    let mut reader = io::BufReader::new(reader);
+    if ext_matches(name, "ncz") {
+        let dest = to.as_ref().join(Path::new(name).with_extension("nca"));
+        debug!(entry = %name, size, to = %dest.display(), "Decompressing");
+        let mut writer = io::BufWriter::new(fs::File::create(&dest)?);
+        Ncz::decompress(reader, &mut writer)
+            .map_err(|err| eyre::eyre!("Failed to decompress '{}': {}", name, err))?;
+        writer.flush()?;
+        return Ok(dest);
+    }
+    let dest = to.as_ref().join(name);
+    debug!(entry = %name, size, to = %dest.display(), "Extracting");
+    let mut writer = io::BufWriter::new(fs::File::create(&dest)?);
+    if io::copy(&mut reader, &mut writer)? != size {
+        bail!("Entry '{}' is truncated", name);
+    }
+    writer.flush()?;
+    Ok(dest)
+}
+
+/// Reads the entry and string tables that follow a PFS0/HFS0 header.
+///
+/// The sizes come from the header, so they're checked against what's left of the `reader`
+/// before anything is allocated, a corrupted header would otherwise ask for gigabytes.
+pub(crate) fn read_entry_tables<R: Read + Seek>(
+    reader: &mut R,
+    entry_count: usize,
+    entry_size: usize,
+    string_table_size: usize,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let pos = reader.stream_position()?;
+    let remaining = reader.seek(SeekFrom::End(0))?.saturating_sub(pos);
+    reader.seek(SeekFrom::Start(pos))?;
+    let entry_table_size = entry_count
+        .checked_mul(entry_size)
+        .filter(|size| {
+            size.checked_add(string_table_size)
+                .map_or(false, |size| size as u64 <= remaining)
+        })
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "Header claims {} entries and a {:#x} byte string table, but only {:#x} bytes are left",
+                entry_count,
+                string_table_size,
+                remaining
+            )
+        })?;
+
+    let mut entry_table = vec![0u8; entry_table_size];
+    reader.read_exact(&mut entry_table)?;
+    let mut string_table = vec![0u8; string_table_size];
+    reader.read_exact(&mut string_table)?;
+    Ok((entry_table, string_table))
+}
+
+/// Rounds `value` up to a multiple of `alignment`.
+pub(crate) fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+pub(crate) fn read_u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_u64_le(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads a NUL terminated string starting at `offset`.
+pub(crate) fn read_cstr(buf: &[u8], offset: usize) -> Result<String> {
+    let bytes = buf
+        .get(offset..)
+        .ok_or_else(|| eyre::eyre!("String offset {:#x} is out of bounds", offset))?;
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+/// Writes `s` to `buf` as a NUL terminated string, the rest of `buf` is zeroed.
+pub(crate) fn write_cstr(buf: &mut [u8], s: &str) -> Result<()> {
+    if s.len() >= buf.len() {
+        bail!(
+            "'{}' is too long, it must be less than {} bytes",
+            s,
+            buf.len()
+        );
+    }
+    buf.fill(0);
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(())
+}
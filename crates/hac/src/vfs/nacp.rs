@@ -1,42 +1,248 @@
+use crate::{
+    keyset::Keyset,
+    vfs::{nca::Nca, read_cstr, read_u32_le, read_u64_le, ticket::TitleKey, write_cstr},
+};
 use common::filename::{self, UNICODE_REPLACEMENT_CHAR};
-use eyre::{bail, Result};
+use derivative::Derivative;
+use eyre::{bail, eyre, Result};
 use fs_err as fs;
 use std::{
-    io::{self, Read, Seek},
+    io::{Read, Write},
     path::{Path, PathBuf},
 };
-use tracing::info;
+use tracing::{debug, info};
 use walkdir::WalkDir;
 
 const NACP_FILENAME: &str = "control.nacp";
 
-/// https://switchbrew.org/wiki/NACP_Format
-///
-/// Provides access to some of the data contained within a NACP file.
-#[derive(Debug, Default, Clone)]
-pub struct NacpData {
-    pub title_entry: ApplicationTitle, // Only using the "AmericanEnglish" entry
-    pub application_version: [u8; 0x10],
+pub use config::Language;
+
+/// Icons of the Control NCA RomFS are named `icon_<Language>.dat`, they're JPEGs.
+pub fn icon_filename(language: Language) -> &'static str {
+    match language {
+        Language::AmericanEnglish => "icon_AmericanEnglish.dat",
+        Language::BritishEnglish => "icon_BritishEnglish.dat",
+        Language::Japanese => "icon_Japanese.dat",
+        Language::French => "icon_French.dat",
+        Language::German => "icon_German.dat",
+        Language::LatinAmericanSpanish => "icon_LatinAmericanSpanish.dat",
+        Language::Spanish => "icon_Spanish.dat",
+        Language::Italian => "icon_Italian.dat",
+        Language::Dutch => "icon_Dutch.dat",
+        Language::CanadianFrench => "icon_CanadianFrench.dat",
+        Language::Portuguese => "icon_Portuguese.dat",
+        Language::Russian => "icon_Russian.dat",
+        Language::Korean => "icon_Korean.dat",
+        Language::TraditionalChinese => "icon_TraditionalChinese.dat",
+        Language::SimplifiedChinese => "icon_SimplifiedChinese.dat",
+        Language::BrazilianPortuguese => "icon_BrazilianPortuguese.dat",
+    }
+}
+
+/// `language` followed by English and then the rest of the languages.
+fn fallbacks(language: Language) -> impl Iterator<Item = Language> {
+    [
+        language,
+        Language::AmericanEnglish,
+        Language::BritishEnglish,
+    ]
+    .into_iter()
+    .chain(Language::ALL)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ApplicationTitle {
-    pub application_name: [u8; 0x200],
-    pub application_publisher: [u8; 0x100],
+    pub name: String,
+    pub publisher: String,
+}
+
+impl ApplicationTitle {
+    const SIZE: usize = 0x300;
+    const NAME_SIZE: usize = 0x200;
+    const PUBLISHER_SIZE: usize = 0x100;
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StartupUserAccount {
+    #[default]
+    None,
+    Required,
+    RequiredWithNetworkServiceAccountAvailable,
+    /// Not documented, it's kept as is so that it's written back unchanged.
+    Unknown(u8),
+}
+
+impl From<u8> for StartupUserAccount {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => StartupUserAccount::None,
+            1 => StartupUserAccount::Required,
+            2 => StartupUserAccount::RequiredWithNetworkServiceAccountAvailable,
+            other => StartupUserAccount::Unknown(other),
+        }
+    }
+}
+
+impl From<StartupUserAccount> for u8 {
+    fn from(value: StartupUserAccount) -> Self {
+        match value {
+            StartupUserAccount::None => 0,
+            StartupUserAccount::Required => 1,
+            StartupUserAccount::RequiredWithNetworkServiceAccountAvailable => 2,
+            StartupUserAccount::Unknown(byte) => byte,
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/NACP#Screenshot
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Screenshot {
+    #[default]
+    Allow,
+    Deny,
+    /// Not documented, it's kept as is so that it's written back unchanged.
+    Unknown(u8),
+}
+
+impl From<u8> for Screenshot {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => Screenshot::Allow,
+            1 => Screenshot::Deny,
+            other => Screenshot::Unknown(other),
+        }
+    }
+}
+
+impl From<Screenshot> for u8 {
+    fn from(value: Screenshot) -> Self {
+        match value {
+            Screenshot::Allow => 0,
+            Screenshot::Deny => 1,
+            Screenshot::Unknown(byte) => byte,
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/NACP#LogoType
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogoType {
+    #[default]
+    LicensedByNintendo,
+    DistributedByNintendo,
+    Nintendo,
+    /// Not documented, it's kept as is so that it's written back unchanged.
+    Unknown(u8),
+}
+
+impl From<u8> for LogoType {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => LogoType::LicensedByNintendo,
+            1 => LogoType::DistributedByNintendo,
+            2 => LogoType::Nintendo,
+            other => LogoType::Unknown(other),
+        }
+    }
 }
 
-impl Default for ApplicationTitle {
-    fn default() -> Self {
-        Self {
-            application_name: std::array::from_fn(|_| Default::default()),
-            application_publisher: std::array::from_fn(|_| Default::default()),
+impl From<LogoType> for u8 {
+    fn from(value: LogoType) -> Self {
+        match value {
+            LogoType::LicensedByNintendo => 0,
+            LogoType::DistributedByNintendo => 1,
+            LogoType::Nintendo => 2,
+            LogoType::Unknown(byte) => byte,
         }
     }
 }
 
-impl NacpData {
-    const TITLE_ENTRY_OFFSET: usize = 0x0;
-    const APPLICATION_VERSION_OFFSET: usize = 0x3060;
+/// https://switchbrew.org/wiki/NACP#LogoHandling
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogoHandling {
+    #[default]
+    Auto,
+    Manual,
+    /// Not documented, it's kept as is so that it's written back unchanged.
+    Unknown(u8),
+}
+
+impl From<u8> for LogoHandling {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => LogoHandling::Auto,
+            1 => LogoHandling::Manual,
+            other => LogoHandling::Unknown(other),
+        }
+    }
+}
+
+impl From<LogoHandling> for u8 {
+    fn from(value: LogoHandling) -> Self {
+        match value {
+            LogoHandling::Auto => 0,
+            LogoHandling::Manual => 1,
+            LogoHandling::Unknown(byte) => byte,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SaveDataSizes {
+    pub user_account: i64,
+    pub user_account_journal: i64,
+    pub device: i64,
+    pub device_journal: i64,
+    pub bcat_delivery_cache: i64,
+}
+
+/// https://switchbrew.org/wiki/NACP
+///
+/// Application control properties, i.e. the `control.nacp` file in the Control NCA's RomFS.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Nacp {
+    /// Title entries, indexed by `Language`.
+    pub titles: [ApplicationTitle; 16],
+    pub isbn: String,
+    pub startup_user_account: StartupUserAccount,
+    /// Bitmask of `Language`s.
+    pub supported_language_flag: u32,
+    pub screenshot: Screenshot,
+    pub presence_group_id: u64,
+    /// Minimum age for each rating organization, `-1` if unrated.
+    pub rating_age: [i8; 0x20],
+    pub display_version: String,
+    pub add_on_content_base_id: u64,
+    pub save_data_owner_id: u64,
+    pub save_data_sizes: SaveDataSizes,
+    pub logo_type: LogoType,
+    pub logo_handling: LogoHandling,
+    /// The whole file, for the fields that aren't parsed.
+    #[derivative(Debug = "ignore")]
+    pub raw: Vec<u8>,
+}
+
+impl Nacp {
+    pub const SIZE: usize = 0x4000;
+    const ISBN_OFFSET: usize = 0x3000;
+    const ISBN_SIZE: usize = 0x25;
+    const STARTUP_USER_ACCOUNT_OFFSET: usize = 0x3025;
+    const SUPPORTED_LANGUAGE_FLAG_OFFSET: usize = 0x302C;
+    const SCREENSHOT_OFFSET: usize = 0x3034;
+    const PRESENCE_GROUP_ID_OFFSET: usize = 0x3038;
+    const RATING_AGE_OFFSET: usize = 0x3040;
+    const DISPLAY_VERSION_OFFSET: usize = 0x3060;
+    const DISPLAY_VERSION_SIZE: usize = 0x10;
+    const ADD_ON_CONTENT_BASE_ID_OFFSET: usize = 0x3070;
+    const SAVE_DATA_OWNER_ID_OFFSET: usize = 0x3078;
+    const SAVE_DATA_SIZES_OFFSET: usize = 0x3080;
+    const LOGO_TYPE_OFFSET: usize = 0x30F0;
+    const LOGO_HANDLING_OFFSET: usize = 0x30F1;
 
     pub fn try_new<P: AsRef<Path>>(nacp_path: P) -> Result<Self> {
         if !nacp_path.as_ref().is_file() || !is_nacp(nacp_path.as_ref()) {
@@ -44,33 +250,239 @@ impl NacpData {
         }
 
         info!("Reading NACP data");
+        let nacp = Nacp::read(&mut fs::File::open(nacp_path.as_ref())?)?;
+        info!("Successfully read NACP data");
+
+        Ok(nacp)
+    }
+    /// Reads `control.nacp` straight from the RomFS of a Control NCA.
+    pub fn from_control_nca<'a, I>(nca: &Nca, keyset: &Keyset, title_keys: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        info!(nca = %nca.path.display(), "Reading NACP data from NCA");
+        let buf = nca
+            .open_romfs(keyset, title_keys)?
+            .read_file(NACP_FILENAME)?;
+        Nacp::read(&mut buf.as_slice())
+    }
+    /// Reads the icon straight from the RomFS of a Control NCA in the `preferred` language,
+    /// falling back to English and then to the first one present.
+    pub fn icon_from_control_nca<'a, I>(
+        nca: &Nca,
+        keyset: &Keyset,
+        title_keys: I,
+        preferred: Language,
+    ) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let mut romfs = nca.open_romfs(keyset, title_keys)?;
+        let filename = fallbacks(preferred)
+            .map(icon_filename)
+            .find(|filename| romfs.file(filename).is_some())
+            .ok_or_else(|| eyre!("Couldn't find an icon in '{}'", nca.path.display()))?;
+        info!(nca = %nca.path.display(), icon = %filename, "Reading icon from NCA");
+        romfs.read_file(filename)
+    }
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut buf = vec![0u8; Nacp::SIZE];
+        reader.read_exact(&mut buf)?;
+        Nacp::parse(buf)
+    }
+    fn parse(buf: Vec<u8>) -> Result<Self> {
+        let mut titles: [ApplicationTitle; 16] = Default::default();
+        for (i, title) in titles.iter_mut().enumerate() {
+            let entry = &buf[i * ApplicationTitle::SIZE..(i + 1) * ApplicationTitle::SIZE];
+            title.name = read_cstr(&entry[..ApplicationTitle::NAME_SIZE], 0)?;
+            title.publisher = read_cstr(&entry[ApplicationTitle::NAME_SIZE..], 0)?;
+        }
 
-        let mut nacp_data = NacpData::default();
-        let mut nacp = fs::File::open(nacp_path.as_ref())?;
+        let save_data_size =
+            |i: usize| read_u64_le(&buf, Nacp::SAVE_DATA_SIZES_OFFSET + i * 8) as i64;
+        let nacp = Self {
+            titles,
+            isbn: read_cstr(
+                &buf[Nacp::ISBN_OFFSET..Nacp::ISBN_OFFSET + Nacp::ISBN_SIZE],
+                0,
+            )?,
+            startup_user_account: buf[Nacp::STARTUP_USER_ACCOUNT_OFFSET].into(),
+            supported_language_flag: read_u32_le(&buf, Nacp::SUPPORTED_LANGUAGE_FLAG_OFFSET),
+            screenshot: buf[Nacp::SCREENSHOT_OFFSET].into(),
+            presence_group_id: read_u64_le(&buf, Nacp::PRESENCE_GROUP_ID_OFFSET),
+            rating_age: std::array::from_fn(|i| buf[Nacp::RATING_AGE_OFFSET + i] as i8),
+            display_version: read_cstr(
+                &buf[Nacp::DISPLAY_VERSION_OFFSET
+                    ..Nacp::DISPLAY_VERSION_OFFSET + Nacp::DISPLAY_VERSION_SIZE],
+                0,
+            )?,
+            add_on_content_base_id: read_u64_le(&buf, Nacp::ADD_ON_CONTENT_BASE_ID_OFFSET),
+            save_data_owner_id: read_u64_le(&buf, Nacp::SAVE_DATA_OWNER_ID_OFFSET),
+            save_data_sizes: SaveDataSizes {
+                user_account: save_data_size(0),
+                user_account_journal: save_data_size(1),
+                device: save_data_size(2),
+                device_journal: save_data_size(3),
+                bcat_delivery_cache: save_data_size(4),
+            },
+            logo_type: buf[Nacp::LOGO_TYPE_OFFSET].into(),
+            logo_handling: buf[Nacp::LOGO_HANDLING_OFFSET].into(),
+            raw: buf,
+        };
+        debug!(?nacp);
 
-        nacp.seek(io::SeekFrom::Start(NacpData::TITLE_ENTRY_OFFSET as _))?;
-        nacp.read_exact(nacp_data.title_entry.application_name.as_mut())?;
-        nacp.read_exact(nacp_data.title_entry.application_publisher.as_mut())?;
+        Ok(nacp)
+    }
+    /// Serializes the NACP, fields that aren't parsed are kept from `raw`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = self.raw.clone();
+        buf.resize(Nacp::SIZE, 0);
 
-        nacp.seek(io::SeekFrom::Start(
-            NacpData::APPLICATION_VERSION_OFFSET as _,
-        ))?;
-        nacp.read_exact(nacp_data.application_version.as_mut())?;
+        for (i, title) in self.titles.iter().enumerate() {
+            let entry = &mut buf[i * ApplicationTitle::SIZE..(i + 1) * ApplicationTitle::SIZE];
+            let (name, publisher) = entry.split_at_mut(ApplicationTitle::NAME_SIZE);
+            write_cstr(name, &title.name)?;
+            write_cstr(
+                &mut publisher[..ApplicationTitle::PUBLISHER_SIZE],
+                &title.publisher,
+            )?;
+        }
+        write_cstr(
+            &mut buf[Nacp::ISBN_OFFSET..Nacp::ISBN_OFFSET + Nacp::ISBN_SIZE],
+            &self.isbn,
+        )?;
+        buf[Nacp::STARTUP_USER_ACCOUNT_OFFSET] = self.startup_user_account.into();
+        let mut write_at = |offset: usize, bytes: &[u8]| {
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        write_at(
+            Nacp::SUPPORTED_LANGUAGE_FLAG_OFFSET,
+            &self.supported_language_flag.to_le_bytes(),
+        );
+        write_at(Nacp::SCREENSHOT_OFFSET, &[self.screenshot.into()]);
+        write_at(
+            Nacp::PRESENCE_GROUP_ID_OFFSET,
+            &self.presence_group_id.to_le_bytes(),
+        );
+        write_at(
+            Nacp::RATING_AGE_OFFSET,
+            &self.rating_age.map(|age| age as u8),
+        );
+        write_at(
+            Nacp::ADD_ON_CONTENT_BASE_ID_OFFSET,
+            &self.add_on_content_base_id.to_le_bytes(),
+        );
+        write_at(
+            Nacp::SAVE_DATA_OWNER_ID_OFFSET,
+            &self.save_data_owner_id.to_le_bytes(),
+        );
+        let sizes = &self.save_data_sizes;
+        for (i, size) in [
+            sizes.user_account,
+            sizes.user_account_journal,
+            sizes.device,
+            sizes.device_journal,
+            sizes.bcat_delivery_cache,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            write_at(Nacp::SAVE_DATA_SIZES_OFFSET + i * 8, &size.to_le_bytes());
+        }
+        write_at(Nacp::LOGO_TYPE_OFFSET, &[self.logo_type.into()]);
+        write_at(Nacp::LOGO_HANDLING_OFFSET, &[self.logo_handling.into()]);
+        write_cstr(
+            &mut buf[Nacp::DISPLAY_VERSION_OFFSET
+                ..Nacp::DISPLAY_VERSION_OFFSET + Nacp::DISPLAY_VERSION_SIZE],
+            &self.display_version,
+        )?;
 
-        info!("Successfully read NACP data");
+        Ok(buf)
+    }
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.to_bytes()?)?;
+        Ok(())
+    }
+    /// Overwrites the NACP file at `nacp_path`.
+    pub fn save<P: AsRef<Path>>(&self, nacp_path: P) -> Result<()> {
+        // Serializing first so that a bad field doesn't leave a truncated file behind
+        let buf = self.to_bytes()?;
+        fs::write(nacp_path.as_ref(), buf)?;
+        info!(nacp = %nacp_path.as_ref().display(), "Saved NACP data");
+        Ok(())
+    }
+    /// Sets the title entry of `language`, `None` fields are left as is.
+    ///
+    /// The language is marked as supported if it ends up with a name.
+    pub fn set_title(
+        &mut self,
+        language: Language,
+        name: Option<&str>,
+        publisher: Option<&str>,
+    ) -> Result<()> {
+        if name.map_or(false, |name| name.len() >= ApplicationTitle::NAME_SIZE) {
+            bail!(
+                "Application name must be less than {} bytes",
+                ApplicationTitle::NAME_SIZE
+            );
+        }
+        if publisher.map_or(false, |publisher| {
+            publisher.len() >= ApplicationTitle::PUBLISHER_SIZE
+        }) {
+            bail!(
+                "Application publisher must be less than {} bytes",
+                ApplicationTitle::PUBLISHER_SIZE
+            );
+        }
 
-        Ok(nacp_data)
+        let title = &mut self.titles[language as usize];
+        if let Some(name) = name {
+            title.name = name.to_owned();
+        }
+        if let Some(publisher) = publisher {
+            title.publisher = publisher.to_owned();
+        }
+        if !title.is_empty() {
+            self.supported_language_flag |= 1 << language as u32;
+        }
+        debug!(?language, title = ?self.titles[language as usize], "Set title entry");
+        Ok(())
+    }
+    pub fn set_display_version(&mut self, version: &str) -> Result<()> {
+        if version.len() >= Nacp::DISPLAY_VERSION_SIZE {
+            bail!(
+                "Display version must be less than {} bytes",
+                Nacp::DISPLAY_VERSION_SIZE
+            );
+        }
+        self.display_version = version.to_owned();
+        Ok(())
+    }
+    pub fn title(&self, language: Language) -> &ApplicationTitle {
+        &self.titles[language as usize]
+    }
+    /// Title entry in the `preferred` language, falling back to English and then
+    /// to the first non-empty entry.
+    pub fn preferred_title(&self, preferred: Language) -> &ApplicationTitle {
+        fallbacks(preferred)
+            .map(|language| self.title(language))
+            .find(|title| !title.is_empty())
+            .unwrap_or_else(|| self.title(preferred))
+    }
+    pub fn supports_language(&self, language: Language) -> bool {
+        self.supported_language_flag & (1 << language as u32) != 0
     }
-    pub fn get_application_name(&self) -> String {
-        NacpData::sanitize(&String::from_utf8_lossy(&self.title_entry.application_name))
+    /// Sanitized for use in filenames.
+    pub fn get_application_name(&self, preferred: Language) -> String {
+        Nacp::sanitize(&self.preferred_title(preferred).name)
     }
-    pub fn get_application_publisher(&self) -> String {
-        NacpData::sanitize(&String::from_utf8_lossy(
-            &self.title_entry.application_publisher,
-        ))
+    /// Sanitized for use in filenames.
+    pub fn get_application_publisher(&self, preferred: Language) -> String {
+        Nacp::sanitize(&self.preferred_title(preferred).publisher)
     }
+    /// Sanitized for use in filenames.
     pub fn get_application_version(&self) -> String {
-        NacpData::sanitize(&String::from_utf8_lossy(&self.application_version))
+        Nacp::sanitize(&self.display_version)
     }
     fn sanitize(s: &str) -> String {
         s.chars()
@@ -96,3 +508,148 @@ pub fn get_nacp_file<P: AsRef<Path>>(from: P) -> Option<PathBuf> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keyset::TEST_KEYS, vfs::nca::tests::write_control_nca};
+
+    fn build_nacp() -> Vec<u8> {
+        let mut buf = vec![0u8; Nacp::SIZE];
+        let mut write_at = |offset: usize, bytes: &[u8]| {
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        let japanese = Language::Japanese as usize * ApplicationTitle::SIZE;
+        write_at(japanese, "ゲーム".as_bytes());
+        write_at(japanese + ApplicationTitle::NAME_SIZE, b"Publisher");
+        write_at(Nacp::ISBN_OFFSET, b"978-3-16-148410-0");
+        write_at(Nacp::STARTUP_USER_ACCOUNT_OFFSET, &[0x07]);
+        write_at(
+            Nacp::SUPPORTED_LANGUAGE_FLAG_OFFSET,
+            &(1u32 << Language::Japanese as u32).to_le_bytes(),
+        );
+        write_at(Nacp::SCREENSHOT_OFFSET, &[0x01]);
+        write_at(Nacp::RATING_AGE_OFFSET, &[0x0C, 0xFF]);
+        write_at(Nacp::DISPLAY_VERSION_OFFSET, b"1.0.2");
+        write_at(
+            Nacp::SAVE_DATA_OWNER_ID_OFFSET,
+            &0x0100_ABCD_0000_0000u64.to_le_bytes(),
+        );
+        write_at(Nacp::SAVE_DATA_SIZES_OFFSET, &0x400000i64.to_le_bytes());
+        write_at(Nacp::LOGO_TYPE_OFFSET, &[0x09]);
+        // Not parsed, i.e. the reserved area at the end
+        write_at(0x3FFF, &[0xEE]);
+        buf
+    }
+
+    #[test]
+    fn read_nacp() -> Result<()> {
+        let nacp = Nacp::read(&mut build_nacp().as_slice())?;
+        assert_eq!(
+            nacp.title(Language::Japanese),
+            &ApplicationTitle {
+                name: "ゲーム".into(),
+                publisher: "Publisher".into(),
+            }
+        );
+        assert!(nacp.title(Language::AmericanEnglish).is_empty());
+        assert!(nacp.supports_language(Language::Japanese));
+        assert!(!nacp.supports_language(Language::AmericanEnglish));
+        assert_eq!(nacp.isbn, "978-3-16-148410-0");
+        assert_eq!(nacp.startup_user_account, StartupUserAccount::Unknown(0x07));
+        assert_eq!(nacp.screenshot, Screenshot::Deny);
+        assert_eq!(nacp.rating_age[..3], [12, -1, 0]);
+        assert_eq!(nacp.get_application_version(), "1.0.2");
+        assert_eq!(nacp.save_data_owner_id, 0x0100_ABCD_0000_0000);
+        assert_eq!(nacp.save_data_sizes.user_account, 0x400000);
+        assert_eq!(nacp.logo_type, LogoType::Unknown(0x09));
+        // Falls back to the only non-empty title
+        assert_eq!(nacp.get_application_name(Language::French), "ゲーム");
+        Ok(())
+    }
+
+    #[test]
+    fn nacp_round_trip() -> Result<()> {
+        let buf = build_nacp();
+        assert_eq!(Nacp::read(&mut buf.as_slice())?.to_bytes()?, buf);
+        Ok(())
+    }
+
+    #[test]
+    fn read_truncated_nacp() {
+        assert!(Nacp::read(&mut vec![0u8; Nacp::SIZE - 1].as_slice()).is_err());
+    }
+
+    #[test]
+    fn edit_nacp() -> Result<()> {
+        let mut nacp = Nacp::read(&mut build_nacp().as_slice())?;
+        nacp.set_title(Language::AmericanEnglish, Some("Game"), None)?;
+        nacp.set_title(Language::Japanese, None, Some("パブリッシャー"))?;
+        nacp.set_display_version("1.1.0")?;
+        assert!(nacp
+            .set_title(
+                Language::German,
+                Some(&"a".repeat(ApplicationTitle::NAME_SIZE)),
+                None
+            )
+            .is_err());
+        assert!(nacp
+            .set_display_version("1.0.0-with-a-long-suffix")
+            .is_err());
+
+        let edited = Nacp::read(&mut nacp.to_bytes()?.as_slice())?;
+        assert_eq!(edited.title(Language::AmericanEnglish).name, "Game");
+        assert!(edited.supports_language(Language::AmericanEnglish));
+        assert!(!edited.supports_language(Language::German));
+        assert_eq!(
+            edited.title(Language::Japanese),
+            &ApplicationTitle {
+                name: "ゲーム".into(),
+                publisher: "パブリッシャー".into(),
+            }
+        );
+        assert_eq!(edited.display_version, "1.1.0");
+        assert_eq!(edited.get_application_name(Language::French), "Game");
+        assert_eq!(edited.raw[0x3FFF], 0xEE);
+        Ok(())
+    }
+
+    #[test]
+    fn icon_from_control_nca() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let romfs_dir = dir.path().join("romfs");
+        fs::create_dir(&romfs_dir)?;
+        fs::write(romfs_dir.join(NACP_FILENAME), build_nacp())?;
+        fs::write(
+            romfs_dir.join(icon_filename(Language::Japanese)),
+            b"japanese",
+        )?;
+        fs::write(
+            romfs_dir.join(icon_filename(Language::BritishEnglish)),
+            b"british",
+        )?;
+        let (control, _) =
+            write_control_nca(&keyset, &romfs_dir, None, &dir.path().join("control.nca"))?;
+
+        let icon = |preferred| Nacp::icon_from_control_nca(&control, &keyset, None, preferred);
+        assert_eq!(icon(Language::Japanese)?, b"japanese");
+        // No French icon, American English comes first but there's only British English
+        assert_eq!(icon(Language::French)?, b"british");
+
+        // Languages other than English are the last resort
+        fs::remove_file(romfs_dir.join(icon_filename(Language::BritishEnglish)))?;
+        let (control, _) =
+            write_control_nca(&keyset, &romfs_dir, None, &dir.path().join("control.nca"))?;
+        assert_eq!(
+            Nacp::icon_from_control_nca(&control, &keyset, None, Language::French)?,
+            b"japanese"
+        );
+
+        fs::remove_file(romfs_dir.join(icon_filename(Language::Japanese)))?;
+        let (control, _) =
+            write_control_nca(&keyset, &romfs_dir, None, &dir.path().join("control.nca"))?;
+        assert!(Nacp::icon_from_control_nca(&control, &keyset, None, Language::French).is_err());
+        Ok(())
+    }
+}
@@ -1,21 +1,37 @@
 use std::{
     collections::{HashMap, HashSet},
     fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
     path::{Path, PathBuf},
     process::{Command, Stdio},
-    str::FromStr,
 };
 
-use common::utils::{ext_matches, get_fmt_size, move_file};
+use common::utils::{ext_matches, get_fmt_size};
 use derivative::Derivative;
 use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use sha2::{Digest, Sha256};
 use strum_macros::EnumString;
 use tracing::{debug, error, info, warn};
 use walkdir::WalkDir;
 
 use crate::{
-    backend::{Backend, BackendKind},
-    vfs::filter_out_key_mismatches,
+    backend::Backend,
+    crypto::{aes_ctr_apply, aes_ecb_decrypt, aes_ecb_encrypt, rsa2048_pss_verify, XtsCipher},
+    keyset::{Key128, Key256, KeyAreaKeyIndex, Keyset},
+    vfs::{
+        align_up,
+        bktr::{AesCtrExEntry, AesCtrExStorage, IndirectEntry, IndirectStorage, PatchInfo},
+        extract_entry_to, filter_out_key_mismatches,
+        ivfc::Ivfc,
+        npdm::Npdm,
+        nsp::Pfs0,
+        read_u32_le, read_u64_le,
+        romfs::{RomFs, RomFsBuilder},
+        ticket::TitleKey,
+        AesCtrStorage, SubStorage, MEDIA_UNIT_SIZE,
+    },
 };
 
 #[derive(Debug, Clone, Copy, EnumString, PartialEq, Eq, Hash)]
@@ -36,15 +52,523 @@ impl fmt::Display for ContentType {
 
 type ProgramID = [u8; 8];
 
-/// https://switchbrew.org/wiki/NCA\
-/// Provides some methods relating to Nca, an encrypted content archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistributionType {
+    Download = 0x00,
+    GameCard = 0x01,
+}
+
+/// https://switchbrew.org/wiki/NCA#FsEntry
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FsEntry {
+    /// Offset of the section in bytes, relative to the start of the NCA.
+    pub start_offset: u64,
+    pub end_offset: u64,
+}
+
+impl FsEntry {
+    pub fn size(&self) -> u64 {
+        self.end_offset.saturating_sub(self.start_offset)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    RomFs = 0x00,
+    PartitionFs = 0x01,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashType {
+    Auto = 0x00,
+    None = 0x01,
+    HierarchicalSha256 = 0x02,
+    HierarchicalIntegrity = 0x03,
+    AutoSha3 = 0x04,
+    HierarchicalSha3256 = 0x05,
+    HierarchicalIntegritySha3 = 0x06,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionType {
+    Auto = 0x00,
+    None = 0x01,
+    AesXts = 0x02,
+    AesCtr = 0x03,
+    AesCtrEx = 0x04,
+    AesCtrSkipLayerHash = 0x05,
+    AesCtrExSkipLayerHash = 0x06,
+}
+
+/// https://switchbrew.org/wiki/NCA#FsHeader
 #[derive(Derivative, Clone)]
 #[derivative(Debug)]
-pub struct Nca {
-    pub path: PathBuf,
+pub struct FsHeader {
+    pub version: u16,
+    pub fs_type: FsType,
+    pub hash_type: HashType,
+    pub encryption_type: EncryptionType,
+    /// Upper half of the AES-CTR counter, i.e. the generation and secure value.
+    pub upper_counter: u64,
+    #[derivative(Debug = "ignore")]
+    pub raw: Vec<u8>,
+}
+
+impl FsHeader {
+    pub const SIZE: usize = 0x200;
+    const HASH_DATA_OFFSET: usize = 0x8;
+    const PATCH_INFO_OFFSET: usize = 0x100;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        Ok(Self {
+            version: u16::from_le_bytes([buf[0x0], buf[0x1]]),
+            fs_type: match buf[0x2] {
+                0x00 => FsType::RomFs,
+                0x01 => FsType::PartitionFs,
+                other => bail!("Unknown FsType {:#x}", other),
+            },
+            hash_type: match buf[0x3] {
+                0x00 => HashType::Auto,
+                0x01 => HashType::None,
+                0x02 => HashType::HierarchicalSha256,
+                0x03 => HashType::HierarchicalIntegrity,
+                0x04 => HashType::AutoSha3,
+                0x05 => HashType::HierarchicalSha3256,
+                0x06 => HashType::HierarchicalIntegritySha3,
+                other => bail!("Unknown HashType {:#x}", other),
+            },
+            encryption_type: match buf[0x4] {
+                0x00 => EncryptionType::Auto,
+                0x01 => EncryptionType::None,
+                0x02 => EncryptionType::AesXts,
+                0x03 => EncryptionType::AesCtr,
+                0x04 => EncryptionType::AesCtrEx,
+                0x05 => EncryptionType::AesCtrSkipLayerHash,
+                0x06 => EncryptionType::AesCtrExSkipLayerHash,
+                other => bail!("Unknown EncryptionType {:#x}", other),
+            },
+            upper_counter: read_u64_le(buf, 0x140),
+            raw: buf.to_vec(),
+        })
+    }
+}
+
+impl FsHeader {
+    /// https://switchbrew.org/wiki/NCA#HashData
+    ///
+    /// Region of the actual FS data, i.e. the last hash layer, as `(offset, size)`
+    /// relative to the start of the section.
+    pub fn data_region(&self) -> Result<(u64, u64)> {
+        let hash_data = &self.raw[FsHeader::HASH_DATA_OFFSET..];
+        match self.hash_type {
+            HashType::HierarchicalSha256 | HashType::HierarchicalSha3256 => {
+                let layer_count = read_u32_le(hash_data, 0x24) as usize;
+                if !(1..=5).contains(&layer_count) {
+                    bail!("Invalid hash layer count {}", layer_count);
+                }
+                let region = 0x28 + (layer_count - 1) * 0x10;
+                Ok((
+                    read_u64_le(hash_data, region),
+                    read_u64_le(hash_data, region + 0x8),
+                ))
+            }
+            HashType::HierarchicalIntegrity | HashType::HierarchicalIntegritySha3 => {
+                if hash_data[..4] != Ivfc::MAGIC {
+                    bail!("Invalid IVFC magic");
+                }
+                // Includes the master hash
+                let level_count = read_u32_le(hash_data, 0xC) as usize;
+                if !(2..=7).contains(&level_count) {
+                    bail!("Invalid IVFC level count {}", level_count);
+                }
+                let level = 0x10 + (level_count - 2) * 0x18;
+                Ok((
+                    read_u64_le(hash_data, level),
+                    read_u64_le(hash_data, level + 0x8),
+                ))
+            }
+            other => bail!("Unsupported HashType {:?}", other),
+        }
+    }
+    /// Only the RomFS section of a patch NCA has it.
+    pub fn patch_info(&self) -> Result<Option<PatchInfo>> {
+        let patch_info = &self.raw[FsHeader::PATCH_INFO_OFFSET..FsHeader::PATCH_INFO_OFFSET + 0x40];
+        // Size of the relocation table
+        if read_u64_le(patch_info, 0x8) == 0 {
+            return Ok(None);
+        }
+        PatchInfo::parse(patch_info).map(Some)
+    }
+}
+
+/// Outcome of checking one of the NCA header signatures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    /// Verifies with Nintendo's key, i.e. the header is as it was signed.
+    Valid,
+    /// Doesn't verify, the header was modified or rebuilt.
+    Invalid,
+    /// Zeroed out, which is the case for NCAs built by yanu.
+    Unsigned,
+    /// Couldn't be checked since the key isn't available.
+    Unchecked,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Signature::Valid => write!(f, "valid"),
+            Signature::Invalid => write!(f, "invalid"),
+            Signature::Unsigned => write!(f, "unsigned"),
+            Signature::Unchecked => write!(f, "unchecked"),
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA#Header
+///
+/// Status of both header signatures, they're over the same 0x200 bytes of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureStatus {
+    /// Made with the fixed key of the SignatureKeyGeneration.
+    pub fixed_key: Signature,
+    /// Made with the ACID public key of the NPDM, only Program NCAs have it.
+    pub acid: Option<Signature>,
+}
+
+impl SignatureStatus {
+    /// Whether the NCA is officially signed, i.e. retail content.
+    pub fn is_official(&self) -> bool {
+        self.fixed_key == Signature::Valid
+            && self.acid.map_or(true, |acid| acid == Signature::Valid)
+    }
+    /// Whether any signature is missing or doesn't verify, i.e. the NCA was rebuilt.
+    pub fn is_rebuilt(&self) -> bool {
+        [Some(self.fixed_key), self.acid]
+            .into_iter()
+            .flatten()
+            .any(|signature| matches!(signature, Signature::Invalid | Signature::Unsigned))
+    }
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fixed key {}", self.fixed_key)?;
+        if let Some(acid) = self.acid {
+            write!(f, ", acid {}", acid)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdkVersion(pub u32);
+
+impl fmt::Display for SdkVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, micro, relstep] = self.0.to_be_bytes();
+        write!(f, "{}.{}.{}.{}", major, minor, micro, relstep)
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA#Header
+///
+/// Decrypted NCA header, only NCA2 and NCA3 are supported.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct NcaHeader {
+    pub version: u8,
+    pub distribution_type: DistributionType,
+    pub content_type: ContentType,
+    /// The larger of the two KeyGeneration fields.
+    pub key_generation: u8,
+    pub key_area_key_index: u8,
+    pub content_size: u64,
     #[derivative(Debug(format_with = "program_id_fmt"))]
     pub program_id: ProgramID,
-    pub content_type: ContentType,
+    pub content_index: u32,
+    pub sdk_version: SdkVersion,
+    #[derivative(Debug(format_with = "hex_fmt"))]
+    pub rights_id: [u8; 0x10],
+    pub fs_entries: [FsEntry; 4],
+    /// FsHeaders of the sections that have a non-empty FsEntry.
+    pub fs_headers: [Option<FsHeader>; 4],
+    #[derivative(Debug = "ignore")]
+    pub encrypted_key_area: [u8; 0x40],
+    /// The whole header, decrypted.
+    #[derivative(Debug = "ignore")]
+    pub raw: Vec<u8>,
+}
+
+fn hex_fmt(bytes: &[u8; 0x10], fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fmt.write_fmt(format_args!("{:?}", hex::encode(bytes)))
+}
+
+impl NcaHeader {
+    pub const SIZE: usize = 0xC00;
+    const SECTOR_SIZE: usize = 0x200;
+    const FS_HEADERS_OFFSET: usize = 0x400;
+    const SIGNATURE_SIZE: usize = 0x100;
+    /// Both signatures are over the header from the magic up to the FsHeaders.
+    const SIGNED_REGION: Range<usize> = 0x200..0x400;
+
+    /// Reads and decrypts the header with the `header_key`, `reader` must be positioned
+    /// at the start of the NCA.
+    pub fn read<R: Read>(reader: &mut R, header_key: &[u8; 0x20]) -> Result<Self> {
+        let mut buf = vec![0u8; NcaHeader::SIZE];
+        reader.read_exact(&mut buf)?;
+
+        let cipher = XtsCipher::new(header_key);
+        cipher.decrypt(
+            &mut buf[..NcaHeader::FS_HEADERS_OFFSET],
+            NcaHeader::SECTOR_SIZE,
+            0,
+        );
+        let version = match &buf[0x200..0x204] {
+            b"NCA3" => {
+                cipher.decrypt(
+                    &mut buf[NcaHeader::FS_HEADERS_OFFSET..],
+                    NcaHeader::SECTOR_SIZE,
+                    (NcaHeader::FS_HEADERS_OFFSET / NcaHeader::SECTOR_SIZE) as _,
+                );
+                3
+            }
+            b"NCA2" => {
+                // Each FsHeader is encrypted on its own as sector 0
+                for fs_header in
+                    buf[NcaHeader::FS_HEADERS_OFFSET..].chunks_exact_mut(FsHeader::SIZE)
+                {
+                    cipher.decrypt(fs_header, NcaHeader::SECTOR_SIZE, 0);
+                }
+                2
+            }
+            b"NCA0" => bail!("is a NCA0, which isn't supported"),
+            _ => bail!("has an invalid NCA magic, 'header_key' is likely incorrect"),
+        };
+
+        let distribution_type = match buf[0x204] {
+            0x00 => DistributionType::Download,
+            0x01 => DistributionType::GameCard,
+            other => bail!("has an unknown DistributionType {:#x}", other),
+        };
+        let content_type = match buf[0x205] {
+            0x00 => ContentType::Program,
+            0x01 => ContentType::Meta,
+            0x02 => ContentType::Control,
+            0x03 => ContentType::Manual,
+            0x04 => ContentType::Data,
+            0x05 => ContentType::PublicData,
+            other => bail!("has an unknown ContentType {:#x}", other),
+        };
+
+        let mut fs_entries = [FsEntry::default(); 4];
+        let mut fs_headers: [Option<FsHeader>; 4] = Default::default();
+        for i in 0..4 {
+            let entry = &buf[0x240 + i * 0x10..];
+            fs_entries[i] = FsEntry {
+                start_offset: read_u32_le(entry, 0x0) as u64 * MEDIA_UNIT_SIZE,
+                end_offset: read_u32_le(entry, 0x4) as u64 * MEDIA_UNIT_SIZE,
+            };
+            if !fs_entries[i].is_empty() {
+                let offset = NcaHeader::FS_HEADERS_OFFSET + i * FsHeader::SIZE;
+                fs_headers[i] = Some(
+                    FsHeader::parse(&buf[offset..offset + FsHeader::SIZE])
+                        .map_err(|err| eyre!("has an invalid FsHeader {}: {}", i, err))?,
+                );
+            }
+        }
+
+        let mut program_id = [0u8; 8];
+        program_id.copy_from_slice(&buf[0x210..0x218]);
+        // Stored in little-endian
+        program_id.reverse();
+
+        Ok(Self {
+            version,
+            distribution_type,
+            content_type,
+            key_generation: buf[0x206].max(buf[0x220]),
+            key_area_key_index: buf[0x207],
+            content_size: read_u64_le(&buf, 0x208),
+            program_id,
+            content_index: read_u32_le(&buf, 0x218),
+            sdk_version: SdkVersion(read_u32_le(&buf, 0x21C)),
+            rights_id: buf[0x230..0x240].try_into().unwrap(),
+            fs_entries,
+            fs_headers,
+            encrypted_key_area: buf[0x300..0x340].try_into().unwrap(),
+            raw: buf,
+        })
+    }
+    /// Generation of the fixed key that the first signature is made with.
+    pub fn signature_key_generation(&self) -> u8 {
+        self.raw[0x221]
+    }
+    /// Checks the first signature with the fixed key of the SignatureKeyGeneration
+    /// and the second one with `acid_public_key` if given.
+    pub fn signature_status(
+        &self,
+        keyset: &Keyset,
+        acid_public_key: Option<&[u8]>,
+    ) -> SignatureStatus {
+        let fixed_key = self.check_signature(
+            0,
+            keyset
+                .nca_header_modulus(self.signature_key_generation())
+                .map_err(|err| debug!(%err, "Can't check the fixed key signature"))
+                .ok(),
+        );
+        SignatureStatus {
+            fixed_key,
+            acid: acid_public_key.map(|key| self.check_signature(1, Some(key))),
+        }
+    }
+    fn check_signature(&self, index: usize, modulus: Option<&[u8]>) -> Signature {
+        let signature =
+            &self.raw[index * NcaHeader::SIGNATURE_SIZE..(index + 1) * NcaHeader::SIGNATURE_SIZE];
+        if signature.iter().all(|byte| *byte == 0) {
+            return Signature::Unsigned;
+        }
+        match modulus {
+            Some(modulus)
+                if rsa2048_pss_verify(modulus, &self.raw[NcaHeader::SIGNED_REGION], signature) =>
+            {
+                Signature::Valid
+            }
+            Some(_) => Signature::Invalid,
+            None => Signature::Unchecked,
+        }
+    }
+    /// Whether the NCA uses TitleKey crypto instead of its key area.
+    pub fn has_rights_id(&self) -> bool {
+        self.rights_id.iter().any(|byte| *byte != 0)
+    }
+    /// Master key revision that the key area and TitleKey are encrypted with,
+    /// both KeyGeneration 0 and 1 use the first one.
+    pub fn master_key_revision(&self) -> u8 {
+        self.key_generation.saturating_sub(1)
+    }
+    pub fn decrypt_key_area(&self, keyset: &Keyset) -> Result<KeyArea> {
+        let index = KeyAreaKeyIndex::from_byte(self.key_area_key_index).ok_or_else(|| {
+            eyre!(
+                "Unknown KeyAreaEncryptionKeyIndex {:#x}",
+                self.key_area_key_index
+            )
+        })?;
+        let mut key_area = self.encrypted_key_area;
+        aes_ecb_decrypt(
+            keyset.key_area_key(index, self.master_key_revision())?,
+            &mut key_area,
+        );
+        Ok(KeyArea(key_area))
+    }
+    /// Key for the AES-CTR sections, i.e. the decrypted TitleKey matching the RightsID
+    /// for NCAs with TitleKey crypto, otherwise the one from the key area.
+    pub fn content_key<'a, I>(&self, keyset: &Keyset, title_keys: I) -> Result<Key128>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        if self.has_rights_id() {
+            title_keys
+                .into_iter()
+                .find(|title_key| title_key.rights_id() == &self.rights_id)
+                .ok_or_else(|| {
+                    eyre!(
+                        "Couldn't find the TitleKey for RightsID '{}'",
+                        hex::encode(self.rights_id)
+                    )
+                })?
+                .decrypt(keyset, self.master_key_revision())
+        } else {
+            Ok(*self.decrypt_key_area(keyset)?.ctr_key())
+        }
+    }
+    /// Moves the NCA from TitleKey crypto to standard crypto, i.e. the decrypted TitleKey goes
+    /// in the key area and the RightsID is cleared. Returns the encrypted header, the sections
+    /// stay as they are since the key doesn't change.
+    ///
+    /// The header signatures don't match anymore, same as with any other rebuilt NCA.
+    pub fn to_standard_crypto<'a, I>(&self, keyset: &Keyset, title_keys: I) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        if self.version != 3 {
+            bail!("Only NCA3s can be moved to standard crypto");
+        }
+        let mut key_area = [0u8; 0x40];
+        key_area[0x20..0x30].copy_from_slice(&self.content_key(keyset, title_keys)?);
+        aes_ecb_encrypt(
+            keyset.key_area_key(KeyAreaKeyIndex::Application, self.master_key_revision())?,
+            &mut key_area,
+        );
+
+        let mut header = self.raw.clone();
+        header[0x207] = KeyAreaKeyIndex::Application as u8;
+        header[0x230..0x240].fill(0);
+        header[0x300..0x340].copy_from_slice(&key_area);
+        XtsCipher::new(keyset.header_key()?).encrypt(&mut header, NcaHeader::SECTOR_SIZE, 0);
+        Ok(header)
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA#Header
+///
+/// Decrypted key area, it's only used by NCAs without a RightsID.
+#[derive(Clone)]
+pub struct KeyArea([u8; 0x40]);
+
+impl fmt::Debug for KeyArea {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyArea(..)")
+    }
+}
+
+impl KeyArea {
+    pub fn xts_key(&self) -> Key256 {
+        self.0[..0x20].try_into().unwrap()
+    }
+    pub fn ctr_key(&self) -> &Key128 {
+        self.0[0x20..0x30].try_into().unwrap()
+    }
+}
+
+/// Decrypted view of a NCA section.
+#[derive(Debug)]
+pub enum SectionStorage {
+    Plain(SubStorage<fs::File>),
+    AesCtr(AesCtrStorage<SubStorage<fs::File>>),
+}
+
+/// RomFS section of a base NCA with the BKTR of a patch NCA applied.
+pub type PatchedSection = IndirectStorage<SectionStorage, AesCtrExStorage<SubStorage<fs::File>>>;
+
+impl Read for SectionStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SectionStorage::Plain(storage) => storage.read(buf),
+            SectionStorage::AesCtr(storage) => storage.read(buf),
+        }
+    }
+}
+
+impl Seek for SectionStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SectionStorage::Plain(storage) => storage.seek(pos),
+            SectionStorage::AesCtr(storage) => storage.seek(pos),
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/NCA\
+/// Provides some methods relating to Nca, an encrypted content archive.
+#[derive(Debug, Clone)]
+pub struct Nca {
+    pub path: PathBuf,
+    pub header: NcaHeader,
 }
 
 fn program_id_fmt(program_id: &ProgramID, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -54,97 +578,267 @@ fn program_id_fmt(program_id: &ProgramID, fmt: &mut std::fmt::Formatter) -> std:
 // TODO?: Add the stdout to the logs in case an error is catched in main
 
 impl Nca {
-    pub fn try_new<P: AsRef<Path>>(reader: &Backend, file_path: P) -> Result<Self> {
-        // Can't rely on Backend tools to check for NCA file because they're
-        // pretty bad cli tools (don't even have non zero exit status on failure)
-        // excluding Hactoolnet.
+    const PFS0_HASH_BLOCK_SIZE: usize = 0x1000;
+
+    pub fn try_new<P: AsRef<Path>>(header_key: &[u8; 0x20], file_path: P) -> Result<Self> {
         if !file_path.as_ref().is_file() || !ext_matches(file_path.as_ref(), "nca") {
             bail!("'{}' is not a NCA file", file_path.as_ref().display())
         }
 
-        info!(
+        debug!(
             nca = %file_path.as_ref().display(),
             size = %get_fmt_size(file_path.as_ref()).unwrap_or_default(),
-            "Identifying TitleID and ContentType",
+            "Reading NCA header",
         );
 
-        let output = Command::new(reader.path())
-            .args([file_path.as_ref()])
-            .output()?; // All streams are piped
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        if !output.status.success() {
-            warn!(
-                nca = %file_path.as_ref().display(),
-                backend = ?reader.kind(),
-                %stderr,
-                "Encountered an error while viewing info",
-            );
-        }
-        let stdout = String::from_utf8_lossy(&output.stdout);
-
-        let program_id_pat = match reader.kind() {
-            #[cfg(all(
-                target_arch = "x86_64",
-                any(target_os = "windows", target_os = "linux")
-            ))]
-            BackendKind::Hactoolnet => "TitleID:",
-            // On all supported platforms
-            BackendKind::Hactool => "Title ID:",
-            BackendKind::Hac2l => "Program Id:",
-            _ => unimplemented!(),
-        };
-        let mut program_id = [0u8; 8];
-        stdout
-            .lines()
-            .find(|line| line.contains(program_id_pat))
-            .and_then(|line| line.trim().split(' ').last())
-            .map(|id_str| hex::decode_to_slice(id_str, program_id.as_mut()))
-            .ok_or_else(|| {
-                eyre!(
-                    "Failed to process ProgramID of '{}'",
-                    file_path.as_ref().display()
-                )
-            })??;
-        debug!(program_id = ?hex::encode(program_id));
-
-        let content_type = match stdout
-            .lines()
-            .find(|line| line.contains("Content Type:"))
-            .and_then(|line| line.trim().split(' ').last().map(ContentType::from_str))
-            .transpose()
-        {
-            Ok(content_type) => content_type.ok_or_else(|| {
-                eyre!(
-                    "Failed to process ContentType of '{}'",
-                    file_path.as_ref().display()
-                )
-            })?,
-            Err(err) => {
-                // Unknown ContentType
-                warn!(
-                    nca = %file_path.as_ref().display(),
-                    backend = ?reader.kind(),
-                    stdout = %stdout,
-                    "Dumping stdout"
-                );
-                bail!(err);
-            }
-        };
-        debug!(?content_type);
+        let header = NcaHeader::read(&mut fs::File::open(file_path.as_ref())?, header_key)
+            .map_err(|err| eyre!("'{}' {}", file_path.as_ref().display(), err))?;
+        debug!(?header);
 
         Ok(Self {
             path: file_path.as_ref().to_owned(),
-            program_id,
-            content_type,
+            header,
         })
     }
     pub fn get_program_id(&self) -> String {
-        hex::encode(self.program_id)
+        hex::encode(self.header.program_id)
+    }
+    /// Checks the header signatures, the ACID public key for the second one is
+    /// read from the NPDM of Program NCAs.
+    pub fn signature_status<'a, I>(&self, keyset: &Keyset, title_keys: I) -> SignatureStatus
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let npdm = match self.header.content_type {
+            ContentType::Program => match Npdm::from_program_nca(self, keyset, title_keys) {
+                Ok(npdm) => Some(npdm),
+                Err(err) => {
+                    warn!(nca = %self.path.display(), %err, "Can't check the ACID signature");
+                    None
+                }
+            },
+            _ => None,
+        };
+        let mut status = self.header.signature_status(
+            keyset,
+            npdm.as_ref().map(|npdm| npdm.acid.public_key.as_slice()),
+        );
+        if self.header.content_type == ContentType::Program && npdm.is_none() {
+            status.acid = Some(Signature::Unchecked);
+        }
+        debug!(nca = %self.path.display(), %status, "Checked header signatures");
+        status
+    }
+    /// Opens section `index` for reading, TitleKeys are only needed if the NCA has a RightsID.
+    pub fn open_section<'a, I>(
+        &self,
+        index: usize,
+        keyset: &Keyset,
+        title_keys: I,
+    ) -> Result<SectionStorage>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let (entry, fs_header) = self
+            .header
+            .fs_headers
+            .get(index)
+            .and_then(|fs_header| fs_header.as_ref())
+            .map(|fs_header| (self.header.fs_entries[index], fs_header))
+            .ok_or_else(|| eyre!("'{}' has no section {}", self.path.display(), index))?;
+        let storage = SubStorage::new(
+            fs::File::open(&self.path)?,
+            entry.start_offset,
+            entry.size(),
+        );
+        match fs_header.encryption_type {
+            EncryptionType::None => Ok(SectionStorage::Plain(storage)),
+            EncryptionType::AesCtr | EncryptionType::AesCtrSkipLayerHash => {
+                Ok(SectionStorage::AesCtr(AesCtrStorage::new(
+                    storage,
+                    self.header.content_key(keyset, title_keys)?,
+                    fs_header.upper_counter,
+                    entry.start_offset,
+                )))
+            }
+            other => bail!(
+                "'{}' section {} uses {:?} encryption, which isn't supported",
+                self.path.display(),
+                index,
+                other
+            ),
+        }
+    }
+    /// Opens the FS data of section `index`, i.e. the PFS0 or the RomFS without
+    /// the hash layers.
+    pub fn open_fs_data<'a, I>(
+        &self,
+        index: usize,
+        keyset: &Keyset,
+        title_keys: I,
+    ) -> Result<SubStorage<SectionStorage>>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let section = self.open_section(index, keyset, title_keys)?;
+        let (offset, size) = self.header.fs_headers[index]
+            .as_ref()
+            .expect("Should be Some since the section was opened")
+            .data_region()
+            .map_err(|err| eyre!("'{}' section {}: {}", self.path.display(), index, err))?;
+        Ok(SubStorage::new(section, offset, size))
+    }
+    /// Index of the first section of `fs_type`.
+    pub fn section_index(&self, fs_type: FsType) -> Option<usize> {
+        self.header.fs_headers.iter().position(|fs_header| {
+            fs_header
+                .as_ref()
+                .map_or(false, |fs_header| fs_header.fs_type == fs_type)
+        })
     }
-    pub fn unpack_romfs<P: AsRef<Path>>(&self, extractor: &Backend, romfs_dir: P) -> Result<()> {
+    /// Opens the RomFS section without extracting anything, TitleKeys are only needed
+    /// if the NCA has a RightsID.
+    pub fn open_romfs<'a, I>(
+        &self,
+        keyset: &Keyset,
+        title_keys: I,
+    ) -> Result<RomFs<SubStorage<SectionStorage>>>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let index = self
+            .section_index(FsType::RomFs)
+            .ok_or_else(|| eyre!("'{}' has no RomFS section", self.path.display()))?;
+        RomFs::new(self.open_fs_data(index, keyset, title_keys)?)
+            .map_err(|err| eyre!("'{}' {}", self.path.display(), err))
+    }
+    /// https://switchbrew.org/wiki/NCA#Patch_RomFS
+    ///
+    /// Opens the RomFS of this base NCA with the BKTR of the `patch` NCA applied,
+    /// i.e. the RomFS of the updated program.
+    pub fn open_patched_romfs<'a, I>(
+        &self,
+        patch: &Nca,
+        keyset: &Keyset,
+        title_keys: I,
+    ) -> Result<RomFs<SubStorage<PatchedSection>>>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let title_keys = title_keys.into_iter().collect::<Vec<_>>();
+        let base_index = self
+            .section_index(FsType::RomFs)
+            .ok_or_else(|| eyre!("'{}' has no RomFS section", self.path.display()))?;
+        let patch_index = patch
+            .section_index(FsType::RomFs)
+            .ok_or_else(|| eyre!("'{}' has no RomFS section", patch.path.display()))?;
+        let patch_header = patch.header.fs_headers[patch_index]
+            .as_ref()
+            .expect("Should be Some as it has a FsType");
+        let patch_info = patch_header
+            .patch_info()
+            .map_err(|err| eyre!("'{}' {}", patch.path.display(), err))?
+            .ok_or_else(|| eyre!("'{}' isn't a patch NCA", patch.path.display()))?;
+        let entry = patch.header.fs_entries[patch_index];
+        let key = patch
+            .header
+            .content_key(keyset, title_keys.iter().copied())?;
+        let patch_section = || -> Result<SubStorage<fs::File>> {
+            Ok(SubStorage::new(
+                fs::File::open(&patch.path)?,
+                entry.start_offset,
+                entry.size(),
+            ))
+        };
+
+        // The tables themselves are under the usual AES-CTR of the section
+        let mut tables = AesCtrStorage::new(
+            patch_section()?,
+            key,
+            patch_header.upper_counter,
+            entry.start_offset,
+        );
+        let (indirect_entries, patched_size) =
+            IndirectEntry::read_table(&patch_info.indirect, &mut tables)
+                .map_err(|err| eyre!("'{}' {}", patch.path.display(), err))?;
+        let (aes_ctr_ex_entries, _) =
+            AesCtrExEntry::read_table(&patch_info.aes_ctr_ex, &mut tables)
+                .map_err(|err| eyre!("'{}' {}", patch.path.display(), err))?;
+        debug!(
+            indirect_entries = indirect_entries.len(),
+            aes_ctr_ex_entries = aes_ctr_ex_entries.len(),
+            patched_size,
+            "Read BKTR tables"
+        );
+
+        let patched = IndirectStorage::new(
+            self.open_section(base_index, keyset, title_keys.iter().copied())?,
+            AesCtrExStorage::new(
+                patch_section()?,
+                key,
+                patch_header.upper_counter,
+                entry.start_offset,
+                aes_ctr_ex_entries,
+            ),
+            indirect_entries,
+            patched_size,
+        );
+        let (offset, size) = patch_header
+            .data_region()
+            .map_err(|err| eyre!("'{}' {}", patch.path.display(), err))?;
+        RomFs::new(SubStorage::new(patched, offset, size))
+            .map_err(|err| eyre!("'{}' patched {}", patch.path.display(), err))
+    }
+    /// Extracts the files of the ExeFS section to `exefs_dir`, without a backend.
+    pub fn extract_exefs<'a, I, P>(
+        &self,
+        keyset: &Keyset,
+        title_keys: I,
+        exefs_dir: P,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+        P: AsRef<Path>,
+    {
+        let index = self
+            .section_index(FsType::PartitionFs)
+            .ok_or_else(|| eyre!("'{}' has no ExeFS section", self.path.display()))?;
+        let mut exefs = self.open_fs_data(index, keyset, title_keys)?;
+        let pfs0 = Pfs0::read(&mut exefs)
+            .map_err(|err| eyre!("'{}' ExeFS {}", self.path.display(), err))?;
+        fs::create_dir_all(exefs_dir.as_ref())?;
+        for entry in &pfs0.entries {
+            extract_entry_to(
+                SubStorage::new(&mut exefs, entry.offset, entry.size),
+                &entry.name,
+                entry.size,
+                exefs_dir.as_ref(),
+            )?;
+        }
+        info!(
+            nca = %self.path.display(),
+            exefs = %exefs_dir.as_ref().display(),
+            "Extracted ExeFS"
+        );
+        Ok(())
+    }
+    pub fn unpack_romfs<'a, I, P>(
+        &self,
+        extractor: &Backend,
+        keyset: &Keyset,
+        title_keys: I,
+        romfs_dir: P,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+        P: AsRef<Path>,
+    {
         info!(nca = %self.path.display(), "Unpacking RomFS from NCA");
+        let title_keys_file = backend_title_keys(&[self], keyset, title_keys)?;
         let output = Command::new(extractor.path())
             .args([
+                "--titlekeys".as_ref(),
+                title_keys_file.path(),
                 self.path.as_path(),
                 "--romfsdir".as_ref(),
                 romfs_dir.as_ref(),
@@ -172,16 +866,26 @@ impl Nca {
 
         Ok(())
     }
-    pub fn unpack_all<P: AsRef<Path>, Q: AsRef<Path>>(
+    pub fn unpack_all<'a, I, P, Q>(
         &self,
         extractor: &Backend,
         aux: &Nca,
+        keyset: &Keyset,
+        title_keys: I,
         romfs_dir: P,
         exefs_dir: Q,
-    ) -> Result<()> {
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
         info!(basenca = %self.path.display(), nca = %aux.path.display(), "Unpacking RomFS/ExeFS from NCAs");
+        let title_keys_file = backend_title_keys(&[self, aux], keyset, title_keys)?;
         let mut cmd = Command::new(extractor.path());
         cmd.args([
+            "--titlekeys".as_ref(),
+            title_keys_file.path(),
             "--basenca".as_ref(),
             self.path.as_path(),
             aux.path.as_path(),
@@ -213,21 +917,25 @@ impl Nca {
         );
         Ok(())
     }
-    pub fn pack_program<'a, P, Q, R, K, I>(
-        readers: I,
+    /// Packs the ExeFS with hacpack, the RomFS is built natively with `RomFsBuilder`
+    /// and added to the NCA afterwards.
+    #[allow(clippy::too_many_arguments)]
+    pub fn pack_program<P, Q, R, K, T>(
+        header_key: &[u8; 0x20],
         packer: &Backend,
         program_id: &str,
         keyfile: K,
         romfs_dir: P,
         exefs_dir: Q,
         outdir: R,
+        tempdir_in: T,
     ) -> Result<Nca>
     where
         P: AsRef<Path>,
         Q: AsRef<Path>,
         R: AsRef<Path>,
         K: AsRef<Path>,
-        I: IntoIterator<Item = &'a Backend>,
+        T: AsRef<Path>,
     {
         info!(
             romfs = ?romfs_dir.as_ref(),
@@ -235,45 +943,27 @@ impl Nca {
             to = ?outdir.as_ref(),
             "Packing"
         );
-        let mut cmd = Command::new(packer.path());
-        cmd.args([
-            "--keyset".as_ref(),
+        run_hacpack(
+            packer,
             keyfile.as_ref(),
-            "--type".as_ref(),
-            "nca".as_ref(),
-            "--ncatype".as_ref(),
-            "program".as_ref(),
-            "--plaintext".as_ref(),
-            "--exefsdir".as_ref(),
-            exefs_dir.as_ref(),
-            "--romfsdir".as_ref(),
-            romfs_dir.as_ref(),
-            "--titleid".as_ref(),
-            program_id.as_ref(),
-            "--outdir".as_ref(),
+            ContentType::Program,
+            [
+                "--plaintext".as_ref(),
+                "--exefsdir".as_ref(),
+                exefs_dir.as_ref(),
+                "--titleid".as_ref(),
+                program_id.as_ref(),
+            ],
             outdir.as_ref(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            warn!(
-                backend = ?packer.kind(),
-                exit_code = ?output.status.code(),
-                %stderr,
-                "Encountered an error while packing FS files to NCA"
-            );
-        }
+            tempdir_in.as_ref(),
+        )?;
 
-        let patched_nca = readers
-            .into_iter()
-            .inspect(|reader| info!("Using {:?} as reader", reader.kind()))
-            .map(|reader| nca_with_kind(reader, outdir.as_ref(), ContentType::Program))
-            .find(|filtered| filtered.is_some())
-            .flatten()
+        let exefs_nca = nca_with_kind(header_key, outdir.as_ref(), ContentType::Program)
             .ok_or_else(|| eyre!("Failed to pack FS files to NCA"))?
             .remove(0);
+        let romfs = RomFsBuilder::new(romfs_dir.as_ref())?;
+        Nca::add_romfs_section(header_key, &exefs_nca.path, 1, &romfs)?;
+        let patched_nca = Nca::try_new(header_key, &exefs_nca.path)?;
         info!(
             nca = %patched_nca.path.display(),
             outdir = %outdir.as_ref().display(),
@@ -281,88 +971,301 @@ impl Nca {
         );
         Ok(patched_nca)
     }
-    pub fn create_meta<K, O, T>(
+    /// Packs an unpacked Control RomFS, i.e. a dir with `control.nacp` and the icons.
+    pub fn pack_control<P, R, K, T>(
+        header_key: &[u8; 0x20],
         packer: &Backend,
         program_id: &str,
         keyfile: K,
-        program: &Nca,
-        control: &Nca,
-        outdir: O,
+        romfs_dir: P,
+        outdir: R,
         tempdir_in: T,
-    ) -> Result<()>
+    ) -> Result<Nca>
     where
+        P: AsRef<Path>,
+        R: AsRef<Path>,
         K: AsRef<Path>,
-        O: AsRef<Path>,
         T: AsRef<Path>,
     {
-        // figure out a solution for this
-        info!(?program.path, ?control.path, "Generating Meta NCA");
-
-        let temp_outdir = tempfile::tempdir_in(tempdir_in.as_ref())?;
-        let mut cmd = Command::new(packer.path());
-        cmd.args([
-            "--keyset".as_ref(),
+        info!(
+            romfs = ?romfs_dir.as_ref(),
+            to = ?outdir.as_ref(),
+            "Packing Control"
+        );
+        run_hacpack(
+            packer,
             keyfile.as_ref(),
-            "--type".as_ref(),
-            "nca".as_ref(),
-            "--ncatype".as_ref(),
-            "meta".as_ref(),
-            "--titletype".as_ref(),
-            "application".as_ref(),
-            "--programnca".as_ref(),
-            program.path.as_path(),
-            "--controlnca".as_ref(),
-            control.path.as_path(),
-            "--titleid".as_ref(),
-            program_id.as_ref(),
-            "--outdir".as_ref(),
-            temp_outdir.path(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            warn!(
-                backend = ?packer.kind(),
-                code = ?output.status.code(),
-                %stderr,
-                "Encountered an error while generating Meta NCA"
+            ContentType::Control,
+            [
+                "--romfsdir".as_ref(),
+                romfs_dir.as_ref(),
+                "--titleid".as_ref(),
+                program_id.as_ref(),
+            ],
+            outdir.as_ref(),
+            tempdir_in.as_ref(),
+        )?;
+
+        let control_nca = nca_with_kind(header_key, outdir.as_ref(), ContentType::Control)
+            .ok_or_else(|| eyre!("Failed to pack Control RomFS to NCA"))?
+            .remove(0);
+        info!(
+            nca = %control_nca.path.display(),
+            outdir = %outdir.as_ref().display(),
+            "Packing done! Should be Control Type NCA"
+        );
+        Ok(control_nca)
+    }
+    /// Writes a NCA with a single PFS0 section to `path`, the section is encrypted
+    /// with AES-CTR using a key from the key area.
+    ///
+    /// The whole PFS0 is kept in memory so this is only meant for small ones, i.e. Meta NCAs.
+    pub fn write_pfs0<P: AsRef<Path>>(
+        keyset: &Keyset,
+        content_type: ContentType,
+        program_id: u64,
+        key_generation: u8,
+        sdk_version: SdkVersion,
+        pfs0: &[u8],
+        path: P,
+    ) -> Result<Nca> {
+        let header_key = keyset.header_key()?;
+        // Revision that both KeyGeneration 0 and 1 map to
+        let master_key_revision = key_generation.saturating_sub(1);
+
+        // !Hash layers, the hash table followed by the PFS0
+        let hash_table = pfs0
+            .chunks(Nca::PFS0_HASH_BLOCK_SIZE)
+            .flat_map(Sha256::digest)
+            .collect::<Vec<u8>>();
+        let pfs0_offset = align_up(hash_table.len() as u64, MEDIA_UNIT_SIZE);
+        let mut section = hash_table.clone();
+        section.resize(pfs0_offset as usize, 0);
+        section.extend(pfs0);
+        section.resize(align_up(section.len() as u64, MEDIA_UNIT_SIZE) as usize, 0);
+
+        let mut fs_header = vec![0u8; FsHeader::SIZE];
+        fs_header[0x0..0x2].copy_from_slice(&2u16.to_le_bytes());
+        fs_header[0x2] = FsType::PartitionFs as u8;
+        fs_header[0x3] = HashType::HierarchicalSha256 as u8;
+        fs_header[0x4] = EncryptionType::AesCtr as u8;
+        let hash_data = &mut fs_header[FsHeader::HASH_DATA_OFFSET..];
+        hash_data[0x0..0x20].copy_from_slice(&Sha256::digest(&hash_table));
+        hash_data[0x20..0x24].copy_from_slice(&(Nca::PFS0_HASH_BLOCK_SIZE as u32).to_le_bytes());
+        hash_data[0x24..0x28].copy_from_slice(&2u32.to_le_bytes());
+        hash_data[0x30..0x38].copy_from_slice(&(hash_table.len() as u64).to_le_bytes());
+        hash_data[0x38..0x40].copy_from_slice(&pfs0_offset.to_le_bytes());
+        hash_data[0x40..0x48].copy_from_slice(&(pfs0.len() as u64).to_le_bytes());
+
+        // Any key works as long as it's in the key area, deriving it keeps the output reproducible
+        let ctr_key: Key128 = Sha256::digest(pfs0)[..0x10].try_into().unwrap();
+        let mut key_area = [0u8; 0x40];
+        key_area[0x20..0x30].copy_from_slice(&ctr_key);
+        aes_ecb_encrypt(
+            keyset.key_area_key(KeyAreaKeyIndex::Application, master_key_revision)?,
+            &mut key_area,
+        );
+
+        let section_start = NcaHeader::SIZE as u64;
+        let section_end = section_start + section.len() as u64;
+        let mut header = vec![0u8; NcaHeader::SIZE];
+        header[0x200..0x204].copy_from_slice(b"NCA3");
+        header[0x204] = DistributionType::Download as u8;
+        header[0x205] = content_type as u8;
+        // The old field only goes up to 2, the newer one is used above that
+        header[0x206] = key_generation.min(2);
+        header[0x207] = KeyAreaKeyIndex::Application as u8;
+        header[0x208..0x210].copy_from_slice(&section_end.to_le_bytes());
+        header[0x210..0x218].copy_from_slice(&program_id.to_le_bytes());
+        header[0x21C..0x220].copy_from_slice(&sdk_version.0.to_le_bytes());
+        header[0x220] = if key_generation > 2 {
+            key_generation
+        } else {
+            0
+        };
+        header[0x240..0x244]
+            .copy_from_slice(&((section_start / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+        header[0x244..0x248]
+            .copy_from_slice(&((section_end / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+        header[0x280..0x2A0].copy_from_slice(&Sha256::digest(&fs_header));
+        header[0x300..0x340].copy_from_slice(&key_area);
+        header[NcaHeader::FS_HEADERS_OFFSET..NcaHeader::FS_HEADERS_OFFSET + FsHeader::SIZE]
+            .copy_from_slice(&fs_header);
+        // Signatures are left empty, there's no way to make valid ones anyway
+
+        XtsCipher::new(header_key).encrypt(&mut header, NcaHeader::SECTOR_SIZE, 0);
+        // Upper counter is left as 0 in the FsHeader
+        aes_ctr_apply(&ctr_key, 0, section_start, &mut section);
+
+        let mut writer = io::BufWriter::new(fs::File::create(path.as_ref())?);
+        writer.write_all(&header)?;
+        writer.write_all(&section)?;
+        writer.flush()?;
+        drop(writer);
+
+        debug!(nca = %path.as_ref().display(), ?content_type, "Wrote NCA");
+        Nca::try_new(header_key, path.as_ref())
+    }
+    /// Writes the RomFS of `romfs` as section `index` at the end of the NCA,
+    /// it's left as plaintext like the sections that hacpack writes with `--plaintext`.
+    ///
+    /// The header signatures aren't redone, there's no way to make valid ones anyway.
+    fn add_romfs_section<P: AsRef<Path>>(
+        header_key: &[u8; 0x20],
+        path: P,
+        index: usize,
+        romfs: &RomFsBuilder,
+    ) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())?;
+        let mut header = vec![0u8; NcaHeader::SIZE];
+        file.read_exact(&mut header)?;
+        let cipher = XtsCipher::new(header_key);
+        cipher.decrypt(&mut header, NcaHeader::SECTOR_SIZE, 0);
+        if &header[0x200..0x204] != b"NCA3" {
+            bail!("'{}' isn't a NCA3", path.as_ref().display());
+        }
+        let entry_offset = 0x240 + index * 0x10;
+        if header[entry_offset..entry_offset + 0x8] != [0; 8] {
+            bail!(
+                "'{}' already has section {}",
+                path.as_ref().display(),
+                index
             );
         }
 
-        for entry in WalkDir::new(temp_outdir.path())
-            .min_depth(1)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if entry.path().is_file() && ext_matches(entry.path(), "nca") {
-                // Moving NCA file from temp outdir to outdir
-                move_file(entry.path(), outdir.as_ref().join(entry.file_name()))?;
-                info!(outdir = ?outdir.as_ref(), "Generated Meta NCA");
-                return Ok(());
-            }
+        let section_start = align_up(file.metadata()?.len(), MEDIA_UNIT_SIZE);
+        file.seek(SeekFrom::Start(section_start))?;
+        let mut writer = io::BufWriter::new(file);
+        let ivfc = romfs.write_ivfc(&mut writer)?;
+        let section_end = align_up(section_start + ivfc.size(), MEDIA_UNIT_SIZE);
+        io::copy(
+            &mut io::repeat(0).take(section_end - section_start - ivfc.size()),
+            &mut writer,
+        )?;
+
+        let mut fs_header = vec![0u8; FsHeader::SIZE];
+        fs_header[0x0..0x2].copy_from_slice(&2u16.to_le_bytes());
+        fs_header[0x2] = FsType::RomFs as u8;
+        fs_header[0x3] = HashType::HierarchicalIntegrity as u8;
+        fs_header[0x4] = EncryptionType::None as u8;
+        fs_header[FsHeader::HASH_DATA_OFFSET..FsHeader::HASH_DATA_OFFSET + Ivfc::HASH_DATA_SIZE]
+            .copy_from_slice(&ivfc.hash_data());
+
+        header[0x208..0x210].copy_from_slice(&section_end.to_le_bytes());
+        header[entry_offset..entry_offset + 0x4]
+            .copy_from_slice(&((section_start / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+        header[entry_offset + 0x4..entry_offset + 0x8]
+            .copy_from_slice(&((section_end / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+        header[0x280 + index * 0x20..0x2A0 + index * 0x20]
+            .copy_from_slice(&Sha256::digest(&fs_header));
+        let fs_header_offset = NcaHeader::FS_HEADERS_OFFSET + index * FsHeader::SIZE;
+        header[fs_header_offset..fs_header_offset + FsHeader::SIZE].copy_from_slice(&fs_header);
+        cipher.encrypt(&mut header, NcaHeader::SECTOR_SIZE, 0);
+
+        let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.flush()?;
+
+        debug!(nca = %path.as_ref().display(), index, size = romfs.size(), "Added RomFS section to NCA");
+        Ok(())
+    }
+}
+
+/// Writes the TitleKeys of the `ncas` to a file of its own that's passed to the backend
+/// with `--titlekeys`, instead of storing them in the `title.keys` of the switch dir.\
+/// The content keys are derived first to fail early on missing keys.
+fn backend_title_keys<'a, I>(
+    ncas: &[&Nca],
+    keyset: &Keyset,
+    title_keys: I,
+) -> Result<tempfile::NamedTempFile>
+where
+    I: IntoIterator<Item = &'a TitleKey>,
+{
+    let title_keys = title_keys.into_iter().collect::<Vec<_>>();
+    let mut file = tempfile::Builder::new().suffix(".keys").tempfile()?;
+    for nca in ncas {
+        nca.header.content_key(keyset, title_keys.iter().copied())?;
+        if let Some(title_key) = title_keys.iter().find(|title_key| {
+            nca.header.has_rights_id() && title_key.rights_id() == &nca.header.rights_id
+        }) {
+            writeln!(file, "{}", title_key)?;
         }
+    }
+    Ok(file)
+}
 
-        bail!("Failed to generate Meta NCA");
+/// Runs hacpack to pack a `content_type` NCA to `outdir`, failing if it exits with an error.\
+/// Its temp and backup dirs are created in `tempdir_in`, otherwise they'd be in the current dir.
+fn run_hacpack<'a, I>(
+    packer: &Backend,
+    keyfile: &Path,
+    content_type: ContentType,
+    args: I,
+    outdir: &Path,
+    tempdir_in: &Path,
+) -> Result<()>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let hacpack_dirs = tempfile::tempdir_in(tempdir_in)?;
+    let ncatype = content_type.to_string().to_lowercase();
+    let output = Command::new(packer.path())
+        .args([
+            "--tempdir".as_ref(),
+            hacpack_dirs.path().join("hacpack_temp").as_path(),
+            "--backupdir".as_ref(),
+            hacpack_dirs.path().join("hacpack_backup").as_path(),
+            "--keyset".as_ref(),
+            keyfile,
+            "--type".as_ref(),
+            "nca".as_ref(),
+            "--ncatype".as_ref(),
+            ncatype.as_ref(),
+        ])
+        .args(args)
+        .args(["--outdir".as_ref(), outdir])
+        .stderr(Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    let stderr = filter_out_key_mismatches(&output.stderr);
+    eprint!("{}", stderr);
+    if !output.status.success() {
+        error!(
+            backend = ?packer.kind(),
+            exit_code = ?output.status.code(),
+            %stderr,
+            "Encountered an error while packing {} NCA",
+            content_type
+        );
+        bail!("Encountered an error while packing {} NCA", content_type);
     }
+    Ok(())
 }
 
 /// Returns filtered NCA(s) in descending order of size.
 ///
 /// For eg-
-/// ```
+/// ```no_run
+/// # use std::collections::HashSet;
+/// # use hac::{keyset::Keyset, vfs::nca::{nca_with_filters, ContentType}};
+/// # fn main() -> eyre::Result<()> {
 /// // This'll return the largest Control type NCA in "."
-/// nca_with_filters(
-///     Backend::new(BackendKind::Hactoolnet),
+/// let ncas = nca_with_filters(
+///     Keyset::try_default()?.header_key()?,
 ///     ".",
-///     HashSet::from([NcaType::Control]),
-/// )
-/// .get(&NcaType::Control)
-/// .unwrap()[0];
+///     &HashSet::from([ContentType::Control]),
+/// );
+/// let control = &ncas[&ContentType::Control][0];
+/// # Ok(())
+/// # }
 /// ```
 pub fn nca_with_filters<P>(
-    reader: &Backend,
+    header_key: &[u8; 0x20],
     from: P,
     filters: &HashSet<ContentType>,
 ) -> HashMap<ContentType, Vec<Nca>>
@@ -392,11 +1295,11 @@ where
             }
         })
     {
-        match Nca::try_new(reader, entry.path()) {
+        match Nca::try_new(header_key, entry.path()) {
             Ok(nca) => {
-                if filters.contains(&nca.content_type) {
+                if filters.contains(&nca.header.content_type) {
                     filtered_ncas
-                        .entry(nca.content_type)
+                        .entry(nca.header.content_type)
                         .or_insert(vec![])
                         .push(nca);
                 }
@@ -411,9 +1314,320 @@ where
 }
 
 #[allow(unused)]
-pub fn nca_with_kind<P>(reader: &Backend, from: P, kind: ContentType) -> Option<Vec<Nca>>
+pub fn nca_with_kind<P>(header_key: &[u8; 0x20], from: P, kind: ContentType) -> Option<Vec<Nca>>
 where
     P: AsRef<Path>,
 {
-    nca_with_filters(reader, from, &HashSet::from([kind])).remove(&kind)
+    nca_with_filters(header_key, from, &HashSet::from([kind])).remove(&kind)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        keyset::TEST_KEYS,
+        vfs::ticket::{SignatureType, Ticket, TitleKeyType},
+    };
+    use std::io::Cursor;
+
+    /// Made up RSA-2048 key, standing in for the fixed key and the ACID key.
+    const SIGNATURE_MODULUS: &str = "\
+        d57ef87aa7f0b3d9d597e57d6cb9cfe51f48d27070e2f001c236048cf9bd5d2c\
+        f1038005fb3c145da823d19ad03e301b274d75cabd63a9820ee5ae0468a3347d\
+        e2029b9cb6f926a2c798888217dcd697acf90f0f99a9b0bdc88058e92e6de132\
+        cc570d0a516329e19b517bb7772d8ee8af52ea95888750830bde63f4c087263f\
+        c1875fa4e509d1da4e4c6c889cbb9fba3f3bdba3d61d0e31dbe5eecbe6e1691a\
+        e19eb09ef743bd2c075566aa28b0f891b3dcbd91bb81a052076578bea101c824\
+        cd566ea0ed82a7a188aa75be4c12cc5dcbdf6450c45e40bc7b6086eb68690de2\
+        97e6c04cafa729e9c87ca49e3f6cb561b75de821158755f1a4ef2dea05b99739";
+
+    /// RSA-2048-PSS signature of the signed region of `control_header`.
+    const SIGNATURE: &str = "\
+        6b3974b6a83caf0e4f401c70e534f11459a4809540853dd8c0b8b3201be07e43\
+        893b347525455b001a3fb73cae2a5f50fe52ff87b5617843971760ab52ba0348\
+        6d73debf29f52dd5d1e6be1dff39102b9b6d2389d0e3d5dd8b66fc43be48eb48\
+        47fcf80ce291c654393b5b878a08744d34aea3ff91ee0268fe520e0cf12413d2\
+        a2b68dafa87a84cb1fe2f262e7b899e010ca15985025443430fbb70548bfc901\
+        b77aad78c2ee2083907c37ae0542edcf4e74d169b2e40716dd0af472f63e155f\
+        f08a0121812a27333aa84498d1010a43902544709900e95e1a91f65232a13674\
+        2a91b0957c928c1ac5bfedcf6d37b488c7aafec2bcd3f5f0934de94cef57194f";
+
+    /// Plain NCA header of a Control NCA with a single RomFS section.
+    fn control_header() -> Vec<u8> {
+        let mut header = vec![0u8; NcaHeader::SIZE];
+        header[0x200..0x204].copy_from_slice(b"NCA3");
+        header[0x204] = DistributionType::GameCard as u8;
+        header[0x205] = ContentType::Control as u8;
+        header[0x206] = 2;
+        header[0x220] = 0x0B;
+        header[0x208..0x210].copy_from_slice(&0x8000u64.to_le_bytes());
+        header[0x210..0x218].copy_from_slice(&0x0100_ABCD_0000_0000u64.to_le_bytes());
+        header[0x21C..0x220].copy_from_slice(&0x000C_1100u32.to_le_bytes());
+        header[0x230..0x240].copy_from_slice(&[0x42; 0x10]);
+        header[0x240..0x244].copy_from_slice(&6u32.to_le_bytes());
+        header[0x244..0x248].copy_from_slice(&0x40u32.to_le_bytes());
+        header[0x400..0x402].copy_from_slice(&2u16.to_le_bytes());
+        header[0x402] = FsType::RomFs as u8;
+        header[0x403] = HashType::HierarchicalIntegrity as u8;
+        header[0x404] = EncryptionType::AesCtr as u8;
+        header[0x540..0x548].copy_from_slice(&0x1122_3344_5566_7788u64.to_le_bytes());
+        header
+    }
+
+    /// Writes a Control NCA to `path` with the RomFS of `romfs_dir` in an AES-CTR section.
+    /// With a `rights_id` it uses TitleKey crypto, the returned key is then the encrypted
+    /// TitleKey for its ticket, otherwise it's the key area's AES-CTR key.
+    pub(crate) fn write_control_nca(
+        keyset: &Keyset,
+        romfs_dir: &Path,
+        rights_id: Option<[u8; 0x10]>,
+        path: &Path,
+    ) -> Result<(Nca, Key128)> {
+        let header_key = keyset.header_key()?;
+        let mut pfs0 = Pfs0::build_header([("logo", 4)]);
+        pfs0.extend(b"LOGO");
+        let nca = Nca::write_pfs0(
+            keyset,
+            ContentType::Control,
+            0x0100_ABCD_0000_0000,
+            0,
+            SdkVersion(0x000C_1100),
+            &pfs0,
+            path,
+        )?;
+        let mut ctr_key = *nca.header.decrypt_key_area(keyset)?.ctr_key();
+        Nca::add_romfs_section(header_key, path, 1, &RomFsBuilder::new(romfs_dir)?)?;
+
+        let mut data = fs::read(path)?;
+        let cipher = XtsCipher::new(header_key);
+        cipher.decrypt(&mut data[..NcaHeader::SIZE], NcaHeader::SECTOR_SIZE, 0);
+        let start = read_u32_le(&data, 0x250) as usize * MEDIA_UNIT_SIZE as usize;
+        let end = read_u32_le(&data, 0x254) as usize * MEDIA_UNIT_SIZE as usize;
+        aes_ctr_apply(&ctr_key, 0, start as u64, &mut data[start..end]);
+        let fs_header = NcaHeader::FS_HEADERS_OFFSET + FsHeader::SIZE;
+        data[fs_header + 0x4] = EncryptionType::AesCtr as u8;
+        let fs_header_hash = Sha256::digest(&data[fs_header..fs_header + FsHeader::SIZE]);
+        data[0x2A0..0x2C0].copy_from_slice(&fs_header_hash);
+        if let Some(rights_id) = rights_id {
+            data[0x230..0x240].copy_from_slice(&rights_id);
+            data[0x300..0x340].fill(0);
+            aes_ecb_encrypt(keyset.title_kek(0)?, &mut ctr_key);
+        }
+        cipher.encrypt(&mut data[..NcaHeader::SIZE], NcaHeader::SECTOR_SIZE, 0);
+        fs::write(path, data)?;
+        Ok((Nca::try_new(header_key, path)?, ctr_key))
+    }
+
+    #[test]
+    fn read_nca3_header() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let header_key = keyset.header_key()?;
+        let mut encrypted = control_header();
+        XtsCipher::new(header_key).encrypt(&mut encrypted, NcaHeader::SECTOR_SIZE, 0);
+
+        let header = NcaHeader::read(&mut Cursor::new(&encrypted), header_key)?;
+        assert_eq!(header.version, 3);
+        assert_eq!(header.distribution_type, DistributionType::GameCard);
+        assert_eq!(header.content_type, ContentType::Control);
+        assert_eq!(header.key_generation, 0x0B);
+        assert_eq!(header.master_key_revision(), 0x0A);
+        assert_eq!(header.content_size, 0x8000);
+        assert_eq!(header.program_id, 0x0100_ABCD_0000_0000u64.to_be_bytes());
+        assert_eq!(header.sdk_version, SdkVersion(0x000C_1100));
+        assert!(header.has_rights_id());
+        assert_eq!(
+            header.fs_entries[0],
+            FsEntry {
+                start_offset: 0xC00,
+                end_offset: 0x8000
+            }
+        );
+        let fs_header = header.fs_headers[0].as_ref().unwrap();
+        assert_eq!(fs_header.fs_type, FsType::RomFs);
+        assert_eq!(fs_header.encryption_type, EncryptionType::AesCtr);
+        assert_eq!(fs_header.upper_counter, 0x1122_3344_5566_7788);
+        assert!(header.fs_headers[1..].iter().all(Option::is_none));
+        assert_eq!(header.raw, control_header());
+        Ok(())
+    }
+
+    #[test]
+    fn read_nca2_header() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let header_key = keyset.header_key()?;
+        let mut encrypted = control_header();
+        encrypted[0x200..0x204].copy_from_slice(b"NCA2");
+        let cipher = XtsCipher::new(header_key);
+        cipher.encrypt(
+            &mut encrypted[..NcaHeader::FS_HEADERS_OFFSET],
+            NcaHeader::SECTOR_SIZE,
+            0,
+        );
+        for fs_header in encrypted[NcaHeader::FS_HEADERS_OFFSET..].chunks_exact_mut(FsHeader::SIZE)
+        {
+            cipher.encrypt(fs_header, NcaHeader::SECTOR_SIZE, 0);
+        }
+
+        let header = NcaHeader::read(&mut Cursor::new(&encrypted), header_key)?;
+        assert_eq!(header.version, 2);
+        assert_eq!(
+            header.fs_headers[0].as_ref().unwrap().upper_counter,
+            0x1122_3344_5566_7788
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_header_with_wrong_key() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let mut encrypted = control_header();
+        XtsCipher::new(keyset.header_key()?).encrypt(&mut encrypted, NcaHeader::SECTOR_SIZE, 0);
+        assert!(NcaHeader::read(&mut Cursor::new(&encrypted), &[0xFF; 0x20]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_header_signatures() -> Result<()> {
+        let modulus = hex::decode(SIGNATURE_MODULUS)?;
+        let signature = hex::decode(SIGNATURE)?;
+        let keyset = Keyset::parse(&format!(
+            "{}nca_hdr_fixed_key_modulus_00 = {}",
+            TEST_KEYS, SIGNATURE_MODULUS
+        ))?;
+        let header_key = keyset.header_key()?;
+        let read_header = |plain: &[u8]| {
+            let mut encrypted = plain.to_vec();
+            XtsCipher::new(header_key).encrypt(&mut encrypted, NcaHeader::SECTOR_SIZE, 0);
+            NcaHeader::read(&mut Cursor::new(&encrypted), header_key)
+        };
+
+        let mut plain = control_header();
+        let header = read_header(&plain)?;
+        assert_eq!(
+            header.signature_status(&keyset, Some(&modulus)),
+            SignatureStatus {
+                fixed_key: Signature::Unsigned,
+                acid: Some(Signature::Unsigned),
+            }
+        );
+
+        plain[..0x100].copy_from_slice(&signature);
+        plain[0x100..0x200].copy_from_slice(&signature);
+        let header = read_header(&plain)?;
+        let status = header.signature_status(&keyset, Some(&modulus));
+        assert_eq!(
+            status,
+            SignatureStatus {
+                fixed_key: Signature::Valid,
+                acid: Some(Signature::Valid),
+            }
+        );
+        assert!(status.is_official() && !status.is_rebuilt());
+        assert_eq!(status.to_string(), "fixed key valid, acid valid");
+        // No key for the SignatureKeyGeneration
+        assert_eq!(
+            header
+                .signature_status(&Keyset::parse(TEST_KEYS)?, None)
+                .fixed_key,
+            Signature::Unchecked
+        );
+
+        // Signed region modified, i.e. a rebuilt header
+        plain[0x208..0x210].copy_from_slice(&0x9000u64.to_le_bytes());
+        let header = read_header(&plain)?;
+        let status = header.signature_status(&keyset, None);
+        assert_eq!(status.fixed_key, Signature::Invalid);
+        assert!(!status.is_official() && status.is_rebuilt());
+        Ok(())
+    }
+
+    #[test]
+    fn content_key() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let header_key = keyset.header_key()?;
+        let key_area_key = keyset.key_area_key(KeyAreaKeyIndex::Application, 0)?;
+        let ctr_key = [0x5A; 0x10];
+
+        // Key area crypto
+        let mut plain = control_header();
+        plain[0x206] = 0;
+        plain[0x220] = 1;
+        plain[0x230..0x240].fill(0);
+        let mut key_area = [0u8; 0x40];
+        key_area[0x20..0x30].copy_from_slice(&ctr_key);
+        aes_ecb_encrypt(key_area_key, &mut key_area);
+        plain[0x300..0x340].copy_from_slice(&key_area);
+        let mut encrypted = plain.clone();
+        XtsCipher::new(header_key).encrypt(&mut encrypted, NcaHeader::SECTOR_SIZE, 0);
+        let header = NcaHeader::read(&mut Cursor::new(&encrypted), header_key)?;
+        assert!(!header.has_rights_id());
+        assert_eq!(header.master_key_revision(), 0);
+        assert_eq!(header.decrypt_key_area(&keyset)?.ctr_key(), &ctr_key);
+        assert_eq!(header.content_key(&keyset, None)?, ctr_key);
+
+        // TitleKey crypto
+        plain[0x230..0x240].copy_from_slice(&[0x42; 0x10]);
+        let mut encrypted = plain;
+        XtsCipher::new(header_key).encrypt(&mut encrypted, NcaHeader::SECTOR_SIZE, 0);
+        let header = NcaHeader::read(&mut Cursor::new(&encrypted), header_key)?;
+        let mut title_key_block = [0u8; 0x100];
+        title_key_block[..0x10].copy_from_slice(&ctr_key);
+        aes_ecb_encrypt(keyset.title_kek(0)?, &mut title_key_block[..0x10]);
+        let title_key = Ticket {
+            signature_type: SignatureType::Rsa2048Sha256,
+            issuer: "Root-CA00000003-XS00000020".into(),
+            title_key_block,
+            title_key_type: TitleKeyType::Common,
+            key_generation: 1,
+            ticket_id: 0,
+            device_id: 0,
+            rights_id: [0x42; 0x10],
+            account_id: 0,
+        }
+        .title_key()?;
+        assert!(header.content_key(&keyset, None).is_err());
+        assert_eq!(header.content_key(&keyset, [&title_key])?, ctr_key);
+        Ok(())
+    }
+
+    #[test]
+    fn add_romfs_section() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let header_key = keyset.header_key()?;
+        let dir = tempfile::tempdir()?;
+        let romfs_dir = dir.path().join("romfs");
+        fs::create_dir_all(romfs_dir.join("data"))?;
+        fs::write(romfs_dir.join("data/file.bin"), [0x11; 0x4321])?;
+
+        let mut pfs0 = Pfs0::build_header([("main.npdm", 4)]);
+        pfs0.extend(b"META");
+        let nca_path = dir.path().join("program.nca");
+        Nca::write_pfs0(
+            &keyset,
+            ContentType::Program,
+            0x0100_0000_0000_1000,
+            0,
+            SdkVersion(0),
+            &pfs0,
+            &nca_path,
+        )?;
+        Nca::add_romfs_section(header_key, &nca_path, 1, &RomFsBuilder::new(&romfs_dir)?)?;
+
+        let nca = Nca::try_new(header_key, &nca_path)?;
+        assert_eq!(nca.get_program_id(), "0100000000001000");
+        assert_eq!(nca.section_index(FsType::PartitionFs), Some(0));
+        assert_eq!(nca.section_index(FsType::RomFs), Some(1));
+        assert_eq!(
+            nca.header.fs_entries[1].end_offset,
+            fs::metadata(&nca_path)?.len()
+        );
+        let mut romfs = nca.open_romfs(&keyset, None)?;
+        assert_eq!(romfs.read_file("data/file.bin")?, [0x11; 0x4321]);
+        // The section that was already there is left as is
+        let mut exefs = vec![];
+        nca.open_fs_data(0, &keyset, None)?
+            .read_to_end(&mut exefs)?;
+        assert_eq!(exefs, pfs0);
+        Ok(())
+    }
 }
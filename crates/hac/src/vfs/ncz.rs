@@ -0,0 +1,701 @@
+use crate::{
+    crypto::aes_ctr_apply,
+    keyset::{Key128, Keyset},
+    vfs::{
+        nca::{EncryptionType, Nca},
+        read_u32_le, read_u64_le,
+        ticket::TitleKey,
+    },
+};
+use bytesize::ByteSize;
+use derivative::Derivative;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+use tracing::{debug, info};
+
+/// https://github.com/nicoboss/nsz#ncz
+///
+/// A section of the NCA as described in the NCZ section header, the data of the
+/// sections is compressed decrypted and has to be re-encrypted on decompression.
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct NczSection {
+    /// Offset of the section in the NCA.
+    pub offset: u64,
+    pub size: u64,
+    /// Same as the NCA's encryption type, i.e. `1` is none, `3` is AES-CTR
+    /// and `4` is AES-CTR for BKTR sections.
+    pub crypto_type: u64,
+    #[derivative(Debug = "ignore")]
+    pub crypto_key: Key128,
+    #[derivative(Debug(format_with = "counter_fmt"))]
+    pub crypto_counter: [u8; 0x10],
+}
+
+fn counter_fmt(counter: &[u8; 0x10], fmt: &mut fmt::Formatter) -> fmt::Result {
+    fmt.write_fmt(format_args!("{:?}", hex::encode(counter)))
+}
+
+impl NczSection {
+    const SIZE: usize = 0x40;
+    const CRYPTO_NONE: u64 = 1;
+    const CRYPTO_CTR: u64 = 3;
+    const CRYPTO_BKTR: u64 = 4;
+
+    fn is_encrypted(&self) -> bool {
+        matches!(
+            self.crypto_type,
+            NczSection::CRYPTO_CTR | NczSection::CRYPTO_BKTR
+        )
+    }
+    fn upper_counter(&self) -> u64 {
+        u64::from_be_bytes(self.crypto_counter[..8].try_into().unwrap())
+    }
+    fn plain(offset: u64, size: u64) -> Self {
+        Self {
+            offset,
+            size,
+            crypto_type: NczSection::CRYPTO_NONE,
+            crypto_key: Default::default(),
+            crypto_counter: Default::default(),
+        }
+    }
+    fn to_bytes(&self) -> [u8; NczSection::SIZE] {
+        let mut buf = [0u8; NczSection::SIZE];
+        buf[0x0..0x8].copy_from_slice(&self.offset.to_le_bytes());
+        buf[0x8..0x10].copy_from_slice(&self.size.to_le_bytes());
+        buf[0x10..0x18].copy_from_slice(&self.crypto_type.to_le_bytes());
+        buf[0x20..0x30].copy_from_slice(&self.crypto_key);
+        buf[0x30..0x40].copy_from_slice(&self.crypto_counter);
+        buf
+    }
+    /// Encrypts or decrypts, it's AES-CTR so it's the same either way, the part
+    /// of `buf` that overlaps with the section. `offset` is where `buf` starts in the NCA.
+    fn apply_crypto(&self, offset: u64, buf: &mut [u8]) {
+        let start = self.offset.max(offset);
+        let end = (self.offset + self.size).min(offset + buf.len() as u64);
+        if self.is_encrypted() && start < end {
+            aes_ctr_apply(
+                &self.crypto_key,
+                self.upper_counter(),
+                start,
+                &mut buf[(start - offset) as usize..(end - offset) as usize],
+            );
+        }
+    }
+}
+
+/// https://github.com/nicoboss/nsz#block-compression
+///
+/// Header of the block compressed NCZs, the blocks are zstd compressed independently
+/// of each other, unless compressing didn't make them any smaller.
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct NczBlockHeader {
+    pub version: u8,
+    pub kind: u8,
+    pub block_size_exponent: u8,
+    /// Size of the data after the NCZ header, i.e. without the first `Ncz::HEADER_SIZE` bytes.
+    pub decompressed_size: u64,
+    #[derivative(Debug = "ignore")]
+    pub compressed_block_sizes: Vec<u32>,
+}
+
+impl NczBlockHeader {
+    const MAGIC: [u8; 8] = *b"NCZBLOCK";
+    const SIZE: usize = 0x18;
+
+    pub fn block_size(&self) -> u64 {
+        1 << self.block_size_exponent
+    }
+    /// Decompressed size of the block at `index`, only the last one can be smaller.
+    fn decompressed_block_size(&self, index: usize) -> u64 {
+        let block_size = self.block_size();
+        if index + 1 == self.compressed_block_sizes.len()
+            && self.decompressed_size % block_size != 0
+        {
+            self.decompressed_size % block_size
+        } else {
+            block_size
+        }
+    }
+}
+
+/// https://github.com/nicoboss/nsz#ncz
+///
+/// NCA with its sections compressed by [nsz](https://github.com/nicoboss/nsz),
+/// the first `Ncz::HEADER_SIZE` bytes are the NCA's as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ncz {
+    pub sections: Vec<NczSection>,
+    /// `None` if the rest is a single zstd stream.
+    pub block: Option<NczBlockHeader>,
+}
+
+impl Ncz {
+    const MAGIC: [u8; 8] = *b"NCZSECTN";
+    /// Size of the NCA header and anything else before the first section that's not compressed.
+    pub const HEADER_SIZE: u64 = 0x4000;
+    /// Blocks smaller than `2^14` or larger than `2^32` bytes aren't valid.
+    const BLOCK_SIZE_EXPONENT_RANGE: std::ops::RangeInclusive<u8> = 14..=32;
+
+    /// Parses the NCZ header, `reader` must be positioned right after the first `Ncz::HEADER_SIZE` bytes.
+    ///
+    /// Returns the NCZ along with any bytes that were read past the header.
+    fn read_header<R: Read>(reader: &mut R) -> Result<(Self, Vec<u8>)> {
+        let mut buf = [0u8; 0x10];
+        reader.read_exact(&mut buf)?;
+        if buf[..8] != Ncz::MAGIC {
+            bail!(
+                "Invalid NCZ section header magic '{}'",
+                String::from_utf8_lossy(&buf[..8])
+            );
+        }
+        let section_count = read_u64_le(&buf, 0x8) as usize;
+        let mut sections = Vec::with_capacity(section_count.min(0x10));
+        for _ in 0..section_count {
+            let mut buf = [0u8; NczSection::SIZE];
+            reader.read_exact(&mut buf)?;
+            sections.push(NczSection {
+                offset: read_u64_le(&buf, 0x0),
+                size: read_u64_le(&buf, 0x8),
+                crypto_type: read_u64_le(&buf, 0x10),
+                crypto_key: buf[0x20..0x30].try_into().unwrap(),
+                crypto_counter: buf[0x30..0x40].try_into().unwrap(),
+            });
+        }
+        if sections.is_empty() {
+            bail!("NCZ has no sections");
+        }
+
+        // Either the block header or the start of the zstd stream
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != NczBlockHeader::MAGIC {
+            return Ok((
+                Self {
+                    sections,
+                    block: None,
+                },
+                magic.to_vec(),
+            ));
+        }
+
+        let mut buf = [0u8; NczBlockHeader::SIZE - 8];
+        reader.read_exact(&mut buf)?;
+        let block_size_exponent = buf[3];
+        if !Ncz::BLOCK_SIZE_EXPONENT_RANGE.contains(&block_size_exponent) {
+            bail!("Invalid NCZ block size exponent {}", block_size_exponent);
+        }
+        let block_count = read_u32_le(&buf, 0x4) as u64;
+        let decompressed_size = read_u64_le(&buf, 0x8);
+        // Checked before allocating the block sizes, so that a corrupted count can't blow up
+        let block_size = 1u64 << block_size_exponent;
+        if block_count
+            != decompressed_size / block_size + u64::from(decompressed_size % block_size != 0)
+        {
+            bail!(
+                "NCZ has {} blocks of {:#x} bytes which don't match its size of {:#x} bytes",
+                block_count,
+                block_size,
+                decompressed_size
+            );
+        }
+        let mut sizes = vec![0u8; block_count as usize * 4];
+        reader.read_exact(&mut sizes)?;
+        let block = NczBlockHeader {
+            version: buf[0],
+            kind: buf[1],
+            block_size_exponent,
+            decompressed_size,
+            compressed_block_sizes: sizes
+                .chunks_exact(4)
+                .map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+                .collect(),
+        };
+
+        Ok((
+            Self {
+                sections,
+                block: Some(block),
+            },
+            vec![],
+        ))
+    }
+    /// Parses the header of the NCZ, `reader` must be positioned at the start of the NCZ.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        io::copy(&mut reader.by_ref().take(Ncz::HEADER_SIZE), &mut io::sink())?;
+        let (ncz, _) = Ncz::read_header(reader)?;
+        debug!(?ncz, "Parsed NCZ header");
+        Ok(ncz)
+    }
+    /// Size of the NCA once decompressed, the sections cover everything up to its end.
+    pub fn nca_size(&self) -> u64 {
+        let sections_end = self
+            .sections
+            .iter()
+            .map(|section| section.offset + section.size)
+            .max()
+            .unwrap_or_default();
+        match &self.block {
+            Some(block) => sections_end.max(Ncz::HEADER_SIZE + block.decompressed_size),
+            None => sections_end,
+        }
+    }
+    /// Decompresses the NCZ in `reader` back to the NCA it was made from.
+    ///
+    /// Returns the size of the NCA.
+    pub fn decompress<R: Read, W: Write>(mut reader: R, writer: &mut W) -> Result<u64> {
+        let mut header = vec![0u8; Ncz::HEADER_SIZE as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|err| eyre!("NCZ is truncated: {}", err))?;
+        writer.write_all(&header)?;
+
+        let (ncz, peeked) = Ncz::read_header(&mut reader)?;
+        debug!(?ncz, "Decompressing NCZ");
+
+        let mut offset = Ncz::HEADER_SIZE;
+        let mut write_decrypted = |buf: &mut [u8]| -> Result<()> {
+            for section in &ncz.sections {
+                section.apply_crypto(offset, buf);
+            }
+            writer.write_all(buf)?;
+            offset += buf.len() as u64;
+            Ok(())
+        };
+
+        match &ncz.block {
+            Some(block) => {
+                let mut compressed = vec![];
+                for (index, size) in block.compressed_block_sizes.iter().enumerate() {
+                    let block_size = block.decompressed_block_size(index) as usize;
+                    compressed.resize(*size as usize, 0);
+                    reader
+                        .read_exact(&mut compressed)
+                        .map_err(|err| eyre!("NCZ block {} is truncated: {}", index, err))?;
+                    // Stored as is when compressing didn't help
+                    let mut data = if compressed.len() < block_size {
+                        zstd::bulk::decompress(&compressed, block_size).map_err(|err| {
+                            eyre!("Failed to decompress NCZ block {}: {}", index, err)
+                        })?
+                    } else {
+                        compressed[..block_size].to_vec()
+                    };
+                    if data.len() != block_size {
+                        bail!(
+                            "NCZ block {} is {:#x} bytes instead of {:#x}",
+                            index,
+                            data.len(),
+                            block_size
+                        );
+                    }
+                    write_decrypted(&mut data)?;
+                }
+            }
+            None => {
+                let mut decoder = zstd::Decoder::new(io::Cursor::new(peeked).chain(reader))?;
+                let mut buf = vec![0u8; 0x10000];
+                loop {
+                    let read = decoder
+                        .read(&mut buf)
+                        .map_err(|err| eyre!("Failed to decompress NCZ: {}", err))?;
+                    if read == 0 {
+                        break;
+                    }
+                    write_decrypted(&mut buf[..read])?;
+                }
+            }
+        }
+
+        if offset != ncz.nca_size() {
+            bail!(
+                "NCZ decompressed to {:#x} bytes instead of {:#x}",
+                offset,
+                ncz.nca_size()
+            );
+        }
+        Ok(offset)
+    }
+}
+
+/// Compresses NCAs to block compressed NCZs.
+///
+/// The AES-CTR sections are decrypted before compressing, anything else is
+/// compressed as is.
+#[derive(Debug, Clone, Copy)]
+pub struct NczWriter {
+    level: i32,
+    workers: usize,
+}
+
+impl NczWriter {
+    /// 1MiB blocks, same as nsz's default.
+    pub const BLOCK_SIZE_EXPONENT: u8 = 20;
+    const BLOCK_VERSION: u8 = 2;
+    const BLOCK_KIND: u8 = 1;
+
+    /// `workers` is the no. of blocks compressed in parallel, `0` uses all the available cores.
+    pub fn new(level: i32, workers: usize) -> Self {
+        let workers = match workers {
+            0 => thread::available_parallelism().map_or(1, |workers| workers.get()),
+            workers => workers,
+        };
+        Self { level, workers }
+    }
+    /// Sections for the NCZ header, the gaps between the NCA's sections are filled
+    /// in with unencrypted ones since the sections must cover the whole NCA.
+    fn sections<'a, I>(
+        nca: &Nca,
+        nca_size: u64,
+        keyset: &Keyset,
+        title_keys: I,
+    ) -> Result<Vec<NczSection>>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let mut entries = nca
+            .header
+            .fs_entries
+            .iter()
+            .zip(&nca.header.fs_headers)
+            .filter_map(|(entry, fs_header)| Some((*entry, fs_header.as_ref()?)))
+            .filter(|(entry, _)| !entry.is_empty())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(entry, _)| entry.start_offset);
+
+        let is_ctr = |encryption_type| {
+            matches!(
+                encryption_type,
+                EncryptionType::AesCtr | EncryptionType::AesCtrSkipLayerHash
+            )
+        };
+        let key = if entries
+            .iter()
+            .any(|(_, fs_header)| is_ctr(fs_header.encryption_type))
+        {
+            Some(nca.header.content_key(keyset, title_keys)?)
+        } else {
+            None
+        };
+
+        let mut sections = vec![];
+        let mut offset = Ncz::HEADER_SIZE;
+        for (entry, fs_header) in entries {
+            let start = entry.start_offset.max(offset);
+            let end = entry.end_offset.min(nca_size);
+            if start >= end {
+                continue;
+            }
+            if start > offset {
+                sections.push(NczSection::plain(offset, start - offset));
+            }
+            match key {
+                Some(key) if is_ctr(fs_header.encryption_type) => {
+                    let mut crypto_counter = [0u8; 0x10];
+                    crypto_counter[..8].copy_from_slice(&fs_header.upper_counter.to_be_bytes());
+                    sections.push(NczSection {
+                        offset: start,
+                        size: end - start,
+                        crypto_type: NczSection::CRYPTO_CTR,
+                        crypto_key: key,
+                        crypto_counter,
+                    });
+                }
+                _ => sections.push(NczSection::plain(start, end - start)),
+            }
+            offset = end;
+        }
+        if offset < nca_size {
+            sections.push(NczSection::plain(offset, nca_size - offset));
+        }
+        Ok(sections)
+    }
+    /// Writes `nca` as a NCZ, TitleKeys are only needed if the NCA has a RightsID.
+    ///
+    /// Returns the size of the NCZ.
+    pub fn write<'a, I, W>(
+        &self,
+        nca: &Nca,
+        keyset: &Keyset,
+        title_keys: I,
+        writer: &mut W,
+    ) -> Result<u64>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+        W: Write + Seek,
+    {
+        let mut reader = io::BufReader::new(fs::File::open(&nca.path)?);
+        let nca_size = reader.get_ref().metadata()?.len();
+        if nca_size <= Ncz::HEADER_SIZE {
+            bail!("'{}' is too small to be compressed", nca.path.display());
+        }
+        let sections = NczWriter::sections(nca, nca_size, keyset, title_keys)?;
+        debug!(?sections, "Compressing NCA");
+
+        let mut header = vec![0u8; Ncz::HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        writer.write_all(&header)?;
+        writer.write_all(&Ncz::MAGIC)?;
+        writer.write_all(&(sections.len() as u64).to_le_bytes())?;
+        for section in &sections {
+            writer.write_all(&section.to_bytes())?;
+        }
+
+        let block_size = 1 << NczWriter::BLOCK_SIZE_EXPONENT;
+        let decompressed_size = nca_size - Ncz::HEADER_SIZE;
+        let block_count = (decompressed_size + block_size - 1) / block_size;
+        writer.write_all(&NczBlockHeader::MAGIC)?;
+        writer.write_all(&[
+            NczWriter::BLOCK_VERSION,
+            NczWriter::BLOCK_KIND,
+            0,
+            NczWriter::BLOCK_SIZE_EXPONENT,
+        ])?;
+        writer.write_all(&(block_count as u32).to_le_bytes())?;
+        writer.write_all(&decompressed_size.to_le_bytes())?;
+        // Compressed sizes aren't known yet, they're filled in at the end
+        let sizes_offset = writer.stream_position()?;
+        writer.write_all(&vec![0u8; block_count as usize * 4])?;
+
+        let mut sizes = Vec::with_capacity(block_count as usize);
+        let mut offset = Ncz::HEADER_SIZE;
+        while offset < nca_size {
+            // Enough blocks for every worker to get one
+            let mut batch =
+                vec![0u8; (nca_size - offset).min(block_size * self.workers as u64) as usize];
+            reader.read_exact(&mut batch)?;
+            for section in &sections {
+                section.apply_crypto(offset, &mut batch);
+            }
+
+            let level = self.level;
+            let compressed = thread::scope(|scope| {
+                batch
+                    .chunks(block_size as usize)
+                    .map(|block| scope.spawn(move || zstd::bulk::compress(block, level)))
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .map(|handle| handle.join().expect("Compressing a block shouldn't panic"))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .map_err(|err| eyre!("Failed to compress '{}': {}", nca.path.display(), err))?;
+
+            for (block, compressed) in batch.chunks(block_size as usize).zip(&compressed) {
+                // Stored as is when compressing didn't help
+                let data = if compressed.len() < block.len() {
+                    compressed
+                } else {
+                    block
+                };
+                writer.write_all(data)?;
+                sizes.push(data.len() as u32);
+            }
+            offset += batch.len() as u64;
+        }
+
+        let ncz_size = writer.stream_position()?;
+        writer.seek(SeekFrom::Start(sizes_offset))?;
+        for size in sizes {
+            writer.write_all(&size.to_le_bytes())?;
+        }
+        writer.seek(SeekFrom::Start(ncz_size))?;
+        Ok(ncz_size)
+    }
+    /// Compresses `nca` to `<name>.ncz` in the `to` dir.
+    pub fn compress<'a, I, P>(
+        &self,
+        nca: &Nca,
+        keyset: &Keyset,
+        title_keys: I,
+        to: P,
+    ) -> Result<PathBuf>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+        P: AsRef<Path>,
+    {
+        let dest = to.as_ref().join(
+            nca.path
+                .with_extension("ncz")
+                .file_name()
+                .ok_or_else(|| eyre!("Failed to get filename"))?,
+        );
+        info!(
+            nca = %nca.path.display(),
+            level = self.level,
+            workers = self.workers,
+            "Compressing NCA to NCZ"
+        );
+
+        let mut writer = io::BufWriter::new(fs::File::create(&dest)?);
+        let ncz_size = self.write(nca, keyset, title_keys, &mut writer)?;
+        writer.flush()?;
+
+        info!(
+            ncz = %dest.display(),
+            nca_size = %ByteSize(nca.path.metadata()?.len()),
+            ncz_size = %ByteSize(ncz_size),
+            "Compressed NCA"
+        );
+        Ok(dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keyset::TEST_KEYS,
+        vfs::{
+            nca::{ContentType, SdkVersion},
+            nsp::Pfs0,
+        },
+    };
+
+    const KEY: Key128 = [0x33; 0x10];
+
+    /// NCA with an AES-CTR section followed by a plain one, along with its plaintext.
+    fn nca_data() -> (Vec<u8>, Vec<u8>, Vec<NczSection>) {
+        let mut crypto_counter = [0u8; 0x10];
+        crypto_counter[..8].copy_from_slice(&0x0000_0002_0000_0000u64.to_be_bytes());
+        let sections = vec![
+            NczSection {
+                offset: Ncz::HEADER_SIZE,
+                size: 0x1000,
+                crypto_type: NczSection::CRYPTO_CTR,
+                crypto_key: KEY,
+                crypto_counter,
+            },
+            NczSection::plain(Ncz::HEADER_SIZE + 0x1000, 0x5000),
+        ];
+        let mut plain = vec![0xC0; Ncz::HEADER_SIZE as usize];
+        plain.extend(b"section data".repeat(0x6000 / 12 + 1)[..0x6000].iter());
+        let mut nca = plain.clone();
+        sections[0].apply_crypto(0, &mut nca);
+        (nca, plain, sections)
+    }
+
+    fn ncz_header(nca: &[u8], sections: &[NczSection]) -> Vec<u8> {
+        let mut ncz = nca[..Ncz::HEADER_SIZE as usize].to_vec();
+        ncz.extend(Ncz::MAGIC);
+        ncz.extend((sections.len() as u64).to_le_bytes());
+        for section in sections {
+            ncz.extend(section.to_bytes());
+        }
+        ncz
+    }
+
+    #[test]
+    fn decompress_solid_ncz() -> Result<()> {
+        let (nca, plain, sections) = nca_data();
+        assert_ne!(nca, plain);
+        let mut ncz = ncz_header(&nca, &sections);
+        ncz.extend(zstd::encode_all(&plain[Ncz::HEADER_SIZE as usize..], 3)?);
+
+        let parsed = Ncz::read(&mut ncz.as_slice())?;
+        assert_eq!(parsed.sections, sections);
+        assert_eq!(parsed.block, None);
+        assert_eq!(parsed.nca_size(), nca.len() as u64);
+
+        let mut decompressed = vec![];
+        assert_eq!(
+            Ncz::decompress(ncz.as_slice(), &mut decompressed)?,
+            nca.len() as u64
+        );
+        assert_eq!(decompressed, nca);
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_block_ncz() -> Result<()> {
+        let (nca, plain, sections) = nca_data();
+        let data = &plain[Ncz::HEADER_SIZE as usize..];
+        // 2 blocks of 2^14 bytes, the last one is smaller and stored as is
+        let first = zstd::bulk::compress(&data[..0x4000], 3)?;
+        let last = &data[0x4000..];
+        let mut ncz = ncz_header(&nca, &sections);
+        let exponent_offset = ncz.len() + 0xB;
+        ncz.extend(NczBlockHeader::MAGIC);
+        ncz.extend([2, 1, 0, 14]);
+        ncz.extend(2u32.to_le_bytes());
+        ncz.extend((data.len() as u64).to_le_bytes());
+        ncz.extend((first.len() as u32).to_le_bytes());
+        ncz.extend((last.len() as u32).to_le_bytes());
+        ncz.extend(first);
+        ncz.extend(last);
+
+        let parsed = Ncz::read(&mut ncz.as_slice())?;
+        let block = parsed.block.as_ref().unwrap();
+        assert_eq!(block.block_size(), 0x4000);
+        assert_eq!(block.decompressed_block_size(0), 0x4000);
+        assert_eq!(block.decompressed_block_size(1), 0x2000);
+        assert_eq!(parsed.nca_size(), nca.len() as u64);
+
+        let mut decompressed = vec![];
+        Ncz::decompress(ncz.as_slice(), &mut decompressed)?;
+        assert_eq!(decompressed, nca);
+
+        let mut bad_count = ncz.clone();
+        bad_count[exponent_offset + 1..exponent_offset + 5]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Ncz::read(&mut bad_count.as_slice()).is_err());
+        bad_count[exponent_offset + 1..exponent_offset + 5].copy_from_slice(&3u32.to_le_bytes());
+        assert!(Ncz::read(&mut bad_count.as_slice()).is_err());
+
+        ncz[exponent_offset] = 13;
+        assert!(Ncz::read(&mut ncz.as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_rejects_bad_ncz() {
+        let (nca, _, sections) = nca_data();
+        let mut ncz = ncz_header(&nca, &sections);
+        ncz[Ncz::HEADER_SIZE as usize] = b'X';
+        assert!(Ncz::read(&mut ncz.as_slice()).is_err());
+
+        let ncz = ncz_header(&nca, &[]);
+        assert!(Ncz::read(&mut ncz.as_slice()).is_err());
+    }
+
+    #[test]
+    fn ncz_writer_round_trip() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let data = b"compressible".repeat(0x4000);
+        let mut pfs0 = Pfs0::build_header([("data.bin", data.len() as u64)]);
+        pfs0.extend(&data);
+        let nca = Nca::write_pfs0(
+            &keyset,
+            ContentType::Program,
+            0x0100_0000_0000_1000,
+            0,
+            SdkVersion(0),
+            &pfs0,
+            dir.path().join("program.nca"),
+        )?;
+
+        let ncz_path = NczWriter::new(3, 2).compress(&nca, &keyset, None, dir.path())?;
+        assert_eq!(ncz_path, dir.path().join("program.ncz"));
+        let nca_bytes = fs::read(&nca.path)?;
+        let ncz_bytes = fs::read(&ncz_path)?;
+        assert!(ncz_bytes.len() < nca_bytes.len() / 4);
+
+        let ncz = Ncz::read(&mut ncz_bytes.as_slice())?;
+        assert_eq!(ncz.nca_size(), nca_bytes.len() as u64);
+        assert!(ncz
+            .sections
+            .iter()
+            .any(|section| section.crypto_type == NczSection::CRYPTO_CTR));
+        let mut decompressed = vec![];
+        Ncz::decompress(ncz_bytes.as_slice(), &mut decompressed)?;
+        assert_eq!(decompressed, nca_bytes);
+        Ok(())
+    }
+}
@@ -0,0 +1,621 @@
+use crate::{
+    keyset::Keyset,
+    vfs::{
+        align_up,
+        exefs::ExeFs,
+        nca::{FsType, Nca},
+        nsp::Pfs0,
+        read_cstr, read_u32_le, read_u64_le,
+        ticket::TitleKey,
+        SubStorage,
+    },
+};
+use derivative::Derivative;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom},
+    ops::RangeInclusive,
+    path::Path,
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessCategory {
+    RegularTitle,
+    KernelBuiltin,
+    Unknown(u32),
+}
+
+impl From<u32> for ProcessCategory {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => ProcessCategory::RegularTitle,
+            1 => ProcessCategory::KernelBuiltin,
+            other => ProcessCategory::Unknown(other),
+        }
+    }
+}
+
+/// https://switchbrew.org/wiki/NPDM#Kernel_Capability_Descriptors
+///
+/// The descriptor type is given by the no. of trailing 1 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelCapability {
+    ThreadInfo {
+        lowest_priority: u8,
+        highest_priority: u8,
+        min_core: u8,
+        max_core: u8,
+    },
+    /// Allows the SVCs `index * 24 + bit` for every bit set in `mask`.
+    EnableSystemCalls {
+        index: u8,
+        mask: u32,
+    },
+    MemoryMap {
+        address: u64,
+        size: u64,
+        read_only: bool,
+        is_io: bool,
+    },
+    IoMemoryMap {
+        address: u64,
+    },
+    MemoryRegionMap(u32),
+    /// `0x3FF` means unused.
+    EnableInterrupts(u16, u16),
+    MiscParams {
+        program_type: u8,
+    },
+    KernelVersion {
+        major: u16,
+        minor: u8,
+    },
+    HandleTableSize(u16),
+    MiscFlags {
+        enable_debug: bool,
+        force_debug: bool,
+    },
+    Unknown(u32),
+}
+
+impl KernelCapability {
+    const PAGE_SIZE_LOG2: u32 = 12;
+
+    fn parse_all(buf: &[u8]) -> Result<Vec<Self>> {
+        let mut descriptors = buf
+            .chunks_exact(4)
+            .map(|descriptor| read_u32_le(descriptor, 0));
+        let mut capabilities = vec![];
+        while let Some(value) = descriptors.next() {
+            let bits = |from: u32, len: u32| (value >> from) & ((1 << len) - 1);
+            let capability = match (!value).trailing_zeros() {
+                3 => KernelCapability::ThreadInfo {
+                    lowest_priority: bits(4, 6) as _,
+                    highest_priority: bits(10, 6) as _,
+                    min_core: bits(16, 8) as _,
+                    max_core: bits(24, 8) as _,
+                },
+                4 => KernelCapability::EnableSystemCalls {
+                    index: bits(29, 3) as _,
+                    mask: bits(5, 24),
+                },
+                6 => {
+                    // Spans 2 descriptors, the 2nd one has the size
+                    let next = descriptors
+                        .next()
+                        .ok_or_else(|| eyre!("MemoryMap descriptor is missing its size"))?;
+                    KernelCapability::MemoryMap {
+                        address: (bits(7, 24) as u64) << KernelCapability::PAGE_SIZE_LOG2,
+                        size: (((next >> 7) & 0xF_FFFF) as u64) << KernelCapability::PAGE_SIZE_LOG2,
+                        read_only: bits(31, 1) == 1,
+                        is_io: next >> 31 == 0,
+                    }
+                }
+                7 => KernelCapability::IoMemoryMap {
+                    address: (bits(8, 24) as u64) << KernelCapability::PAGE_SIZE_LOG2,
+                },
+                10 => KernelCapability::MemoryRegionMap(bits(11, 21)),
+                11 => KernelCapability::EnableInterrupts(bits(12, 10) as _, bits(22, 10) as _),
+                13 => KernelCapability::MiscParams {
+                    program_type: bits(14, 3) as _,
+                },
+                14 => KernelCapability::KernelVersion {
+                    major: bits(19, 13) as _,
+                    minor: bits(15, 4) as _,
+                },
+                15 => KernelCapability::HandleTableSize(bits(16, 10) as _),
+                16 => KernelCapability::MiscFlags {
+                    enable_debug: bits(17, 1) == 1,
+                    force_debug: bits(18, 1) == 1,
+                },
+                // Padding
+                32 => continue,
+                _ => KernelCapability::Unknown(value),
+            };
+            capabilities.push(capability);
+        }
+        Ok(capabilities)
+    }
+}
+
+/// https://switchbrew.org/wiki/NPDM#FS_Access_Control
+///
+/// What the program is allowed to access, as declared in the ACID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsAccessControl {
+    pub version: u8,
+    pub permissions: u64,
+    pub content_owner_ids: RangeInclusive<u64>,
+    pub save_data_owner_ids: RangeInclusive<u64>,
+}
+
+impl FsAccessControl {
+    const SIZE: usize = 0x2C;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < FsAccessControl::SIZE {
+            bail!("FS Access Control is truncated");
+        }
+        Ok(Self {
+            version: buf[0x0],
+            permissions: read_u64_le(buf, 0x4),
+            content_owner_ids: read_u64_le(buf, 0xC)..=read_u64_le(buf, 0x14),
+            save_data_owner_ids: read_u64_le(buf, 0x1C)..=read_u64_le(buf, 0x24),
+        })
+    }
+}
+
+/// https://switchbrew.org/wiki/NPDM#FS_Access_Header
+///
+/// What the program actually requests, as declared in the ACI0.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsAccessHeader {
+    pub version: u8,
+    pub permissions: u64,
+    pub content_owner_ids: Vec<u64>,
+    pub save_data_owner_ids: Vec<u64>,
+}
+
+impl FsAccessHeader {
+    const SIZE: usize = 0x1C;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < FsAccessHeader::SIZE {
+            bail!("FS Access Header is truncated");
+        }
+        let content_owner_info = region(
+            buf,
+            read_u32_le(buf, 0xC),
+            read_u32_le(buf, 0x10),
+            "ContentOwnerInfo",
+        )?;
+        let save_data_owner_info = region(
+            buf,
+            read_u32_le(buf, 0x14),
+            read_u32_le(buf, 0x18),
+            "SaveDataOwnerInfo",
+        )?;
+
+        let content_owner_ids = if content_owner_info.len() >= 4 {
+            let count = read_u32_le(content_owner_info, 0x0) as usize;
+            read_ids(content_owner_info, 0x4, count)?
+        } else {
+            vec![]
+        };
+        // The IDs come after a byte per ID for its accessibility
+        let save_data_owner_ids = if save_data_owner_info.len() >= 4 {
+            let count = read_u32_le(save_data_owner_info, 0x0) as usize;
+            let ids_offset = align_up(4 + count as u64, 4) as usize;
+            read_ids(save_data_owner_info, ids_offset, count)?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            version: buf[0x0],
+            permissions: read_u64_le(buf, 0x4),
+            content_owner_ids,
+            save_data_owner_ids,
+        })
+    }
+}
+
+/// https://switchbrew.org/wiki/NPDM#ACID
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Acid {
+    #[derivative(Debug = "ignore")]
+    pub signature: Vec<u8>,
+    /// Public key for the second NCA header signature.
+    #[derivative(Debug = "ignore")]
+    pub public_key: Vec<u8>,
+    pub flags: u32,
+    pub program_ids: RangeInclusive<u64>,
+    pub fs_access_control: FsAccessControl,
+    pub kernel_capabilities: Vec<KernelCapability>,
+}
+
+impl Acid {
+    const MAGIC: [u8; 4] = *b"ACID";
+    const SIZE: usize = 0x240;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Acid::SIZE {
+            bail!("ACID is truncated");
+        }
+        if buf[0x200..0x204] != Acid::MAGIC {
+            bail!("Invalid ACID magic");
+        }
+        Ok(Self {
+            signature: buf[..0x100].to_vec(),
+            public_key: buf[0x100..0x200].to_vec(),
+            flags: read_u32_le(buf, 0x20C),
+            program_ids: read_u64_le(buf, 0x210)..=read_u64_le(buf, 0x218),
+            fs_access_control: FsAccessControl::parse(region(
+                buf,
+                read_u32_le(buf, 0x220),
+                read_u32_le(buf, 0x224),
+                "ACID FS Access Control",
+            )?)?,
+            kernel_capabilities: KernelCapability::parse_all(region(
+                buf,
+                read_u32_le(buf, 0x230),
+                read_u32_le(buf, 0x234),
+                "ACID Kernel Capabilities",
+            )?)?,
+        })
+    }
+    /// Whether the ACID is signed with the production key.
+    pub fn is_production(&self) -> bool {
+        self.flags & 1 == 1
+    }
+}
+
+/// https://switchbrew.org/wiki/NPDM#ACI0
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Aci0 {
+    #[derivative(Debug(format_with = "program_id_fmt"))]
+    pub program_id: u64,
+    pub fs_access_header: FsAccessHeader,
+    pub kernel_capabilities: Vec<KernelCapability>,
+}
+
+fn program_id_fmt(program_id: &u64, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "{:016x}", program_id)
+}
+
+impl Aci0 {
+    const MAGIC: [u8; 4] = *b"ACI0";
+    const SIZE: usize = 0x40;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Aci0::SIZE {
+            bail!("ACI0 is truncated");
+        }
+        if buf[..4] != Aci0::MAGIC {
+            bail!("Invalid ACI0 magic");
+        }
+        Ok(Self {
+            program_id: read_u64_le(buf, 0x10),
+            fs_access_header: FsAccessHeader::parse(region(
+                buf,
+                read_u32_le(buf, 0x20),
+                read_u32_le(buf, 0x24),
+                "ACI0 FS Access Header",
+            )?)?,
+            kernel_capabilities: KernelCapability::parse_all(region(
+                buf,
+                read_u32_le(buf, 0x30),
+                read_u32_le(buf, 0x34),
+                "ACI0 Kernel Capabilities",
+            )?)?,
+        })
+    }
+}
+
+/// https://switchbrew.org/wiki/NPDM
+///
+/// Program metadata, found as `main.npdm` in the ExeFS.
+#[derive(Debug, Clone)]
+pub struct Npdm {
+    pub name: String,
+    pub product_code: String,
+    pub flags: u8,
+    pub main_thread_priority: u8,
+    pub main_thread_core: u8,
+    pub system_resource_size: u32,
+    /// Newer revisions of the docs call this field Version, it's the same word.
+    pub process_category: ProcessCategory,
+    pub main_thread_stack_size: u32,
+    pub acid: Acid,
+    pub aci0: Aci0,
+}
+
+impl Npdm {
+    const MAGIC: [u8; 4] = *b"META";
+    const HEADER_SIZE: usize = 0x80;
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Npdm::parse(&fs::read(path.as_ref())?)
+            .map_err(|err| eyre!("Failed to read '{}': {}", path.as_ref().display(), err))
+    }
+    /// Reads `main.npdm` from the ExeFS of a Program NCA, without extracting it.
+    pub fn from_program_nca<'a, I>(nca: &Nca, keyset: &Keyset, title_keys: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a TitleKey>,
+    {
+        let index = nca
+            .section_index(FsType::PartitionFs)
+            .ok_or_else(|| eyre!("'{}' has no ExeFS section", nca.path.display()))?;
+        let mut exefs = nca.open_fs_data(index, keyset, title_keys)?;
+        let pfs0 = Pfs0::read(&mut exefs)?;
+        let entry = pfs0
+            .entry(ExeFs::NPDM_NAME)
+            .ok_or_else(|| eyre!("Couldn't find NPDM in '{}'", nca.path.display()))?;
+        let mut buf = vec![0u8; entry.size as usize];
+        SubStorage::new(&mut exefs, entry.offset, entry.size).read_exact(&mut buf)?;
+        Npdm::parse(&buf)
+    }
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mut buf = vec![];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut buf)?;
+        Npdm::parse(&buf)
+    }
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Npdm::HEADER_SIZE {
+            bail!("NPDM is truncated");
+        }
+        if buf[..4] != Npdm::MAGIC {
+            bail!(
+                "Invalid NPDM magic '{}'",
+                String::from_utf8_lossy(&buf[..4])
+            );
+        }
+        let npdm = Self {
+            flags: buf[0xC],
+            main_thread_priority: buf[0xE],
+            main_thread_core: buf[0xF],
+            // 0x10 is reserved
+            system_resource_size: read_u32_le(buf, 0x14),
+            process_category: ProcessCategory::from(read_u32_le(buf, 0x18)),
+            main_thread_stack_size: read_u32_le(buf, 0x1C),
+            name: read_cstr(&buf[0x20..0x30], 0)?,
+            product_code: read_cstr(&buf[0x30..0x40], 0)?,
+            aci0: Aci0::parse(region(
+                buf,
+                read_u32_le(buf, 0x70),
+                read_u32_le(buf, 0x74),
+                "ACI0",
+            )?)?,
+            acid: Acid::parse(region(
+                buf,
+                read_u32_le(buf, 0x78),
+                read_u32_le(buf, 0x7C),
+                "ACID",
+            )?)?,
+        };
+        debug!(?npdm, "Parsed NPDM");
+        Ok(npdm)
+    }
+    pub fn is_64bit(&self) -> bool {
+        self.flags & 1 == 1
+    }
+    /// Whether the NPDM allows running as `program_id`,
+    /// a mismatching one results in a program that fails to boot.
+    pub fn matches_program_id(&self, program_id: u64) -> bool {
+        self.aci0.program_id == program_id && self.acid.program_ids.contains(&program_id)
+    }
+}
+
+/// `size` bytes at `offset` of `buf`.
+fn region<'a>(buf: &'a [u8], offset: u32, size: u32, name: &str) -> Result<&'a [u8]> {
+    buf.get(offset as usize..offset as usize + size as usize)
+        .ok_or_else(|| eyre!("{} is out of bounds", name))
+}
+
+fn read_ids(buf: &[u8], offset: usize, count: usize) -> Result<Vec<u64>> {
+    buf.get(offset..offset + count * 8)
+        .ok_or_else(|| eyre!("Owner IDs are out of bounds"))
+        .map(|ids| ids.chunks_exact(8).map(|id| read_u64_le(id, 0)).collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const PROGRAM_ID: u64 = 0x0100_ABCD_0000_0000;
+
+    /// 64-bit NPDM of `PROGRAM_ID`, with an ACID allowing `PROGRAM_ID..=PROGRAM_ID + 0xF`.
+    pub(crate) fn build_npdm() -> Vec<u8> {
+        let mut buf = vec![0u8; Npdm::HEADER_SIZE];
+        let write_at = |buf: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        write_at(&mut buf, 0x0, &Npdm::MAGIC);
+        buf[0xC] = 0x1;
+        buf[0xE] = 0x2C;
+        buf[0xF] = 0x0;
+        write_at(&mut buf, 0x14, &0x20_0000u32.to_le_bytes());
+        write_at(&mut buf, 0x18, &1u32.to_le_bytes());
+        write_at(&mut buf, 0x1C, &0x10_0000u32.to_le_bytes());
+        write_at(&mut buf, 0x20, b"Application");
+
+        // ACI0 followed by its FS Access Header and Kernel Capabilities
+        let aci0_offset = buf.len();
+        let mut aci0 = vec![0u8; Aci0::SIZE];
+        write_at(&mut aci0, 0x0, &Aci0::MAGIC);
+        write_at(&mut aci0, 0x10, &PROGRAM_ID.to_le_bytes());
+        let mut fs_access_header = vec![0u8; FsAccessHeader::SIZE];
+        fs_access_header[0x0] = 1;
+        write_at(&mut fs_access_header, 0x4, &u64::MAX.to_le_bytes());
+        write_at(&mut fs_access_header, 0xC, &0x1Cu32.to_le_bytes());
+        write_at(&mut fs_access_header, 0x10, &0xCu32.to_le_bytes());
+        write_at(&mut fs_access_header, 0x14, &0x28u32.to_le_bytes());
+        write_at(&mut fs_access_header, 0x18, &0x10u32.to_le_bytes());
+        fs_access_header.extend(1u32.to_le_bytes());
+        fs_access_header.extend(PROGRAM_ID.to_le_bytes());
+        fs_access_header.extend(1u32.to_le_bytes());
+        fs_access_header.extend([0x3, 0x0, 0x0, 0x0]);
+        fs_access_header.extend((PROGRAM_ID + 1).to_le_bytes());
+        let kernel_capabilities: [u32; 4] = [
+            0x7 | 59 << 4 | 28 << 10 | 3 << 24,
+            0xF | 0x3 << 5 | 1 << 29,
+            0x3FFF | 4 << 15 | 10 << 19,
+            u32::MAX,
+        ];
+        write_at(&mut aci0, 0x20, &(Aci0::SIZE as u32).to_le_bytes());
+        write_at(
+            &mut aci0,
+            0x24,
+            &(fs_access_header.len() as u32).to_le_bytes(),
+        );
+        write_at(
+            &mut aci0,
+            0x30,
+            &((Aci0::SIZE + fs_access_header.len()) as u32).to_le_bytes(),
+        );
+        write_at(&mut aci0, 0x34, &0x10u32.to_le_bytes());
+        aci0.extend(fs_access_header);
+        aci0.extend(
+            kernel_capabilities
+                .iter()
+                .flat_map(|value| value.to_le_bytes()),
+        );
+        write_at(&mut buf, 0x70, &(aci0_offset as u32).to_le_bytes());
+        write_at(&mut buf, 0x74, &(aci0.len() as u32).to_le_bytes());
+        buf.extend(aci0);
+        buf.resize(align_up(buf.len() as u64, 0x10) as usize, 0);
+
+        // ACID followed by its FS Access Control and Kernel Capabilities
+        let acid_offset = buf.len();
+        let mut acid = vec![0xAB; 0x100];
+        acid.extend([0xCD; 0x100]);
+        acid.resize(Acid::SIZE, 0);
+        write_at(&mut acid, 0x200, &Acid::MAGIC);
+        write_at(&mut acid, 0x20C, &1u32.to_le_bytes());
+        write_at(&mut acid, 0x210, &PROGRAM_ID.to_le_bytes());
+        write_at(&mut acid, 0x218, &(PROGRAM_ID + 0xF).to_le_bytes());
+        write_at(&mut acid, 0x220, &(Acid::SIZE as u32).to_le_bytes());
+        write_at(
+            &mut acid,
+            0x224,
+            &(FsAccessControl::SIZE as u32).to_le_bytes(),
+        );
+        write_at(
+            &mut acid,
+            0x230,
+            &((Acid::SIZE + FsAccessControl::SIZE) as u32).to_le_bytes(),
+        );
+        write_at(&mut acid, 0x234, &0x10u32.to_le_bytes());
+        let mut fs_access_control = vec![0u8; FsAccessControl::SIZE];
+        fs_access_control[0x0] = 1;
+        write_at(&mut fs_access_control, 0x4, &u64::MAX.to_le_bytes());
+        write_at(&mut fs_access_control, 0x14, &u64::MAX.to_le_bytes());
+        write_at(&mut fs_access_control, 0x24, &u64::MAX.to_le_bytes());
+        acid.extend(fs_access_control);
+        let kernel_capabilities: [u32; 4] = [
+            0xFFFF | 1 << 17,
+            0x7FFF | 512 << 16,
+            0x3F | 0x7_0000 << 7 | 1 << 31,
+            0x3F | 1 << 7,
+        ];
+        acid.extend(
+            kernel_capabilities
+                .iter()
+                .flat_map(|value| value.to_le_bytes()),
+        );
+        write_at(&mut buf, 0x78, &(acid_offset as u32).to_le_bytes());
+        write_at(&mut buf, 0x7C, &(acid.len() as u32).to_le_bytes());
+        buf.extend(acid);
+        buf
+    }
+
+    #[test]
+    fn parse_npdm() -> Result<()> {
+        let npdm = Npdm::parse(&build_npdm())?;
+        assert_eq!(npdm.name, "Application");
+        assert!(npdm.is_64bit());
+        assert_eq!(npdm.main_thread_priority, 0x2C);
+        assert_eq!(npdm.system_resource_size, 0x20_0000);
+        assert_eq!(npdm.process_category, ProcessCategory::KernelBuiltin);
+        assert_eq!(npdm.main_thread_stack_size, 0x10_0000);
+
+        assert_eq!(npdm.aci0.program_id, PROGRAM_ID);
+        assert_eq!(
+            npdm.aci0.fs_access_header,
+            FsAccessHeader {
+                version: 1,
+                permissions: u64::MAX,
+                content_owner_ids: vec![PROGRAM_ID],
+                save_data_owner_ids: vec![PROGRAM_ID + 1],
+            }
+        );
+        assert_eq!(
+            npdm.aci0.kernel_capabilities,
+            [
+                KernelCapability::ThreadInfo {
+                    lowest_priority: 59,
+                    highest_priority: 28,
+                    min_core: 0,
+                    max_core: 3,
+                },
+                KernelCapability::EnableSystemCalls {
+                    index: 1,
+                    mask: 0x3,
+                },
+                KernelCapability::KernelVersion {
+                    major: 10,
+                    minor: 4,
+                },
+            ]
+        );
+
+        assert!(npdm.acid.is_production());
+        assert_eq!(npdm.acid.public_key, [0xCD; 0x100]);
+        assert_eq!(npdm.acid.fs_access_control.permissions, u64::MAX);
+        assert_eq!(
+            npdm.acid.fs_access_control.save_data_owner_ids,
+            0..=u64::MAX
+        );
+        assert_eq!(
+            npdm.acid.kernel_capabilities,
+            [
+                KernelCapability::MiscFlags {
+                    enable_debug: true,
+                    force_debug: false,
+                },
+                KernelCapability::HandleTableSize(512),
+                KernelCapability::MemoryMap {
+                    address: 0x7000_0000,
+                    size: 0x1000,
+                    read_only: true,
+                    is_io: true,
+                },
+            ]
+        );
+        assert!(npdm.matches_program_id(PROGRAM_ID));
+        assert!(!npdm.matches_program_id(PROGRAM_ID + 1));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_bad_npdm() {
+        let npdm = build_npdm();
+        assert!(Npdm::parse(&npdm[..Npdm::HEADER_SIZE - 1]).is_err());
+        assert!(Npdm::parse(&npdm[..npdm.len() - 1]).is_err());
+
+        let mut bad_magic = npdm.clone();
+        bad_magic[..4].copy_from_slice(b"ATEM");
+        assert!(Npdm::parse(&bad_magic).is_err());
+
+        let mut bad_acid = npdm;
+        let acid_offset = read_u32_le(&bad_acid, 0x78) as usize;
+        bad_acid[acid_offset + 0x200] = 0;
+        assert!(Npdm::parse(&bad_acid).is_err());
+    }
+}
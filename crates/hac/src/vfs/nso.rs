@@ -0,0 +1,338 @@
+use crate::vfs::read_u32_le;
+use derivative::Derivative;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    io::{self, Write},
+    path::Path,
+};
+use tracing::{debug, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Text = 0,
+    Ro = 1,
+    Data = 2,
+}
+
+impl SegmentKind {
+    pub const ALL: [SegmentKind; 3] = [SegmentKind::Text, SegmentKind::Ro, SegmentKind::Data];
+
+    /// Offset of the `SegmentHeader` in the NSO header.
+    fn header_offset(self) -> usize {
+        0x10 + self as usize * 0x10
+    }
+    fn compressed_flag(self) -> u32 {
+        1 << self as u32
+    }
+    fn check_hash_flag(self) -> u32 {
+        1 << (self as u32 + 3)
+    }
+}
+
+/// https://switchbrew.org/wiki/NSO#SegmentHeader
+#[derive(Derivative, Default, Clone, Copy, PartialEq, Eq)]
+#[derivative(Debug)]
+pub struct SegmentHeader {
+    pub file_offset: u32,
+    pub memory_offset: u32,
+    /// Decompressed size.
+    pub size: u32,
+    /// Size in the file, i.e. the compressed size if it's compressed.
+    pub file_size: u32,
+    /// SHA-256 of the decompressed segment.
+    #[derivative(Debug(format_with = "hash_fmt"))]
+    pub hash: [u8; 0x20],
+}
+
+fn hash_fmt(hash: &[u8; 0x20], fmt: &mut fmt::Formatter) -> fmt::Result {
+    fmt.write_fmt(format_args!("{:?}", hex::encode(hash)))
+}
+
+/// https://switchbrew.org/wiki/NSO
+///
+/// An executable of the ExeFS, the segments are kept decompressed in memory.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Nso {
+    pub version: u32,
+    pub flags: u32,
+    pub segments: [SegmentHeader; 3],
+    pub bss_size: u32,
+    /// Also known as the build ID.
+    #[derivative(Debug(format_with = "hash_fmt"))]
+    pub module_id: [u8; 0x20],
+    #[derivative(Debug = "ignore")]
+    pub module_name: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    data: [Vec<u8>; 3],
+    /// The whole header, to keep the fields that aren't parsed when writing.
+    #[derivative(Debug = "ignore")]
+    raw_header: Vec<u8>,
+}
+
+impl Nso {
+    const MAGIC: [u8; 4] = *b"NSO0";
+    const HEADER_SIZE: usize = 0x100;
+    const MODULE_NAME_OFFSET: usize = 0x1C;
+    const MODULE_NAME_SIZE: usize = 0x2C;
+    const MODULE_ID_OFFSET: usize = 0x40;
+    const FILE_SIZES_OFFSET: usize = 0x60;
+    const HASHES_OFFSET: usize = 0xA0;
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Nso::parse(&fs::read(path.as_ref())?)
+            .map_err(|err| eyre!("Failed to read '{}': {}", path.as_ref().display(), err))
+    }
+    /// Parses the NSO and decompresses its segments,
+    /// the segments that have the hash check flag set are also verified.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Nso::HEADER_SIZE {
+            bail!("NSO is truncated");
+        }
+        if buf[..4] != Nso::MAGIC {
+            bail!("Invalid NSO magic '{}'", String::from_utf8_lossy(&buf[..4]));
+        }
+        let flags = read_u32_le(buf, 0xC);
+
+        let mut segments = [SegmentHeader::default(); 3];
+        let mut data: [Vec<u8>; 3] = Default::default();
+        for kind in SegmentKind::ALL {
+            let offset = kind.header_offset();
+            let hash_offset = Nso::HASHES_OFFSET + kind as usize * 0x20;
+            let segment = SegmentHeader {
+                file_offset: read_u32_le(buf, offset),
+                memory_offset: read_u32_le(buf, offset + 0x4),
+                size: read_u32_le(buf, offset + 0x8),
+                file_size: read_u32_le(buf, Nso::FILE_SIZES_OFFSET + kind as usize * 0x4),
+                hash: buf[hash_offset..hash_offset + 0x20].try_into().unwrap(),
+            };
+            let stored = buf
+                .get(
+                    segment.file_offset as usize
+                        ..segment.file_offset as usize + segment.file_size as usize,
+                )
+                .ok_or_else(|| eyre!("{:?} segment is out of bounds", kind))?;
+
+            let decompressed = if flags & kind.compressed_flag() != 0 {
+                lz4_flex::block::decompress(stored, segment.size as usize)
+                    .map_err(|err| eyre!("Failed to decompress {:?} segment: {}", kind, err))?
+            } else {
+                stored.to_vec()
+            };
+            if decompressed.len() != segment.size as usize {
+                bail!(
+                    "{:?} segment is {:#x} bytes instead of {:#x}",
+                    kind,
+                    decompressed.len(),
+                    segment.size
+                );
+            }
+            if flags & kind.check_hash_flag() != 0
+                && Sha256::digest(&decompressed)[..] != segment.hash
+            {
+                bail!("{:?} segment hash mismatch", kind);
+            }
+
+            segments[kind as usize] = segment;
+            data[kind as usize] = decompressed;
+        }
+
+        let module_name_offset = read_u32_le(buf, Nso::MODULE_NAME_OFFSET) as usize;
+        let module_name_size = read_u32_le(buf, Nso::MODULE_NAME_SIZE) as usize;
+        let nso = Self {
+            version: read_u32_le(buf, 0x4),
+            flags,
+            segments,
+            bss_size: read_u32_le(buf, 0x3C),
+            module_id: buf[Nso::MODULE_ID_OFFSET..Nso::MODULE_ID_OFFSET + 0x20]
+                .try_into()
+                .unwrap(),
+            module_name: buf
+                .get(module_name_offset..module_name_offset + module_name_size)
+                .unwrap_or_default()
+                .to_vec(),
+            data,
+            raw_header: buf[..Nso::HEADER_SIZE].to_vec(),
+        };
+        debug!(?nso, "Parsed NSO");
+        Ok(nso)
+    }
+    /// Module ID in uppercase hex, with the trailing zeroes trimmed,
+    /// as used to name the exefs patches.
+    ///
+    /// Cheats only use its first 16 characters.
+    pub fn build_id(&self) -> String {
+        let len = self
+            .module_id
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(0, |pos| pos + 1);
+        hex::encode_upper(&self.module_id[..len])
+    }
+    /// Decompressed data of a segment.
+    pub fn segment(&self, kind: SegmentKind) -> &[u8] {
+        &self.data[kind as usize]
+    }
+    pub fn is_compressed(&self) -> bool {
+        SegmentKind::ALL
+            .iter()
+            .any(|kind| self.flags & kind.compressed_flag() != 0)
+    }
+    /// Writes the NSO with all the segments uncompressed, the hash check flags are kept.
+    pub fn write_uncompressed<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut header = self.raw_header.clone();
+        let flags = SegmentKind::ALL
+            .iter()
+            .fold(self.flags, |flags, kind| flags & !kind.compressed_flag());
+        header[0xC..0x10].copy_from_slice(&flags.to_le_bytes());
+
+        // Module name right after the header, followed by the segments
+        let mut file_offset = (Nso::HEADER_SIZE + self.module_name.len()) as u32;
+        header[Nso::MODULE_NAME_OFFSET..Nso::MODULE_NAME_OFFSET + 4]
+            .copy_from_slice(&(Nso::HEADER_SIZE as u32).to_le_bytes());
+        header[Nso::MODULE_NAME_SIZE..Nso::MODULE_NAME_SIZE + 4]
+            .copy_from_slice(&(self.module_name.len() as u32).to_le_bytes());
+        for kind in SegmentKind::ALL {
+            let size = self.segment(kind).len() as u32;
+            let offset = kind.header_offset();
+            header[offset..offset + 4].copy_from_slice(&file_offset.to_le_bytes());
+            let offset = Nso::FILE_SIZES_OFFSET + kind as usize * 0x4;
+            header[offset..offset + 4].copy_from_slice(&size.to_le_bytes());
+            file_offset += size;
+        }
+
+        writer.write_all(&header)?;
+        writer.write_all(&self.module_name)?;
+        for kind in SegmentKind::ALL {
+            writer.write_all(self.segment(kind))?;
+        }
+        Ok(())
+    }
+    pub fn save_uncompressed<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = io::BufWriter::new(fs::File::create(path.as_ref())?);
+        self.write_uncompressed(&mut writer)?;
+        writer.flush()?;
+        info!(nso = %path.as_ref().display(), "Wrote uncompressed NSO");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment_data(kind: SegmentKind) -> Vec<u8> {
+        match kind {
+            SegmentKind::Text => b"text".repeat(0x100),
+            SegmentKind::Ro => b"read-only data".to_vec(),
+            SegmentKind::Data => vec![0; 0x200],
+        }
+    }
+
+    /// NSO with the Text and Data segments compressed, all 3 with the hash check flag set.
+    fn build_nso() -> Vec<u8> {
+        let module_name = b"\x05main\0";
+        let mut header = vec![0u8; Nso::HEADER_SIZE];
+        header[..4].copy_from_slice(&Nso::MAGIC);
+        let mut flags = 0;
+        let mut body = module_name.to_vec();
+        for kind in SegmentKind::ALL {
+            let data = segment_data(kind);
+            let stored = if kind == SegmentKind::Ro {
+                data.clone()
+            } else {
+                flags |= kind.compressed_flag();
+                lz4_flex::block::compress(&data)
+            };
+            flags |= kind.check_hash_flag();
+
+            let offset = kind.header_offset();
+            let file_offset = (Nso::HEADER_SIZE + body.len()) as u32;
+            header[offset..offset + 4].copy_from_slice(&file_offset.to_le_bytes());
+            header[offset + 0x4..offset + 0x8]
+                .copy_from_slice(&(kind as u32 * 0x1000).to_le_bytes());
+            header[offset + 0x8..offset + 0xC].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            let offset = Nso::FILE_SIZES_OFFSET + kind as usize * 0x4;
+            header[offset..offset + 4].copy_from_slice(&(stored.len() as u32).to_le_bytes());
+            let offset = Nso::HASHES_OFFSET + kind as usize * 0x20;
+            header[offset..offset + 0x20].copy_from_slice(&Sha256::digest(&data));
+            body.extend(stored);
+        }
+        header[0xC..0x10].copy_from_slice(&flags.to_le_bytes());
+        header[Nso::MODULE_NAME_OFFSET..Nso::MODULE_NAME_OFFSET + 4]
+            .copy_from_slice(&(Nso::HEADER_SIZE as u32).to_le_bytes());
+        header[Nso::MODULE_NAME_SIZE..Nso::MODULE_NAME_SIZE + 4]
+            .copy_from_slice(&(module_name.len() as u32).to_le_bytes());
+        header[0x3C..0x40].copy_from_slice(&0x8000u32.to_le_bytes());
+        header[Nso::MODULE_ID_OFFSET..Nso::MODULE_ID_OFFSET + 0xA]
+            .copy_from_slice(&[0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0x00, 0x10]);
+        header.extend(body);
+        header
+    }
+
+    #[test]
+    fn parse_compressed_nso() -> Result<()> {
+        let buf = build_nso();
+        let nso = Nso::parse(&buf)?;
+        assert!(nso.is_compressed());
+        assert_eq!(nso.bss_size, 0x8000);
+        assert_eq!(nso.module_name, b"\x05main\0");
+        assert_eq!(nso.build_id(), "0123456789ABCDEF0010");
+        for kind in SegmentKind::ALL {
+            assert_eq!(nso.segment(kind), segment_data(kind));
+            assert_eq!(
+                nso.segments[kind as usize].memory_offset,
+                kind as u32 * 0x1000
+            );
+        }
+        assert!(
+            nso.segments[SegmentKind::Text as usize].file_size
+                < nso.segments[SegmentKind::Text as usize].size
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_uncompressed_nso() -> Result<()> {
+        let nso = Nso::parse(&build_nso())?;
+        let mut buf = vec![];
+        nso.write_uncompressed(&mut buf)?;
+
+        let uncompressed = Nso::parse(&buf)?;
+        assert!(!uncompressed.is_compressed());
+        assert_eq!(
+            uncompressed.flags,
+            SegmentKind::ALL
+                .iter()
+                .fold(0, |flags, kind| flags | kind.check_hash_flag())
+        );
+        assert_eq!(uncompressed.module_id, nso.module_id);
+        assert_eq!(uncompressed.module_name, nso.module_name);
+        assert_eq!(uncompressed.bss_size, nso.bss_size);
+        for kind in SegmentKind::ALL {
+            assert_eq!(uncompressed.segment(kind), nso.segment(kind));
+            let segment = uncompressed.segments[kind as usize];
+            assert_eq!(segment.file_size, segment.size);
+            assert_eq!(segment.hash, nso.segments[kind as usize].hash);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_bad_nso() {
+        let nso = build_nso();
+        assert!(Nso::parse(&nso[..Nso::HEADER_SIZE - 1]).is_err());
+        assert!(Nso::parse(&nso[..nso.len() - 1]).is_err());
+
+        let mut bad_hash = nso.clone();
+        bad_hash[Nso::HASHES_OFFSET + SegmentKind::Ro as usize * 0x20] ^= 0xFF;
+        assert!(Nso::parse(&bad_hash).is_err());
+
+        let mut bad_magic = nso;
+        bad_magic[3] = b'1';
+        assert!(Nso::parse(&bad_magic).is_err());
+    }
+}
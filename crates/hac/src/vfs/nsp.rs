@@ -1,14 +1,18 @@
-use crate::{
-    backend::Backend,
-    vfs::{filter_out_key_mismatches, ticket::TitleKey},
+use crate::vfs::{
+    extract_entry_to, read_cstr, read_entry_tables, read_u32_le, read_u64_le,
+    split::{is_first_part, SplitFile, SplitWriter},
+    ticket::TitleKey,
+    SubStorage,
 };
-use common::utils::{ext_matches, get_fmt_size};
-use eyre::{bail, Result};
+use bytesize::ByteSize;
+use common::utils::ext_matches;
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
 use std::{
+    io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
-    process::{Command, Stdio},
 };
-use tracing::{error, info};
+use tracing::{debug, error, info};
 use walkdir::WalkDir;
 
 /// https://switchbrew.org/wiki/NCA#PFS0
@@ -17,113 +21,386 @@ use walkdir::WalkDir;
 #[derive(Debug, Default, Clone)]
 pub struct Nsp {
     pub path: PathBuf,
+    /// Size of the whole package, i.e. of all the parts if it's split.
+    pub size: u64,
     pub title_key: Option<TitleKey>,
 }
 
+/// https://switchbrew.org/wiki/NCA#PFS0
+///
+/// Parsed PFS0 header, it only contains the file entries and not the data itself.
+#[derive(Debug, Default, Clone)]
+pub struct Pfs0 {
+    pub entries: Vec<Pfs0Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pfs0Entry {
+    pub name: String,
+    /// Offset of the file data, relative to the start of the PFS0.
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Pfs0 {
+    const MAGIC: [u8; 4] = *b"PFS0";
+    const HEADER_SIZE: usize = 0x10;
+    const ENTRY_SIZE: usize = 0x18;
+    const HEADER_ALIGNMENT: usize = 0x20;
+
+    /// Parses the PFS0 header, `reader` must be positioned at the start of the PFS0.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mut header = [0u8; Pfs0::HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if header[..4] != Pfs0::MAGIC {
+            bail!(
+                "Invalid PFS0 magic '{}'",
+                String::from_utf8_lossy(&header[..4])
+            );
+        }
+        let entry_count = read_u32_le(&header, 0x4) as usize;
+        let string_table_size = read_u32_le(&header, 0x8) as usize;
+        debug!(entry_count, string_table_size, "Reading PFS0 header");
+
+        let (entry_table, string_table) =
+            read_entry_tables(reader, entry_count, Pfs0::ENTRY_SIZE, string_table_size)?;
+
+        let data_offset = (Pfs0::HEADER_SIZE + entry_table.len() + string_table.len()) as u64;
+        let entries = entry_table
+            .chunks_exact(Pfs0::ENTRY_SIZE)
+            .map(|entry| {
+                Ok(Pfs0Entry {
+                    name: read_cstr(&string_table, read_u32_le(entry, 0x10) as _)?,
+                    offset: data_offset + read_u64_le(entry, 0x0),
+                    size: read_u64_le(entry, 0x8),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { entries })
+    }
+    /// Builds a PFS0 header for the given `(name, size)` entries, data is laid out
+    /// contiguously in the same order.
+    pub fn build_header<'a, I>(entries: I) -> Vec<u8>
+    where
+        I: IntoIterator<Item = (&'a str, u64)>,
+    {
+        let entries = entries.into_iter().collect::<Vec<_>>();
+        let mut string_table = vec![];
+        let mut entry_table = vec![];
+        let mut data_offset = 0u64;
+        for (name, size) in &entries {
+            entry_table.extend(data_offset.to_le_bytes());
+            entry_table.extend(size.to_le_bytes());
+            entry_table.extend((string_table.len() as u32).to_le_bytes());
+            entry_table.extend(0u32.to_le_bytes());
+            string_table.extend(name.as_bytes());
+            string_table.push(0);
+            data_offset += size;
+        }
+
+        // Padding the string table so that the data starts at an aligned offset
+        let unpadded_len = Pfs0::HEADER_SIZE + entry_table.len() + string_table.len();
+        let padded_len = (unpadded_len + Pfs0::HEADER_ALIGNMENT - 1) / Pfs0::HEADER_ALIGNMENT
+            * Pfs0::HEADER_ALIGNMENT;
+        string_table.resize(string_table.len() + padded_len - unpadded_len, 0);
+
+        let mut header = Vec::with_capacity(padded_len);
+        header.extend(Pfs0::MAGIC);
+        header.extend((entries.len() as u32).to_le_bytes());
+        header.extend((string_table.len() as u32).to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(entry_table);
+        header.extend(string_table);
+        header
+    }
+    pub fn entry(&self, name: &str) -> Option<&Pfs0Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+    /// Returns entries with the given extension.
+    pub fn entries_with_ext<'a>(&'a self, ext: &'a str) -> impl Iterator<Item = &'a Pfs0Entry> {
+        self.entries
+            .iter()
+            .filter(move |entry| ext_matches(&entry.name, ext))
+    }
+}
+
+/// Where the data of a NSP entry comes from.
+#[derive(Debug, Clone)]
+enum NspSource {
+    File(PathBuf),
+    /// `size` bytes at `offset` of a package, i.e. an entry of another container.
+    Entry {
+        name: String,
+        package: PathBuf,
+        offset: u64,
+        size: u64,
+    },
+}
+
+impl NspSource {
+    fn name(&self) -> Result<&str> {
+        match self {
+            NspSource::File(path) => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| eyre!("'{}' has an invalid filename", path.display())),
+            NspSource::Entry { name, .. } => Ok(name),
+        }
+    }
+    fn size(&self) -> Result<u64> {
+        Ok(match self {
+            NspSource::File(path) => path.metadata()?.len(),
+            NspSource::Entry { size, .. } => *size,
+        })
+    }
+    fn open(&self) -> Result<Box<dyn Read>> {
+        Ok(match self {
+            NspSource::File(path) => Box::new(fs::File::open(path)?),
+            NspSource::Entry {
+                package,
+                offset,
+                size,
+                ..
+            } => Box::new(io::BufReader::new(SubStorage::new(
+                SplitFile::open(package)?,
+                *offset,
+                *size,
+            ))),
+        })
+    }
+}
+
+/// Writes files to a PFS0 in the order they were added.
+///
+/// The header is padded to `Pfs0::HEADER_ALIGNMENT` and the file data
+/// is streamed right after it.
+#[derive(Debug, Default, Clone)]
+pub struct NspWriter {
+    sources: Vec<NspSource>,
+}
+
+impl NspWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Collects the NCAs and NCZs (sorted by name) followed by the Tickets and Certs in `dir`,
+    /// anything else is ignored.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let mut files = WalkDir::new(dir.as_ref())
+            .min_depth(1)
+            .max_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect::<Vec<_>>();
+        const ORDER: [&str; 4] = ["nca", "ncz", "tik", "cert"];
+        files.retain(|path| ORDER.iter().any(|ext| ext_matches(path, ext)));
+        files.sort_by_key(|path| ORDER.iter().position(|ext| ext_matches(path, ext)));
+
+        if !files
+            .iter()
+            .any(|path| ext_matches(path, "nca") || ext_matches(path, "ncz"))
+        {
+            bail!("Couldn't find any NCA in '{}'", dir.as_ref().display());
+        }
+
+        Ok(Self {
+            sources: files.into_iter().map(NspSource::File).collect(),
+        })
+    }
+    pub fn add<P: AsRef<Path>>(&mut self, file: P) -> &mut Self {
+        self.sources.push(NspSource::File(file.as_ref().to_owned()));
+        self
+    }
+    /// Adds `size` bytes at `offset` of the `package` as `name`, for eg. an entry of a XCI,
+    /// it's streamed straight from the package so it needn't be extracted first.
+    ///
+    /// The `package` can be split, see `SplitFile`.
+    pub fn add_entry<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        package: P,
+        offset: u64,
+        size: u64,
+    ) -> &mut Self {
+        self.sources.push(NspSource::Entry {
+            name: name.to_owned(),
+            package: package.as_ref().to_owned(),
+            offset,
+            size,
+        });
+        self
+    }
+    /// Names of the entries in the order they were added.
+    pub fn names(&self) -> Result<Vec<&str>> {
+        self.sources.iter().map(|source| source.name()).collect()
+    }
+    fn names_and_sizes(&self) -> Result<(Vec<&str>, Vec<u64>)> {
+        let mut names = vec![];
+        let mut sizes = vec![];
+        for source in &self.sources {
+            names.push(source.name()?);
+            sizes.push(source.size()?);
+        }
+        Ok((names, sizes))
+    }
+    /// Size of the NSP that'd be written.
+    pub fn size(&self) -> Result<u64> {
+        let (names, sizes) = self.names_and_sizes()?;
+        let header = Pfs0::build_header(names.iter().copied().zip(sizes.iter().copied()));
+        Ok(header.len() as u64 + sizes.iter().sum::<u64>())
+    }
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let (names, sizes) = self.names_and_sizes()?;
+        let header = Pfs0::build_header(names.iter().copied().zip(sizes.iter().copied()));
+        debug!(header_size = header.len(), sources = ?self.sources, "Writing PFS0");
+
+        writer.write_all(&header)?;
+        for ((source, name), size) in self.sources.iter().zip(&names).zip(sizes) {
+            debug!(file = %name, size, "Writing entry");
+            if io::copy(&mut source.open()?, writer)? != size {
+                bail!("'{}' was modified or truncated while being packed", name);
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    /// Writes the PFS0 to `nsp_path`, the file is overwritten if it exists.
+    ///
+    /// The returned `Nsp` has the size of what was written.
+    pub fn write<P: AsRef<Path>>(&self, nsp_path: P) -> Result<Nsp> {
+        let mut writer = io::BufWriter::new(fs::File::create(nsp_path.as_ref())?);
+        self.write_to(&mut writer)?;
+        drop(writer);
+
+        let nsp = Nsp::try_new(nsp_path.as_ref())?;
+        info!(
+            nsp = %nsp.path.display(),
+            size = %ByteSize(nsp.size),
+            "Packed NCAs to NSP"
+        );
+        Ok(nsp)
+    }
+    /// Same as `write`, except that a NSP larger than `part_size` is split into
+    /// `<name>.ns0`, `<name>.ns1`, ... parts of at most `part_size` bytes.
+    pub fn write_split<P: AsRef<Path>>(&self, nsp_path: P, part_size: u64) -> Result<Nsp> {
+        if self.size()? <= part_size {
+            return self.write(nsp_path);
+        }
+        let mut writer = SplitWriter::new(nsp_path.as_ref(), "ns", part_size)?;
+        self.write_to(&mut writer)?;
+        let parts = writer.finish()?;
+
+        let nsp = Nsp::try_new(&parts[0])?;
+        info!(
+            nsp = %nsp.path.display(),
+            parts = parts.len(),
+            size = %ByteSize(nsp.size),
+            "Packed NCAs to split NSP"
+        );
+        Ok(nsp)
+    }
+}
+
 impl Nsp {
+    /// NSZs are accepted as well, their NCZs are decompressed whenever they're extracted.
+    ///
+    /// So are split NSPs, i.e. the first `.ns0` part or a `.nsp` directory of `00`, `01`, ... parts.
     pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        if !path.as_ref().is_file() || !ext_matches(path.as_ref(), "nsp") {
+        let is_nsp = ["nsp", "nsz"]
+            .iter()
+            .any(|ext| ext_matches(path.as_ref(), ext));
+        let is_valid = if path.as_ref().is_dir() {
+            is_nsp
+        } else {
+            path.as_ref().is_file() && (is_nsp || is_first_part(path.as_ref(), "ns"))
+        };
+        if !is_valid {
             bail!("'{}' is not a NSP file", path.as_ref().display());
         }
 
         Ok(Self {
             path: path.as_ref().to_owned(),
+            size: SplitFile::open(path.as_ref())?.size(),
             ..Default::default()
         })
     }
-    pub fn unpack<P: AsRef<Path>>(&self, extractor: &Backend, to: P) -> Result<()> {
+    /// Reads the PFS0 header of the NSP, without extracting anything.
+    pub fn pfs0(&self) -> Result<Pfs0> {
+        Pfs0::read(&mut io::BufReader::new(SplitFile::open(&self.path)?))
+            .map_err(|err| eyre!("Failed to read '{}': {}", self.path.display(), err))
+    }
+    /// Returns a reader over the data of a single entry.
+    pub fn open_entry(&self, entry: &Pfs0Entry) -> Result<SubStorage<SplitFile>> {
+        Ok(SubStorage::new(
+            SplitFile::open(&self.path)?,
+            entry.offset,
+            entry.size,
+        ))
+    }
+    /// Streams a single entry out to the `to` dir, NCZs are extracted as NCAs.
+    pub fn extract_entry<P: AsRef<Path>>(&self, entry: &Pfs0Entry, to: P) -> Result<PathBuf> {
+        extract_entry_to(self.open_entry(entry)?, &entry.name, entry.size, to)
+            .map_err(|err| eyre!("Failed to extract from '{}': {}", self.path.display(), err))
+    }
+    pub fn unpack<P: AsRef<Path>>(&self, to: P) -> Result<()> {
         info!(nsp = %self.path.display(), "Unpacking NSP");
-        let mut cmd = Command::new(extractor.path());
-        cmd.args([
-            "-t".as_ref(),
-            "pfs0".as_ref(),
-            "--outdir".as_ref(),
-            to.as_ref(),
-            self.path.as_path(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        // Better to have it lossy since accuracy doesn't matter here,
-        // also it won't bail from the function anymore.
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            error!(
-                nsp = %self.path.display(),
-                backend = ?extractor.kind(),
-                %stderr,
-                "Encountered an error while unpacking NSP"
-            );
-            bail!("Failed to extract '{}'", self.path.display());
+        fs::create_dir_all(to.as_ref())?;
+        for entry in &self.pfs0()?.entries {
+            if let Err(err) = self.extract_entry(entry, to.as_ref()) {
+                error!(
+                    nsp = %self.path.display(),
+                    %err,
+                    "Encountered an error while unpacking NSP"
+                );
+                bail!("Failed to extract '{}'", self.path.display());
+            }
         }
 
         info!(nsp = %self.path.display(), to = %to.as_ref().display(), "Unpacked NSP");
         Ok(())
     }
-    pub fn pack<K, P, Q>(
-        packer: &Backend,
+    /// Packs the NCAs, Tickets and Certs in `nca_dir` to a NSP named `<program_id>.nsp`,
+    /// or `<program_id>.nsz` if any of them is a NCZ.
+    ///
+    /// It's split into parts of at most `part_size` bytes if given, see `NspWriter::write_split`.
+    pub fn pack<P, Q>(
         program_id: &str,
-        keyfile: K,
         nca_dir: P,
         outdir: Q,
+        part_size: Option<u64>,
     ) -> Result<Nsp>
     where
-        K: AsRef<Path>,
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
         info!(nca_dir = ?nca_dir.as_ref(), "Packing NCAs to NSP");
-        let mut cmd = Command::new(packer.path());
-        cmd.args([
-            "--keyset".as_ref(),
-            keyfile.as_ref(),
-            "--type".as_ref(),
-            "nsp".as_ref(),
-            "--ncadir".as_ref(),
-            nca_dir.as_ref(),
-            "--titleid".as_ref(),
-            program_id.as_ref(),
-            "--outdir".as_ref(),
-            outdir.as_ref(),
-        ])
-        .stderr(Stdio::piped());
-        let output = cmd.spawn()?.wait_with_output()?;
-        let stderr = filter_out_key_mismatches(&output.stderr);
-        eprint!("{}", stderr);
-        if !output.status.success() {
-            error!(
-                backend = ?packer.kind(),
-                code = ?output.status.code(),
-                %stderr,
-                "Encountered an error while packing NCAs to NSP"
-            );
-            bail!("Encountered an error while packing NCAs to NSP");
+        let writer = NspWriter::from_dir(nca_dir.as_ref())?;
+        let ext = if writer.names()?.iter().any(|name| ext_matches(name, "ncz")) {
+            "nsz"
+        } else {
+            "nsp"
+        };
+        let nsp_path = outdir.as_ref().join(format!("{}.{}", program_id, ext));
+        match part_size {
+            Some(part_size) => writer.write_split(nsp_path, part_size),
+            None => writer.write(nsp_path),
         }
-
-        let nsp_path = outdir.as_ref().join(format!("{}.nsp", program_id));
-        info!(
-            outdir = %outdir.as_ref().display(),
-            size = %get_fmt_size(&nsp_path).unwrap_or_default(),
-            "Packed NCAs to NSP"
-        );
-        Nsp::try_new(nsp_path)
     }
-    pub fn derive_title_key<P: AsRef<Path>>(&mut self, data_path: P) -> Result<()> {
+    /// Derives TitleKey from the first Ticket in the NSP, it's read in place.
+    pub fn derive_title_key(&mut self) -> Result<()> {
         if self.title_key.is_none() {
             info!(nsp = %self.path.display(), "Deriving TitleKey");
-            for entry in WalkDir::new(data_path.as_ref())
-                .min_depth(1)
-                .into_iter()
-                .filter_map(|e| e.ok())
-            {
-                if ext_matches(entry.path(), "tik") {
-                    self.title_key = Some(TitleKey::try_new(entry.path())?);
-                    break;
-                }
-            }
-            if self.title_key.is_none() {
-                bail!("Couldn't find a Ticket file in '{}'", self.path.display());
-            }
+            let pfs0 = self.pfs0()?;
+            let ticket = pfs0
+                .entries_with_ext("tik")
+                .next()
+                .ok_or_else(|| eyre!("Couldn't find a Ticket file in '{}'", self.path.display()))?;
+            debug!(tik = %ticket.name, "Found Ticket");
+            self.title_key = Some(TitleKey::from_reader(&mut self.open_entry(ticket)?)?);
             info!("Derived TitleKey");
         } else {
             info!(
@@ -135,3 +412,91 @@ impl Nsp {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_pfs0() -> Result<()> {
+        let mut pfs0 = b"PFS0".to_vec();
+        pfs0.extend(2u32.to_le_bytes());
+        pfs0.extend(5u32.to_le_bytes());
+        pfs0.extend(0u32.to_le_bytes());
+        for (offset, size, name_offset) in [(0u64, 3u64, 0u32), (3, 2, 2)] {
+            pfs0.extend(offset.to_le_bytes());
+            pfs0.extend(size.to_le_bytes());
+            pfs0.extend(name_offset.to_le_bytes());
+            pfs0.extend(0u32.to_le_bytes());
+        }
+        pfs0.extend(b"a\0bc\0");
+        pfs0.extend(b"xyzuv");
+
+        let parsed = Pfs0::read(&mut Cursor::new(&pfs0))?;
+        assert_eq!(
+            parsed.entries,
+            [
+                Pfs0Entry {
+                    name: "a".into(),
+                    offset: 0x45,
+                    size: 3
+                },
+                Pfs0Entry {
+                    name: "bc".into(),
+                    offset: 0x48,
+                    size: 2
+                },
+            ]
+        );
+        let entry = parsed.entry("bc").unwrap();
+        assert_eq!(
+            &pfs0[entry.offset as usize..(entry.offset + entry.size) as usize],
+            b"uv"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_pfs0_rejects_bogus_entry_count() {
+        let mut pfs0 = b"PFS0".to_vec();
+        pfs0.extend(u32::MAX.to_le_bytes());
+        pfs0.extend(u32::MAX.to_le_bytes());
+        pfs0.extend(0u32.to_le_bytes());
+        pfs0.extend([0; 0x18]);
+        assert!(Pfs0::read(&mut Cursor::new(&pfs0)).is_err());
+    }
+
+    #[test]
+    fn nsp_writer_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files = [
+            ("b.nca", vec![0xBB; 0x123]),
+            ("a.nca", vec![0xAA; 0x10]),
+            ("0100.tik", vec![0x77; 0x2C0]),
+            ("0100.cert", vec![0x55; 0x700]),
+        ];
+        for (name, data) in &files {
+            fs::write(dir.path().join(name), data)?;
+        }
+        fs::write(dir.path().join("ignored.txt"), "not packed")?;
+
+        let writer = NspWriter::from_dir(dir.path())?;
+        assert_eq!(writer.names()?, ["a.nca", "b.nca", "0100.tik", "0100.cert"]);
+        let outdir = tempfile::tempdir()?;
+        let nsp = writer.write(outdir.path().join("out.nsp"))?;
+        assert_eq!(nsp.size, writer.size()?);
+        assert_eq!(nsp.size, fs::metadata(&nsp.path)?.len());
+
+        let pfs0 = nsp.pfs0()?;
+        assert_eq!(pfs0.entries.len(), files.len());
+        assert_eq!(pfs0.entries[0].offset % Pfs0::HEADER_ALIGNMENT as u64, 0);
+        for (name, data) in &files {
+            let mut read = vec![];
+            nsp.open_entry(pfs0.entry(name).unwrap())?
+                .read_to_end(&mut read)?;
+            assert_eq!(&read, data);
+        }
+        Ok(())
+    }
+}
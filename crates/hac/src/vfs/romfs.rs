@@ -0,0 +1,763 @@
+use crate::vfs::{
+    align_up,
+    ivfc::{Ivfc, IvfcWriter},
+    read_u32_le, read_u64_le, SubStorage,
+};
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info};
+
+/// https://switchbrew.org/wiki/RomFS#RomFS_Header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RomFsHeader {
+    pub dir_hash_table_offset: u64,
+    pub dir_hash_table_size: u64,
+    pub dir_meta_table_offset: u64,
+    pub dir_meta_table_size: u64,
+    pub file_hash_table_offset: u64,
+    pub file_hash_table_size: u64,
+    pub file_meta_table_offset: u64,
+    pub file_meta_table_size: u64,
+    pub file_data_offset: u64,
+}
+
+impl RomFsHeader {
+    pub const SIZE: usize = 0x50;
+
+    /// Offset of the file data, the header is padded up to it.
+    const FILE_DATA_OFFSET: u64 = 0x200;
+
+    fn parse(buf: &[u8]) -> Result<Self> {
+        let header_size = read_u64_le(buf, 0x0);
+        if header_size != RomFsHeader::SIZE as u64 {
+            bail!("Invalid RomFS header size {:#x}", header_size);
+        }
+        Ok(Self {
+            dir_hash_table_offset: read_u64_le(buf, 0x8),
+            dir_hash_table_size: read_u64_le(buf, 0x10),
+            dir_meta_table_offset: read_u64_le(buf, 0x18),
+            dir_meta_table_size: read_u64_le(buf, 0x20),
+            file_hash_table_offset: read_u64_le(buf, 0x28),
+            file_hash_table_size: read_u64_le(buf, 0x30),
+            file_meta_table_offset: read_u64_le(buf, 0x38),
+            file_meta_table_size: read_u64_le(buf, 0x40),
+            file_data_offset: read_u64_le(buf, 0x48),
+        })
+    }
+    fn to_bytes(self) -> [u8; RomFsHeader::SIZE] {
+        let mut buf = [0u8; RomFsHeader::SIZE];
+        for (index, value) in [
+            RomFsHeader::SIZE as u64,
+            self.dir_hash_table_offset,
+            self.dir_hash_table_size,
+            self.dir_meta_table_offset,
+            self.dir_meta_table_size,
+            self.file_hash_table_offset,
+            self.file_hash_table_size,
+            self.file_meta_table_offset,
+            self.file_meta_table_size,
+            self.file_data_offset,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            buf[index * 0x8..index * 0x8 + 0x8].copy_from_slice(&value.to_le_bytes());
+        }
+        buf
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomFsFile {
+    /// Path from the root, separated by `/` and without a leading one.
+    pub path: String,
+    /// Offset of the file data, relative to the start of the RomFS.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// https://switchbrew.org/wiki/RomFS
+///
+/// Level 3 of the IVFC, i.e. the actual file system. The tables are read up front
+/// while the file data is only read on demand.
+#[derive(Debug)]
+pub struct RomFs<R> {
+    reader: R,
+    pub header: RomFsHeader,
+    /// Paths of every directory except the root, including the empty ones.
+    pub dirs: Vec<String>,
+    pub files: Vec<RomFsFile>,
+}
+
+impl RomFs<fs::File> {
+    /// Opens an extracted `.romfs` image.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        RomFs::new(fs::File::open(path.as_ref())?)
+            .map_err(|err| eyre!("Failed to read '{}': {}", path.as_ref().display(), err))
+    }
+}
+
+const EMPTY_ENTRY: u32 = 0xFFFF_FFFF;
+const DIR_ENTRY_SIZE: usize = 0x18;
+const FILE_ENTRY_SIZE: usize = 0x20;
+
+impl<R: Read + Seek> RomFs<R> {
+    /// Reads the RomFS tables, `reader` must start at the RomFS header.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut buf = [0u8; RomFsHeader::SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut buf)?;
+        let header = RomFsHeader::parse(&buf)?;
+        debug!(?header, "Reading RomFS");
+
+        let dir_table = read_table(
+            &mut reader,
+            header.dir_meta_table_offset,
+            header.dir_meta_table_size,
+        )?;
+        let file_table = read_table(
+            &mut reader,
+            header.file_meta_table_offset,
+            header.file_meta_table_size,
+        )?;
+
+        let mut files = vec![];
+        let mut dir_paths = vec![];
+        // (dir offset, path) pairs, starting from the root dir
+        let mut dirs = vec![(0u32, String::new())];
+        // Guards against malformed tables with cycles
+        let mut visited = 0usize;
+        while let Some((dir_offset, dir_path)) = dirs.pop() {
+            visited += 1;
+            if visited > dir_table.len() / DIR_ENTRY_SIZE + 1 {
+                bail!("RomFS directory table has a cycle");
+            }
+            let dir = entry_at(&dir_table, dir_offset, DIR_ENTRY_SIZE)?;
+
+            let mut child_dir = read_u32_le(dir, 0x8);
+            while child_dir != EMPTY_ENTRY {
+                let entry = entry_at(&dir_table, child_dir, DIR_ENTRY_SIZE)?;
+                let name = entry_name(entry, DIR_ENTRY_SIZE)?;
+                let path = join_path(&dir_path, &name);
+                dir_paths.push(path.clone());
+                dirs.push((child_dir, path));
+                child_dir = read_u32_le(entry, 0x4);
+            }
+
+            let mut child_file = read_u32_le(dir, 0xC);
+            while child_file != EMPTY_ENTRY {
+                if files.len() > file_table.len() / FILE_ENTRY_SIZE {
+                    bail!("RomFS file table has a cycle");
+                }
+                let entry = entry_at(&file_table, child_file, FILE_ENTRY_SIZE)?;
+                let name = entry_name(entry, FILE_ENTRY_SIZE)?;
+                files.push(RomFsFile {
+                    path: join_path(&dir_path, &name),
+                    offset: header.file_data_offset + read_u64_le(entry, 0x8),
+                    size: read_u64_le(entry, 0x10),
+                });
+                child_file = read_u32_le(entry, 0x4);
+            }
+        }
+        dir_paths.sort();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        debug!(
+            dirs = dir_paths.len(),
+            files = files.len(),
+            "Read RomFS tables"
+        );
+
+        Ok(Self {
+            reader,
+            header,
+            dirs: dir_paths,
+            files,
+        })
+    }
+    pub fn file(&self, path: &str) -> Option<&RomFsFile> {
+        let path = path.trim_start_matches('/');
+        self.files.iter().find(|file| file.path == path)
+    }
+    /// Files whose path matches the glob `pattern`, for eg. `*.nacp` or `data/**/*.bin`.
+    pub fn files_matching(&self, pattern: &str) -> Result<Vec<&RomFsFile>> {
+        let pattern = glob::Pattern::new(pattern.trim_start_matches('/'))
+            .map_err(|err| eyre!("Invalid pattern '{}': {}", pattern, err))?;
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        Ok(self
+            .files
+            .iter()
+            .filter(|file| pattern.matches_with(&file.path, options))
+            .collect())
+    }
+    /// Returns a reader over the data of a single file.
+    pub fn open_file(&mut self, file: &RomFsFile) -> SubStorage<&mut R> {
+        SubStorage::new(&mut self.reader, file.offset, file.size)
+    }
+    /// Reads a whole file to memory, meant for small ones like `control.nacp`.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let file = self
+            .file(path)
+            .cloned()
+            .ok_or_else(|| eyre!("Couldn't find '{}' in RomFS", path))?;
+        let mut buf = vec![0u8; file.size as usize];
+        self.open_file(&file).read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    /// Extracts a single file to `to`, keeping its path relative to the RomFS root.
+    pub fn extract_file<P: AsRef<Path>>(&mut self, file: &RomFsFile, to: P) -> Result<PathBuf> {
+        if file
+            .path
+            .split('/')
+            .any(|part| part == ".." || part.is_empty())
+        {
+            bail!("Invalid RomFS path '{}'", file.path);
+        }
+        let dest = to.as_ref().join(&file.path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        debug!(file = %file.path, size = file.size, to = %dest.display(), "Extracting");
+
+        let mut reader = io::BufReader::new(self.open_file(file));
+        let mut writer = io::BufWriter::new(fs::File::create(&dest)?);
+        if io::copy(&mut reader, &mut writer)? != file.size {
+            bail!("RomFS file '{}' is truncated", file.path);
+        }
+        writer.flush()?;
+        Ok(dest)
+    }
+    /// Extracts the files matching the glob `pattern` to `to`, see `files_matching`.
+    pub fn extract_matching<P: AsRef<Path>>(
+        &mut self,
+        pattern: &str,
+        to: P,
+    ) -> Result<Vec<PathBuf>> {
+        let files = self
+            .files_matching(pattern)?
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if files.is_empty() {
+            bail!("No file in RomFS matches '{}'", pattern);
+        }
+        let extracted = files
+            .iter()
+            .map(|file| self.extract_file(file, to.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        info!(
+            pattern,
+            files = extracted.len(),
+            to = %to.as_ref().display(),
+            "Extracted from RomFS"
+        );
+        Ok(extracted)
+    }
+    /// Extracts every file to `to`.
+    pub fn extract_all<P: AsRef<Path>>(&mut self, to: P) -> Result<()> {
+        fs::create_dir_all(to.as_ref())?;
+        for dir in &self.dirs {
+            if dir.split('/').any(|part| part == ".." || part.is_empty()) {
+                bail!("Invalid RomFS path '{}'", dir);
+            }
+            fs::create_dir_all(to.as_ref().join(dir))?;
+        }
+        for file in self.files.clone() {
+            self.extract_file(&file, to.as_ref())?;
+        }
+        info!(
+            files = self.files.len(),
+            to = %to.as_ref().display(),
+            "Extracted RomFS"
+        );
+        Ok(())
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[derive(Debug)]
+struct BuilderDir {
+    source: PathBuf,
+    name: String,
+    parent: usize,
+    dirs: Vec<usize>,
+    files: Vec<usize>,
+    entry_offset: u32,
+}
+
+#[derive(Debug)]
+struct BuilderFile {
+    source: PathBuf,
+    name: String,
+    parent: usize,
+    size: u64,
+    /// Relative to the start of the file data.
+    data_offset: u64,
+    entry_offset: u32,
+}
+
+/// Builds a RomFS image out of a directory, it can be read back with `RomFs`.
+///
+/// Only the tables are kept in memory, the file data is streamed from the disk
+/// while writing.
+#[derive(Debug)]
+pub struct RomFsBuilder {
+    header: RomFsHeader,
+    files: Vec<BuilderFile>,
+    dir_hash_table: Vec<u8>,
+    dir_table: Vec<u8>,
+    file_hash_table: Vec<u8>,
+    file_table: Vec<u8>,
+}
+
+impl RomFsBuilder {
+    const FILE_ALIGNMENT: u64 = 0x10;
+
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let mut dirs = vec![BuilderDir {
+            source: root.as_ref().to_owned(),
+            name: String::new(),
+            parent: 0,
+            dirs: vec![],
+            files: vec![],
+            entry_offset: 0,
+        }];
+        let mut files = vec![];
+
+        // Breadth first, so that the files of a dir are next to each other
+        let mut index = 0;
+        while index < dirs.len() {
+            let mut entries = fs::read_dir(&dirs[index].source)?
+                .map(|entry| {
+                    let entry = entry?;
+                    let name = entry.file_name().into_string().map_err(|name| {
+                        eyre!("'{}' isn't a valid UTF-8 name", name.to_string_lossy())
+                    })?;
+                    Ok((name, entry.path()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            entries.sort();
+            for (name, source) in entries {
+                // Follows symlinks
+                let metadata = fs::metadata(&source)?;
+                if metadata.is_dir() {
+                    let child = dirs.len();
+                    dirs[index].dirs.push(child);
+                    dirs.push(BuilderDir {
+                        source,
+                        name,
+                        parent: index,
+                        dirs: vec![],
+                        files: vec![],
+                        entry_offset: 0,
+                    });
+                } else {
+                    dirs[index].files.push(files.len());
+                    files.push(BuilderFile {
+                        source,
+                        name,
+                        parent: index,
+                        size: metadata.len(),
+                        data_offset: 0,
+                        entry_offset: 0,
+                    });
+                }
+            }
+            index += 1;
+        }
+
+        let mut offset = 0u32;
+        for dir in dirs.iter_mut() {
+            dir.entry_offset = offset;
+            offset += entry_size(DIR_ENTRY_SIZE, &dir.name)?;
+        }
+        let mut offset = 0u32;
+        let mut data_size = 0u64;
+        for file in files.iter_mut() {
+            file.entry_offset = offset;
+            offset += entry_size(FILE_ENTRY_SIZE, &file.name)?;
+            file.data_offset = align_up(data_size, RomFsBuilder::FILE_ALIGNMENT);
+            data_size = file.data_offset + file.size;
+        }
+
+        let dir_offset =
+            |index: Option<&usize>| index.map_or(EMPTY_ENTRY, |index| dirs[*index].entry_offset);
+        let file_offset =
+            |index: Option<&usize>| index.map_or(EMPTY_ENTRY, |index| files[*index].entry_offset);
+        // Offset of the next entry in the same dir
+        let mut dir_siblings = vec![EMPTY_ENTRY; dirs.len()];
+        let mut file_siblings = vec![EMPTY_ENTRY; files.len()];
+        for dir in &dirs {
+            for pair in dir.dirs.windows(2) {
+                dir_siblings[pair[0]] = dirs[pair[1]].entry_offset;
+            }
+            for pair in dir.files.windows(2) {
+                file_siblings[pair[0]] = files[pair[1]].entry_offset;
+            }
+        }
+
+        let mut dir_hash_table = vec![EMPTY_ENTRY; hash_table_len(dirs.len())];
+        let mut dir_table = vec![];
+        for (index, dir) in dirs.iter().enumerate() {
+            let parent = &dirs[dir.parent];
+            let hash_sibling = insert_hash(
+                &mut dir_hash_table,
+                path_hash(parent.entry_offset, &dir.name),
+                dir.entry_offset,
+            );
+            dir_table.extend(parent.entry_offset.to_le_bytes());
+            dir_table.extend(dir_siblings[index].to_le_bytes());
+            dir_table.extend(dir_offset(dir.dirs.first()).to_le_bytes());
+            dir_table.extend(file_offset(dir.files.first()).to_le_bytes());
+            push_entry_name(&mut dir_table, hash_sibling, &dir.name);
+        }
+
+        let mut file_hash_table = vec![EMPTY_ENTRY; hash_table_len(files.len())];
+        let mut file_table = vec![];
+        for (index, file) in files.iter().enumerate() {
+            let parent = &dirs[file.parent];
+            let hash_sibling = insert_hash(
+                &mut file_hash_table,
+                path_hash(parent.entry_offset, &file.name),
+                file.entry_offset,
+            );
+            file_table.extend(parent.entry_offset.to_le_bytes());
+            file_table.extend(file_siblings[index].to_le_bytes());
+            file_table.extend(file.data_offset.to_le_bytes());
+            file_table.extend(file.size.to_le_bytes());
+            push_entry_name(&mut file_table, hash_sibling, &file.name);
+        }
+
+        let to_bytes = |table: Vec<u32>| {
+            table
+                .into_iter()
+                .flat_map(u32::to_le_bytes)
+                .collect::<Vec<u8>>()
+        };
+        let dir_hash_table = to_bytes(dir_hash_table);
+        let file_hash_table = to_bytes(file_hash_table);
+
+        let dir_hash_table_offset = align_up(RomFsHeader::FILE_DATA_OFFSET + data_size, 4);
+        let dir_meta_table_offset = dir_hash_table_offset + dir_hash_table.len() as u64;
+        let file_hash_table_offset = dir_meta_table_offset + dir_table.len() as u64;
+        let file_meta_table_offset = file_hash_table_offset + file_hash_table.len() as u64;
+        let header = RomFsHeader {
+            dir_hash_table_offset,
+            dir_hash_table_size: dir_hash_table.len() as _,
+            dir_meta_table_offset,
+            dir_meta_table_size: dir_table.len() as _,
+            file_hash_table_offset,
+            file_hash_table_size: file_hash_table.len() as _,
+            file_meta_table_offset,
+            file_meta_table_size: file_table.len() as _,
+            file_data_offset: RomFsHeader::FILE_DATA_OFFSET,
+        };
+        debug!(
+            ?header,
+            dirs = dirs.len(),
+            files = files.len(),
+            "Built RomFS tables"
+        );
+
+        Ok(Self {
+            header,
+            files,
+            dir_hash_table,
+            dir_table,
+            file_hash_table,
+            file_table,
+        })
+    }
+    /// Size of the RomFS image.
+    pub fn size(&self) -> u64 {
+        self.header.file_meta_table_offset + self.header.file_meta_table_size
+    }
+    /// Writes the RomFS image, returns the no. of bytes written.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<u64> {
+        let mut pos = 0u64;
+        let pad_to = |writer: &mut W, pos: &mut u64, to: u64| -> Result<()> {
+            io::copy(&mut io::repeat(0).take(to - *pos), writer)?;
+            *pos = to;
+            Ok(())
+        };
+
+        writer.write_all(&self.header.to_bytes())?;
+        pos += RomFsHeader::SIZE as u64;
+        for file in &self.files {
+            pad_to(
+                writer,
+                &mut pos,
+                self.header.file_data_offset + file.data_offset,
+            )?;
+            let mut reader = io::BufReader::new(fs::File::open(&file.source)?);
+            if io::copy(&mut (&mut reader).take(file.size), writer)? != file.size
+                || reader.read(&mut [0u8])? != 0
+            {
+                bail!("'{}' changed while building RomFS", file.source.display());
+            }
+            pos += file.size;
+        }
+        pad_to(writer, &mut pos, self.header.dir_hash_table_offset)?;
+        for table in [
+            &self.dir_hash_table,
+            &self.dir_table,
+            &self.file_hash_table,
+            &self.file_table,
+        ] {
+            writer.write_all(table)?;
+            pos += table.len() as u64;
+        }
+        debug_assert_eq!(pos, self.size());
+        Ok(pos)
+    }
+    /// Writes the RomFS image to `path`.
+    pub fn build<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = io::BufWriter::new(fs::File::create(path.as_ref())?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        info!(romfs = %path.as_ref().display(), size = self.size(), "Built RomFS");
+        Ok(())
+    }
+    /// Writes the RomFS image after its IVFC hash levels, i.e. as the data of a NCA section.
+    ///
+    /// `writer` must be at the start of the section,
+    /// the returned IVFC header goes in the section's FsHeader.
+    pub fn write_ivfc<W: Write + Seek>(&self, writer: W) -> Result<Ivfc> {
+        let mut ivfc_writer = IvfcWriter::new(writer, self.size())?;
+        self.write(&mut ivfc_writer)?;
+        let (ivfc, _) = ivfc_writer.finish()?;
+        debug!(?ivfc, "Wrote RomFS with IVFC");
+        Ok(ivfc)
+    }
+}
+
+fn read_table<R: Read + Seek>(reader: &mut R, offset: u64, size: u64) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut table = vec![];
+    if reader.take(size).read_to_end(&mut table)? as u64 != size {
+        bail!("RomFS table at {:#x} is truncated", offset);
+    }
+    Ok(table)
+}
+
+fn entry_at(table: &[u8], offset: u32, header_size: usize) -> Result<&[u8]> {
+    table
+        .get(offset as usize..)
+        .filter(|entry| entry.len() >= header_size)
+        .ok_or_else(|| eyre!("RomFS entry offset {:#x} is out of bounds", offset))
+}
+
+fn entry_name(entry: &[u8], header_size: usize) -> Result<String> {
+    let name_len = read_u32_le(entry, header_size - 4) as usize;
+    let name = entry
+        .get(header_size..header_size + name_len)
+        .ok_or_else(|| eyre!("RomFS entry name is out of bounds"))?;
+    let name = String::from_utf8_lossy(name).into_owned();
+    if name.contains('/') {
+        bail!("Invalid RomFS entry name '{}'", name);
+    }
+    Ok(name)
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn entry_size(header_size: usize, name: &str) -> Result<u32> {
+    u32::try_from(header_size as u64 + align_up(name.len() as u64, 4))
+        .map_err(|_| eyre!("RomFS entry name '{}' is too long", name))
+}
+
+fn push_entry_name(table: &mut Vec<u8>, hash_sibling: u32, name: &str) {
+    table.extend(hash_sibling.to_le_bytes());
+    table.extend((name.len() as u32).to_le_bytes());
+    table.extend(name.as_bytes());
+    table.resize(align_up(table.len() as u64, 4) as usize, 0);
+}
+
+/// Adds an entry to its bucket, returns the entry it was chained to.
+fn insert_hash(table: &mut [u32], hash: u32, entry_offset: u32) -> u32 {
+    let bucket = hash as usize % table.len();
+    std::mem::replace(&mut table[bucket], entry_offset)
+}
+
+/// https://switchbrew.org/wiki/RomFS#Hash_Table
+fn path_hash(parent_offset: u32, name: &str) -> u32 {
+    name.bytes().fold(parent_offset ^ 123456789, |hash, byte| {
+        hash.rotate_right(5) ^ byte as u32
+    })
+}
+
+/// Bucket count used by Nintendo's tools, a no. that's coprime to the small primes.
+fn hash_table_len(entries: usize) -> usize {
+    if entries < 3 {
+        3
+    } else if entries < 19 {
+        entries | 1
+    } else {
+        let mut count = entries;
+        while [2, 3, 5, 7, 11, 13, 17]
+            .iter()
+            .any(|prime| count % prime == 0)
+        {
+            count += 1;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::ivfc::IvfcLevel;
+    use sha2::{Digest, Sha256};
+    use std::io::Cursor;
+
+    fn level_data(image: &[u8], level: IvfcLevel) -> &[u8] {
+        &image[level.offset as usize..(level.offset + level.size) as usize]
+    }
+
+    fn block_hashes(data: &[u8]) -> Vec<u8> {
+        data.chunks(Ivfc::BLOCK_SIZE as usize)
+            .flat_map(|block| {
+                let mut block = block.to_vec();
+                block.resize(Ivfc::BLOCK_SIZE as usize, 0);
+                Sha256::digest(block)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builder_round_trip() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        fs::create_dir_all(root.path().join("data/sub"))?;
+        fs::create_dir(root.path().join("empty"))?;
+        let files = [
+            ("a.bin", vec![0xAA; 5]),
+            ("data/b.txt", b"hello".to_vec()),
+            ("data/empty.bin", vec![]),
+            // Spans a few IVFC blocks
+            (
+                "data/sub/ünicode.dat",
+                (0..0x9000u32).map(|i| i as u8).collect(),
+            ),
+        ];
+        for (path, data) in &files {
+            fs::write(root.path().join(path), data)?;
+        }
+
+        let builder = RomFsBuilder::new(root.path())?;
+        let mut image = Cursor::new(vec![]);
+        let ivfc = builder.write_ivfc(&mut image)?;
+        let image = image.into_inner();
+        assert_eq!(image.len() as u64, ivfc.size());
+        assert_eq!(ivfc.data_level().size, builder.size());
+
+        // Each level holds the hashes of the blocks of the next one
+        assert_eq!(
+            block_hashes(level_data(&image, ivfc.levels[0])),
+            ivfc.master_hash
+        );
+        for pair in ivfc.levels.windows(2) {
+            assert_eq!(
+                block_hashes(level_data(&image, pair[1])),
+                level_data(&image, pair[0])
+            );
+        }
+
+        let mut romfs = RomFs::new(Cursor::new(level_data(&image, ivfc.data_level())))?;
+        assert_eq!(romfs.dirs, ["data", "data/sub", "empty"]);
+        assert_eq!(romfs.files.len(), files.len());
+        for (path, data) in &files {
+            assert_eq!(&romfs.read_file(path)?, data);
+        }
+        Ok(())
+    }
+
+    /// Built from a dir with `files`, along with the dir to extract to.
+    fn build_romfs(files: &[(&str, &[u8])]) -> Result<(RomFs<Cursor<Vec<u8>>>, tempfile::TempDir)> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("root");
+        for (path, data) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, data)?;
+        }
+        let mut image = vec![];
+        RomFsBuilder::new(&root)?.write(&mut image)?;
+        Ok((RomFs::new(Cursor::new(image))?, dir))
+    }
+
+    #[test]
+    fn files_matching() -> Result<()> {
+        let (romfs, _dir) = build_romfs(&[
+            ("control.nacp", b"nacp"),
+            ("icon_Japanese.dat", b"icon"),
+            ("data/a.bin", b"a"),
+            ("data/sub/b.bin", b"b"),
+        ])?;
+        let matching = |pattern| -> Result<Vec<&str>> {
+            Ok(romfs
+                .files_matching(pattern)?
+                .into_iter()
+                .map(|file| file.path.as_str())
+                .collect())
+        };
+
+        assert_eq!(matching("*.nacp")?, ["control.nacp"]);
+        assert_eq!(matching("/icon_*.dat")?, ["icon_Japanese.dat"]);
+        // `*` doesn't cross directories while `**` does
+        assert!(matching("*.bin")?.is_empty());
+        assert_eq!(matching("data/*.bin")?, ["data/a.bin"]);
+        assert_eq!(matching("data/**/*.bin")?, ["data/a.bin", "data/sub/b.bin"]);
+        assert_eq!(matching("**/*")?.len(), 4);
+        assert!(romfs.files_matching("data/[").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn extract_files() -> Result<()> {
+        let (mut romfs, dir) = build_romfs(&[
+            ("control.nacp", b"nacp"),
+            ("data/a.bin", b"a"),
+            ("data/sub/b.bin", b"b"),
+        ])?;
+        let to = dir.path().join("out");
+
+        let file = romfs.file("/data/sub/b.bin").cloned().unwrap();
+        let extracted = romfs.extract_file(&file, &to)?;
+        assert_eq!(extracted, to.join("data/sub/b.bin"));
+        assert_eq!(fs::read(&extracted)?, b"b");
+        assert!(!to.join("data/a.bin").exists());
+
+        let extracted = romfs.extract_matching("data/**/*.bin", &to)?;
+        assert_eq!(
+            extracted,
+            [to.join("data/a.bin"), to.join("data/sub/b.bin")]
+        );
+        assert_eq!(fs::read(to.join("data/a.bin"))?, b"a");
+        assert!(!to.join("control.nacp").exists());
+        assert!(romfs.extract_matching("*.dat", &to).is_err());
+
+        // Paths of a malformed RomFS must stay inside `to`
+        let escaping = RomFsFile {
+            path: "../escaped.bin".into(),
+            ..file
+        };
+        assert!(romfs.extract_file(&escaping, &to).is_err());
+        assert!(!dir.path().join("escaped.bin").exists());
+        Ok(())
+    }
+}
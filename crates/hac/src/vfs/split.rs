@@ -0,0 +1,331 @@
+use common::utils::{ext_matches, move_file};
+use eyre::{bail, eyre, Result};
+use fs_err as fs;
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+/// Largest file FAT32 can hold, i.e. 4GiB - 1.
+pub const FAT32_PART_SIZE: u64 = 0xFFFF_FFFF;
+
+/// Whether `path` is the first part of a split package with the given extension prefix,
+/// i.e. `<name>.ns0` for `ns` or `<name>.xc0` for `xc`.
+pub fn is_first_part<P: AsRef<Path>>(path: P, prefix: &str) -> bool {
+    ext_matches(path, &format!("{prefix}0"))
+}
+
+/// Parts of a package in order, the package can be one of:
+/// - A directory with the parts named `00`, `01`, ..., i.e. the archive bit layout.
+/// - The first part of a `<name>.ns0`, `<name>.ns1`, ... split, same goes for `xc`.
+/// - A regular file, which is the only part then.
+pub fn split_parts<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let mut parts = vec![];
+    if path.is_dir() {
+        for index in 0.. {
+            let part = path.join(format!("{:02}", index));
+            if !part.is_file() {
+                break;
+            }
+            parts.push(part);
+        }
+        if parts.is_empty() {
+            bail!("'{}' has no '00' part", path.display());
+        }
+    } else if let Some(prefix) = ["ns", "xc"]
+        .into_iter()
+        .find(|prefix| is_first_part(path, prefix))
+    {
+        for index in 0.. {
+            let part = path.with_extension(format!("{prefix}{index}"));
+            if !part.is_file() {
+                break;
+            }
+            parts.push(part);
+        }
+    } else {
+        parts.push(path.to_owned());
+    }
+    Ok(parts)
+}
+
+/// Size of the package at `path`, i.e. of all its parts, see `split_parts`.
+pub fn package_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let mut size = 0;
+    for part in split_parts(path)? {
+        size += part.metadata()?.len();
+    }
+    Ok(size)
+}
+
+/// A package split into several parts, read as if it were a single file.
+#[derive(Debug)]
+pub struct SplitFile {
+    /// Parts along with where they start in the package.
+    parts: Vec<(fs::File, u64)>,
+    size: u64,
+    pos: u64,
+}
+
+impl SplitFile {
+    /// Opens the parts of the package at `path`, see `split_parts`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut parts = vec![];
+        let mut size = 0;
+        for part in split_parts(path.as_ref())? {
+            let file = fs::File::open(&part)?;
+            let part_size = file.metadata()?.len();
+            parts.push((file, size));
+            size += part_size;
+        }
+        if parts.len() > 1 {
+            debug!(package = %path.as_ref().display(), parts = parts.len(), size, "Opened split package");
+        }
+        Ok(Self {
+            parts,
+            size,
+            pos: 0,
+        })
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for SplitFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size {
+            return Ok(0);
+        }
+        let index = self.parts.partition_point(|(_, start)| *start <= self.pos) - 1;
+        let part_end = self
+            .parts
+            .get(index + 1)
+            .map_or(self.size, |(_, start)| *start);
+        let (file, start) = &mut self.parts[index];
+        let len = buf.len().min((part_end - self.pos) as usize);
+        file.seek(SeekFrom::Start(self.pos - *start))?;
+        let read = file.read(&mut buf[..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SplitFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match new_pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Writes a package as `<name>.<prefix>0`, `<name>.<prefix>1`, ... with each part
+/// being at most `part_size` bytes.
+#[derive(Debug)]
+pub struct SplitWriter {
+    path: PathBuf,
+    prefix: &'static str,
+    part_size: u64,
+    parts: Vec<PathBuf>,
+    current: Option<io::BufWriter<fs::File>>,
+    written: u64,
+}
+
+impl SplitWriter {
+    pub fn new<P: AsRef<Path>>(path: P, prefix: &'static str, part_size: u64) -> Result<Self> {
+        if part_size == 0 {
+            bail!("Part size must be larger than 0");
+        }
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            prefix,
+            part_size,
+            parts: vec![],
+            current: None,
+            written: 0,
+        })
+    }
+    fn next_part(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.current.take() {
+            writer.flush()?;
+        }
+        let part = self
+            .path
+            .with_extension(format!("{}{}", self.prefix, self.parts.len()));
+        debug!(part = %part.display(), "Writing part");
+        self.current = Some(io::BufWriter::new(fs::File::create(&part)?));
+        self.parts.push(part);
+        self.written = 0;
+        Ok(())
+    }
+    /// Flushes the last part and returns the paths of all the parts.
+    pub fn finish(mut self) -> Result<Vec<PathBuf>> {
+        match self.current.take() {
+            Some(mut writer) => writer.flush()?,
+            None => self.next_part()?,
+        }
+        Ok(self.parts)
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.is_none() || self.written >= self.part_size {
+            self.next_part()?;
+        }
+        let len = buf.len().min((self.part_size - self.written) as usize);
+        let written = self
+            .current
+            .as_mut()
+            .expect("Part should've been created")
+            .write(&buf[..len])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Moves every part of the package at `from` so that the first one ends up at `to`,
+/// the rest keep their own extension, i.e. `.ns1`, `.ns2`, ...
+pub(crate) fn move_parts<P, Q>(from: P, to: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    if from.as_ref().is_dir() {
+        bail!(
+            "Moving split directories isn't supported, '{}'",
+            from.as_ref().display()
+        );
+    }
+    for (index, part) in split_parts(from.as_ref())?.iter().enumerate() {
+        let dest = match index {
+            0 => to.as_ref().to_owned(),
+            _ => to.as_ref().with_extension(
+                part.extension()
+                    .ok_or_else(|| eyre!("'{}' has no extension", part.display()))?,
+            ),
+        };
+        move_file(part, dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        (0..0x2800).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn split_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("package.nsp");
+        let mut writer = SplitWriter::new(&path, "ns", 0x1000)?;
+        writer.write_all(&data())?;
+        let parts = writer.finish()?;
+        assert_eq!(
+            parts,
+            ["ns0", "ns1", "ns2"].map(|ext| path.with_extension(ext))
+        );
+        assert_eq!(parts[2].metadata()?.len(), 0x800);
+
+        assert!(is_first_part(&parts[0], "ns"));
+        assert!(!is_first_part(&parts[1], "ns"));
+        assert_eq!(split_parts(&parts[0])?, parts);
+        assert_eq!(package_size(&parts[0])?, 0x2800);
+
+        let mut file = SplitFile::open(&parts[0])?;
+        assert_eq!(file.size(), 0x2800);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        assert_eq!(buf, data());
+
+        // Reading across the parts
+        file.seek(SeekFrom::End(-0x1810))?;
+        let mut buf = [0u8; 0x20];
+        file.read_exact(&mut buf)?;
+        assert_eq!(buf, data()[0xFF0..0x1010]);
+        assert!(file.seek(SeekFrom::Current(-0x2000)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn split_dir_parts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let package = dir.path().join("package.nsp");
+        assert!(split_parts(dir.path()).is_err());
+
+        fs::create_dir(&package)?;
+        for (name, chunk) in ["00", "01"].iter().zip(data().chunks(0x2000)) {
+            fs::write(package.join(name), chunk)?;
+        }
+        // Not contiguous, so it's not a part
+        fs::write(package.join("03"), [0u8; 0x10])?;
+        assert_eq!(
+            split_parts(&package)?,
+            [package.join("00"), package.join("01")]
+        );
+        assert_eq!(package_size(&package)?, 0x2800);
+
+        let mut buf = vec![];
+        SplitFile::open(&package)?.read_to_end(&mut buf)?;
+        assert_eq!(buf, data());
+        Ok(())
+    }
+
+    #[test]
+    fn move_split_parts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let from = dir.path().join("temp.xc0");
+        let mut writer = SplitWriter::new(&from, "xc", 0x2000)?;
+        writer.write_all(&data())?;
+        writer.finish()?;
+
+        let to = dir.path().join("game.xc0");
+        move_parts(&from, &to)?;
+        assert!(!from.exists());
+        assert_eq!(split_parts(&to)?, [to.clone(), to.with_extension("xc1")]);
+        assert_eq!(package_size(&to)?, 0x2800);
+
+        // A regular file is a single part
+        let file = dir.path().join("game.nsp");
+        fs::write(&file, data())?;
+        assert_eq!(split_parts(&file)?, vec![file]);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_split_writer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(SplitWriter::new(dir.path().join("empty.nsp"), "ns", 0).is_err());
+        let parts = SplitWriter::new(dir.path().join("empty.nsp"), "ns", 0x1000)?.finish()?;
+        assert_eq!(parts, [dir.path().join("empty.ns0")]);
+        assert_eq!(parts[0].metadata()?.len(), 0);
+        Ok(())
+    }
+}
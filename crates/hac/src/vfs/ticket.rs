@@ -1,21 +1,166 @@
 //! https://switchbrew.org/wiki/Ticket
 //!
-//! Contains method for extracting `TitleKey` from Tickets, a format used to store an encrypted title key.\
-//! Cheap implementation only supporting 'Common' Title key type.
+//! Contains methods for parsing Tickets, a format used to store an encrypted title key,
+//! and extracting the `TitleKey` from them.\
+//! Only the 'Common' TitleKey type is supported for extraction.
 
-use eyre::Result;
+use crate::{
+    crypto::aes_ecb_decrypt,
+    keyset::{Key128, Keyset},
+    vfs::{read_cstr, read_u32_le, read_u64_le},
+};
+use derivative::Derivative;
+use eyre::{bail, eyre, Result};
 use fs_err as fs;
 use std::{
     fmt,
-    io::{self, Read, Seek},
+    io::{Read, Seek, SeekFrom},
     path::Path,
 };
 use tracing::{debug, info};
 
+/// https://switchbrew.org/wiki/Ticket#Signature_type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureType {
+    Rsa4096Sha1 = 0x10000,
+    Rsa2048Sha1 = 0x10001,
+    EcdsaSha1 = 0x10002,
+    Rsa4096Sha256 = 0x10003,
+    Rsa2048Sha256 = 0x10004,
+    EcdsaSha256 = 0x10005,
+    HmacSha1 = 0x10006,
+}
+
+impl SignatureType {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        Some(match value {
+            0x10000 => SignatureType::Rsa4096Sha1,
+            0x10001 => SignatureType::Rsa2048Sha1,
+            0x10002 => SignatureType::EcdsaSha1,
+            0x10003 => SignatureType::Rsa4096Sha256,
+            0x10004 => SignatureType::Rsa2048Sha256,
+            0x10005 => SignatureType::EcdsaSha256,
+            0x10006 => SignatureType::HmacSha1,
+            _ => return None,
+        })
+    }
+    /// Size of the signature followed by its padding.
+    fn signature_size(&self) -> usize {
+        match self {
+            SignatureType::Rsa4096Sha1 | SignatureType::Rsa4096Sha256 => 0x200 + 0x3C,
+            SignatureType::Rsa2048Sha1 | SignatureType::Rsa2048Sha256 => 0x100 + 0x3C,
+            SignatureType::EcdsaSha1 | SignatureType::EcdsaSha256 => 0x3C + 0x40,
+            SignatureType::HmacSha1 => 0x14 + 0x28,
+        }
+    }
+    /// Offset of the ticket data, i.e. after the signature type, signature and padding.
+    pub fn body_offset(&self) -> usize {
+        0x4 + self.signature_size()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleKeyType {
+    Common = 0x00,
+    Personalized = 0x01,
+}
+
+/// https://switchbrew.org/wiki/Ticket#Ticket_Data
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct Ticket {
+    pub signature_type: SignatureType,
+    pub issuer: String,
+    #[derivative(Debug = "ignore")]
+    pub title_key_block: [u8; 0x100],
+    pub title_key_type: TitleKeyType,
+    /// Master key revision the TitleKey is encrypted with.
+    pub key_generation: u8,
+    pub ticket_id: u64,
+    pub device_id: u64,
+    #[derivative(Debug(format_with = "rights_id_fmt"))]
+    pub rights_id: [u8; 0x10],
+    pub account_id: u32,
+}
+
+fn rights_id_fmt(rights_id: &[u8; 0x10], fmt: &mut fmt::Formatter) -> fmt::Result {
+    fmt.write_fmt(format_args!("{:?}", hex::encode(rights_id)))
+}
+
+impl Ticket {
+    const BODY_SIZE: usize = 0x180;
+
+    pub fn try_new<P: AsRef<Path>>(tik_file: P) -> Result<Self> {
+        info!(tik = %tik_file.as_ref().display(), "Reading ticket");
+        Ticket::read(&mut fs::File::open(tik_file.as_ref())?)
+            .map_err(|err| eyre!("'{}' {}", tik_file.as_ref().display(), err))
+    }
+    /// Parses the ticket, `reader` must be positioned at the start of the ticket.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let start = reader.stream_position()?;
+        let mut signature_type = [0u8; 0x4];
+        reader.read_exact(&mut signature_type)?;
+        let signature_type = SignatureType::from_u32(u32::from_le_bytes(signature_type))
+            .ok_or_else(|| {
+                eyre!(
+                    "has an unknown signature type {:#x}",
+                    u32::from_le_bytes(signature_type)
+                )
+            })?;
+
+        reader.seek(SeekFrom::Start(start + signature_type.body_offset() as u64))?;
+        let mut body = [0u8; Ticket::BODY_SIZE];
+        reader
+            .read_exact(&mut body)
+            .map_err(|_| eyre!("is truncated, the ticket data is incomplete"))?;
+
+        let title_key_type = match body[0x141] {
+            0x00 => TitleKeyType::Common,
+            0x01 => TitleKeyType::Personalized,
+            other => bail!("has an unknown TitleKey type {:#x}", other),
+        };
+
+        let ticket = Self {
+            signature_type,
+            issuer: read_cstr(&body[..0x40], 0x0)?,
+            title_key_block: body[0x40..0x140].try_into().unwrap(),
+            title_key_type,
+            key_generation: body[0x145],
+            ticket_id: read_u64_le(&body, 0x150),
+            device_id: read_u64_le(&body, 0x158),
+            rights_id: body[0x160..0x170].try_into().unwrap(),
+            account_id: read_u32_le(&body, 0x170),
+        };
+        debug!(?ticket);
+
+        Ok(ticket)
+    }
+    /// Only Common tickets are supported, as the TitleKey of Personalized ones is
+    /// encrypted with the console specific RSA key.
+    pub fn title_key(&self) -> Result<TitleKey> {
+        if self.title_key_type != TitleKeyType::Common {
+            bail!(
+                "Ticket '{}' is {:?}, only Common tickets are supported",
+                hex::encode(self.rights_id),
+                self.title_key_type
+            );
+        }
+
+        let mut title_key = [0u8; 0x10];
+        title_key.copy_from_slice(&self.title_key_block[..0x10]);
+        Ok(TitleKey {
+            rights_id: self.rights_id,
+            title_key,
+            key_generation: self.key_generation,
+        })
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct TitleKey {
     rights_id: [u8; 0x10],
     title_key: [u8; 0x10], // for Common TitleKey type
+    key_generation: u8,
 }
 
 impl fmt::Display for TitleKey {
@@ -30,28 +175,101 @@ impl fmt::Display for TitleKey {
 }
 
 impl TitleKey {
-    const RIGHTS_ID_OFFSET: usize = 0x2a0;
-    const TITLE_KEY_OFFSET: usize = 0x180;
-
     pub fn try_new<P: AsRef<Path>>(tik_file: P) -> Result<TitleKey> {
-        let mut title_key = TitleKey::default();
-        let mut ticket = fs::File::open(tik_file.as_ref())?;
+        Ticket::try_new(tik_file)?.title_key()
+    }
+    pub fn from_reader<R: Read + Seek>(ticket: &mut R) -> Result<TitleKey> {
+        let title_key = Ticket::read(ticket)
+            .map_err(|err| eyre!("Ticket {}", err))?
+            .title_key()?;
+        debug!(title_key = %title_key);
 
-        info!(tik = %tik_file.as_ref().display(), "Reading ticket");
+        Ok(title_key)
+    }
+    /// Decrypts the TitleKey with the title KEK of the given master key revision,
+    /// which should be the one of the NCA it's used for.
+    pub fn decrypt(&self, keyset: &Keyset, master_key_revision: u8) -> Result<Key128> {
+        let mut title_key = self.title_key;
+        aes_ecb_decrypt(keyset.title_kek(master_key_revision)?, &mut title_key);
+        Ok(title_key)
+    }
+    pub fn rights_id(&self) -> &[u8; 0x10] {
+        &self.rights_id
+    }
+    pub fn key_generation(&self) -> u8 {
+        self.key_generation
+    }
+}
 
-        ticket.seek(io::SeekFrom::Start(TitleKey::RIGHTS_ID_OFFSET as _))?;
-        ticket.read_exact(&mut title_key.rights_id)?;
-
-        ticket.seek(io::SeekFrom::Start(TitleKey::TITLE_KEY_OFFSET as _))?;
-        ticket.read_exact(&mut title_key.title_key)?;
-        debug!(
-            title_key = ?format!(
-                "{}={}",
-                hex::encode(title_key.rights_id),
-                hex::encode(title_key.title_key)
-            )
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn build_ticket(title_key_type: u8) -> Vec<u8> {
+        let signature_type = SignatureType::Rsa2048Sha256;
+        let mut tik = vec![0u8; signature_type.body_offset() + Ticket::BODY_SIZE];
+        tik[..0x4].copy_from_slice(&(signature_type as u32).to_le_bytes());
+        let body = &mut tik[signature_type.body_offset()..];
+        body[..0x1A].copy_from_slice(b"Root-CA00000003-XS00000020");
+        body[0x40..0x50].copy_from_slice(&[0xAA; 0x10]);
+        body[0x141] = title_key_type;
+        body[0x145] = 0x0B;
+        body[0x150..0x158].copy_from_slice(&0x1122_3344_5566_7788u64.to_le_bytes());
+        body[0x160..0x170].copy_from_slice(&[
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x0B,
+        ]);
+        tik
+    }
+
+    /// Common ticket with an encrypted `title_key` for `rights_id`.
+    pub(crate) fn common_ticket(rights_id: [u8; 0x10], title_key: Key128) -> Vec<u8> {
+        let mut tik = build_ticket(0x00);
+        let body = &mut tik[SignatureType::Rsa2048Sha256.body_offset()..];
+        body[0x40..0x50].copy_from_slice(&title_key);
+        body[0x145] = 0;
+        body[0x160..0x170].copy_from_slice(&rights_id);
+        tik
+    }
+
+    #[test]
+    fn read_common_ticket() -> Result<()> {
+        let ticket = Ticket::read(&mut Cursor::new(build_ticket(0x00)))?;
+        assert_eq!(ticket.signature_type, SignatureType::Rsa2048Sha256);
+        assert_eq!(ticket.issuer, "Root-CA00000003-XS00000020");
+        assert_eq!(ticket.title_key_type, TitleKeyType::Common);
+        assert_eq!(ticket.key_generation, 0x0B);
+        assert_eq!(ticket.ticket_id, 0x1122_3344_5566_7788);
+
+        let title_key = ticket.title_key()?;
+        assert_eq!(title_key.key_generation(), 0x0B);
+        assert_eq!(title_key.rights_id(), &ticket.rights_id);
+        assert_eq!(
+            title_key.to_string(),
+            format!("0100000000001000000000000000000b={}", "aa".repeat(0x10))
         );
+        Ok(())
+    }
 
-        Ok(title_key)
+    #[test]
+    fn read_personalized_ticket() -> Result<()> {
+        let ticket = Ticket::read(&mut Cursor::new(build_ticket(0x01)))?;
+        assert_eq!(ticket.title_key_type, TitleKeyType::Personalized);
+        assert!(ticket.title_key().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_rejects_bad_tickets() {
+        let mut tik = build_ticket(0x00);
+        tik[..0x4].copy_from_slice(&0x20000u32.to_le_bytes());
+        assert!(Ticket::read(&mut Cursor::new(tik)).is_err());
+
+        let mut tik = build_ticket(0x00);
+        tik.truncate(tik.len() - 1);
+        assert!(Ticket::read(&mut Cursor::new(tik)).is_err());
+
+        assert!(Ticket::read(&mut Cursor::new(build_ticket(0x02))).is_err());
     }
 }
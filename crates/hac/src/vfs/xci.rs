@@ -1,22 +1,660 @@
-use super::nsp::Nsp;
-use crate::backend::{Backend, BackendKind};
-use common::{
-    defines::DEFAULT_PRODKEYS_PATH,
-    utils::{ext_matches, get_fmt_size, move_file},
+use crate::{
+    keyset::Keyset,
+    utils::formatted_nsp_rename,
+    vfs::{
+        cnmt::{self, Cnmt, ContentMetaType},
+        extract_entry_to,
+        nacp::{Language, Nacp},
+        nca::{Nca, NcaHeader},
+        ncz::Ncz,
+        nsp::{Nsp, NspWriter},
+        read_cstr, read_entry_tables, read_u32_le, read_u64_le,
+        split::{is_first_part, SplitFile},
+        ticket::TitleKey,
+        SubStorage, MEDIA_UNIT_SIZE,
+    },
 };
-use eyre::{bail, Result};
+use common::utils::{ext_matches, get_fmt_size};
+use eyre::{bail, eyre, Result};
 use fs_err as fs;
-use std::{path::Path, process::Command};
-use tracing::{info, warn};
-use walkdir::WalkDir;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use strum_macros::{Display, EnumString};
+use tracing::{debug, info, warn};
+
+/// https://switchbrew.org/wiki/XCI
+///
+/// Provides access to the gamecard header and the HFS0 partitions of a XCI.
+#[derive(Debug, Clone)]
+pub struct Xci {
+    pub path: PathBuf,
+    pub header: XciHeader,
+    pub partitions: Vec<XciPartition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct XciHeader {
+    /// Offset of the header in the file, it's non-zero for dumps with the key area prepended.
+    pub offset: u64,
+    pub card_size: CardSize,
+    pub package_id: u64,
+    pub flags: u8,
+    pub title_key_dec_index: u8,
+    pub secure_area_start: u32,
+    pub valid_data_end: u64,
+    pub hfs0_offset: u64,
+    pub hfs0_header_size: u64,
+    pub hfs0_header_hash: [u8; 0x20],
+}
+
+/// https://switchbrew.org/wiki/Gamecard_Format#Cartridge_Header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CardSize {
+    Gb1 = 0xFA,
+    Gb2 = 0xF8,
+    Gb4 = 0xF0,
+    Gb8 = 0xE0,
+    Gb16 = 0xE1,
+    Gb32 = 0xE2,
+}
+
+impl CardSize {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0xFA => CardSize::Gb1,
+            0xF8 => CardSize::Gb2,
+            0xF0 => CardSize::Gb4,
+            0xE0 => CardSize::Gb8,
+            0xE1 => CardSize::Gb16,
+            0xE2 => CardSize::Gb32,
+            _ => return None,
+        })
+    }
+    /// Smallest card that can fit `size` bytes, see `usable_capacity`.
+    pub fn fitting(size: u64) -> Option<Self> {
+        [
+            CardSize::Gb1,
+            CardSize::Gb2,
+            CardSize::Gb4,
+            CardSize::Gb8,
+            CardSize::Gb16,
+            CardSize::Gb32,
+        ]
+        .into_iter()
+        .find(|card_size| card_size.usable_capacity() >= size)
+    }
+    /// Capacity in bytes as advertised.
+    pub fn capacity(&self) -> u64 {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        match self {
+            CardSize::Gb1 => GIB,
+            CardSize::Gb2 => 2 * GIB,
+            CardSize::Gb4 => 4 * GIB,
+            CardSize::Gb8 => 8 * GIB,
+            CardSize::Gb16 => 16 * GIB,
+            CardSize::Gb32 => 32 * GIB,
+        }
+    }
+    /// Capacity in bytes that's left for the XCI, part of the advertised one is
+    /// taken by the card itself. Same limits as XCI tools go by.
+    pub fn usable_capacity(&self) -> u64 {
+        match self {
+            CardSize::Gb1 => 0x3B80_0000,
+            CardSize::Gb2 => 0x7700_0000,
+            CardSize::Gb4 => 0xEC58_0000,
+            CardSize::Gb8 => 0x1_D880_0000,
+            CardSize::Gb16 => 0x3_B100_0000,
+            CardSize::Gb32 => 0x7_6200_0000,
+        }
+    }
+}
+
+impl fmt::Display for CardSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}GB", self.capacity() >> 30)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum PartitionKind {
+    Update,
+    Normal,
+    Secure,
+    Logo,
+}
+
+#[derive(Debug, Clone)]
+pub struct XciPartition {
+    pub name: String,
+    pub hfs0: Hfs0,
+}
+
+/// https://switchbrew.org/wiki/XCI#Hash_File_System
+#[derive(Debug, Default, Clone)]
+pub struct Hfs0 {
+    pub header_size: u64,
+    pub entries: Vec<Hfs0Entry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hfs0Entry {
+    pub name: String,
+    /// Offset of the file data, relative to the start of the XCI file.
+    pub offset: u64,
+    pub size: u64,
+    pub hashed_region_size: u32,
+    pub hash: [u8; 0x20],
+}
+
+impl Hfs0 {
+    const MAGIC: [u8; 4] = *b"HFS0";
+    const HEADER_SIZE: usize = 0x10;
+    const ENTRY_SIZE: usize = 0x40;
+    const HEADER_ALIGNMENT: usize = MEDIA_UNIT_SIZE as _;
+    /// Only this much of a file is hashed at most.
+    const HASHED_REGION_SIZE: u64 = 0x200;
+
+    /// Parses the HFS0 header at `offset`, entry offsets are resolved relative to the `reader`.
+    pub fn read<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Self> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; Hfs0::HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if header[..4] != Hfs0::MAGIC {
+            bail!(
+                "Invalid HFS0 magic '{}' at {:#x}",
+                String::from_utf8_lossy(&header[..4]),
+                offset
+            );
+        }
+        let entry_count = read_u32_le(&header, 0x4) as usize;
+        let string_table_size = read_u32_le(&header, 0x8) as usize;
+
+        let (entry_table, string_table) =
+            read_entry_tables(reader, entry_count, Hfs0::ENTRY_SIZE, string_table_size)?;
+
+        let header_size = (Hfs0::HEADER_SIZE + entry_table.len() + string_table.len()) as u64;
+        let entries = entry_table
+            .chunks_exact(Hfs0::ENTRY_SIZE)
+            .map(|entry| {
+                Ok(Hfs0Entry {
+                    name: read_cstr(&string_table, read_u32_le(entry, 0x10) as _)?,
+                    offset: offset + header_size + read_u64_le(entry, 0x0),
+                    size: read_u64_le(entry, 0x8),
+                    hashed_region_size: read_u32_le(entry, 0x14),
+                    hash: entry[0x20..0x40].try_into().unwrap(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            header_size,
+            entries,
+        })
+    }
+    /// Builds a HFS0 header for the given entries, data is laid out contiguously
+    /// in the same order, so only the name, size and hash fields of the entries are used.
+    pub fn build_header(entries: &[Hfs0Entry]) -> Vec<u8> {
+        let mut string_table = vec![];
+        let mut entry_table = vec![];
+        let mut data_offset = 0u64;
+        for entry in entries {
+            entry_table.extend(data_offset.to_le_bytes());
+            entry_table.extend(entry.size.to_le_bytes());
+            entry_table.extend((string_table.len() as u32).to_le_bytes());
+            entry_table.extend(entry.hashed_region_size.to_le_bytes());
+            entry_table.extend([0u8; 0x8]);
+            entry_table.extend(entry.hash);
+            string_table.extend(entry.name.as_bytes());
+            string_table.push(0);
+            data_offset += entry.size;
+        }
+
+        let unpadded_len = Hfs0::HEADER_SIZE + entry_table.len() + string_table.len();
+        let padded_len = (unpadded_len + Hfs0::HEADER_ALIGNMENT - 1) / Hfs0::HEADER_ALIGNMENT
+            * Hfs0::HEADER_ALIGNMENT;
+        string_table.resize(string_table.len() + padded_len - unpadded_len, 0);
+
+        let mut header = Vec::with_capacity(padded_len);
+        header.extend(Hfs0::MAGIC);
+        header.extend((entries.len() as u32).to_le_bytes());
+        header.extend((string_table.len() as u32).to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(entry_table);
+        header.extend(string_table);
+        header
+    }
+    pub fn entry(&self, name: &str) -> Option<&Hfs0Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+}
+
+impl Xci {
+    const MAGIC: [u8; 4] = *b"HEAD";
+    const HEADER_SIZE: usize = 0x200;
+    /// Size of the key area that some dumps have prepended to the header.
+    const KEY_AREA_SIZE: u64 = 0x1000;
+    /// Where the root HFS0 is placed on retail gamecards.
+    const ROOT_HFS0_OFFSET: u64 = 0xF000;
+
+    pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        is_xci(path.as_ref())?;
+        info!(xci = %path.as_ref().display(), "Reading XCI");
+
+        let mut file = io::BufReader::new(SplitFile::open(path.as_ref())?);
+        let file_size = file.get_ref().size();
+
+        let header = Xci::read_header(&mut file)
+            .map_err(|err| eyre!("'{}' {}", path.as_ref().display(), err))?;
+        debug!(?header);
+
+        let valid_data_size = (header.valid_data_end + 1) * MEDIA_UNIT_SIZE;
+        if file_size < header.offset + valid_data_size {
+            warn!(
+                file_size,
+                valid_data_size, "XCI is smaller than its valid data size, dump could be truncated"
+            );
+        }
+
+        // Root HFS0
+        let root_offset = header.offset + header.hfs0_offset;
+        let root_hash = hash_region(&mut file, root_offset, header.hfs0_header_size)?;
+        if root_hash != header.hfs0_header_hash {
+            bail!(
+                "Root HFS0 header hash mismatch in '{}', dump is corrupted",
+                path.as_ref().display()
+            );
+        }
+        let root = Hfs0::read(&mut file, root_offset)?;
+
+        let mut partitions = vec![];
+        for entry in &root.entries {
+            if entry.offset + entry.size > file_size {
+                bail!(
+                    "'{}' partition ends at {:#x} but the file is only {:#x} bytes, dump is truncated",
+                    entry.name,
+                    entry.offset + entry.size,
+                    file_size
+                );
+            }
+            let hash = hash_region(&mut file, entry.offset, entry.hashed_region_size as _)?;
+            if hash != entry.hash {
+                warn!(partition = %entry.name, "Partition header hash mismatch, dump could be corrupted");
+            }
+            let hfs0 = Hfs0::read(&mut file, entry.offset)
+                .map_err(|err| eyre!("Failed to read '{}' partition: {}", entry.name, err))?;
+            if let Some(end) = hfs0.entries.iter().map(|e| e.offset + e.size).max() {
+                if end > entry.offset + entry.size {
+                    bail!(
+                        "'{}' partition entries exceed the partition bounds, dump is corrupted",
+                        entry.name
+                    );
+                }
+            }
+            partitions.push(XciPartition {
+                name: entry.name.clone(),
+                hfs0,
+            });
+        }
+
+        info!(
+            card_size = %header.card_size,
+            package_id = %format!("{:016x}", header.package_id),
+            partitions = ?partitions
+                .iter()
+                .map(|partition| (&partition.name, partition.hfs0.entries.len()))
+                .collect::<Vec<_>>(),
+            "Read XCI"
+        );
+
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            header,
+            partitions,
+        })
+    }
+    fn read_header<R: Read + Seek>(reader: &mut R) -> Result<XciHeader> {
+        let mut buf = [0u8; Xci::HEADER_SIZE];
+        let mut offset = 0;
+        for candidate in [0, Xci::KEY_AREA_SIZE] {
+            reader.seek(SeekFrom::Start(candidate))?;
+            reader.read_exact(&mut buf)?;
+            if buf[0x100..0x104] == Xci::MAGIC {
+                offset = candidate;
+                break;
+            }
+            if candidate == Xci::KEY_AREA_SIZE {
+                bail!("is not a valid XCI, gamecard header magic is missing");
+            }
+        }
+
+        let card_size = CardSize::from_byte(buf[0x10D])
+            .ok_or_else(|| eyre!("has an unknown gamecard size {:#x}", buf[0x10D]))?;
+
+        Ok(XciHeader {
+            offset,
+            card_size,
+            package_id: read_u64_le(&buf, 0x110),
+            flags: buf[0x10F],
+            title_key_dec_index: buf[0x10C],
+            secure_area_start: read_u32_le(&buf, 0x104),
+            valid_data_end: read_u64_le(&buf, 0x118),
+            hfs0_offset: read_u64_le(&buf, 0x130),
+            hfs0_header_size: read_u64_le(&buf, 0x138),
+            hfs0_header_hash: buf[0x140..0x160].try_into().unwrap(),
+        })
+    }
+    pub fn partition(&self, kind: PartitionKind) -> Option<&Hfs0> {
+        let name = kind.to_string();
+        self.partitions
+            .iter()
+            .find(|partition| partition.name == name)
+            .map(|partition| &partition.hfs0)
+    }
+    /// Returns a reader over the data of a single entry.
+    pub fn open_entry(&self, entry: &Hfs0Entry) -> Result<SubStorage<SplitFile>> {
+        Ok(SubStorage::new(
+            SplitFile::open(&self.path)?,
+            entry.offset,
+            entry.size,
+        ))
+    }
+    /// Streams the entry with `name` from the given partition out to the `to` dir.
+    pub fn extract_entry<P: AsRef<Path>>(
+        &self,
+        kind: PartitionKind,
+        name: &str,
+        to: P,
+    ) -> Result<PathBuf> {
+        let entry = self
+            .partition(kind)
+            .ok_or_else(|| eyre!("'{}' has no {} partition", self.path.display(), kind))?
+            .entry(name)
+            .ok_or_else(|| eyre!("Couldn't find '{}' in the {} partition", name, kind))?;
+        extract_entry_to(self.open_entry(entry)?, &entry.name, entry.size, to)
+            .map_err(|err| eyre!("Failed to extract from '{}': {}", self.path.display(), err))
+    }
+}
+
+/// Builds a XCI out of the NCAs of an Application NSP.
+///
+/// The root HFS0 has an empty update and normal partition, and everything goes
+/// into the secure partition. The gamecard header isn't signed, and its encrypted
+/// gamecard info is left zeroed. Gamecards have no Tickets, so NCAs with TitleKey
+/// crypto are moved to standard crypto using the Tickets of the NSP.
+///
+/// Meta NCAs are extracted to `tempdir_in` to check that the NSP is of an Application.
+pub fn nsp_to_xci<P, Q, R>(nsp: P, outdir: Q, tempdir_in: R) -> Result<Xci>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    convert_nsp_to_xci(&Keyset::try_default()?, nsp, outdir, tempdir_in)
+}
+
+fn convert_nsp_to_xci<P, Q, R>(keyset: &Keyset, nsp: P, outdir: Q, tempdir_in: R) -> Result<Xci>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let nsp = Nsp::try_new(nsp.as_ref())?;
+    let pfs0 = nsp.pfs0()?;
+
+    let header_key = keyset.header_key()?;
+    let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
+    let mut meta_ncas = pfs0
+        .entries
+        .iter()
+        .filter(|entry| entry.name.ends_with(".cnmt.nca"))
+        .peekable();
+    if meta_ncas.peek().is_none() {
+        bail!(
+            "Couldn't find a Meta NCA in '{}', only Application NSPs can be converted",
+            nsp.path.display()
+        );
+    }
+    for entry in meta_ncas {
+        let cnmt = nsp
+            .extract_entry(entry, temp_dir.path())
+            .and_then(|path| Nca::try_new(header_key, path))
+            .and_then(|nca| Cnmt::from_nca(&nca, keyset))
+            .map_err(|err| eyre!("Failed to read CNMT from '{}': {}", entry.name, err))?;
+        if cnmt.meta_type != ContentMetaType::Application {
+            bail!(
+                "'{}' is a {} NSP of {}, only Application NSPs can be converted",
+                nsp.path.display(),
+                cnmt.meta_type,
+                cnmt.title_id_str()
+            );
+        }
+    }
+    drop(temp_dir);
+    // Only needed if there are NCAs with TitleKey crypto, which then fail to convert
+    let title_keys = pfs0
+        .entries_with_ext("tik")
+        .filter_map(|entry| {
+            match nsp
+                .open_entry(entry)
+                .and_then(|mut tik| TitleKey::from_reader(&mut tik))
+            {
+                Ok(title_key) => Some(title_key),
+                Err(err) => {
+                    warn!(tik = %entry.name, %err);
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    info!(
+        nsp = %nsp.path.display(),
+        size = %get_fmt_size(&nsp.path).unwrap_or_default(),
+        "Converting to XCI"
+    );
+
+    let mut reader = io::BufReader::new(SplitFile::open(&nsp.path)?);
+    let mut sources = vec![];
+    let mut secure_entries = vec![];
+    for entry in pfs0
+        .entries
+        .iter()
+        .filter(|entry| ext_matches(&entry.name, "nca") || ext_matches(&entry.name, "ncz"))
+    {
+        // NCZs go in decompressed, their NCA header is kept as is so the hashed region is too
+        let (name, size) = if ext_matches(&entry.name, "ncz") {
+            let ncz = Ncz::read(&mut nsp.open_entry(entry)?)
+                .map_err(|err| eyre!("Failed to read '{}': {}", entry.name, err))?;
+            (
+                Path::new(&entry.name)
+                    .with_extension("nca")
+                    .to_string_lossy()
+                    .into_owned(),
+                ncz.nca_size(),
+            )
+        } else {
+            (entry.name.clone(), entry.size)
+        };
+        // NCZs start with the NCA header as well
+        reader.seek(SeekFrom::Start(entry.offset))?;
+        let nca_header = NcaHeader::read(&mut reader, header_key)
+            .map_err(|err| eyre!("'{}' {}", entry.name, err))?;
+        let converted_header = if nca_header.has_rights_id() {
+            info!(nca = %entry.name, "Moving NCA from TitleKey crypto to standard crypto");
+            Some(
+                nca_header
+                    .to_standard_crypto(keyset, &title_keys)
+                    .map_err(|err| eyre!("Failed to convert '{}': {}", entry.name, err))?,
+            )
+        } else {
+            None
+        };
+        let hashed_region_size = size.min(Hfs0::HASHED_REGION_SIZE);
+        let hash = match &converted_header {
+            Some(header) => Sha256::digest(&header[..hashed_region_size as usize]).into(),
+            None => hash_region(&mut reader, entry.offset, hashed_region_size)?,
+        };
+        secure_entries.push(Hfs0Entry {
+            name,
+            offset: entry.offset,
+            size,
+            hashed_region_size: hashed_region_size as _,
+            hash,
+        });
+        sources.push((entry, converted_header));
+    }
+
+    let update_header = Hfs0::build_header(&[]);
+    let normal_header = Hfs0::build_header(&[]);
+    let secure_header = Hfs0::build_header(&secure_entries);
+    let secure_size =
+        secure_header.len() as u64 + secure_entries.iter().map(|e| e.size).sum::<u64>();
+    let partitions = [
+        (
+            PartitionKind::Update,
+            &update_header,
+            update_header.len() as u64,
+        ),
+        (
+            PartitionKind::Normal,
+            &normal_header,
+            normal_header.len() as u64,
+        ),
+        (PartitionKind::Secure, &secure_header, secure_size),
+    ];
+    let root_entries = partitions
+        .iter()
+        .map(|(kind, header, size)| Hfs0Entry {
+            name: kind.to_string(),
+            offset: 0,
+            size: *size,
+            hashed_region_size: header.len() as _,
+            hash: Sha256::digest(header).into(),
+        })
+        .collect::<Vec<_>>();
+    let root_header = Hfs0::build_header(&root_entries);
+
+    // Partitions are laid out back to back, the header sizes are already media unit aligned
+    let secure_offset = Xci::ROOT_HFS0_OFFSET
+        + (root_header.len() + update_header.len() + normal_header.len()) as u64;
+    let total_size =
+        (secure_offset + secure_size + MEDIA_UNIT_SIZE - 1) / MEDIA_UNIT_SIZE * MEDIA_UNIT_SIZE;
+    let card_size = CardSize::fitting(total_size)
+        .ok_or_else(|| eyre!("'{}' is too large to fit in a gamecard", nsp.path.display()))?;
+    let package_id = u64::from_le_bytes(Sha256::digest(&secure_header)[..8].try_into().unwrap());
+    debug!(%card_size, total_size, secure_offset, "XCI layout");
+
+    let mut header = vec![0u8; Xci::HEADER_SIZE];
+    header[0x100..0x104].copy_from_slice(&Xci::MAGIC);
+    header[0x104..0x108].copy_from_slice(&((secure_offset / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+    // Backup area start, it's unused
+    header[0x108..0x10C].copy_from_slice(&u32::MAX.to_le_bytes());
+    header[0x10D] = card_size as u8;
+    header[0x110..0x118].copy_from_slice(&package_id.to_le_bytes());
+    header[0x118..0x120].copy_from_slice(&(total_size / MEDIA_UNIT_SIZE - 1).to_le_bytes());
+    header[0x130..0x138].copy_from_slice(&Xci::ROOT_HFS0_OFFSET.to_le_bytes());
+    header[0x138..0x140].copy_from_slice(&(root_header.len() as u64).to_le_bytes());
+    header[0x140..0x160].copy_from_slice(&Sha256::digest(&root_header));
+    header[0x180..0x184].copy_from_slice(&1u32.to_le_bytes());
+    header[0x184..0x188].copy_from_slice(&2u32.to_le_bytes());
+    header[0x18C..0x190].copy_from_slice(&((secure_offset / MEDIA_UNIT_SIZE) as u32).to_le_bytes());
+
+    fs::create_dir_all(outdir.as_ref())?;
+    let xci_path = outdir.as_ref().join(
+        nsp.path
+            .with_extension("xci")
+            .file_name()
+            .ok_or_else(|| eyre!("Failed to get filename"))?,
+    );
+    let mut writer = io::BufWriter::new(fs::File::create(&xci_path)?);
+    writer.write_all(&header)?;
+    io::copy(
+        &mut io::repeat(0).take(Xci::ROOT_HFS0_OFFSET - header.len() as u64),
+        &mut writer,
+    )?;
+    for header in [&root_header, &update_header, &normal_header, &secure_header] {
+        writer.write_all(header)?;
+    }
+    let mut offset = secure_offset + secure_header.len() as u64;
+    for (entry, (source, converted_header)) in secure_entries.iter().zip(&sources) {
+        debug!(file = %entry.name, size = entry.size, "Writing entry");
+        reader.seek(SeekFrom::Start(source.offset))?;
+        let written = if ext_matches(&source.name, "ncz") {
+            Ncz::decompress(reader.by_ref().take(source.size), &mut writer)
+                .map_err(|err| eyre!("Failed to decompress '{}': {}", source.name, err))?
+        } else {
+            io::copy(&mut reader.by_ref().take(source.size), &mut writer)?
+        };
+        if written != entry.size {
+            bail!(
+                "Unexpected EOF while reading '{}' from the NSP",
+                source.name
+            );
+        }
+        if let Some(header) = converted_header {
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(header)?;
+            writer.seek(SeekFrom::Start(offset + entry.size))?;
+        }
+        offset += entry.size;
+    }
+    io::copy(
+        &mut io::repeat(0).take(total_size - secure_offset - secure_size),
+        &mut writer,
+    )?;
+    writer.flush()?;
+    drop(writer);
+
+    info!(
+        xci = %xci_path.display(),
+        %card_size,
+        size = %get_fmt_size(&xci_path).unwrap_or_default(),
+        "Converted to XCI"
+    );
+
+    Xci::try_new(xci_path)
+}
 
-pub fn xci_to_nsps<P, Q, R>(xci: P, outdir: Q, tempdir_in: R) -> Result<Vec<Nsp>>
+fn hash_region<R: Read + Seek>(reader: &mut R, offset: u64, size: u64) -> Result<[u8; 0x20]> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut hasher = Sha256::new();
+    if io::copy(&mut reader.by_ref().take(size), &mut hasher)? != size {
+        bail!("Unexpected EOF while hashing region at {:#x}", offset);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Repackages the NCAs in the secure partition to NSPs, one for every title,
+/// i.e. Application, Patch or AddOnContent, found in the XCI.
+///
+/// Titles are told apart by their Meta NCAs, and every other NCA goes to the title whose
+/// CNMT lists its ContentID. Entries are streamed straight from the XCI, only the Meta and
+/// Control NCAs are extracted to `tempdir_in` to be read. NCZs of a XCZ are kept compressed,
+/// so those titles come out as NSZs. NSPs are split into parts of at most `part_size` bytes
+/// if given, and named after the title in the `language` when it has one.
+pub fn xci_to_nsps<P, Q, R>(
+    xci: P,
+    outdir: Q,
+    tempdir_in: R,
+    part_size: Option<u64>,
+    language: Language,
+) -> Result<Vec<Nsp>>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
     R: AsRef<Path>,
 {
-    is_xci(xci.as_ref())?;
+    // Bails early with proper diagnostics on bad dumps
+    let parsed = Xci::try_new(xci.as_ref())?;
+    let secure = parsed
+        .partition(PartitionKind::Secure)
+        .ok_or_else(|| eyre!("'{}' has no secure partition", xci.as_ref().display()))?;
 
     info!(
         xci = %xci.as_ref().display(),
@@ -24,44 +662,128 @@ where
         "Converting to NSP"
     );
 
-    let backend = Backend::try_new(BackendKind::FourNXCI)?;
+    let keyset = Keyset::try_default()?;
+    let header_key = keyset.header_key()?;
     let temp_dir = tempfile::tempdir_in(tempdir_in.as_ref())?;
-    let temp_outdir = tempfile::tempdir_in(tempdir_in.as_ref())?;
-    fs::create_dir_all(&temp_outdir)?;
-    if !Command::new(backend.path())
-        .args([
-            "--keyset".as_ref(),
-            DEFAULT_PRODKEYS_PATH.as_path(),
-            "--tempdir".as_ref(),
-            temp_dir.path(),
-            "--outdir".as_ref(),
-            temp_outdir.path(),
-            "--rename".as_ref(),
-            xci.as_ref(),
-        ])
-        .status()?
-        .success()
+
+    let mut titles = vec![];
+    for entry in secure
+        .entries
+        .iter()
+        .filter(|entry| entry.name.ends_with(".cnmt.nca"))
     {
-        warn!("Encountered an error while trying to convert XCI to NSP");
+        let cnmt = parsed
+            .extract_entry(PartitionKind::Secure, &entry.name, temp_dir.path())
+            .and_then(|path| Nca::try_new(header_key, path))
+            .and_then(|nca| Cnmt::from_nca(&nca, &keyset))
+            .map_err(|err| eyre!("Failed to read CNMT from '{}': {}", entry.name, err))?;
+        titles.push((entry, cnmt));
+    }
+    if titles.is_empty() {
+        bail!(
+            "Couldn't find any Meta NCA in the secure partition of '{}'",
+            xci.as_ref().display()
+        );
     }
 
-    let mut nsps = vec![];
-    for entry in WalkDir::new(temp_outdir.path())
-        .min_depth(1)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if let Ok(mut nsp) = Nsp::try_new(entry.path()) {
-            // Moving NSP file from temp outdir to outdir
-            let dest = outdir.as_ref().join(entry.file_name());
-            move_file(&nsp.path, &dest)?;
-            nsp.path = dest;
-            nsps.push(nsp);
+    let find_entry = |content_id: &str| {
+        ["nca", "ncz"]
+            .iter()
+            .find_map(|ext| secure.entry(&format!("{}.{}", content_id, ext)))
+    };
+    // Every NCA must belong to a title, so that nothing on the cartridge is lost
+    let mut packages = vec![];
+    let mut referenced = BTreeSet::new();
+    for (meta_entry, cnmt) in &titles {
+        let mut entries = vec![*meta_entry];
+        for record in &cnmt.content_records {
+            let content_id = hex::encode(record.content_id);
+            match find_entry(&content_id) {
+                Some(entry) => entries.push(entry),
+                None if record.content_type == cnmt::ContentType::DeltaFragment => {
+                    debug!(%content_id, "Skipping DeltaFragment that isn't on the cartridge");
+                }
+                None => bail!(
+                    "'{}' is listed in the CNMT of {} but isn't in the secure partition",
+                    record.nca_filename(),
+                    cnmt.title_id_str()
+                ),
+            }
         }
+        // Tickets and Certs are named after the RightsID, which starts with the TitleID
+        entries.extend(secure.entries.iter().filter(|entry| {
+            (ext_matches(&entry.name, "tik") || ext_matches(&entry.name, "cert"))
+                && entry.name.starts_with(&cnmt.title_id_str())
+        }));
+        referenced.extend(entries.iter().map(|entry| entry.name.as_str()));
+        packages.push((cnmt, entries));
     }
+    let unreferenced = secure
+        .entries
+        .iter()
+        .filter(|entry| {
+            (ext_matches(&entry.name, "nca") || ext_matches(&entry.name, "ncz"))
+                && !referenced.contains(entry.name.as_str())
+        })
+        .map(|entry| entry.name.as_str())
+        .collect::<Vec<_>>();
+    if !unreferenced.is_empty() {
+        bail!(
+            "{:?} in the secure partition of '{}' aren't listed in any CNMT",
+            unreferenced,
+            xci.as_ref().display()
+        );
+    }
+
+    let mut nsps = vec![];
+    for (cnmt, entries) in packages {
+        let title_id_str = cnmt.title_id_str();
+        let kind = cnmt.meta_type;
+        info!(title_id = %title_id_str, %kind, entries = entries.len(), "Packing title");
+
+        let mut writer = NspWriter::new();
+        for entry in &entries {
+            writer.add_entry(&entry.name, &parsed.path, entry.offset, entry.size);
+        }
+        let ext = if entries.iter().any(|entry| ext_matches(&entry.name, "ncz")) {
+            "nsz"
+        } else {
+            "nsp"
+        };
+        let nsp_path = outdir
+            .as_ref()
+            .join(format!("{} [{}].{}", title_id_str, kind, ext));
+        let mut nsp = match part_size {
+            Some(part_size) => writer.write_split(nsp_path, part_size)?,
+            None => writer.write(nsp_path)?,
+        };
 
-    if nsps.is_empty() {
-        bail!("Failed to convert XCI to NSP");
+        // Naming it after the NACP when possible, AddOnContents don't have a Control NCA
+        if let Some(control) = cnmt
+            .contents(cnmt::ContentType::Control)
+            .first()
+            .and_then(|record| find_entry(&hex::encode(record.content_id)))
+        {
+            // Game card NCAs don't use TitleKey crypto
+            if let Err(err) = parsed
+                .extract_entry(PartitionKind::Secure, &control.name, temp_dir.path())
+                .and_then(|path| Nca::try_new(header_key, path))
+                .and_then(|nca| Nacp::from_control_nca(&nca, &keyset, None::<&TitleKey>))
+                .and_then(|nacp_data| {
+                    formatted_nsp_rename(
+                        &mut nsp.path,
+                        &nacp_data,
+                        &title_id_str,
+                        &format!("[{}]", kind),
+                        language,
+                    )
+                })
+            {
+                warn!(%err, "Failed to name NSP after the NACP");
+            }
+        }
+
+        nsps.push(nsp);
     }
 
     info!(?nsps, "Converted to NSPs");
@@ -70,8 +792,207 @@ where
 }
 
 fn is_xci<P: AsRef<Path>>(path: P) -> Result<()> {
-    if !path.as_ref().is_file() || !ext_matches(path.as_ref(), "xci") {
+    let is_xci = ["xci", "xcz"]
+        .iter()
+        .any(|ext| ext_matches(path.as_ref(), ext));
+    let is_valid = if path.as_ref().is_dir() {
+        is_xci
+    } else {
+        path.as_ref().is_file() && (is_xci || is_first_part(path.as_ref(), "xc"))
+    };
+    if !is_valid {
         bail!("'{}' is not a XCI file", path.as_ref().display());
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keyset::TEST_KEYS,
+        vfs::{
+            align_up, cnmt::CnmtBuilder, nca, nca::tests::write_control_nca,
+            ticket::tests::common_ticket,
+        },
+    };
+
+    fn hfs0_entry(name: &str, size: u64, hashed: &[u8]) -> Hfs0Entry {
+        Hfs0Entry {
+            name: name.into(),
+            offset: 0,
+            size,
+            hashed_region_size: hashed.len() as _,
+            hash: Sha256::digest(hashed).into(),
+        }
+    }
+
+    /// A XCI with an empty update partition and a secure partition holding `files`.
+    fn build_xci(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut secure = Hfs0::build_header(
+            &files
+                .iter()
+                .map(|(name, data)| hfs0_entry(name, data.len() as _, data))
+                .collect::<Vec<_>>(),
+        );
+        // Partitions are hashed up to the end of their header
+        let secure_header_size = secure.len();
+        for (_, data) in files {
+            secure.extend(*data);
+        }
+        let update = Hfs0::build_header(&[]);
+        let mut root = Hfs0::build_header(&[
+            hfs0_entry("update", update.len() as _, &update),
+            hfs0_entry("secure", secure.len() as _, &secure[..secure_header_size]),
+        ]);
+        let root_header_size = root.len();
+        root.extend(update);
+        root.extend(secure);
+
+        let mut xci = vec![0u8; Xci::ROOT_HFS0_OFFSET as usize];
+        xci[0x100..0x104].copy_from_slice(&Xci::MAGIC);
+        xci[0x10D] = CardSize::Gb1 as u8;
+        xci[0x110..0x118].copy_from_slice(&0x1234u64.to_le_bytes());
+        xci[0x130..0x138].copy_from_slice(&Xci::ROOT_HFS0_OFFSET.to_le_bytes());
+        xci[0x138..0x140].copy_from_slice(&(root_header_size as u64).to_le_bytes());
+        let root_hash = Sha256::digest(&root[..root_header_size]);
+        xci[0x140..0x160].copy_from_slice(&root_hash);
+        xci.extend(root);
+        let valid_data_end = align_up(xci.len() as u64, MEDIA_UNIT_SIZE) / MEDIA_UNIT_SIZE - 1;
+        xci[0x118..0x120].copy_from_slice(&valid_data_end.to_le_bytes());
+        xci
+    }
+
+    #[test]
+    fn read_xci() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let files: [(&str, &[u8]); 2] = [("a.nca", b"first"), ("b.tik", b"second one")];
+        let path = dir.path().join("game.xci");
+        fs::write(&path, build_xci(&files))?;
+
+        let xci = Xci::try_new(&path)?;
+        assert_eq!(xci.header.offset, 0);
+        assert_eq!(xci.header.card_size, CardSize::Gb1);
+        assert_eq!(xci.header.package_id, 0x1234);
+        assert!(xci
+            .partition(PartitionKind::Update)
+            .unwrap()
+            .entries
+            .is_empty());
+        let secure = xci.partition(PartitionKind::Secure).unwrap();
+        assert_eq!(secure.entries.len(), files.len());
+        for (name, data) in files {
+            let mut read = vec![];
+            xci.open_entry(secure.entry(name).unwrap())?
+                .read_to_end(&mut read)?;
+            assert_eq!(read, data);
+        }
+
+        // Dumps with the key area prepended are read the same way
+        let mut with_key_area = vec![0u8; Xci::KEY_AREA_SIZE as usize];
+        with_key_area.extend(fs::read(&path)?);
+        fs::write(&path, with_key_area)?;
+        let xci = Xci::try_new(&path)?;
+        assert_eq!(xci.header.offset, Xci::KEY_AREA_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn read_xci_rejects_root_hash_mismatch() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut xci = build_xci(&[("a.nca", b"data")]);
+        xci[0x140] ^= 0xFF;
+        let path = dir.path().join("game.xci");
+        fs::write(&path, xci)?;
+        assert!(Xci::try_new(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn card_size_fitting() {
+        assert_eq!(CardSize::fitting(0x3B80_0000), Some(CardSize::Gb1));
+        assert_eq!(CardSize::fitting(0x3B80_0001), Some(CardSize::Gb2));
+        // Advertised capacity isn't usable as a whole
+        assert_eq!(
+            CardSize::fitting(CardSize::Gb4.capacity()),
+            Some(CardSize::Gb8)
+        );
+        assert_eq!(CardSize::fitting(0x7_6200_0001), None);
+    }
+
+    /// An Application NSP holding a Control NCA with TitleKey crypto, and its Ticket if `with_ticket`.
+    fn build_nsp(keyset: &Keyset, dir: &Path, with_ticket: bool) -> Result<PathBuf> {
+        let romfs_dir = dir.join("romfs");
+        fs::create_dir(&romfs_dir)?;
+        let mut nacp = Nacp::read(&mut vec![0u8; Nacp::SIZE].as_slice())?;
+        nacp.set_title(Language::AmericanEnglish, Some("Game"), Some("Publisher"))?;
+        nacp.save(romfs_dir.join("control.nacp"))?;
+
+        let nca_dir = dir.join("ncas");
+        fs::create_dir(&nca_dir)?;
+        let rights_id = [0x01, 0x00, 0xAB, 0xCD, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (control, title_key) = write_control_nca(
+            keyset,
+            &romfs_dir,
+            Some(rights_id),
+            &nca_dir.join("control.nca"),
+        )?;
+        CnmtBuilder::new(0x0100_ABCD_0000_0000, ContentMetaType::Application)
+            .add_nca(&control)?
+            .build(keyset, &nca_dir)?;
+        if with_ticket {
+            fs::write(
+                nca_dir.join(format!("{}.tik", hex::encode(rights_id))),
+                common_ticket(rights_id, title_key),
+            )?;
+        }
+        let nsp_path = dir.join("game.nsp");
+        NspWriter::from_dir(&nca_dir)?.write(&nsp_path)?;
+        Ok(nsp_path)
+    }
+
+    #[test]
+    fn convert_nsp() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let nsp_path = build_nsp(&keyset, dir.path(), true)?;
+        let outdir = dir.path().join("out");
+        let converted = convert_nsp_to_xci(&keyset, &nsp_path, &outdir, dir.path())?;
+
+        let xci = Xci::try_new(&converted.path)?;
+        assert_eq!(xci.path, outdir.join("game.xci"));
+        assert_eq!(xci.header.card_size, CardSize::Gb1);
+        let secure = xci.partition(PartitionKind::Secure).unwrap();
+        assert_eq!(secure.entries.len(), 2);
+        assert!(secure
+            .entries
+            .iter()
+            .all(|entry| ext_matches(&entry.name, "nca")));
+
+        // Control NCA is readable without the Ticket
+        let header_key = keyset.header_key()?;
+        let control = secure
+            .entries
+            .iter()
+            .map(|entry| xci.extract_entry(PartitionKind::Secure, &entry.name, dir.path()))
+            .map(|path| Nca::try_new(header_key, path?))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .find(|nca| nca.header.content_type == nca::ContentType::Control)
+            .unwrap();
+        assert!(!control.header.has_rights_id());
+        let nacp = Nacp::from_control_nca(&control, &keyset, None)?;
+        assert_eq!(nacp.title(Language::AmericanEnglish).name, "Game");
+        Ok(())
+    }
+
+    #[test]
+    fn convert_nsp_without_ticket() -> Result<()> {
+        let keyset = Keyset::parse(TEST_KEYS)?;
+        let dir = tempfile::tempdir()?;
+        let nsp_path = build_nsp(&keyset, dir.path(), false)?;
+        let outdir = dir.path().join("out");
+        assert!(convert_nsp_to_xci(&keyset, &nsp_path, &outdir, dir.path()).is_err());
+        Ok(())
+    }
+}
@@ -5,19 +5,33 @@ use common::{
     defines::{APP_CONFIG_PATH, DEFAULT_PRODKEYS_PATH},
     format::HumanDuration,
     log,
-    utils::{ext_matches, get_disk_free, get_fmt_size, get_paths_size},
+    utils::{ext_matches, get_disk_free, get_fmt_size},
 };
 use config::Config;
 #[cfg(not(feature = "android-proot"))]
-use config::{NcaExtractor, NspExtractor};
+use config::NcaExtractor;
 use console::style;
 use eyre::{bail, eyre, Result};
 use fs_err as fs;
 #[cfg(unix)]
 use hac::backend::{Backend, BackendKind};
 use hac::{
-    utils::{formatted_nsp_rename, pack::pack_fs_data, unpack::unpack_nsp, update::update_nsp},
-    vfs::{nsp::Nsp, validate_program_id, xci::xci_to_nsps},
+    keyset::Keyset,
+    utils::{
+        edit::{edit_control_nca, edit_nacp_dir, NacpEdit},
+        formatted_nsp_rename, nsp_control_data,
+        pack::pack_fs_data,
+        unpack::unpack_nsp,
+        update::update_nsp,
+        verify::verify_package,
+    },
+    vfs::{
+        nacp::StartupUserAccount,
+        nsp::Nsp,
+        split::{package_size, FAT32_PART_SIZE},
+        validate_program_id,
+        xci::{nsp_to_xci, xci_to_nsps},
+    },
 };
 use tracing::{debug, error, info, warn};
 use yanu_cli::opts::{self, YanuCli};
@@ -35,7 +49,12 @@ macro_rules! path_exists {
 
 macro_rules! check_space_with_prompt {
     ($modifier:expr, $paths:expr, $disk_path:expr) => {{
-        let recommended_space = bytesize::ByteSize(get_paths_size($paths)?.as_u64() * $modifier);
+        let mut size = 0;
+        // Split packages are counted as a whole instead of just their first part
+        for path in $paths {
+            size += package_size(path)?;
+        }
+        let recommended_space = bytesize::ByteSize(size * $modifier);
         let available_space = get_disk_free($disk_path)?;
         if recommended_space > available_space {
             warn!(?recommended_space, ?available_space);
@@ -106,18 +125,18 @@ fn run() -> Result<()> {
         }
         info!(?keyfile, "Selected keyfile");
 
-        let default_path = DEFAULT_PRODKEYS_PATH.as_path();
-        fs::create_dir_all(
-            default_path
-                .parent()
-                .ok_or_else(|| eyre!("Failed to find parent"))?,
-        )?;
-        fs::copy(&keyfile, default_path)?;
+        let keyset = Keyset::import(&keyfile)?;
         info!("Copied keys successfully to the C2 ^-^");
         eprintln!(
-            "{} '{}'",
+            "{} '{}' {}",
             style("Copied keys successfully from").green().bold(),
-            keyfile.display()
+            keyfile.display(),
+            style(format!(
+                "(master keys: {})",
+                keyset.master_key_revisions_fmt()
+            ))
+            .bold()
+            .dim()
         )
     }
 
@@ -135,6 +154,9 @@ fn run() -> Result<()> {
                 validate_program_id(program_id)?;
             }
 
+            apply_compression(&mut config, opts.compression);
+            config.split_output |= opts.split;
+
             info!("Started patching!");
             timer = Some(Instant::now());
             let (mut patched, nacp_data, program_id) = update_nsp(
@@ -149,6 +171,7 @@ fn run() -> Result<()> {
                 &nacp_data,
                 &program_id,
                 concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-patched]"),
+                config.language,
             )?;
             eprintln!(
                 "{} '{}'",
@@ -165,15 +188,19 @@ fn run() -> Result<()> {
             // ?let clap do this instead
             path_exists!(
                 Some(&opts.controlnca),
+                opts.ticket.as_ref(),
                 Some(&opts.romfsdir),
                 Some(&opts.exefsdir)
             )?;
 
             validate_program_id(&opts.titleid)?;
+            apply_compression(&mut config, opts.compression);
+            config.split_output |= opts.split;
 
             timer = Some(Instant::now());
             let (mut patched, nacp_data) = pack_fs_data(
                 opts.controlnca,
+                opts.ticket.as_deref(),
                 opts.titleid.clone(),
                 opts.romfsdir,
                 opts.exefsdir,
@@ -185,6 +212,7 @@ fn run() -> Result<()> {
                 &nacp_data,
                 &opts.titleid,
                 concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-packed]"),
+                config.language,
             )?;
             eprintln!(
                 "{} '{}'",
@@ -192,6 +220,65 @@ fn run() -> Result<()> {
                 patched.path.display()
             );
         }
+        Some(opts::Commands::EditNacp(opts)) => {
+            path_exists!(
+                Some(&opts.input),
+                opts.ticket.as_ref(),
+                opts.outdir.as_ref()
+            )?;
+
+            let edit = NacpEdit {
+                name: opts.name,
+                publisher: opts.publisher,
+                languages: opts.language,
+                display_version: opts.display_version,
+                startup_user_account: opts.startup_user_account.map(|account| match account {
+                    opts::StartupUserAccount::None => StartupUserAccount::None,
+                    opts::StartupUserAccount::Required => StartupUserAccount::Required,
+                    opts::StartupUserAccount::RequiredWithNetworkServiceAccountAvailable => {
+                        StartupUserAccount::RequiredWithNetworkServiceAccountAvailable
+                    }
+                }),
+            };
+            if edit.is_empty() {
+                bail!("Nothing to edit, see `yanu-cli edit-nacp --help`");
+            }
+
+            timer = Some(Instant::now());
+            let nacp = if opts.input.is_dir() {
+                let nacp = edit_nacp_dir(&opts.input, &edit)?;
+                eprintln!(
+                    "{} '{}'",
+                    style("Edited NACP in").green().bold(),
+                    opts.input.display()
+                );
+                nacp
+            } else {
+                if !DEFAULT_PRODKEYS_PATH.is_file() {
+                    bail!("Failed to find keyfile");
+                }
+                let (nca, nacp) = edit_control_nca(
+                    &opts.input,
+                    opts.ticket.as_deref(),
+                    &edit,
+                    opts.outdir.unwrap_or(default_outdir()?),
+                    &config,
+                )?;
+                eprintln!(
+                    "{} '{}'",
+                    style("Edited Control NCA created at").green().bold(),
+                    nca.path.display()
+                );
+                nacp
+            };
+            let title = nacp.preferred_title(config.language);
+            eprintln!(
+                "{} {} {}",
+                style(&title.name).bold(),
+                style(format!("v{}", nacp.display_version)).bold().dim(),
+                style(format!("({})", title.publisher)).dim()
+            );
+        }
         Some(opts::Commands::Unpack(opts)) => {
             if !DEFAULT_PRODKEYS_PATH.is_file() {
                 bail!("Failed to find keyfile");
@@ -210,20 +297,33 @@ fn run() -> Result<()> {
                 tempfile::Builder::new()
                     .prefix(prefix)
                     .tempdir_in(std::env::current_dir()?)?
-                    .into_path(),
+                    .keep(),
             );
             timer = Some(Instant::now());
-            unpack_nsp(
-                &mut Nsp::try_new(opts.base)?,
-                opts.update.and_then(|f| Nsp::try_new(f).ok()).as_mut(),
-                &outdir,
-                &config,
-            )?;
+            let mut base = Nsp::try_new(opts.base)?;
+            let mut update = opts.update.and_then(|f| Nsp::try_new(f).ok());
+            unpack_nsp(&mut base, update.as_mut(), &outdir, &config)?;
             eprintln!(
                 "{} '{}'",
                 style("Unpacked to").green().bold(),
                 outdir.display()
             );
+            if let Some(icon_path) = opts.icon {
+                let (_, icon) = nsp_control_data(
+                    update.as_mut().unwrap_or(&mut base),
+                    config.language,
+                    &config.temp_dir,
+                )?;
+                fs::write(
+                    &icon_path,
+                    icon.ok_or_else(|| eyre!("Couldn't find an icon in the Control NCA"))?,
+                )?;
+                eprintln!(
+                    "{} '{}'",
+                    style("Exported icon to").green().bold(),
+                    icon_path.display()
+                );
+            }
         }
         Some(opts::Commands::Convert(opts)) => {
             path_exists!(Some(&opts.file), opts.outdir.as_ref())?;
@@ -233,9 +333,15 @@ fn run() -> Result<()> {
             match opts.kind {
                 opts::ConvertKind::Nsp => {
                     match opts.file.extension().map(|ext| ext.to_ascii_lowercase()) {
-                        Some(ext) if ext == "xci" => {
+                        Some(ext) if ext == "xci" || ext == "xcz" || ext == "xc0" => {
                             timer = Some(Instant::now());
-                            let nsps = xci_to_nsps(opts.file, outdir, &config.temp_dir)?;
+                            let nsps = xci_to_nsps(
+                                opts.file,
+                                outdir,
+                                &config.temp_dir,
+                                (config.split_output || opts.split).then_some(FAT32_PART_SIZE),
+                                config.language,
+                            )?;
                             println!("{}", style("\nPath to converted NSPs:").bold().underlined());
                             for nsp in nsps {
                                 println!(
@@ -258,8 +364,84 @@ fn run() -> Result<()> {
                         None => bail!("Non Unicode chars"),
                     }
                 }
+                opts::ConvertKind::Xci => {
+                    match opts.file.extension().map(|ext| ext.to_ascii_lowercase()) {
+                        Some(ext) if ext == "nsp" || ext == "nsz" || ext == "ns0" => {
+                            timer = Some(Instant::now());
+                            let xci = nsp_to_xci(opts.file, outdir, &config.temp_dir)?;
+                            println!(
+                                "{} '{}' {}",
+                                style("Converted XCI created at").green().bold(),
+                                xci.path.display(),
+                                style(format!(
+                                    "({}, {} card)",
+                                    get_fmt_size(&xci.path).unwrap_or_default(),
+                                    xci.header.card_size
+                                ))
+                                .bold()
+                                .dim()
+                            );
+                        }
+                        Some(ext) => bail!(
+                            "Not supported conversion '{} -> {:?}'",
+                            ext.to_string_lossy(),
+                            opts.kind
+                        ),
+                        None => bail!("Non Unicode chars"),
+                    }
+                }
             }
         }
+        Some(opts::Commands::Verify(opts)) => {
+            if !DEFAULT_PRODKEYS_PATH.is_file() {
+                bail!("Failed to find keyfile");
+            }
+            path_exists!(Some(&opts.file))?;
+
+            timer = Some(Instant::now());
+            let report = verify_package(&opts.file, &config.temp_dir)?;
+            for content in &report.contents {
+                let status = if content.is_ok() {
+                    style(format!("[{}]", content.status)).green().bold()
+                } else {
+                    style(format!("[{}]", content.status)).red().bold()
+                };
+                let signature = match &content.signature {
+                    Some(signature) if signature.is_official() => style("signed").green(),
+                    Some(signature) if signature.is_rebuilt() => style("rebuilt").yellow(),
+                    _ => style("unchecked").dim(),
+                };
+                println!(
+                    "{} {} {} {}",
+                    status,
+                    content.name,
+                    signature,
+                    style(format!(
+                        "({:?}, {}{})",
+                        content.content_type,
+                        content.title_id,
+                        content
+                            .signature
+                            .as_ref()
+                            .map(|signature| format!(", {}", signature))
+                            .unwrap_or_default()
+                    ))
+                    .dim()
+                );
+            }
+            if !report.is_ok() {
+                bail!(
+                    "{} of {} contents failed verification",
+                    report.failed().count(),
+                    report.contents.len()
+                );
+            }
+            eprintln!(
+                "{} '{}'",
+                style("All contents are valid in").green().bold(),
+                opts.file.display()
+            );
+        }
         Some(opts::Commands::Config(opts)) => {
             if let Some(yanu_dir) = opts.yanu_dir {
                 if yanu_dir.is_dir() {
@@ -283,22 +465,22 @@ fn run() -> Result<()> {
             }
 
             #[cfg(not(feature = "android-proot"))]
-            if let Some(nsp_extractor) = opts.nsp_extractor {
+            if let Some(nca_extractor) = opts.nca_extractor {
                 // ? How to do this better? and also not have dup enums
-                config.nsp_extractor = match dbg!(nsp_extractor) {
-                    opts::NspExtractor::Hactoolnet => NspExtractor::Hactoolnet,
-                    opts::NspExtractor::Hactool => NspExtractor::Hactool,
-                };
-            }
-
-            #[cfg(not(feature = "android-proot"))]
-            if let Some(nsp_extractor) = opts.nca_extractor {
-                config.nca_extractor = match dbg!(nsp_extractor) {
+                config.nca_extractor = match dbg!(nca_extractor) {
                     opts::NcaExtractor::Hactoolnet => NcaExtractor::Hactoolnet,
                     opts::NcaExtractor::Hac2l => NcaExtractor::Hac2l,
                 };
             }
 
+            if let Some(language) = opts.language {
+                config.language = language;
+            }
+            apply_compression(&mut config, opts.compression);
+            if let Some(split_output) = opts.split_output {
+                config.split_output = split_output;
+            }
+
             info!("Updating config at '{}'", APP_CONFIG_PATH.display());
             Config::store(config)?;
             eprintln!("{}", style("Successfully modified config").green().bold());
@@ -364,13 +546,7 @@ fn run() -> Result<()> {
                     bail!("Invalid keyfile");
                 }
 
-                let default_path = DEFAULT_PRODKEYS_PATH.as_path();
-                fs::create_dir_all(
-                    default_path
-                        .parent()
-                        .ok_or_else(|| eyre!("Failed to find parent"))?,
-                )?;
-                fs::copy(keyfile_path, default_path)?;
+                Keyset::import(keyfile_path)?;
                 info!("Copied keys successfully to the C2 ^-^");
             }
 
@@ -378,7 +554,12 @@ fn run() -> Result<()> {
                 .min_depth(1)
                 .into_iter()
                 .filter_map(|e| e.ok())
-                .filter(|entry| entry.file_type().is_file() && ext_matches(entry.path(), "nsp"))
+                .filter(|entry| {
+                    entry.file_type().is_file()
+                        && ["nsp", "nsz", "ns0"]
+                            .iter()
+                            .any(|ext| ext_matches(entry.path(), ext))
+                })
                 .collect::<Vec<_>>();
 
             let options = roms_path
@@ -443,6 +624,7 @@ fn run() -> Result<()> {
                     &nacp_data,
                     &program_id,
                     concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-patched]"),
+                    config.language,
                 )?;
                 eprintln!(
                     "{} '{}'",
@@ -461,12 +643,10 @@ fn run() -> Result<()> {
                 res_pool.push(Backend::build(BackendKind::Hacpack));
                 res_pool.push(Backend::build(BackendKind::Hactool));
                 res_pool.push(Backend::build(BackendKind::Hac2l));
-                res_pool.push(Backend::build(BackendKind::FourNXCI));
             } else {
                 res_pool.push(Backend::try_new(BackendKind::Hacpack));
                 res_pool.push(Backend::try_new(BackendKind::Hactool));
                 res_pool.push(Backend::try_new(BackendKind::Hac2l));
-                res_pool.push(Backend::try_new(BackendKind::FourNXCI));
             }
             #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
             res_pool.push(Backend::try_new(BackendKind::Hactoolnet));
@@ -514,3 +694,19 @@ fn default_outdir() -> Result<PathBuf> {
 
     Ok(outdir)
 }
+
+/// Overrides the compression settings in `config` with the ones that were passed.
+fn apply_compression(config: &mut Config, compression: opts::Compression) {
+    if let Some(format) = compression.compress {
+        config.compression = match format {
+            opts::CompressionFormat::None => config::Compression::None,
+            opts::CompressionFormat::Nsz => config::Compression::Nsz,
+        };
+    }
+    if let Some(level) = compression.zstd_level {
+        config.zstd_level = level;
+    }
+    if let Some(workers) = compression.workers {
+        config.compression_workers = workers;
+    }
+}
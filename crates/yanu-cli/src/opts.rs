@@ -1,5 +1,6 @@
 use crate::utils::get_section;
 use clap::{Args, Parser, Subcommand};
+use config::Language;
 use std::path::PathBuf;
 
 const SECTION_PADDING: &str = "  ";
@@ -26,12 +27,18 @@ pub enum Commands {
     /// Pack FS files to NSP
     #[command()]
     Pack(Pack),
+    /// Edit the NACP of a Control NCA or an unpacked Control RomFS
+    #[command()]
+    EditNacp(EditNacp),
     /// Unpack a NSP
     #[command()]
     Unpack(Unpack),
     /// Convert Switch file formats
     #[command()]
     Convert(Convert),
+    /// Verify the NCAs of a NSP/XCI against the hashes in their CNMT
+    #[command()]
+    Verify(Verify),
     /// Manage yanu's config
     #[command(visible_alias = "cfg")]
     Config(Config),
@@ -69,6 +76,11 @@ pub struct Update {
     pub titleid: Option<String>,
     #[arg(short, long, value_name = "DIR")]
     pub outdir: Option<PathBuf>,
+    #[command(flatten)]
+    pub compression: Compression,
+    /// Split the output NSP into FAT32 sized parts, i.e. `.ns0`, `.ns1`, ...
+    #[arg(long, action)]
+    pub split: bool,
 }
 
 #[derive(Debug, Args, Default, PartialEq, Eq)]
@@ -84,6 +96,14 @@ pub struct Pack {
     /// Set Control NCA, it's typically around 1MB in size.
     #[arg(long, value_name = "FILE")]
     pub controlnca: PathBuf,
+    /// Set Ticket of the Control NCA, only needed if it has a RightsID
+    #[arg(
+        long,
+        value_name = "FILE",
+        long_help = "Set Ticket of the Control NCA, only needed if it has a RightsID\n\
+        By default the '.tik' next to the Control NCA is used."
+    )]
+    pub ticket: Option<PathBuf>,
     /// Set TitleID
     #[arg(
         short,
@@ -100,6 +120,85 @@ pub struct Pack {
     pub exefsdir: PathBuf,
     #[arg(short, long, value_name = "DIR")]
     pub outdir: Option<PathBuf>,
+    #[command(flatten)]
+    pub compression: Compression,
+    /// Split the output NSP into FAT32 sized parts, i.e. `.ns0`, `.ns1`, ...
+    #[arg(long, action)]
+    pub split: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum CompressionFormat {
+    #[default]
+    None,
+    /// Compress the Program NCA to a NCZ, making it a NSZ
+    Nsz,
+}
+
+/// Overrides the compression settings from the config.
+#[derive(Debug, Args, Default, PartialEq, Eq)]
+pub struct Compression {
+    /// Output package format
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub compress: Option<CompressionFormat>,
+    /// zstd level to compress with, higher is smaller but slower
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub zstd_level: Option<i32>,
+    /// No. of blocks compressed in parallel, 0 uses all the cores
+    #[arg(long, value_name = "N")]
+    pub workers: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum StartupUserAccount {
+    None,
+    Required,
+    RequiredWithNetworkServiceAccountAvailable,
+}
+
+#[derive(Debug, Args, Default, PartialEq, Eq)]
+#[command(arg_required_else_help = true)]
+#[command(after_long_help = get_section("Examples", r#"For renaming a mod build so it doesn't collide with the retail one:
+$ yanu-cli edit-nacp \
+            --name 'Game (Modded)' \
+            --display-version '1.0.0-mod' \
+            './base+update.xxxxxx/patchdata/control.nca'
+The edited Control NCA can then be passed to `pack` with `--controlnca`.
+"#, SECTION_PADDING))]
+pub struct EditNacp {
+    /// Control NCA or a directory with its unpacked RomFS
+    #[arg(
+        value_name = "PATH",
+        long_help = "Control NCA or a directory with its unpacked RomFS\n\
+        An unpacked RomFS is edited in place, a Control NCA is rebuilt to outdir."
+    )]
+    pub input: PathBuf,
+    /// Set application name
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Set application publisher
+    #[arg(long)]
+    pub publisher: Option<String>,
+    /// Title entries to set name/publisher for, by default all the non-empty ones
+    #[arg(long, value_enum)]
+    pub language: Vec<Language>,
+    /// Set display version
+    #[arg(long)]
+    pub display_version: Option<String>,
+    /// Set whether a user account must be selected at startup
+    #[arg(long, value_enum)]
+    pub startup_user_account: Option<StartupUserAccount>,
+    /// Set Ticket of the Control NCA, only needed if it has a RightsID
+    #[arg(
+        long,
+        value_name = "FILE",
+        long_help = "Set Ticket of the Control NCA, only needed if it has a RightsID\n\
+        By default the '.tik' next to the Control NCA is used."
+    )]
+    pub ticket: Option<PathBuf>,
+    /// Only used for Control NCAs, by default it'll be 'pwd'
+    #[arg(short, long, value_name = "DIR")]
+    pub outdir: Option<PathBuf>,
 }
 
 #[derive(Debug, Args, Default, PartialEq, Eq)]
@@ -117,16 +216,31 @@ pub struct Unpack {
     pub update: Option<PathBuf>,
     #[arg(short, long, value_name = "DIR")]
     pub outdir: Option<PathBuf>,
+    /// Export the icon (JPEG) in the configured language to a file,
+    /// it's of the update if selected
+    #[arg(long, value_name = "FILE")]
+    pub icon: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, PartialEq, Eq)]
+pub struct Verify {
+    /// Input file, NSZ/XCZ and split packages are accepted as well
+    #[arg()]
+    pub file: PathBuf,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum ConvertKind {
     Nsp,
+    Xci,
 }
 
 #[derive(Debug, Args, PartialEq, Eq)]
 #[command(after_help = get_section("Examples", r#"For converting XCI to NSP:
 $ yanu-cli convert --kind nsp gta6.xci
+
+For converting NSP to XCI:
+$ yanu-cli convert --kind xci gta6.nsp
 "#, SECTION_PADDING))]
 pub struct Convert {
     /// File format to convert to
@@ -136,7 +250,8 @@ pub struct Convert {
         value_enum,
         long_help = r#"File format to convert to
 Possible coversions:
-    To nsp: xci"#
+    To nsp: xci, xcz, xc0
+    To xci: nsp, nsz, ns0"#
     )]
     pub kind: ConvertKind,
     /// Input file
@@ -145,14 +260,9 @@ Possible coversions:
     /// By default it'll be 'pwd'
     #[arg(short, long)]
     pub outdir: Option<PathBuf>,
-}
-
-#[cfg(not(feature = "android-proot"))]
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum NspExtractor {
-    #[default]
-    Hactoolnet,
-    Hactool,
+    /// Split the output NSPs into FAT32 sized parts, i.e. `.ns0`, `.ns1`, ...
+    #[arg(long, action)]
+    pub split: bool,
 }
 
 #[cfg(not(feature = "android-proot"))]
@@ -179,8 +289,13 @@ pub struct Config {
     pub temp_dir: Option<PathBuf>,
     #[cfg(not(feature = "android-proot"))]
     #[arg(long, value_enum)]
-    pub nsp_extractor: Option<NspExtractor>,
-    #[cfg(not(feature = "android-proot"))]
-    #[arg(long, value_enum)]
     pub nca_extractor: Option<NcaExtractor>,
+    /// Preferred language for naming NSPs, falls back to English if a title doesn't have it
+    #[arg(long, value_enum)]
+    pub language: Option<Language>,
+    #[command(flatten)]
+    pub compression: Compression,
+    /// Split the output NSPs into FAT32 sized parts by default
+    #[arg(long, value_name = "BOOL")]
+    pub split_output: Option<bool>,
 }
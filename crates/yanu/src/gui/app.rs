@@ -1,21 +1,30 @@
-use std::{path::PathBuf, sync::mpsc::TryRecvError, thread, time::Instant};
+use std::{fmt, path::PathBuf, sync::mpsc::TryRecvError, thread, time::Instant};
 
 use common::{
-    defines::{APP_CACHE_DIR, APP_CONFIG_DIR, DEFAULT_PRODKEYS_PATH, SWITCH_DIR},
+    defines::{APP_CACHE_DIR, APP_CONFIG_DIR, SWITCH_DIR},
     format::HumanDuration,
     utils::get_fmt_size,
 };
-use config::{Config, NcaExtractor, NspExtractor};
+use config::{Compression, Config, Language, NcaExtractor};
 use eframe::egui;
 use egui::RichText;
 use egui_modal::Modal;
 use eyre::{bail, Result};
-use fs_err as fs;
 use hac::{
-    utils::{formatted_nsp_rename, pack::pack_fs_data, unpack::unpack_nsp, update::update_nsp},
-    vfs::{nsp::Nsp, validate_program_id, xci::xci_to_nsps},
+    keyset::Keyset,
+    utils::{
+        formatted_nsp_rename, nsp_control_data, pack::pack_fs_data, unpack::unpack_nsp,
+        update::update_nsp,
+    },
+    vfs::{
+        nacp::Nacp,
+        nsp::Nsp,
+        split::FAT32_PART_SIZE,
+        validate_program_id,
+        xci::{nsp_to_xci, xci_to_nsps, Xci},
+    },
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use super::{cross_centered, increase_font_size_by};
 use crate::{
@@ -39,6 +48,10 @@ pub struct YanuApp {
     // Update/Unpack Page
     base_pkg_path_buf: String,
     update_pkg_path_buf: String,
+    preview: Option<Preview>,
+    /// Base/Update paths the preview was last requested for.
+    preview_paths: (String, String),
+    preview_channel: MpscChannel<PreviewMessage>,
 
     // Pack Page
     control_nca_path_buf: String,
@@ -65,12 +78,14 @@ enum Page {
 enum ConvertKind {
     #[default]
     Nsp,
+    Xci,
 }
 
 impl ConvertKind {
     fn reach_from_types(&self) -> &[&'static str] {
         match self {
-            ConvertKind::Nsp => &["xci"],
+            ConvertKind::Nsp => &["xci", "xcz", "xc0"],
+            ConvertKind::Xci => &["nsp", "nsz", "ns0"],
         }
     }
 }
@@ -78,6 +93,36 @@ impl ConvertKind {
 #[derive(Debug)]
 enum Converted {
     Nsp(Vec<Nsp>),
+    Xci(Xci),
+}
+
+/// Title and icon of the picked Update, to confirm the Base/Update pair before starting.
+struct Preview {
+    name: String,
+    publisher: String,
+    version: String,
+    /// Title of the Base if it doesn't match the Update's.
+    base_name: Option<String>,
+    icon: Option<egui::TextureHandle>,
+}
+
+impl fmt::Debug for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Preview")
+            .field("name", &self.name)
+            .field("publisher", &self.publisher)
+            .field("version", &self.version)
+            .field("base_name", &self.base_name)
+            .field("icon", &self.icon.as_ref().map(|icon| icon.id()))
+            .finish()
+    }
+}
+
+/// NACPs of the Base and Update along with the decoded icon of the Update.
+#[derive(Debug)]
+struct PreviewMessage {
+    paths: (String, String),
+    data: Result<(Nacp, Nacp, Option<egui::ColorImage>)>,
 }
 
 #[derive(Debug)]
@@ -114,6 +159,7 @@ impl YanuApp {
 const HEADING_SIZE: f32 = 21.6; // 1.2x of default
 const BODY_SIZE: f32 = 12.5; // 1.2x of default
 const PADDING: f32 = BODY_SIZE * 0.5; // 0.5x of default Body size
+const ICON_SIZE: f32 = 64.;
 
 impl eframe::App for YanuApp {
     /// Called by the frame work to save state before shutdown.
@@ -137,6 +183,13 @@ impl eframe::App for YanuApp {
 
         show_top_bar(ctx, frame, &dialog_modal, &mut self.config, &self.page);
 
+        if matches!(self.page, Page::Update | Page::Unpack) {
+            self.request_preview(ctx);
+        }
+        if let Ok(message) = self.preview_channel.rx.try_recv() {
+            self.set_preview(ctx, message);
+        }
+
         if self.page != Page::Loading {
             egui::SidePanel::left("options panel")
                 .resizable(false)
@@ -191,6 +244,11 @@ impl eframe::App for YanuApp {
                                 };
                             });
 
+                            if let Some(preview) = &self.preview {
+                                ui.add_space(PADDING);
+                                show_preview(ui, preview);
+                            }
+
                             ui.add_space(PADDING);
 
                             ui.checkbox(&mut self.overwrite_titleid, "Overwrite TitleID");
@@ -204,6 +262,8 @@ impl eframe::App for YanuApp {
                                                                 ACI0 TitleID: xxxxxxxxxxxxxxxx'",
                                 );
                             }
+
+                            show_output_options(ui, &mut self.config);
                         });
                     });
 
@@ -244,6 +304,11 @@ impl eframe::App for YanuApp {
                                     });
                                 };
                             });
+
+                            if let Some(preview) = &self.preview {
+                                ui.add_space(PADDING);
+                                show_preview(ui, preview);
+                            }
                         });
                     });
 
@@ -336,6 +401,10 @@ impl eframe::App for YanuApp {
                                     );
                                 };
                             });
+
+                            ui.add_space(PADDING);
+
+                            show_output_options(ui, &mut self.config);
                         });
                     });
 
@@ -386,8 +455,19 @@ impl eframe::App for YanuApp {
                                             ConvertKind::Nsp,
                                             "Nsp",
                                         );
+                                        ui.selectable_value(
+                                            &mut self.convert_kind,
+                                            ConvertKind::Xci,
+                                            "Xci",
+                                        );
                                     });
                             });
+
+                            if self.convert_kind == ConvertKind::Nsp {
+                                ui.add_space(PADDING);
+
+                                show_split_checkbox(ui, &mut self.config);
+                            }
                         });
                     });
 
@@ -501,6 +581,17 @@ impl eframe::App for YanuApp {
                                                         Some(egui_modal::Icon::Success),
                                                     );
                                                 },
+                                                Converted::Xci(xci) => {
+                                                    dialog_modal.open_dialog(
+                                                        None::<&str>,
+                                                        Some(format!(
+                                                            "Converted XCI ({} card):\n- \"{}\"",
+                                                            xci.header.card_size,
+                                                            xci.path.file_name().unwrap_or_default().to_string_lossy()
+                                                        )),
+                                                        Some(egui_modal::Icon::Success),
+                                                    );
+                                                },
                                             }
                                         }
                                     );
@@ -537,7 +628,7 @@ fn show_top_bar(
                             ui.close_menu();
                             consume_err(
                                 dialog_modal,
-                                || -> Result<PathBuf> {
+                                || -> Result<(PathBuf, Keyset)> {
                                     let keyfile_path = rfd::FileDialog::new()
                                         .set_title("Pick a Keyfile")
                                         .add_filter("Keyfile", &["keys"])
@@ -546,18 +637,17 @@ fn show_top_bar(
                                     info!(?keyfile_path, "Picked keyfile");
                                     assert!(keyfile_path.is_file());
 
-                                    let dest = DEFAULT_PRODKEYS_PATH.as_path();
-                                    fs::create_dir_all(
-                                        dest.parent()
-                                            .ok_or_else(|| eyre::eyre!("Failed to get parent"))?,
-                                    )?;
-                                    fs::copy(keyfile_path.as_path(), dest)?;
-                                    Ok(keyfile_path)
+                                    let keyset = Keyset::import(keyfile_path.as_path())?;
+                                    Ok((keyfile_path, keyset))
                                 }(),
-                                |keyfile_path| {
+                                |(keyfile_path, keyset)| {
                                     dialog_modal.open_dialog(
                                         None::<&str>,
-                                        Some(format!("Imported '{}'", keyfile_path.display())),
+                                        Some(format!(
+                                            "Imported '{}'\nMaster keys: {}",
+                                            keyfile_path.display(),
+                                            keyset.master_key_revisions_fmt()
+                                        )),
                                         Some(egui_modal::Icon::Success),
                                     );
                                 },
@@ -626,18 +716,6 @@ fn show_top_bar(
                         })
                         .response
                         .on_hover_text("Temp dirs will be created in this folder");
-                        ui.menu_button("NSP Extractor", |ui| {
-                            ui.radio_value(
-                                &mut config.nsp_extractor,
-                                NspExtractor::Hactool,
-                                "Hactool",
-                            );
-                            ui.radio_value(
-                                &mut config.nsp_extractor,
-                                NspExtractor::Hactoolnet,
-                                "Hactoolnet",
-                            );
-                        });
                         ui.menu_button("NCA Extractor", |ui| {
                             ui.radio_value(&mut config.nca_extractor, NcaExtractor::Hac2l, "Hac2l");
                             ui.radio_value(
@@ -646,6 +724,30 @@ fn show_top_bar(
                                 "Hactoolnet",
                             );
                         });
+                        ui.menu_button("Title Language", |ui| {
+                            for language in Language::ALL {
+                                ui.radio_value(
+                                    &mut config.language,
+                                    language,
+                                    format!("{:?}", language),
+                                );
+                            }
+                        })
+                        .response
+                        .on_hover_text(
+                            "Used for naming NSPs, falls back to English if a title doesn't have it",
+                        );
+                        ui.menu_button("NSZ Compression", |ui| {
+                            ui.add(
+                                egui::Slider::new(&mut config.zstd_level, 1..=22).text("zstd level"),
+                            )
+                            .on_hover_text("Higher is smaller but slower");
+                            ui.add(
+                                egui::Slider::new(&mut config.compression_workers, 0..=64)
+                                    .text("Workers"),
+                            )
+                            .on_hover_text("No. of blocks compressed in parallel, 0 uses all the cores");
+                        });
                     });
                 });
             });
@@ -663,7 +765,107 @@ fn show_top_bar(
     });
 }
 
+fn show_output_options(ui: &mut egui::Ui, config: &mut Config) {
+    let mut compress = config.compression == Compression::Nsz;
+    if ui
+        .checkbox(&mut compress, "Compress to NSZ")
+        .on_hover_text("Compresses the Program NCA, set the level in Config")
+        .changed()
+    {
+        config.compression = if compress {
+            Compression::Nsz
+        } else {
+            Compression::None
+        };
+    }
+    show_split_checkbox(ui, config);
+}
+
+fn show_preview(ui: &mut egui::Ui, preview: &Preview) {
+    ui.horizontal(|ui| {
+        if let Some(icon) = &preview.icon {
+            ui.image(icon, egui::vec2(ICON_SIZE, ICON_SIZE));
+        }
+        ui.vertical(|ui| {
+            ui.label(RichText::new(&preview.name).strong());
+            ui.label(RichText::new(format!("v{}", preview.version)).weak());
+            ui.label(RichText::new(&preview.publisher).weak());
+            if let Some(base_name) = &preview.base_name {
+                ui.label(
+                    RichText::new(format!("⚠ Base is '{}'", base_name))
+                        .color(egui::Color32::YELLOW),
+                );
+            }
+        });
+    });
+}
+
+fn show_split_checkbox(ui: &mut egui::Ui, config: &mut Config) {
+    ui.checkbox(&mut config.split_output, "Split for FAT32")
+        .on_hover_text("Splits NSPs larger than 4GiB into .ns0, .ns1, ... parts");
+}
+
 impl YanuApp {
+    /// Reads the title and icon of the picked packages in the background,
+    /// whenever the Base/Update paths change.
+    fn request_preview(&mut self, ctx: &egui::Context) {
+        let paths = (
+            self.base_pkg_path_buf.clone(),
+            self.update_pkg_path_buf.clone(),
+        );
+        if paths == self.preview_paths {
+            return;
+        }
+        self.preview_paths = paths.clone();
+        self.preview = None;
+        if paths.0.is_empty() || paths.1.is_empty() || check_keyfile_exists().is_err() {
+            return;
+        }
+
+        let language = self.config.language;
+        let tempdir_in = self.config.temp_dir.clone();
+        let tx = self.preview_channel.tx.clone();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let data = || -> Result<(Nacp, Nacp, Option<egui::ColorImage>)> {
+                let (base_nacp, _) =
+                    nsp_control_data(&mut Nsp::try_new(&paths.0)?, language, &tempdir_in)?;
+                let (nacp, icon) =
+                    nsp_control_data(&mut Nsp::try_new(&paths.1)?, language, &tempdir_in)?;
+                let icon = icon.and_then(|icon| {
+                    egui_extras::image::load_image_bytes(&icon)
+                        .map_err(|err| warn!(%err, "Failed to decode icon"))
+                        .ok()
+                });
+                Ok((base_nacp, nacp, icon))
+            }();
+            if tx.send(PreviewMessage { paths, data }).is_ok() {
+                ctx.request_repaint();
+            }
+        });
+    }
+    fn set_preview(&mut self, ctx: &egui::Context, message: PreviewMessage) {
+        // Paths were changed again while it was being read
+        if message.paths != self.preview_paths {
+            return;
+        }
+        match message.data {
+            Ok((base_nacp, nacp, icon)) => {
+                let language = self.config.language;
+                let title = nacp.preferred_title(language);
+                let base_title = base_nacp.preferred_title(language);
+                self.preview = Some(Preview {
+                    name: title.name.clone(),
+                    publisher: title.publisher.clone(),
+                    version: nacp.display_version.clone(),
+                    base_name: (base_title.name != title.name).then(|| base_title.name.clone()),
+                    icon: icon
+                        .map(|icon| ctx.load_texture("preview icon", icon, Default::default())),
+                });
+            }
+            Err(err) => warn!(%err, "Failed to read the title of the picked packages"),
+        }
+    }
     fn do_update(&mut self, dialog_modal: &Modal) {
         if let Err(err) = || -> Result<()> {
             check_keyfile_exists()?;
@@ -701,6 +903,7 @@ impl YanuApp {
                         &nacp_data,
                         &program_id,
                         concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-patched]"),
+                        config.language,
                     )?;
                     Ok(patched)
                 }()))
@@ -740,7 +943,7 @@ impl YanuApp {
             let outdir = tempfile::Builder::new()
                 .prefix(prefix)
                 .tempdir_in(std::env::current_dir()?)?
-                .into_path();
+                .keep();
 
             let config = self.config.clone();
             let tx = self.channel.tx.clone();
@@ -793,6 +996,7 @@ impl YanuApp {
                 tx.send(Message::Pack(|| -> Result<Nsp> {
                     let (mut patched, nacp_data) = pack_fs_data(
                         control_path,
+                        None,
                         program_id.clone(),
                         romfs_dir,
                         exefs_dir,
@@ -804,6 +1008,7 @@ impl YanuApp {
                         &nacp_data,
                         &program_id,
                         concat!("[yanu-", env!("CARGO_PKG_VERSION"), "-packed]"),
+                        config.language,
                     )?;
                     Ok(patched)
                 }()))
@@ -845,14 +1050,29 @@ impl YanuApp {
             let convert_kind = self.convert_kind;
             let outdir = default_pack_outdir()?;
             let tempdir_in = self.config.temp_dir.clone();
+            let split_output = self.config.split_output;
+            let language = self.config.language;
 
             let tx = self.channel.tx.clone();
             thread::spawn(move || {
                 tx.send(Message::Convert(|| -> Result<Converted> {
                     let converted = match convert_kind {
                         ConvertKind::Nsp => match source_path.extension() {
-                            Some(ext) if ext == "xci" => {
-                                Converted::Nsp(xci_to_nsps(source_path, outdir, tempdir_in)?)
+                            Some(ext) if ext == "xci" || ext == "xcz" || ext == "xc0" => {
+                                Converted::Nsp(xci_to_nsps(
+                                    source_path,
+                                    outdir,
+                                    tempdir_in,
+                                    split_output.then_some(FAT32_PART_SIZE),
+                                    language,
+                                )?)
+                            }
+                            Some(_) => bail!("Need to implement"),
+                            None => bail!("Non Unicode in the path"),
+                        },
+                        ConvertKind::Xci => match source_path.extension() {
+                            Some(ext) if ext == "nsp" || ext == "nsz" || ext == "ns0" => {
+                                Converted::Xci(nsp_to_xci(source_path, outdir, tempdir_in)?)
                             }
                             Some(_) => bail!("Need to implement"),
                             None => bail!("Non Unicode in the path"),
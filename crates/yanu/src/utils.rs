@@ -52,7 +52,7 @@ pub fn consume_err_or<T>(
 }
 
 pub fn pick_nsp_file(dialog_modal: &Modal, title: Option<&str>, on_success: impl FnOnce(PathBuf)) {
-    let mut dialog = rfd::FileDialog::new().add_filter("NSP", &["nsp"]);
+    let mut dialog = rfd::FileDialog::new().add_filter("NSP", &["nsp", "nsz", "ns0"]);
     if let Some(title) = title {
         dialog = dialog.set_title(title);
     }